@@ -0,0 +1,82 @@
+/*
+ * Yulvon runtime support library.
+ *
+ * Linked into every native executable produced by `yulc build`. Keep this file
+ * free of dependencies beyond libc; the compiler embeds it and builds it with
+ * the system C compiler at link time.
+ */
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+void yul_print_int(int64_t value) {
+    printf("%lld\n", (long long)value);
+}
+
+void yul_print_uint(uint64_t value) {
+    printf("%llu\n", (unsigned long long)value);
+}
+
+void yul_print_float(double value) {
+    printf("%g\n", value);
+}
+
+void yul_print_bool(int8_t value) {
+    puts(value ? "true" : "false");
+}
+
+/* Writes a Unicode scalar value as UTF-8. */
+void yul_print_char(uint32_t c) {
+    char buf[5] = {0};
+    if (c < 0x80) {
+        buf[0] = (char)c;
+    } else if (c < 0x800) {
+        buf[0] = (char)(0xC0 | (c >> 6));
+        buf[1] = (char)(0x80 | (c & 0x3F));
+    } else if (c < 0x10000) {
+        buf[0] = (char)(0xE0 | (c >> 12));
+        buf[1] = (char)(0x80 | ((c >> 6) & 0x3F));
+        buf[2] = (char)(0x80 | (c & 0x3F));
+    } else {
+        buf[0] = (char)(0xF0 | (c >> 18));
+        buf[1] = (char)(0x80 | ((c >> 12) & 0x3F));
+        buf[2] = (char)(0x80 | ((c >> 6) & 0x3F));
+        buf[3] = (char)(0x80 | (c & 0x3F));
+    }
+    puts(buf);
+}
+
+void yul_print_str(const char *data, int64_t len) {
+    fwrite(data, 1, (size_t)len, stdout);
+    fputc('\n', stdout);
+}
+
+/* Heap memory for `alloc`: zeroed, and never NULL; running out of memory
+ * aborts instead. */
+void *yul_alloc(int64_t size) {
+    void *memory = calloc(1, size > 0 ? (size_t)size : 1);
+    if (memory == NULL) {
+        fflush(stdout);
+        fputs("panic: out of memory\n", stderr);
+        abort();
+    }
+    return memory;
+}
+
+void yul_free(void *memory) {
+    free(memory);
+}
+
+/* Called by bounds checks that failed; never returns. */
+void yul_panic_index(int64_t index, int64_t len) {
+    fflush(stdout);
+    fprintf(stderr, "panic: index %lld is out of bounds for length %lld\n", (long long)index, (long long)len);
+    abort();
+}
+
+void yul_panic_range(int64_t start, int64_t end, int64_t len) {
+    fflush(stdout);
+    fprintf(stderr, "panic: range %lld..%lld is out of bounds for length %lld\n",
+            (long long)start, (long long)end, (long long)len);
+    abort();
+}
@@ -1,26 +1,38 @@
 use crate::ast::*;
 use crate::ir::*;
+use crate::source_map::{Location, SourceMap};
+use std::fmt;
 
+/// An optimization suggestion about the code at `location`.
 pub struct Hint {
     pub message: String,
-    pub location: Option<String>,
+    pub location: Location,
 }
 
-pub fn analyze_ast(program: &Program) -> Vec<Hint> {
+impl fmt::Display for Hint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: hint: {}", self.location, self.message)
+    }
+}
+
+pub fn analyze_ast(program: &Program, sources: &SourceMap) -> Vec<Hint> {
     let mut hints = Vec::new();
     for func in &program.functions {
         if func.body.len() > 100 {
             hints.push(Hint {
                 message: format!("Function '{}' is large; consider splitting for better cache locality.", func.name),
-                location: Some(func.name.clone()),
+                location: sources.location(func.span),
             });
         }
         for stmt in &func.body {
-            if let Stmt::Let { name, expr } = stmt {
-                if let Expr::Int(0) = expr {
+            if let StmtKind::Let { name, expr, .. } = &stmt.kind {
+                if let ExprKind::Int { value: 0, .. } = expr.kind {
                     hints.push(Hint {
-                        message: format!("Variable '{}' initialized to 0; check if zero-initialization is needed.", name),
-                        location: Some(name.clone()),
+                        message: format!(
+                            "Variable '{}' initialized to 0; check if zero-initialization is needed.",
+                            source_name(name)
+                        ),
+                        location: sources.location(stmt.span),
                     });
                 }
             }
@@ -29,15 +41,38 @@ pub fn analyze_ast(program: &Program) -> Vec<Hint> {
     hints
 }
 
-pub fn analyze_ir(ir: &IrModule) -> Vec<Hint> {
+pub fn analyze_ir(ir: &IrModule, sources: &SourceMap) -> Vec<Hint> {
     let mut hints = Vec::new();
     for func in &ir.functions {
-        if func.body.len() > 200 {
+        let inst_count: usize = func.blocks.iter().map(|b| b.body.len()).sum();
+        if inst_count > 200 {
             hints.push(Hint {
                 message: format!("IR function '{}' is very large; consider inlining or splitting.", func.name),
-                location: Some(func.name.clone()),
+                location: sources.location(func.span),
             });
         }
     }
     hints
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lex_at;
+    use crate::parser::Parser;
+
+    #[test]
+    fn hints_point_into_their_file() {
+        let mut sources = SourceMap::new();
+        sources.add_file("main.yul", "fn main() {}\n");
+        let source = "fn f() {\n    let count = 0\n}\n";
+        let start = sources.add_file("f.yul", source);
+        let program = Parser::new(&lex_at(source, start)).parse_program();
+        let hints = analyze_ast(&program, &sources);
+        assert_eq!(hints.len(), 1);
+        assert_eq!(
+            hints[0].to_string(),
+            "f.yul:2:5: hint: Variable 'count' initialized to 0; check if zero-initialization is needed."
+        );
+    }
+}
@@ -1,8 +1,142 @@
-#[derive(Debug, Clone)]
+pub use crate::types::Type;
+
+/// Byte range `start..end` into the program's source text; `SourceMap`
+/// turns it into a file, line and column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+    /// Smallest span covering both `self` and `other`.
+    pub fn to(self, other: Span) -> Span {
+        Span::new(self.start.min(other.start), self.end.max(other.end))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct Program {
+    pub structs: Vec<StructDef>,
+    pub enums: Vec<EnumDef>,
     pub functions: Vec<Function>,
     pub externs: Vec<ExternFunction>,
     pub macros: Vec<MacroDef>,
+    pub traits: Vec<TraitDef>,
+    pub impls: Vec<ImplDef>,
+    pub mods: Vec<ModDecl>,
+    pub imports: Vec<Import>,
+    /// The code macro expansion made, in the order it was made.
+    pub expansions: Vec<Expansion>,
+}
+
+/// Code that macro expansion made: its spans lie in `region`, a copy of a
+/// file's text in the source map, and it runs as if it were at the position
+/// `at`, which may itself lie in an earlier expansion.
+#[derive(Debug, Clone)]
+pub struct Expansion {
+    pub region: Span,
+    pub at: usize,
+}
+
+/// `mod name`: declares the submodule `name`, whose items are in the file
+/// `name.yul` or `name/mod.yul` in this module's directory below the package
+/// root.
+#[derive(Debug, Clone)]
+pub struct ModDecl {
+    pub name: String,
+    /// Other modules may use paths through it, not just this one and its submodules.
+    pub is_pub: bool,
+    pub span: Span,
+}
+
+/// `import a::b::name`: makes the item or module the path names usable as
+/// `name` in this module.
+#[derive(Debug, Clone)]
+pub struct Import {
+    pub path: Vec<String>,
+    pub span: Span,
+}
+
+/// `struct Name<T, ...> { field: Type, ... }`, optionally prefixed with `packed`.
+#[derive(Debug, Clone)]
+pub struct StructDef {
+    pub name: String,
+    /// Names of the type parameters, which field types may use.
+    pub type_params: Vec<String>,
+    pub fields: Vec<Field>,
+    /// Lay the fields out without padding instead of at their natural alignment.
+    pub packed: bool,
+    pub is_pub: bool,
+    pub doc: Option<String>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub struct Field {
+    pub name: String,
+    pub ty: Type,
+    pub span: Span,
+}
+
+/// `enum Name<T, ...> { Variant, Variant(Type, ...), ... }`: a tagged union.
+#[derive(Debug, Clone)]
+pub struct EnumDef {
+    pub name: String,
+    /// Names of the type parameters, which variant fields may use as types.
+    pub type_params: Vec<String>,
+    pub variants: Vec<Variant>,
+    pub is_pub: bool,
+    pub doc: Option<String>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub struct Variant {
+    pub name: String,
+    /// Payload types; empty for a variant without data.
+    pub fields: Vec<Type>,
+    pub span: Span,
+}
+
+/// `trait Name { fn method(self, ...) -> Type ... }`: methods a type
+/// provides by implementing the trait, in which `Self` is that type.
+#[derive(Debug, Clone)]
+pub struct TraitDef {
+    pub name: String,
+    pub methods: Vec<TraitMethod>,
+    pub is_pub: bool,
+    pub doc: Option<String>,
+    pub span: Span,
+}
+
+/// A method a trait declares. One with a body is a default that impls may
+/// leave out; the others only have a signature and an empty `body`.
+#[derive(Debug, Clone)]
+pub struct TraitMethod {
+    pub func: Function,
+    pub has_default: bool,
+}
+
+/// `impl Trait for Type { fn ... }`, or `impl Type { fn ... }` for methods
+/// of the type alone.
+#[derive(Debug, Clone)]
+pub struct ImplDef {
+    pub trait_name: Option<String>,
+    pub ty: Type,
+    pub methods: Vec<Function>,
+    pub doc: Option<String>,
+    pub span: Span,
+}
+
+impl ImplDef {
+    /// `ty` with `Self` replaced by the implementing type.
+    pub fn resolve_self(&self, ty: &Type) -> Type {
+        ty.substitute(&["Self".to_string()], std::slice::from_ref(&self.ty))
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -10,41 +144,247 @@ pub struct MacroDef {
     pub name: String,
     pub params: Vec<String>,
     pub body: Vec<Stmt>,
+    pub is_pub: bool,
+    /// Text of the `///` comments preceding the item, one line per comment.
+    pub doc: Option<String>,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone)]
 pub struct ExternFunction {
     pub name: String,
     pub params: Vec<Param>,
-    pub ret_ty: String,
+    pub ret_ty: Type,
+    pub is_pub: bool,
+    pub doc: Option<String>,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone)]
 pub struct Function {
     pub name: String,
+    /// Names of the type parameters of a generic function, which its
+    /// signature and body may use as types.
+    pub type_params: Vec<String>,
+    /// `T: Trait` requirements on the type parameters, as (parameter, trait) pairs.
+    pub bounds: Vec<(String, String)>,
+    /// Methods take `self` first, with the type `Self`.
     pub params: Vec<Param>,
+    pub ret_ty: Option<Type>,
     pub body: Vec<Stmt>,
     pub is_async: bool,
+    /// Declared `unsafe fn`: its body may do what needs an `unsafe` block,
+    /// and calls of it need one.
+    pub is_unsafe: bool,
+    /// Declared `pub`: usable from other modules, not only from this one and
+    /// its submodules.
+    pub is_pub: bool,
+    pub doc: Option<String>,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone)]
 pub struct Param {
     pub name: String,
-    pub ty: String,
+    pub ty: Type,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone)]
-pub enum Stmt {
-    Let { name: String, expr: Expr },
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span,
+}
+
+impl Stmt {
+    pub fn new(kind: StmtKind, span: Span) -> Self {
+        Self { kind, span }
+    }
+}
+
+/// A variable's name as written. Name resolution renames a variable that
+/// reuses the name of an earlier one in its body to `name#n`.
+pub fn source_name(var: &str) -> &str {
+    var.split_once('#').map_or(var, |(name, _)| name)
+}
+
+#[derive(Debug, Clone)]
+pub enum StmtKind {
+    /// `let name: ty = expr;` or `let mut name: ty = expr;`, where the type
+    /// may be left out.
+    Let { name: String, mutable: bool, ty: Option<Type>, expr: Expr },
     Expr(Expr),
+    /// `target = value;` where `target` is a place such as `x` or `v.x`.
+    Assign { target: Expr, value: Expr },
     MacroInvoke { name: String, args: Vec<Expr> },
+    If { cond: Expr, then_body: Vec<Stmt>, else_body: Option<Vec<Stmt>> },
+    While { cond: Expr, body: Vec<Stmt> },
+    Loop { body: Vec<Stmt> },
+    Break,
+    Continue,
+    Return(Option<Expr>),
+    /// `unsafe { ... }`: a block that may use raw pointers.
+    Unsafe(Vec<Stmt>),
+    /// `{ ... }`: a block whose variables go out of scope at its end.
+    Block(Vec<Stmt>),
+    /// Placeholder for a statement that failed to parse. Only present when
+    /// the parser reported errors, so later passes never see it.
+    Error,
 }
 
 #[derive(Debug, Clone)]
-pub enum Expr {
-    Int(i64),
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        Self { kind, span }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ExprKind {
+    /// Integer literal; `ty` is the explicit suffix (`42u8`), if any.
+    Int { value: u64, ty: Option<String> },
+    Float { value: f64, ty: Option<String> },
+    Bool(bool),
+    Char(char),
+    Str(String),
+    /// A variable or a function; before name resolution also a path such as
+    /// `math::sqrt` or `Enum::Variant`.
     Ident(String),
-    Call { name: String, args: Vec<Expr> },
+    /// `name(args)`: a call of the function `name`, or of the function
+    /// pointer or closure in the variable `name`. Before name resolution
+    /// `name` may be a path, which can also name a variant. `name<T, ...>(args)`
+    /// gives the type arguments of a generic function instead of inferring them.
+    Call { name: String, type_args: Vec<Type>, args: Vec<Expr> },
+    /// `callee(args)` for any other callee, such as `(s.handler)(x)`: a call
+    /// through a function pointer or closure.
+    CallValue { callee: Box<Expr>, args: Vec<Expr> },
+    /// `receiver.method(args)`; a reference or pointer receiver is read through.
+    MethodCall { receiver: Box<Expr>, method: String, args: Vec<Expr> },
+    /// `Name { field: value, ... }`
+    StructLit { name: String, fields: Vec<(String, Expr)> },
+    /// `expr.field`; also reads through a reference or pointer to a struct.
+    Field { expr: Box<Expr>, field: String },
+    /// `&place`, or `&mut place` when `mutable` is set.
+    AddrOf { expr: Box<Expr>, mutable: bool },
+    /// `*expr`: the value a reference or pointer refers to.
+    Deref(Box<Expr>),
+    /// `[a, b, ...]`
+    ArrayLit(Vec<Expr>),
+    /// `[value; count]`: an array of `count` copies of `value`.
+    ArrayRepeat { value: Box<Expr>, count: u64 },
+    /// `expr[index]`: an element of an array or slice; also reads through a
+    /// reference or pointer to an array.
+    Index { expr: Box<Expr>, index: Box<Expr> },
+    /// `expr[start..end]`, where either bound may be left out: a slice of an
+    /// array or of another slice.
+    Slice { expr: Box<Expr>, start: Option<Box<Expr>>, end: Option<Box<Expr>> },
+    /// `Enum::Variant` or `Enum::Variant(args...)`; name resolution makes these
+    /// from the `Ident` and `Call` paths that name a variant.
+    Variant { enum_name: String, variant: String, args: Vec<Expr> },
+    Match { scrutinee: Box<Expr>, arms: Vec<MatchArm> },
+    Binary { op: BinOp, lhs: Box<Expr>, rhs: Box<Expr> },
+    Unary { op: UnaryOp, expr: Box<Expr> },
+    /// `expr?`: unwraps `Some`/`Ok`, or returns `None`/`Err` from the function.
+    Try(Box<Expr>),
     Await(Box<Expr>),
     Spawn(Box<Expr>),
+    Lambda(Box<Lambda>),
+}
+
+/// `|a: T, b| expr`, or `|a: T| -> R { ... }` for a body that is a block.
+/// Captured variables are referred to in place, or copied into the closure
+/// when it is written `move |...| ...`.
+#[derive(Debug, Clone)]
+pub struct Lambda {
+    pub params: Vec<LambdaParam>,
+    pub ret_ty: Option<Type>,
+    pub body: LambdaBody,
+    pub by_value: bool,
+}
+
+/// A closure parameter; its type may be left out where the closure is
+/// passed or assigned to a place of known function type.
+#[derive(Debug, Clone)]
+pub struct LambdaParam {
+    pub name: String,
+    pub ty: Option<Type>,
+    pub span: Span,
+}
+
+/// A closure returns the value of its expression body; a block body returns
+/// with `return`, like a function's.
+#[derive(Debug, Clone)]
+pub enum LambdaBody {
+    Expr(Box<Expr>),
+    Block(Vec<Stmt>),
+}
+
+#[derive(Debug, Clone)]
+pub struct MatchArm {
+    pub pattern: Pattern,
+    pub body: ArmBody,
+    pub span: Span,
+}
+
+/// An arm either yields the value of an expression or runs a block; a
+/// `match` with any block arm produces no value.
+#[derive(Debug, Clone)]
+pub enum ArmBody {
+    Expr(Expr),
+    Block(Vec<Stmt>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    pub kind: PatternKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub enum PatternKind {
+    /// `_`
+    Wildcard,
+    /// A name that binds the matched value.
+    Binding(String),
+    /// An integer (possibly negated), `char` or `bool` literal expression.
+    Literal(Expr),
+    /// `Enum::Variant` or `Enum::Variant(patterns...)`
+    Variant { enum_name: String, variant: String, fields: Vec<Pattern> },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+}
+
+impl BinOp {
+    pub fn is_comparison(self) -> bool {
+        matches!(self, BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge)
+    }
+    pub fn is_logical(self) -> bool {
+        matches!(self, BinOp::And | BinOp::Or)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
 }
@@ -0,0 +1,1132 @@
+use crate::ast::*;
+use crate::bail_at;
+use crate::error::SpannedError;
+use crate::semantic::TypeTable;
+use anyhow::Result;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// Whether values of a type can hold references whose borrows have to be
+/// tracked while the value is around.
+pub type HoldsReferences<'a> = &'a dyn Fn(&Type) -> bool;
+
+/// Checks ownership and borrowing in a function body that type checking
+/// accepted: that moved values are not used again, that nothing is used in
+/// a way a live borrow of it forbids, and that references to the
+/// function's variables do not outlive it.
+///
+/// The environment of a `move` closure can live in the frame of the function
+/// creating it, which the closure then borrows. Returned are the spans of
+/// the `move` closures that cannot: those that outlive that function, and
+/// those created again while the closure from the time before is in use.
+pub fn check_function(
+    body: &[Stmt],
+    types: &TypeTable,
+    holds_references: HoldsReferences,
+    expansions: &[Expansion],
+) -> Result<HashSet<Span>> {
+    let order = Order(expansions);
+    let live = Liveness::of(types, order, |uses| uses.block(body));
+    let mut checker = Checker::new(live, Vec::new(), types, holds_references, order);
+    checker.block(body)?;
+    Ok(checker.escaping)
+}
+
+/// A step from a value to part of it or to what it refers to.
+#[derive(Debug, Clone, PartialEq)]
+enum Proj {
+    Field(String),
+    /// Some element of an array or slice; all of them count as the same one.
+    Index,
+    Deref,
+}
+
+/// Storage reached from a variable.
+#[derive(Debug, Clone, PartialEq)]
+struct Place {
+    var: String,
+    projs: Vec<Proj>,
+}
+
+impl Place {
+    fn var(name: &str) -> Self {
+        Place { var: name.to_string(), projs: Vec::new() }
+    }
+
+    fn project(mut self, proj: Proj) -> Self {
+        self.projs.push(proj);
+        self
+    }
+
+    /// Whether the two places may share storage, as when one contains the other.
+    fn overlaps(&self, other: &Place) -> bool {
+        self.var == other.var && self.projs.iter().zip(&other.projs).all(|(a, b)| a == b)
+    }
+
+    /// Whether `self` is part of `other` or all of it.
+    fn within(&self, other: &Place) -> bool {
+        self.overlaps(other) && self.projs.len() >= other.projs.len()
+    }
+
+    /// Whether the function owns the storage, rather than reaching it
+    /// through a reference or pointer.
+    fn is_owned(&self) -> bool {
+        !self.projs.contains(&Proj::Deref)
+    }
+}
+
+/// Shows a place the way it is written; field access and indexing read
+/// through references without a `*`.
+impl fmt::Display for Place {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut text = source_name(&self.var).to_string();
+        for (i, proj) in self.projs.iter().enumerate() {
+            match proj {
+                Proj::Field(name) => text = format!("{}.{}", text, name),
+                Proj::Index => text.push_str("[_]"),
+                Proj::Deref if matches!(self.projs.get(i + 1), Some(Proj::Field(_) | Proj::Index)) => {}
+                Proj::Deref => text = format!("*{}", text),
+            }
+        }
+        write!(f, "{}", text)
+    }
+}
+
+/// A borrow of a place, which lasts as long as a value holding it is used.
+#[derive(Debug, Clone, PartialEq)]
+struct Loan {
+    place: Place,
+    mutable: bool,
+    /// The `&`, slice or closure that made it.
+    span: Span,
+    origin: Origin,
+}
+
+/// What made a loan.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Origin {
+    /// `&`, `&mut` or a slice.
+    Borrow,
+    /// A closure capturing the place's variable by reference.
+    Capture,
+    /// A `move` closure, of its own environment, whose place is named by
+    /// `env_place`.
+    Env,
+}
+
+/// The environment of the `move` closure at `span`, as a place of the
+/// function that no variable can name.
+fn env_place(span: Span) -> Place {
+    Place::var(&format!("closure.env@{}", span.start))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Access {
+    Read,
+    Move,
+    Write,
+    Borrow { mutable: bool },
+}
+
+/// What the checker knows at a point of the body.
+#[derive(Debug, Clone, Default)]
+struct State {
+    /// Places whose values were moved out, and where.
+    moved: Vec<(Place, Span)>,
+    /// Borrows held by the value of each variable.
+    holds: HashMap<String, Vec<Loan>>,
+}
+
+impl State {
+    /// Combines what holds after either of two paths through the body.
+    fn merge(&mut self, other: &State) {
+        for moved in &other.moved {
+            if !self.moved.contains(moved) {
+                self.moved.push(moved.clone());
+            }
+        }
+        for (var, loans) in &other.holds {
+            let held = self.holds.entry(var.clone()).or_default();
+            for loan in loans {
+                if !held.contains(loan) {
+                    held.push(loan.clone());
+                }
+            }
+        }
+    }
+}
+
+/// A point of a function body in the order its code runs: a source
+/// position, after the positions that the macro expansions around it stand
+/// at, outermost first.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Pos(Vec<usize>);
+
+/// Turns source positions into `Pos`es, knowing the code macro expansion
+/// made.
+#[derive(Clone, Copy)]
+struct Order<'a>(&'a [Expansion]);
+
+impl Order<'_> {
+    fn pos(self, offset: usize) -> Pos {
+        let mut path = vec![offset];
+        while let Some(expansion) = self.0.iter().find(|e| e.region.start <= path[0] && path[0] <= e.region.end) {
+            path.insert(0, expansion.at);
+        }
+        Pos(path)
+    }
+}
+
+/// Where each variable is used last, by source position. A variable used in
+/// a loop it was declared outside of counts as used at the end of the loop,
+/// which may run the use again.
+struct Liveness {
+    last_use: HashMap<String, (Pos, Span)>,
+}
+
+impl Liveness {
+    /// Liveness of the variables of the code `visit` shows to a `Uses`.
+    fn of(types: &TypeTable, order: Order, visit: impl FnOnce(&mut Uses)) -> Self {
+        let mut uses = Uses { types, order, uses: Vec::new(), decls: HashMap::new(), loops: Vec::new(), at: None };
+        visit(&mut uses);
+        let mut last_use: HashMap<String, (Pos, Span)> = HashMap::new();
+        for (var, span, mut pos, loops) in uses.uses {
+            let decls = uses.decls.get(&var).map(Vec::as_slice).unwrap_or_default();
+            let outside = |l: &&Span| !decls.iter().any(|d| order.pos(l.start) <= *d && *d < order.pos(l.end));
+            if let Some(outer) = loops.iter().find(outside) {
+                pos = pos.max(order.pos(outer.end));
+            }
+            match last_use.get(&var) {
+                Some((last, _)) if pos < *last => {}
+                _ => {
+                    last_use.insert(var, (pos, span));
+                }
+            }
+        }
+        Liveness { last_use }
+    }
+
+    /// Whether `var` is used after the position `now`.
+    fn is_live(&self, var: &str, now: &Pos) -> bool {
+        matches!(self.last_use.get(var), Some((pos, _)) if pos > now)
+    }
+}
+
+/// Collects the uses and declarations of variables for `Liveness`.
+struct Uses<'a> {
+    types: &'a TypeTable,
+    order: Order<'a>,
+    /// Each use: the variable, where it is, the position it counts at and
+    /// the loops around it, outermost first.
+    uses: Vec<(String, Span, Pos, Vec<Span>)>,
+    /// Positions of the `let` statements and pattern bindings of each name.
+    decls: HashMap<String, Vec<Pos>>,
+    loops: Vec<Span>,
+    /// Position at which uses count instead of their own, such as the end
+    /// of an assignment for the uses in its target.
+    at: Option<Pos>,
+}
+
+impl Uses<'_> {
+    fn use_var(&mut self, var: &str, span: Span) {
+        let pos = self.at.clone().unwrap_or_else(|| self.order.pos(span.start));
+        self.uses.push((var.to_string(), span, pos, self.loops.clone()));
+    }
+
+    fn declare(&mut self, name: &str, pos: usize) {
+        let pos = self.order.pos(pos);
+        self.decls.entry(name.to_string()).or_default().push(pos);
+    }
+
+    fn block(&mut self, stmts: &[Stmt]) {
+        for stmt in stmts {
+            self.stmt(stmt);
+        }
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        match &stmt.kind {
+            StmtKind::Let { name, expr, .. } => {
+                self.expr(expr);
+                self.declare(name, stmt.span.start);
+            }
+            StmtKind::Expr(expr) | StmtKind::Return(Some(expr)) => self.expr(expr),
+            StmtKind::Assign { target, value } => {
+                self.expr(value);
+                self.at = Some(self.order.pos(stmt.span.end));
+                self.expr(target);
+                self.at = None;
+            }
+            StmtKind::MacroInvoke { args, .. } => {
+                for arg in args {
+                    self.expr(arg);
+                }
+            }
+            StmtKind::If { cond, then_body, else_body } => {
+                self.expr(cond);
+                self.block(then_body);
+                if let Some(else_body) = else_body {
+                    self.block(else_body);
+                }
+            }
+            StmtKind::While { cond, body } => {
+                self.loops.push(stmt.span);
+                self.expr(cond);
+                self.block(body);
+                self.loops.pop();
+            }
+            StmtKind::Loop { body } => {
+                self.loops.push(stmt.span);
+                self.block(body);
+                self.loops.pop();
+            }
+            StmtKind::Unsafe(body) | StmtKind::Block(body) => self.block(body),
+            StmtKind::Break | StmtKind::Continue | StmtKind::Return(None) | StmtKind::Error => {}
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Ident(name) if !self.types.fn_values.contains(&expr.span) => self.use_var(name, expr.span),
+            ExprKind::Call { name, .. } if self.types.indirect_calls.contains_key(&expr.span) => {
+                self.use_var(name, expr.span)
+            }
+            // The body is checked on its own; creating the closure uses
+            // what it captures.
+            ExprKind::Lambda(_) => {
+                for (var, _) in self.types.captures.get(&expr.span).into_iter().flatten() {
+                    self.use_var(var, expr.span);
+                }
+                return;
+            }
+            ExprKind::Match { scrutinee, arms } => {
+                self.expr(scrutinee);
+                for arm in arms {
+                    for (name, _) in binding_spans(&arm.pattern) {
+                        self.declare(name, arm.pattern.span.start);
+                    }
+                    match &arm.body {
+                        ArmBody::Expr(body) => self.expr(body),
+                        ArmBody::Block(body) => self.block(body),
+                    }
+                }
+                return;
+            }
+            _ => {}
+        }
+        for child in children(expr) {
+            self.expr(child);
+        }
+    }
+}
+
+/// The operands of an expression, except for the bodies of closures and
+/// `match` arms.
+fn children(expr: &Expr) -> Vec<&Expr> {
+    match &expr.kind {
+        ExprKind::Int { .. }
+        | ExprKind::Float { .. }
+        | ExprKind::Bool(_)
+        | ExprKind::Char(_)
+        | ExprKind::Str(_)
+        | ExprKind::Ident(_)
+        | ExprKind::Lambda(_) => Vec::new(),
+        ExprKind::Call { args, .. } | ExprKind::Variant { args, .. } | ExprKind::ArrayLit(args) => args.iter().collect(),
+        ExprKind::CallValue { callee: first, args } | ExprKind::MethodCall { receiver: first, args, .. } => {
+            std::iter::once(&**first).chain(args).collect()
+        }
+        ExprKind::StructLit { fields, .. } => fields.iter().map(|(_, value)| value).collect(),
+        ExprKind::Index { expr: lhs, index: rhs } | ExprKind::Binary { lhs, rhs, .. } => vec![lhs, rhs],
+        ExprKind::Slice { expr: base, start, end } => {
+            std::iter::once(&**base).chain(start.as_deref()).chain(end.as_deref()).collect()
+        }
+        ExprKind::Match { scrutinee, .. } => vec![scrutinee],
+        ExprKind::Field { expr: inner, .. }
+        | ExprKind::AddrOf { expr: inner, .. }
+        | ExprKind::Deref(inner)
+        | ExprKind::ArrayRepeat { value: inner, .. }
+        | ExprKind::Unary { expr: inner, .. }
+        | ExprKind::Try(inner)
+        | ExprKind::Await(inner)
+        | ExprKind::Spawn(inner) => vec![inner],
+    }
+}
+
+/// An expression as the checker sees it: storage it names, or a value
+/// computed from other ones, with the borrows that value holds.
+enum Operand {
+    Place(Place),
+    Value(Vec<Loan>),
+}
+
+/// States at the `break` and `continue` statements of a loop.
+#[derive(Default)]
+struct LoopExits {
+    breaks: Vec<State>,
+    continues: Vec<State>,
+}
+
+struct Checker<'a> {
+    types: &'a TypeTable,
+    order: Order<'a>,
+    holds_references: HoldsReferences<'a>,
+    live: Liveness,
+    state: State,
+    /// Borrows held by the operands evaluated so far of the call or literal
+    /// being checked, which last until it is complete.
+    temps: Vec<Loan>,
+    /// Set when control cannot reach the current statement.
+    diverged: bool,
+    loops: Vec<LoopExits>,
+    /// In a closure body, the variables the closure captured.
+    captured: Vec<String>,
+    /// Captured variables the closure body writes to or borrows mutably.
+    written: Vec<String>,
+    /// `move` closures whose environments cannot be in the function's
+    /// frame; see `check_function`.
+    escaping: HashSet<Span>,
+}
+
+impl<'a> Checker<'a> {
+    fn new(
+        live: Liveness,
+        captured: Vec<String>,
+        types: &'a TypeTable,
+        holds_references: HoldsReferences<'a>,
+        order: Order<'a>,
+    ) -> Self {
+        Checker {
+            types,
+            order,
+            holds_references,
+            live,
+            state: State::default(),
+            temps: Vec::new(),
+            diverged: false,
+            loops: Vec::new(),
+            captured,
+            written: Vec::new(),
+            escaping: HashSet::new(),
+        }
+    }
+
+    fn block(&mut self, stmts: &[Stmt]) -> Result<()> {
+        for stmt in stmts {
+            self.stmt(stmt)?;
+        }
+        Ok(())
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) -> Result<()> {
+        match &stmt.kind {
+            StmtKind::Let { name, expr, .. } => {
+                // What the variable held before is from an earlier iteration
+                // of a loop around it; resolution gives every `let` its own
+                // name, so the value cannot refer to it.
+                self.state.holds.remove(name);
+                let loans = self.value(expr)?;
+                self.state.moved.retain(|(place, _)| place.var != *name);
+                self.state.holds.insert(name.clone(), loans);
+            }
+            StmtKind::Expr(expr) => {
+                self.value(expr)?;
+            }
+            StmtKind::Assign { target, value } => {
+                let loans = self.value(value)?;
+                let place = match self.operand(target)? {
+                    Operand::Place(place) => place,
+                    Operand::Value(_) => return Ok(()),
+                };
+                self.access(&place, Access::Write, target.span, stmt.span.end)?;
+                self.state.moved.retain(|(moved, _)| !moved.within(&place));
+                // Closures stored through a reference, or in what a closure
+                // captured, may outlive the function.
+                if !place.is_owned() || self.captured.contains(&place.var) {
+                    self.escape(&loans);
+                }
+                // Storage reached through a reference that borrows none of
+                // this function's variables is not this function's.
+                if !place.is_owned() && self.held(&place.var).is_empty() {
+                    if let Some(loan) = loans.iter().find(|loan| self.is_local(&loan.place)) {
+                        let message = format!("Cannot store a reference to `{}` in `{}`, which outlives it", loan.place, place);
+                        return Err(with_origin(SpannedError::new(value.span, message), loan, "borrowed").into());
+                    }
+                }
+                self.state.holds.entry(place.var).or_default().extend(loans);
+            }
+            StmtKind::MacroInvoke { args, .. } => {
+                for arg in args {
+                    self.value(arg)?;
+                }
+            }
+            StmtKind::If { cond, then_body, else_body } => {
+                self.value(cond)?;
+                let before = self.state.clone();
+                self.block(then_body)?;
+                let then_state = std::mem::replace(&mut self.state, before);
+                let then_diverged = std::mem::replace(&mut self.diverged, false);
+                if let Some(else_body) = else_body {
+                    self.block(else_body)?;
+                }
+                match (then_diverged, self.diverged) {
+                    (true, _) => {}
+                    (false, true) => {
+                        self.state = then_state;
+                        self.diverged = false;
+                    }
+                    (false, false) => self.state.merge(&then_state),
+                }
+            }
+            StmtKind::While { cond, body } => self.loop_(Some(cond), body)?,
+            StmtKind::Loop { body } => self.loop_(None, body)?,
+            StmtKind::Unsafe(body) | StmtKind::Block(body) => self.block(body)?,
+            StmtKind::Break | StmtKind::Continue => {
+                let exits = self.loops.last_mut().expect("type checking rejects `break` outside loops");
+                let states = if matches!(stmt.kind, StmtKind::Break) { &mut exits.breaks } else { &mut exits.continues };
+                states.push(self.state.clone());
+                self.diverged = true;
+            }
+            StmtKind::Return(value) => {
+                if let Some(value) = value {
+                    self.returned(value)?;
+                }
+                self.diverged = true;
+            }
+            StmtKind::Error => {}
+        }
+        Ok(())
+    }
+
+    /// Checks a loop's body twice, the second time starting from what holds
+    /// after any iteration, so uses of values an earlier iteration moved
+    /// and borrows still held from one are found.
+    fn loop_(&mut self, cond: Option<&Expr>, body: &[Stmt]) -> Result<()> {
+        let mut head = self.state.clone();
+        self.loops.push(LoopExits::default());
+        for pass in 0..2 {
+            self.state = head.clone();
+            self.diverged = false;
+            if let Some(cond) = cond {
+                self.value(cond)?;
+            }
+            self.block(body)?;
+            let exits = self.loops.last_mut().unwrap();
+            if pass == 0 {
+                if !self.diverged {
+                    head.merge(&self.state);
+                }
+                for state in std::mem::take(&mut exits.continues) {
+                    head.merge(&state);
+                }
+                exits.breaks.clear();
+            }
+        }
+        let exits = self.loops.pop().unwrap();
+        let mut exit = match cond {
+            // A `while` loop also ends when its condition is false.
+            Some(_) => {
+                if !self.diverged {
+                    head.merge(&self.state);
+                }
+                Some(head)
+            }
+            None => None,
+        };
+        for state in &exits.breaks {
+            match &mut exit {
+                Some(exit) => exit.merge(state),
+                None => exit = Some(state.clone()),
+            }
+        }
+        self.diverged = exit.is_none();
+        self.state = exit.unwrap_or_default();
+        Ok(())
+    }
+
+    /// Checks the value a function or closure returns, which must not hold
+    /// borrows of its own variables.
+    fn returned(&mut self, value: &Expr) -> Result<()> {
+        let loans = self.value(value)?;
+        self.escape(&loans);
+        let Some(loan) = loans.iter().find(|loan| self.is_local(&loan.place)) else {
+            return Ok(());
+        };
+        let (err, what) = if loan.origin == Origin::Capture {
+            let err = SpannedError::new(
+                value.span,
+                format!(
+                    "Cannot return a closure that captures `{}` by reference, as `{}` does not outlive the function; capture it with `move`",
+                    loan.place, loan.place
+                ),
+            );
+            (err, "captured")
+        } else {
+            let err = SpannedError::new(
+                value.span,
+                format!("Cannot return a reference to `{}`, which does not outlive the function", loan.place),
+            );
+            (err, "borrowed")
+        };
+        Err(with_origin(err, loan, what).into())
+    }
+
+    /// Takes the `move` closures whose environments `loans` borrow out of the
+    /// function's frame, for values that outlive it.
+    fn escape(&mut self, loans: &[Loan]) {
+        for loan in loans.iter().filter(|loan| loan.origin == Origin::Env) {
+            self.escaping.insert(loan.span);
+        }
+    }
+
+    /// Whether `place` is storage of the function being checked, which goes
+    /// away when it returns. Closure environments are not: those that
+    /// outlive the function are not in its frame.
+    fn is_local(&self, place: &Place) -> bool {
+        place.is_owned() && !self.captured.contains(&place.var) && !place.var.starts_with("closure.env@")
+    }
+
+    /// Checks `expr` where its value is used, moving it if it is a place of
+    /// a type that is not copied, and returns the borrows the value holds.
+    fn value(&mut self, expr: &Expr) -> Result<Vec<Loan>> {
+        let loans = self.eval(expr)?;
+        let ty = self.types.coercions.get(&expr.span).or_else(|| self.types.exprs.get(&expr.span));
+        if matches!(ty, Some(ty) if (self.holds_references)(ty)) {
+            Ok(loans)
+        } else {
+            Ok(Vec::new())
+        }
+    }
+
+    /// Checks each of `operands` in turn, keeping what each holds borrowed
+    /// while the later ones are checked, and returns all their borrows.
+    fn operands<'e>(&mut self, operands: impl IntoIterator<Item = &'e Expr>) -> Result<Vec<Loan>> {
+        let outer = self.temps.len();
+        for operand in operands {
+            let loans = self.value(operand)?;
+            self.temps.extend(loans);
+        }
+        Ok(self.temps.split_off(outer))
+    }
+
+    fn eval(&mut self, expr: &Expr) -> Result<Vec<Loan>> {
+        match &expr.kind {
+            ExprKind::Int { .. }
+            | ExprKind::Float { .. }
+            | ExprKind::Bool(_)
+            | ExprKind::Char(_)
+            | ExprKind::Str(_) => Ok(Vec::new()),
+            ExprKind::Ident(_) if self.types.fn_values.contains(&expr.span) => Ok(Vec::new()),
+            ExprKind::Ident(_) | ExprKind::Field { .. } | ExprKind::Index { .. } | ExprKind::Deref(_) => {
+                match self.operand(expr)? {
+                    Operand::Place(place) => self.use_place(&place, expr),
+                    Operand::Value(loans) => Ok(loans),
+                }
+            }
+            // What a call returns may borrow what its arguments borrow, but
+            // not what the closure called holds.
+            ExprKind::Call { name, args, .. } => {
+                if self.types.indirect_calls.contains_key(&expr.span) {
+                    self.access(&Place::var(name), Access::Read, expr.span, expr.span.start)?;
+                }
+                self.operands(args)
+            }
+            ExprKind::MethodCall { receiver, args, .. } => {
+                // The method gets a copy of the receiver, which stays usable.
+                let receiver = match self.operand(receiver)? {
+                    Operand::Place(place) => {
+                        self.access(&place, Access::Read, receiver.span, receiver.span.start)?;
+                        self.held(&place.var)
+                    }
+                    Operand::Value(loans) => loans,
+                };
+                let outer = self.temps.len();
+                self.temps.extend(receiver);
+                let args = self.operands(args)?;
+                self.temps.extend(args);
+                Ok(self.temps.split_off(outer))
+            }
+            ExprKind::CallValue { callee, args } => {
+                self.value(callee)?;
+                self.operands(args)
+            }
+            ExprKind::StructLit { fields, .. } => self.operands(fields.iter().map(|(_, value)| value)),
+            ExprKind::Variant { args, .. } | ExprKind::ArrayLit(args) => self.operands(args),
+            ExprKind::ArrayRepeat { value, .. } => self.value(value),
+            ExprKind::Slice { expr: base, start, end } => {
+                let operand = self.operand(base)?;
+                for bound in [start, end].into_iter().flatten() {
+                    self.value(bound)?;
+                }
+                match operand {
+                    // Slices can be written through, unless they are taken
+                    // of an array behind a shared reference.
+                    Operand::Place(place) => {
+                        let base_ty = self.types.exprs.get(&base.span);
+                        let place = match base_ty {
+                            Some(ty) if ty.pointee().is_some() || matches!(ty, Type::Slice(_)) => place.project(Proj::Deref),
+                            _ => place,
+                        };
+                        let mutable = !matches!(base_ty, Some(Type::Ref(_, false)));
+                        self.borrow(place, mutable, expr.span)
+                    }
+                    Operand::Value(loans) => Ok(loans),
+                }
+            }
+            ExprKind::AddrOf { expr: inner, mutable } => match self.operand(inner)? {
+                Operand::Place(place) => self.borrow(place, *mutable, expr.span),
+                Operand::Value(loans) => Ok(loans),
+            },
+            ExprKind::Match { scrutinee, arms } => self.match_(scrutinee, arms),
+            ExprKind::Binary { lhs, rhs, .. } => {
+                self.value(lhs)?;
+                self.value(rhs)?;
+                Ok(Vec::new())
+            }
+            ExprKind::Unary { expr: inner, .. }
+            | ExprKind::Try(inner)
+            | ExprKind::Await(inner)
+            | ExprKind::Spawn(inner) => self.value(inner),
+            ExprKind::Lambda(lambda) => self.lambda(expr, lambda),
+        }
+    }
+
+    /// The place `expr` names, after checking the indices it uses, or the
+    /// borrows of its value if it names none.
+    fn operand(&mut self, expr: &Expr) -> Result<Operand> {
+        let through = |base: &Expr, slices: bool| match self.types.exprs.get(&base.span) {
+            Some(ty) => ty.pointee().is_some() || (slices && matches!(ty, Type::Slice(_))),
+            None => false,
+        };
+        Ok(match &expr.kind {
+            ExprKind::Ident(name) if !self.types.fn_values.contains(&expr.span) => Operand::Place(Place::var(name)),
+            ExprKind::Field { expr: base, field } => {
+                let deref = through(base, false);
+                match self.operand(base)? {
+                    Operand::Place(place) => {
+                        let place = if deref { place.project(Proj::Deref) } else { place };
+                        Operand::Place(place.project(Proj::Field(field.clone())))
+                    }
+                    value => value,
+                }
+            }
+            ExprKind::Index { expr: base, index } => {
+                let deref = through(base, true);
+                let operand = self.operand(base)?;
+                self.value(index)?;
+                match operand {
+                    Operand::Place(place) => {
+                        let place = if deref { place.project(Proj::Deref) } else { place };
+                        Operand::Place(place.project(Proj::Index))
+                    }
+                    value => value,
+                }
+            }
+            ExprKind::Deref(inner) => match self.operand(inner)? {
+                Operand::Place(place) => Operand::Place(place.project(Proj::Deref)),
+                value => value,
+            },
+            _ => Operand::Value(self.eval(expr)?),
+        })
+    }
+
+    /// Borrows the variable's value holds, which anything read from it or
+    /// borrowed of it may hold too.
+    fn held(&self, var: &str) -> Vec<Loan> {
+        self.state.holds.get(var).cloned().unwrap_or_default()
+    }
+
+    /// Uses the value at `place`, which `expr` names, moving it out unless
+    /// its type is copied.
+    fn use_place(&mut self, place: &Place, expr: &Expr) -> Result<Vec<Loan>> {
+        let copied = !matches!(self.types.exprs.get(&expr.span), Some(ty) if !ty.is_copy());
+        if copied {
+            self.access(place, Access::Read, expr.span, expr.span.start)?;
+            return Ok(self.held(&place.var));
+        }
+        self.access(place, Access::Move, expr.span, expr.span.start)?;
+        self.check_movable(place, expr.span)?;
+        self.state.moved.push((place.clone(), expr.span));
+        Ok(self.held(&place.var))
+    }
+
+    fn check_movable(&self, place: &Place, span: Span) -> Result<()> {
+        if self.captured.contains(&place.var) {
+            bail_at!(
+                span,
+                "Cannot move `{}` out of the variables a closure captured, as the closure may be called again; borrow it with `&` instead",
+                place
+            );
+        }
+        if !place.is_owned() {
+            bail_at!(span, "Cannot move `{}` out from behind a reference or pointer; borrow it with `&` instead", place);
+        }
+        if place.projs.contains(&Proj::Index) {
+            bail_at!(span, "Cannot move `{}` out of an array; borrow the element with `&` instead", place);
+        }
+        Ok(())
+    }
+
+    fn borrow(&mut self, place: Place, mutable: bool, span: Span) -> Result<Vec<Loan>> {
+        self.access(&place, Access::Borrow { mutable }, span, span.start)?;
+        let mut loans = self.held(&place.var);
+        loans.push(Loan { place, mutable, span, origin: Origin::Borrow });
+        Ok(loans)
+    }
+
+    /// Checks an access to `place` at the source position `now`: that its
+    /// value, or the part of it accessed, was not moved out, and that no
+    /// borrow still in use forbids the access.
+    fn access(&mut self, place: &Place, access: Access, span: Span, now: usize) -> Result<()> {
+        let now = self.order.pos(now);
+        if self.captured.contains(&place.var) && matches!(access, Access::Write | Access::Borrow { mutable: true }) {
+            self.written.push(place.var.clone());
+        }
+        for (moved, at) in &self.state.moved {
+            // Assigning to what was moved out puts a value back.
+            let refills = access == Access::Write && place.within(moved) && place.projs.len() == moved.projs.len();
+            if !moved.overlaps(place) || refills || (access == Access::Write && moved.within(place)) {
+                continue;
+            }
+            let message = if place.within(moved) {
+                format!("Use of moved value `{}`", place)
+            } else {
+                format!("Use of partially moved value `{}`", place)
+            };
+            let note = if self.order.pos(at.start) >= self.order.pos(span.start) {
+                format!("`{}` was moved here, in an earlier iteration of the loop", moved)
+            } else {
+                format!("`{}` was moved here", moved)
+            };
+            return Err(SpannedError::new(span, message).with_note(*at, note).into());
+        }
+        let held = self.state.holds.iter()
+            .filter(|(var, _)| self.live.is_live(var, &now))
+            .flat_map(|(var, loans)| loans.iter().map(move |loan| (loan, Some(var.as_str()))));
+        let conflict = held
+            .chain(self.temps.iter().map(|loan| (loan, None)))
+            .filter(|(loan, _)| loan.place.overlaps(place))
+            .filter(|(loan, _)| match access {
+                Access::Read | Access::Borrow { mutable: false } => loan.mutable,
+                Access::Move | Access::Write | Access::Borrow { mutable: true } => true,
+            })
+            .min_by_key(|(loan, _)| self.order.pos(loan.span.start));
+        let Some((loan, holder)) = conflict else {
+            return Ok(());
+        };
+        let borrowed = if loan.mutable { "mutably borrowed" } else { "borrowed" };
+        let message = match access {
+            Access::Read => format!("Cannot use `{}` while it is mutably borrowed", place),
+            Access::Move => format!("Cannot move out of `{}` while it is borrowed", place),
+            Access::Write => format!("Cannot assign to `{}` while it is borrowed", place),
+            Access::Borrow { mutable: true } => format!("Cannot borrow `{}` as mutable while it is also {}", place, borrowed),
+            Access::Borrow { mutable: false } => format!("Cannot borrow `{}` while it is mutably borrowed", place),
+        };
+        let how = if loan.origin == Origin::Capture { "captured by a closure" } else { borrowed };
+        let mut err = SpannedError::new(span, message).with_note(loan.span, format!("`{}` is {} here", loan.place, how));
+        if let Some((_, used)) = holder.and_then(|var| self.live.last_use.get(var)) {
+            err = err.with_note(*used, "and the borrow is used again here");
+        }
+        Err(err.into())
+    }
+
+    /// Whether a value still used after the source position `now` holds a
+    /// borrow of `place`.
+    fn in_use(&self, place: &Place, now: usize) -> bool {
+        let now = self.order.pos(now);
+        self.state.holds.iter()
+            .filter(|(var, _)| self.live.is_live(var, &now))
+            .flat_map(|(_, loans)| loans)
+            .chain(&self.temps)
+            .any(|loan| loan.place.overlaps(place))
+    }
+
+    /// Checks a `match`: arms that bind a value of a type that is not
+    /// copied move the scrutinee, and bindings hold what it holds.
+    fn match_(&mut self, scrutinee: &Expr, arms: &[MatchArm]) -> Result<Vec<Loan>> {
+        // As for `let`, what the bindings held is from an earlier iteration.
+        for (name, _) in arms.iter().flat_map(|arm| binding_spans(&arm.pattern)) {
+            self.state.holds.remove(name);
+        }
+        let place = match self.operand(scrutinee)? {
+            Operand::Place(place) => {
+                self.access(&place, Access::Read, scrutinee.span, scrutinee.span.start)?;
+                Some(place)
+            }
+            Operand::Value(_) => None,
+        };
+        let held = match &place {
+            Some(place) => self.held(&place.var),
+            None => Vec::new(),
+        };
+        let before = self.state.clone();
+        let mut after: Option<State> = None;
+        let mut loans = Vec::new();
+        for arm in arms {
+            self.state = before.clone();
+            self.diverged = false;
+            let moves = binding_spans(&arm.pattern)
+                .any(|(_, span)| matches!(self.types.bindings.get(&span), Some(ty) if !ty.is_copy()));
+            if let (Some(place), true) = (&place, moves) {
+                self.access(place, Access::Move, scrutinee.span, scrutinee.span.start)?;
+                self.check_movable(place, scrutinee.span)?;
+                self.state.moved.push((place.clone(), scrutinee.span));
+            }
+            for (name, span) in binding_spans(&arm.pattern) {
+                let holds = matches!(self.types.bindings.get(&span), Some(ty) if (self.holds_references)(ty));
+                self.state.moved.retain(|(moved, _)| moved.var != name);
+                self.state.holds.insert(name.to_string(), if holds { held.clone() } else { Vec::new() });
+            }
+            match &arm.body {
+                ArmBody::Expr(body) => loans.extend(self.value(body)?),
+                ArmBody::Block(body) => self.block(body)?,
+            }
+            if !self.diverged {
+                match &mut after {
+                    Some(after) => after.merge(&self.state),
+                    None => after = Some(self.state.clone()),
+                }
+            }
+        }
+        self.diverged = after.is_none() && !arms.is_empty();
+        self.state = after.unwrap_or(before);
+        Ok(loans)
+    }
+
+    /// Checks the creation of a closure and then its body. Creating it moves
+    /// or copies what a `move` closure captures, and borrows what others
+    /// capture for as long as the closure is used.
+    fn lambda(&mut self, expr: &Expr, lambda: &Lambda) -> Result<Vec<Loan>> {
+        let captures = self.types.captures.get(&expr.span).cloned().unwrap_or_default();
+        let names = captures.iter().map(|(var, _)| var.clone()).collect();
+        let live = Liveness::of(self.types, self.order, |uses| match &lambda.body {
+            LambdaBody::Expr(value) => uses.expr(value),
+            LambdaBody::Block(stmts) => uses.block(stmts),
+        });
+        let mut body = Checker::new(live, names, self.types, self.holds_references, self.order);
+        match &lambda.body {
+            LambdaBody::Expr(value) => body.returned(value)?,
+            LambdaBody::Block(stmts) => body.block(stmts)?,
+        }
+        let mut loans = Vec::new();
+        for (var, ty) in &captures {
+            let place = Place::var(var);
+            if lambda.by_value {
+                let access = if ty.is_copy() { Access::Read } else { Access::Move };
+                self.access(&place, access, expr.span, expr.span.start)?;
+                if access == Access::Move {
+                    self.check_movable(&place, expr.span)?;
+                    self.state.moved.push((place, expr.span));
+                }
+            } else {
+                let mutable = body.written.contains(var);
+                self.access(&place, Access::Borrow { mutable }, expr.span, expr.span.start)?;
+                loans.push(Loan { place, mutable, span: expr.span, origin: Origin::Capture });
+            }
+            loans.extend(self.held(var));
+        }
+        self.escaping.extend(body.escaping);
+        if lambda.by_value && !captures.is_empty() {
+            // The environment is reused each time the closure is created.
+            let env = env_place(expr.span);
+            if self.in_use(&env, expr.span.start) {
+                self.escaping.insert(expr.span);
+            }
+            loans.push(Loan { place: env, mutable: false, span: expr.span, origin: Origin::Env });
+        }
+        Ok(loans)
+    }
+}
+
+/// Adds a note showing where `loan` was made, unless the error already
+/// points there.
+fn with_origin(err: SpannedError, loan: &Loan, what: &str) -> SpannedError {
+    if loan.span == err.span {
+        return err;
+    }
+    let note = format!("`{}` is {} here", loan.place, what);
+    err.with_note(loan.span, note)
+}
+
+/// Names a pattern binds, with the spans of their binding patterns.
+fn binding_spans(pattern: &Pattern) -> Box<dyn Iterator<Item = (&str, Span)> + '_> {
+    match &pattern.kind {
+        PatternKind::Binding(name) => Box::new(std::iter::once((name.as_str(), pattern.span))),
+        PatternKind::Variant { fields, .. } => Box::new(fields.iter().flat_map(binding_spans)),
+        PatternKind::Wildcard | PatternKind::Literal(_) => Box::new(std::iter::empty()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{check, error};
+
+    /// The `move` closures of `source` whose environments go on the heap.
+    fn escaping(source: &str) -> Vec<&str> {
+        let (_, types) = check(source);
+        let mut closures: Vec<_> = types.escaping.iter().map(|span| &source[span.start..span.end]).collect();
+        closures.sort();
+        closures
+    }
+
+    #[test]
+    fn move_closures_that_outlive_their_function_go_on_the_heap() {
+        assert_eq!(
+            escaping(
+                "fn adder(n: i64) -> Fn(i64) -> i64 { return move |x: i64| x + n }
+fn set(f: &mut Fn(i64) -> i64, n: i64) { *f = move |x: i64| x * n }
+fn apply(f: Fn(i64) -> i64) -> i64 { return f(1) }
+fn main() {
+    let n = 2
+    let mut f = adder(n)
+    set(&mut f, 3)
+    print(f(1) + apply(move |x: i64| x - n))
+}
+"
+            ),
+            ["move |x: i64| x * n", "move |x: i64| x + n"]
+        );
+    }
+
+    #[test]
+    fn move_closures_made_again_while_in_use_go_on_the_heap() {
+        assert_eq!(
+            escaping(
+                "fn main() {
+    let mut fs: [Fn(i64) -> i64; 2] = [|x: i64| x, |x: i64| x]
+    let mut i = 0
+    while i < 2 {
+        fs[i] = move |x: i64| x + i
+        i = i + 1
+    }
+    print(fs[0](1))
+}
+"
+            ),
+            ["move |x: i64| x + i"]
+        );
+    }
+
+    #[test]
+    fn moved_values_cannot_be_used() {
+        assert_eq!(
+            error("struct S { x: i64 }\nfn take(s: S) {}\nfn main() {\n    let s = S { x: 1 }\n    take(s)\n    print(s.x)\n}\n"),
+            "main.yul:6:11: semantic error: Use of moved value `s.x`\nmain.yul:5:10: note: `s` was moved here"
+        );
+    }
+
+    #[test]
+    fn shared_borrows_exclude_mutable_ones() {
+        assert_eq!(
+            error("fn main() {\n    let mut a = 1\n    let r = &a\n    let m = &mut a\n    print(*r + *m)\n}\n"),
+            "main.yul:4:13: semantic error: Cannot borrow `a` as mutable while it is also borrowed\n\
+             main.yul:3:13: note: `a` is borrowed here\n\
+             main.yul:5:12: note: and the borrow is used again here"
+        );
+        check("fn main() {\n    let mut a = 1\n    let r = &a\n    print(*r)\n    let m = &mut a\n    *m = 2\n}\n");
+    }
+
+    #[test]
+    fn borrowed_values_cannot_be_moved_out() {
+        assert_eq!(
+            error("struct S { x: i64 }\nfn main() {\n    let s = S { x: 1 }\n    let r = &s\n    let t = s\n    print(r.x)\n}\n"),
+            "main.yul:5:13: semantic error: Cannot move out of `s` while it is borrowed\n\
+             main.yul:4:13: note: `s` is borrowed here\n\
+             main.yul:6:11: note: and the borrow is used again here"
+        );
+        assert_eq!(
+            error("struct S { x: i64 }\nfn take(r: &S) -> S { return *r }\nfn main() {}\n"),
+            "main.yul:2:30: semantic error: Cannot move `*r` out from behind a reference or pointer; borrow it with `&` instead"
+        );
+    }
+
+    #[test]
+    fn moves_on_some_paths_count_after_the_branches() {
+        let if_source = "struct S { x: i64 }
+fn take(s: S) {}
+fn main() {
+    let s = S { x: 1 }
+    if s.x > 0 { take(s) }
+    print(s.x)
+}
+";
+        assert_eq!(
+            error(if_source),
+            "main.yul:6:11: semantic error: Use of moved value `s.x`\nmain.yul:5:23: note: `s` was moved here"
+        );
+        let while_source = "struct S { x: i64 }
+fn take(s: S) {}
+fn main() {
+    let s = S { x: 1 }
+    let mut i = 0
+    while i < 2 {
+        take(s)
+        i = i + 1
+    }
+}
+";
+        assert_eq!(
+            error(while_source),
+            "main.yul:7:14: semantic error: Use of moved value `s`\n\
+             main.yul:7:14: note: `s` was moved here, in an earlier iteration of the loop"
+        );
+        check(
+            "struct S { x: i64 }
+fn take(s: S) {}
+fn main() {
+    let mut s = S { x: 1 }
+    if s.x > 0 { take(s) } else { s.x = 2 }
+    s = S { x: 3 }
+    let mut i = 0
+    while i < 2 {
+        take(s)
+        s = S { x: i }
+        i = i + 1
+    }
+    print(s.x)
+}
+",
+        );
+    }
+
+    #[test]
+    fn fields_are_borrowed_separately() {
+        check(
+            "struct P { a: i64, b: i64 }
+fn main() {
+    let mut p = P { a: 1, b: 2 }
+    let a = &mut p.a
+    let b = &p.b
+    *a = *b
+    print(p.a)
+}
+",
+        );
+        assert_eq!(
+            error("struct P { a: i64, b: i64 }\nfn main() {\n    let mut p = P { a: 1, b: 2 }\n    let a = &mut p.a\n    let q = &p\n    *a = q.b\n}\n"),
+            "main.yul:5:13: semantic error: Cannot borrow `p` while it is mutably borrowed\n\
+             main.yul:4:13: note: `p.a` is mutably borrowed here\n\
+             main.yul:6:6: note: and the borrow is used again here"
+        );
+    }
+
+    #[test]
+    fn borrows_end_with_the_loop_iteration_that_made_them() {
+        check(
+            "fn main() {
+    let mut x = 0
+    let mut i = 0
+    while i < 3 {
+        let r = &mut x
+        *r = i
+        i = i + 1
+    }
+    print(x)
+}
+",
+        );
+        assert_eq!(
+            escaping("fn main() {\n    let mut i = 0\n    while i < 3 {\n        let f = move |x: i64| x + i\n        print(f(1))\n        i = i + 1\n    }\n}\n"),
+            Vec::<&str>::new()
+        );
+    }
+}
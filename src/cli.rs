@@ -1,5 +1,9 @@
 use clap::{Parser, Subcommand};
-use crate::error::YulcResult;
+use crate::ast::Program;
+use crate::error::{SpannedError, YulcError, YulcResult};
+use crate::source_map::SourceMap;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
 
 #[derive(Parser)]
 #[command(name = "yulc", about = "Yulvon Compiler: The fastest programming language in the world.")]
@@ -19,81 +23,257 @@ pub enum YulcCommand {
         target: Option<String>, // e.g. "x86_64", "wasm32"
         #[arg(long)]
         output: Option<String>, // output file name
+        #[arg(long)]
+        forbid_unsafe: bool, // reject `unsafe` blocks and functions
+        #[arg(long)]
+        hints: bool, // print optimization hints
     },
 }
 
 impl YulcCli {
     pub fn run(&self) -> YulcResult<()> {
         match &self.command {
-            YulcCommand::Build { file, release, target } => {
+            YulcCommand::Build { file, release, target, output, forbid_unsafe, hints } => {
                 use std::fs;
-                use crate::lexer::lex;
-                use crate::parser::Parser;
-                use crate::ast::Program;
-                use crate::error::YulcError;
-
-                let source = fs::read_to_string(file)
-                    .map_err(|e| YulcError::Io(e))?;
-                let tokens = lex(&source);
-                let mut parser = Parser::new(&tokens);
-                let program: Program = parser.parse_program()
-                    .map_err(|e| YulcError::Parse(format!("{e}")))?;
-                crate::semantic::analyze_program(&program)
-                    .map_err(|e| YulcError::Other(format!("semantic error: {e}")))?;
+
+                let mut sources = SourceMap::new();
+                let mut program = load_program(file, &mut sources)?;
+                crate::prelude::add_to(&mut program, &mut sources);
+                crate::macro_expand::expand_macros(&mut program, &mut sources)
+                    .map_err(|e| YulcError::semantic(e, &sources))?;
+                let types = crate::semantic::analyze_program(&program, *forbid_unsafe)
+                    .map_err(|e| YulcError::semantic(e, &sources))?;
+                for warning in &types.warnings {
+                    eprintln!("{}", crate::error::format_warning(warning, &sources));
+                }
                 println!("AST: {:#?}", program);
-                let irmod = crate::irgen::lower_program(&program);
+                let irmod = crate::irgen::lower_program(&program, &types);
+                let irmod = crate::mono::monomorphize(&irmod)
+                    .map_err(|e| YulcError::semantic(e, &sources))?;
                 println!("IR: {:#?}", irmod);
+                if *hints {
+                    let ast_hints = crate::ai_hints::analyze_ast(&program, &sources);
+                    for hint in ast_hints.iter().chain(&crate::ai_hints::analyze_ir(&irmod, &sources)) {
+                        eprintln!("{}", hint);
+                    }
+                }
                 use inkwell::context::Context;
+                use inkwell::targets::FileType;
+                let target = target.as_deref().unwrap_or("x86_64");
+                let output = output.as_deref().unwrap_or("a.out");
+                let machine = target_machine(target)?;
                 let context = Context::create();
-                let mut codegen = crate::codegen::Codegen::new(&context, "yulmod");
+                let mut codegen = crate::codegen::Codegen::new(&context, "yulmod", &machine);
+                codegen.bounds_checks = !release;
                 codegen.codegen_module(&irmod)
                     .map_err(|e| YulcError::Codegen(format!("{e}")))?;
-                let target = target.as_deref().unwrap_or("x86_64");
-                let output = output.as_deref().unwrap_or("a.out");
-                match target {
-                    "x86_64" => {
-                        use inkwell::targets::{InitializationConfig, Target, TargetMachine, FileType};
-                        Target::initialize_all(&InitializationConfig::default());
-                        let triple = TargetMachine::get_default_triple();
-                        let target = Target::from_triple(&triple).unwrap();
-                        let machine = target
-                            .create_target_machine(
-                                &triple,
-                                "generic",
-                                "",
-                                inkwell::OptimizationLevel::Default,
-                                inkwell::targets::RelocMode::Default,
-                                inkwell::targets::CodeModel::Default,
-                            )
-                            .unwrap();
-                        codegen.module.write_to_file(FileType::Object, output).unwrap();
-                        println!("Native object file written to {}", output);
-                    }
-                    "wasm32" => {
-                        // WASM output via LLVM (requires LLVM built with WASM backend)
-                        use inkwell::targets::{InitializationConfig, Target, FileType};
-                        Target::initialize_webassembly(&InitializationConfig::default());
-                        let triple = inkwell::targets::TargetTriple::create("wasm32-unknown-unknown");
-                        let target = Target::from_triple(&triple).unwrap();
-                        let machine = target
-                            .create_target_machine(
-                                &triple,
-                                "generic",
-                                "",
-                                inkwell::OptimizationLevel::Default,
-                                inkwell::targets::RelocMode::Default,
-                                inkwell::targets::CodeModel::Default,
-                            )
-                            .unwrap();
-                        codegen.module.write_to_file(FileType::Object, output).unwrap();
-                        println!("WASM object file written to {}", output);
-                    }
-                    _ => {
-                        return Err(YulcError::Other(format!("Unknown target: {}", target)));
-                    }
+                if target == "wasm32" {
+                    machine.write_to_file(&codegen.module, FileType::Object, Path::new(output))
+                        .map_err(|e| YulcError::Codegen(e.to_string()))?;
+                    println!("WASM object file written to {}", output);
+                } else {
+                    let object = PathBuf::from(format!("{}.o", output));
+                    machine.write_to_file(&codegen.module, FileType::Object, &object)
+                        .map_err(|e| YulcError::Codegen(e.to_string()))?;
+                    crate::linker::link_executable(&object, Path::new(output))?;
+                    let _ = fs::remove_file(&object);
+                    println!("Native executable written to {}", output);
                 }
                 Ok(())
             }
         }
     }
 }
+
+/// The machine that generates code for `target`: `x86_64`, the host, or
+/// `wasm32`, which needs an LLVM built with the WebAssembly backend. The code
+/// is position independent, as C compilers that link executables as PIE by
+/// default require.
+pub(crate) fn target_machine(target: &str) -> YulcResult<inkwell::targets::TargetMachine> {
+    use inkwell::targets::{CodeModel, InitializationConfig, RelocMode, Target, TargetMachine, TargetTriple};
+    let triple = match target {
+        "x86_64" => {
+            Target::initialize_all(&InitializationConfig::default());
+            TargetMachine::get_default_triple()
+        }
+        "wasm32" => {
+            Target::initialize_webassembly(&InitializationConfig::default());
+            TargetTriple::create("wasm32-unknown-unknown")
+        }
+        _ => return Err(YulcError::Other(format!("Unknown target: {}", target))),
+    };
+    let target = Target::from_triple(&triple).map_err(|e| YulcError::Codegen(e.to_string()))?;
+    let level = inkwell::OptimizationLevel::Default;
+    target
+        .create_target_machine(&triple, "generic", "", level, RelocMode::PIC, CodeModel::Default)
+        .ok_or_else(|| YulcError::Codegen(format!("Cannot generate code for {}", triple)))
+}
+
+/// Parses `file` and the files of the modules it declares, recursively, and
+/// resolves the names used across them into one program. The directory of
+/// `file` is the package root, below which the module `a::b` is the file
+/// `a/b.yul` or `a/b/mod.yul`. Syntax errors of all files are reported
+/// together.
+fn load_program(file: &str, sources: &mut SourceMap) -> YulcResult<Program> {
+    use crate::lexer::lex_at;
+    use crate::parser::Parser;
+    use crate::resolve::Module;
+
+    let root = Path::new(file).parent().unwrap_or(Path::new(""));
+    let mut modules = Vec::new();
+    let mut errors = Vec::new();
+    let mut pending = VecDeque::from([(Vec::new(), PathBuf::from(file))]);
+    while let Some((path, file)) = pending.pop_front() {
+        let source = std::fs::read_to_string(&file)?;
+        let start = sources.add_file(file.display().to_string(), source.as_str());
+        let tokens = lex_at(&source, start);
+        let mut parser = Parser::new(&tokens);
+        let program = parser.parse_program();
+        errors.extend_from_slice(parser.errors());
+        for decl in &program.mods {
+            let mut child = path.clone();
+            child.push(decl.name.clone());
+            match module_file(root, &child) {
+                Ok(file) => pending.push_back((child, file)),
+                Err(message) => errors.push(SpannedError::new(decl.span, message)),
+            }
+        }
+        modules.push(Module { path, program });
+    }
+    if !errors.is_empty() {
+        return Err(YulcError::parse(&errors, sources));
+    }
+    crate::resolve::resolve(modules).map_err(|e| YulcError::semantic(e, sources))
+}
+
+/// The file that holds the module at `path` below the package root `root`.
+fn module_file(root: &Path, path: &[String]) -> Result<PathBuf, String> {
+    let base: PathBuf = path.iter().collect();
+    let flat = root.join(&base).with_extension("yul");
+    let nested = root.join(&base).join("mod.yul");
+    match (flat.is_file(), nested.is_file()) {
+        (true, false) => Ok(flat),
+        (false, true) => Ok(nested),
+        (true, true) => Err(format!(
+            "Module '{}' is in both {} and {}; remove one",
+            path.join("::"),
+            flat.display(),
+            nested.display()
+        )),
+        (false, false) => Err(format!(
+            "Cannot find module '{}': expected {} or {}",
+            path.join("::"),
+            flat.display(),
+            nested.display()
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::load_program;
+    use crate::source_map::SourceMap;
+    use crate::test_util::run;
+    use std::path::{Path, PathBuf};
+
+    /// A fresh directory holding `files`, by path relative to it.
+    fn package(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("yulc-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        for (path, source) in files {
+            let file = root.join(path);
+            std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+            std::fs::write(file, source).unwrap();
+        }
+        root
+    }
+
+    /// Loads the package's `main.yul`, then removes the package, and gives
+    /// the error, if any.
+    fn load(root: &Path) -> Result<(), String> {
+        let main = root.join("main.yul");
+        let result = load_program(main.to_str().unwrap(), &mut SourceMap::new());
+        std::fs::remove_dir_all(root).unwrap();
+        result.map(drop).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn hello_world_builds_and_runs() {
+        assert_eq!(run("fn main() {\n  print(\"Hello, world!\")\n}\n"), "Hello, world!\n");
+    }
+
+    #[test]
+    fn print_writes_escaped_strings_and_ints() {
+        let source = "fn main() {\n    print(\"tab\\there \\\"quoted\\\" \\\\\");\n    print(6 * 7);\n    print(-1);\n}\n";
+        assert_eq!(run(source), "tab\there \"quoted\" \\\n42\n-1\n");
+    }
+
+    #[test]
+    fn struct_fields_are_read_by_value_and_through_references() {
+        let source = "struct P { x: i64, y: i64 }
+fn sum(p: P) -> i64 {
+    return p.x + p.y
+}
+fn first(p: &P) -> i64 {
+    return p.x
+}
+fn main() {
+    let p = P { x: 1, y: 2 }
+    print(first(&p))
+    print(sum(p))
+}
+";
+        assert_eq!(run(source), "1\n3\n");
+    }
+
+    #[test]
+    fn modules_are_flat_files_or_directories() {
+        let root = package(
+            "layouts",
+            &[
+                ("main.yul", "mod a\nmod c\nfn main() { print(a::b::f() + c::g()) }\n"),
+                ("a/mod.yul", "pub mod b\n"),
+                ("a/b.yul", "pub fn f() -> i64 { return 1 }\n"),
+                ("c.yul", "pub fn g() -> i64 { return 2 }\n"),
+            ],
+        );
+        assert_eq!(load(&root), Ok(()));
+    }
+
+    #[test]
+    fn a_module_has_one_file() {
+        let root = package("both", &[("main.yul", "mod a\nfn main() {}\n"), ("a.yul", ""), ("a/mod.yul", "")]);
+        assert_eq!(
+            load(&root),
+            Err(format!(
+                "{main}:1:1: parsing error: Module 'a' is in both {flat} and {nested}; remove one\naborting due to 1 parsing error",
+                main = root.join("main.yul").display(),
+                flat = root.join("a.yul").display(),
+                nested = root.join("a").join("mod.yul").display(),
+            ))
+        );
+    }
+
+    #[test]
+    fn missing_modules_name_the_files_looked_for() {
+        let root = package("missing", &[("main.yul", "fn main() {}\nmod a\n"), ("a/readme.txt", "")]);
+        assert_eq!(
+            load(&root),
+            Err(format!(
+                "{main}:2:1: parsing error: Cannot find module 'a': expected {flat} or {nested}\naborting due to 1 parsing error",
+                main = root.join("main.yul").display(),
+                flat = root.join("a.yul").display(),
+                nested = root.join("a").join("mod.yul").display(),
+            ))
+        );
+    }
+
+    #[test]
+    fn syntax_errors_of_all_files_are_reported() {
+        let root = package("syntax", &[("main.yul", "mod a\nfn main() { let = 1 }\n"), ("a.yul", "pub fn () {}\n")]);
+        let err = load(&root).unwrap_err();
+        assert!(err.starts_with(&format!("{}:2:17: parsing error: Expected identifier", root.join("main.yul").display())), "{}", err);
+        assert!(err.contains(&format!("{}:1:8: parsing error: Expected identifier", root.join("a.yul").display())), "{}", err);
+        assert!(err.ends_with("aborting due to 2 parsing errors"), "{}", err);
+    }
+}
@@ -1,101 +1,952 @@
+use crate::ast::{BinOp, UnaryOp};
 use crate::ir::*;
+use crate::types::Type;
 use inkwell::context::Context;
+use inkwell::basic_block::BasicBlock;
 use inkwell::builder::Builder;
 use inkwell::module::Module;
-use inkwell::values::FunctionValue;
+use inkwell::targets::{TargetData, TargetMachine};
+use inkwell::types::{BasicMetadataTypeEnum, BasicType, BasicTypeEnum, FunctionType, StructType};
+use inkwell::values::{BasicMetadataValueEnum, BasicValueEnum, FloatValue, FunctionValue, IntValue, PointerValue};
+use inkwell::AddressSpace;
+use inkwell::{FloatPredicate, IntPredicate};
 use anyhow::Result;
+use std::collections::{HashMap, HashSet};
+
+/// Stack slot and value type of each local variable.
+type Vars<'ctx> = HashMap<String, (PointerValue<'ctx>, BasicTypeEnum<'ctx>)>;
 
 pub struct Codegen<'ctx> {
     pub context: &'ctx Context,
     pub module: Module<'ctx>,
     pub builder: Builder<'ctx>,
+    /// Basic blocks of the function currently being emitted, by IR label.
+    blocks: HashMap<String, BasicBlock<'ctx>>,
+    /// LLVM types of the program's structs and enums, by name.
+    structs: HashMap<String, StructType<'ctx>>,
+    /// Payload layout of each variant of the program's enums, by enum name.
+    enums: HashMap<String, Vec<StructType<'ctx>>>,
+    /// Types of the methods of the traits used as `dyn`, by trait name, in
+    /// vtable order and with `self` passed as an `i8*`.
+    dyn_methods: HashMap<String, Vec<FunctionType<'ctx>>>,
+    /// Emit the checks of `CheckIndex` and `CheckRange`; without them those
+    /// instructions do nothing.
+    pub bounds_checks: bool,
+    /// Sizes and alignments of the target the module is generated for.
+    layout: TargetData,
 }
 
 impl<'ctx> Codegen<'ctx> {
-    pub fn new(context: &'ctx Context, name: &str) -> Self {
+    /// A generator of the module `name` for the target of `machine`.
+    pub fn new(context: &'ctx Context, name: &str, machine: &TargetMachine) -> Self {
         let module = context.create_module(name);
+        let layout = machine.get_target_data();
+        module.set_triple(&machine.get_triple());
+        module.set_data_layout(&layout.get_data_layout());
         let builder = context.create_builder();
-        Self { context, module, builder }
+        Self {
+            context,
+            module,
+            builder,
+            blocks: HashMap::new(),
+            structs: HashMap::new(),
+            enums: HashMap::new(),
+            dyn_methods: HashMap::new(),
+            bounds_checks: true,
+            layout,
+        }
     }
 
     pub fn codegen_module(&mut self, ir: &IrModule) -> Result<()> {
-        use inkwell::types::BasicTypeEnum;
-        use inkwell::values::{BasicValueEnum, IntValue};
+        // Name every struct and enum before filling in bodies so fields may
+        // point to types declared later.
+        let names: Vec<&String> = ir.structs.iter().map(|s| &s.name).chain(ir.enums.iter().map(|e| &e.name)).collect();
+        for name in &names {
+            self.structs.insert(name.to_string(), self.context.opaque_struct_type(name));
+        }
+        let mut defined = HashSet::new();
+        for name in names {
+            self.define_type(name, ir, &mut defined)?;
+        }
+        for def in &ir.traits {
+            let methods = def.methods.iter().map(|m| self.method_type(m)).collect::<Result<_>>()?;
+            self.dyn_methods.insert(def.name.clone(), methods);
+        }
 
         // Declare external functions
         for ext in &ir.externs {
-            let param_types: Vec<BasicTypeEnum> = ext.params.iter().map(|_| self.context.i64_type().into()).collect();
-            let ret_type = self.context.i64_type(); // For now, assume all externs return i64
-            let fn_type = ret_type.fn_type(&param_types, false);
+            let param_types = ext.params.iter()
+                .map(|ty| Ok(self.llvm_type(ty)?.into()))
+                .collect::<Result<Vec<BasicMetadataTypeEnum>>>()?;
+            let fn_type = self.llvm_type(&ext.ret_ty)?.fn_type(&param_types, false);
             self.module.add_function(&ext.name, fn_type, None);
         }
 
+        // Declare every function before emitting bodies so calls may refer to
+        // functions defined later in the file.
+        for func in &ir.functions {
+            let param_types = func.params.iter()
+                .map(|p| Ok(self.llvm_type(&p.ty)?.into()))
+                .collect::<Result<Vec<BasicMetadataTypeEnum>>>()?;
+            // `main` keeps the C entry point signature when it declares no return type.
+            let fn_type = match (&func.ret_ty, func.name == "main") {
+                (Some(ty), _) => self.llvm_type(ty)?.fn_type(&param_types, false),
+                (None, true) => self.context.i32_type().fn_type(&param_types, false),
+                (None, false) => self.context.void_type().fn_type(&param_types, false),
+            };
+            self.module.add_function(&func.name, fn_type, None);
+        }
+
+        for vtable in &ir.vtables {
+            let entries = vtable.functions.iter()
+                .zip(&self.dyn_methods[&vtable.trait_name])
+                .map(|(name, fn_type)| {
+                    let function = self.module.get_function(name)
+                        .ok_or_else(|| anyhow::anyhow!("Unknown function: {}", name))?;
+                    let ptr = function.as_global_value().as_pointer_value();
+                    Ok(ptr.const_cast(fn_type.ptr_type(AddressSpace::default())).into())
+                })
+                .collect::<Result<Vec<BasicValueEnum>>>()?;
+            let table = self.context.const_struct(&entries, false);
+            let global = self.module.add_global(table.get_type(), None, &vtable.name);
+            global.set_initializer(&table);
+            global.set_constant(true);
+        }
+
         for func in &ir.functions {
-            let fn_type = self.context.i64_type().fn_type(&[], false);
-            let function = self.module.add_function(&func.name, fn_type, None);
-            let entry = self.context.append_basic_block(function, "entry");
-            self.builder.position_at_end(entry);
-            let mut vars = std::collections::HashMap::new();
-            for inst in &func.body {
-                self.codegen_inst(inst, &mut vars)?;
+            let function = self.module.get_function(&func.name).unwrap();
+            self.blocks = func.blocks.iter()
+                .map(|b| (b.label.clone(), self.context.append_basic_block(function, &b.label)))
+                .collect();
+            let mut vars = HashMap::new();
+            self.builder.position_at_end(self.blocks["entry"]);
+            for (param, value) in func.params.iter().zip(function.get_param_iter()) {
+                value.set_name(&param.name);
+                self.bind_local(&param.name, value, &mut vars);
+            }
+            for block in &func.blocks {
+                self.builder.position_at_end(self.blocks[&block.label]);
+                for inst in &block.body {
+                    self.codegen_inst(inst, &mut vars)?;
+                }
+                let current = self.builder.get_insert_block().unwrap();
+                if current.get_terminator().is_none() {
+                    if func.ret_ty.is_some() {
+                        // Semantic analysis guarantees every path returns, so this is dead code.
+                        self.builder.build_unreachable();
+                    } else {
+                        self.build_void_return();
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets the body of the named struct or enum type, after those of the
+    /// types it holds by value, since its size depends on theirs.
+    fn define_type(&mut self, name: &str, ir: &IrModule, defined: &mut HashSet<String>) -> Result<()> {
+        if !defined.insert(name.to_string()) {
+            return Ok(());
+        }
+        let struct_def = ir.structs.iter().find(|s| s.name == name);
+        let enum_def = ir.enums.iter().find(|e| e.name == name);
+        let contained = struct_def.map(|s| &s.fields)
+            .into_iter()
+            .chain(enum_def.into_iter().flat_map(|e| &e.variants))
+            .flatten();
+        for mut ty in contained {
+            // An array holds its elements by value, so their type comes first.
+            while let Type::Array(elem, _) = ty {
+                ty = elem;
+            }
+            if let Type::Named(dep) = ty {
+                if self.structs.contains_key(dep) {
+                    self.define_type(dep, ir, defined)?;
+                }
             }
-            self.builder.build_return(Some(&self.context.i64_type().const_zero()));
+        }
+
+        if let Some(def) = struct_def {
+            let fields = def.fields.iter()
+                .map(|ty| self.llvm_type(ty))
+                .collect::<Result<Vec<_>>>()?;
+            self.structs[name].set_body(&fields, def.packed);
+        }
+        if let Some(def) = enum_def {
+            let payloads = def.variants.iter()
+                .map(|fields| {
+                    let fields = fields.iter().map(|ty| self.llvm_type(ty)).collect::<Result<Vec<_>>>()?;
+                    Ok(self.context.struct_type(&fields, false))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let size = payloads.iter().map(|p| self.layout.get_abi_size(p)).max().unwrap_or(0);
+            let words = self.context.i64_type().array_type(size.div_ceil(8) as u32);
+            self.structs[name].set_body(&[self.context.i32_type().into(), words.into()], false);
+            self.enums.insert(name.to_string(), payloads);
         }
         Ok(())
     }
 
-    fn codegen_inst(&self, inst: &IrInst, vars: &mut std::collections::HashMap<String, IntValue>) -> Result<Option<IntValue>> {
+    /// Maps a source-level type to its LLVM representation.
+    ///
+    /// Structs become named LLVM struct types with their fields in
+    /// declaration order. Without `packed`, each field sits at its natural
+    /// alignment exactly as a C compiler would place it; `packed` structs
+    /// have no padding at all. Structs passed by value to `extern fn`s do not
+    /// follow the platform C ABI, so FFI should pass pointers to them.
+    ///
+    /// Enums become `{ i32, [N x i64] }`: the index of the variant, then an
+    /// 8-byte aligned area large enough for the fields of any variant, which
+    /// are laid out there like those of an unpacked struct. Monomorphization
+    /// gives each instance of a generic enum such as `Option<i64>` its own
+    /// type with this layout, so `Option` and `Result` cost only the tag word
+    /// over their payload.
+    ///
+    /// `dyn Trait` values are `{ i8*, i8* }`: the address of the value and
+    /// that of its type's vtable for the trait, a constant struct of pointers
+    /// to functions taking the value's address as their first argument.
+    ///
+    /// References `&T` and raw pointers `*T` are both plain `T*` pointers.
+    ///
+    /// `[T; N]` arrays are LLVM arrays of `N` elements; `[T]` slices are
+    /// `{ T*, i64 }`, the address of the first element and the length.
+    ///
+    /// `fn` values are plain function pointers. `Fn` closures are
+    /// `{ i8*, i8* }` too: a function and the address of the environment
+    /// struct it is called with as an extra first argument, which holds the
+    /// captured values or their addresses.
+    fn llvm_type(&self, ty: &Type) -> Result<BasicTypeEnum<'ctx>> {
+        let name = match ty {
+            Type::Named(name) => name.as_str(),
+            Type::Generic(..) => return Err(anyhow::anyhow!("Generic type {} was not monomorphized", ty)),
+            Type::Pointer(pointee) | Type::Ref(pointee, _) => {
+                return Ok(self.llvm_type(pointee)?.ptr_type(AddressSpace::default()).into());
+            }
+            Type::Array(elem, len) => return Ok(self.llvm_type(elem)?.array_type(*len as u32).into()),
+            Type::Slice(elem) => {
+                let ptr = self.llvm_type(elem)?.ptr_type(AddressSpace::default());
+                return Ok(self.context.struct_type(&[ptr.into(), self.context.i64_type().into()], false).into());
+            }
+            Type::Dyn(_) | Type::Closure(..) => return Ok(self.pair_type().into()),
+            Type::FnPtr(params, ret_ty) => {
+                return Ok(self.function_type(false, params, ret_ty)?.ptr_type(AddressSpace::default()).into());
+            }
+            Type::Void => return Err(anyhow::anyhow!("void is not a value type")),
+            Type::Var(_) => return Err(anyhow::anyhow!("Type {} was not inferred", ty)),
+        };
+        let llvm_ty = match name {
+            "i8" | "u8" => self.context.i8_type().into(),
+            "i16" | "u16" => self.context.i16_type().into(),
+            "i32" | "u32" => self.context.i32_type().into(),
+            "i64" | "u64" => self.context.i64_type().into(),
+            "f32" => self.context.f32_type().into(),
+            "f64" => self.context.f64_type().into(),
+            "bool" => self.context.bool_type().into(),
+            // Unicode scalar value
+            "char" => self.context.i32_type().into(),
+            "string" => self.string_type().into(),
+            _ => match self.structs.get(name) {
+                Some(struct_ty) => (*struct_ty).into(),
+                None => return Err(anyhow::anyhow!("Unknown type: {}", ty)),
+            },
+        };
+        Ok(llvm_ty)
+    }
+
+    fn struct_type(&self, ty: &Type) -> Result<StructType<'ctx>> {
+        ty.as_named().and_then(|name| self.structs.get(name)).copied()
+            .ok_or_else(|| anyhow::anyhow!("Unknown struct: {}", ty))
+    }
+
+    /// Layout of the payload of variant `variant` of the enum type `ty`.
+    fn payload_type(&self, ty: &Type, variant: u32) -> Result<StructType<'ctx>> {
+        ty.as_named().and_then(|name| self.enums.get(name)).map(|payloads| payloads[variant as usize])
+            .ok_or_else(|| anyhow::anyhow!("Unknown enum: {}", ty))
+    }
+
+    /// Address of the payload of variant `variant` of the enum behind `ptr`.
+    fn payload_ptr(&self, ty: &Type, variant: u32, ptr: PointerValue<'ctx>) -> Result<PointerValue<'ctx>> {
+        let area = self.builder.build_struct_gep(self.struct_type(ty)?, ptr, 1, "payload")
+            .map_err(|_| anyhow::anyhow!("Unknown enum: {}", ty))?;
+        let payload_ty = self.payload_type(ty, variant)?;
+        Ok(self.builder.build_pointer_cast(area, payload_ty.ptr_type(AddressSpace::default()), "payloadptr"))
+    }
+
+    /// Allocates a stack slot in the function's entry block, where LLVM's
+    /// mem2reg pass can promote it back to a register.
+    fn entry_alloca(&self, ty: BasicTypeEnum<'ctx>, name: &str) -> PointerValue<'ctx> {
+        let function = self.builder.get_insert_block().unwrap().get_parent().unwrap();
+        let entry = function.get_first_basic_block().unwrap();
+        let alloca_builder = self.context.create_builder();
+        match entry.get_first_instruction() {
+            Some(first) => alloca_builder.position_before(&first),
+            None => alloca_builder.position_at_end(entry),
+        }
+        alloca_builder.build_alloca(ty, name)
+    }
+
+    /// Gives a local variable a stack slot holding `value`.
+    fn bind_local(&self, name: &str, value: BasicValueEnum<'ctx>, vars: &mut Vars<'ctx>) {
+        let ty = value.get_type();
+        let slot = self.entry_alloca(ty, name);
+        self.builder.build_store(slot, value);
+        vars.insert(name.to_string(), (slot, ty));
+    }
+
+    /// Strings are passed around as `{ i8*, i64 }`: a pointer to UTF-8 bytes
+    /// and their length in bytes. The bytes are not guaranteed to be NUL-terminated.
+    fn string_type(&self) -> StructType<'ctx> {
+        let ptr = self.context.i8_type().ptr_type(AddressSpace::default());
+        self.context.struct_type(&[ptr.into(), self.context.i64_type().into()], false)
+    }
+
+    /// Layout of `dyn` values and closures: two pointers.
+    fn pair_type(&self) -> StructType<'ctx> {
+        let ptr = self.context.i8_type().ptr_type(AddressSpace::default());
+        self.context.struct_type(&[ptr.into(), ptr.into()], false)
+    }
+
+    /// Pair of `first` and `second`, both cast to `i8*`.
+    fn build_pair(&self, first: PointerValue<'ctx>, second: PointerValue<'ctx>, name: &str) -> BasicValueEnum<'ctx> {
+        let i8_ptr = self.context.i8_type().ptr_type(AddressSpace::default());
+        let mut value = self.pair_type().get_undef();
+        for (i, field) in [first, second].into_iter().enumerate() {
+            let field = self.builder.build_pointer_cast(field, i8_ptr, name);
+            value = self.builder.build_insert_value(value, field, i as u32, name)
+                .unwrap()
+                .into_struct_value();
+        }
+        value.into()
+    }
+
+    /// Type of a function taking `params` and returning `ret_ty`, which is
+    /// `Void` for none. With `with_env`, it takes an `i8*` first, as trait
+    /// methods called through a vtable and closures do.
+    fn function_type(&self, with_env: bool, params: &[Type], ret_ty: &Type) -> Result<FunctionType<'ctx>> {
+        let mut param_types: Vec<BasicMetadataTypeEnum> = Vec::new();
+        if with_env {
+            param_types.push(self.context.i8_type().ptr_type(AddressSpace::default()).into());
+        }
+        for ty in params {
+            param_types.push(self.llvm_type(ty)?.into());
+        }
+        Ok(match ret_ty {
+            Type::Void => self.context.void_type().fn_type(&param_types, false),
+            ty => self.llvm_type(ty)?.fn_type(&param_types, false),
+        })
+    }
+
+    /// Type of a trait method called through a vtable.
+    fn method_type(&self, method: &IrMethod) -> Result<FunctionType<'ctx>> {
+        self.function_type(true, &method.params, method.ret_ty.as_ref().unwrap_or(&Type::Void))
+    }
+
+    /// Function through which a closure made from a function pointer of
+    /// type `ty` calls it; the pointer is its environment.
+    fn closure_thunk(&self, ty: &Type) -> Result<FunctionValue<'ctx>> {
+        // No identifier contains the parentheses of the type's name.
+        let name = format!("{}.thunk", ty);
+        if let Some(thunk) = self.module.get_function(&name) {
+            return Ok(thunk);
+        }
+        let (params, ret_ty) = match ty {
+            Type::FnPtr(params, ret_ty) => (params, ret_ty),
+            _ => return Err(anyhow::anyhow!("Cannot make a closure from a value of type {}", ty)),
+        };
+        let target_type = self.function_type(false, params, ret_ty)?;
+        let thunk = self.module.add_function(&name, self.function_type(true, params, ret_ty)?, None);
+        let builder = self.context.create_builder();
+        builder.position_at_end(self.context.append_basic_block(thunk, "entry"));
+        let mut values = thunk.get_param_iter();
+        let env = values.next().unwrap().into_pointer_value();
+        let target = builder.build_pointer_cast(env, target_type.ptr_type(AddressSpace::default()), "fn");
+        let args: Vec<BasicMetadataValueEnum> = values.map(|v| v.into()).collect();
+        let call = builder.build_indirect_call(target_type, target, &args, "calltmp");
+        match call.try_as_basic_value().left() {
+            Some(value) => builder.build_return(Some(&value)),
+            None => builder.build_return(None),
+        };
+        Ok(thunk)
+    }
+
+    /// Looks up a function from the runtime support library, declaring it on first use.
+    fn runtime_fn(&self, name: &str, fn_type: FunctionType<'ctx>) -> FunctionValue<'ctx> {
+        self.module.get_function(name)
+            .unwrap_or_else(|| self.module.add_function(name, fn_type, None))
+    }
+
+    fn codegen_inst(&self, inst: &IrInst, vars: &mut Vars<'ctx>) -> Result<Option<BasicValueEnum<'ctx>>> {
         match inst {
-            IrInst::Const(i) => Ok(Some(self.context.i64_type().const_int(*i as u64, true))),
+            IrInst::Const(value, ty) => {
+                let int_ty = self.llvm_type(ty)?.into_int_type();
+                Ok(Some(int_ty.const_int(*value, false).into()))
+            }
+            IrInst::Float(value, ty) => {
+                let float_ty = self.llvm_type(ty)?.into_float_type();
+                Ok(Some(float_ty.const_float(*value).into()))
+            }
+            IrInst::Bool(b) => Ok(Some(self.context.bool_type().const_int(*b as u64, false).into())),
+            IrInst::Char(c) => Ok(Some(self.context.i32_type().const_int(*c as u64, false).into())),
+            IrInst::Str(s) => {
+                let data = self.builder.build_global_string_ptr(s, "str");
+                let len = self.context.i64_type().const_int(s.len() as u64, false);
+                let value = self.string_type().const_named_struct(&[data.as_pointer_value().into(), len.into()]);
+                Ok(Some(value.into()))
+            }
             IrInst::Load(name) => {
-                if let Some(&val) = vars.get(name) {
-                    Ok(Some(val))
+                if let Some(&(slot, ty)) = vars.get(name) {
+                    Ok(Some(self.builder.build_load(ty, slot, name)))
                 } else {
                     Err(anyhow::anyhow!("Undefined variable: {}", name))
                 }
             }
             IrInst::Store(name, value) => {
-                let val = self.codegen_inst(value, vars)?.unwrap();
-                vars.insert(name.clone(), val);
+                let val = self.codegen_value(value, vars)?;
+                self.bind_local(name, val, vars);
+                Ok(None)
+            }
+            IrInst::StructLit(ty, fields) => {
+                let mut value = self.struct_type(ty)?.get_undef();
+                for (i, field) in fields.iter().enumerate() {
+                    let field = self.codegen_value(field, vars)?;
+                    value = self.builder.build_insert_value(value, field, i as u32, "structtmp")
+                        .unwrap()
+                        .into_struct_value();
+                }
+                Ok(Some(value.into()))
+            }
+            IrInst::Extract(base, index) => {
+                let base = self.codegen_value(base, vars)?.into_struct_value();
+                Ok(Some(self.builder.build_extract_value(base, *index, "fieldtmp").unwrap()))
+            }
+            IrInst::EnumLit(ty, variant, fields) => {
+                let fields = fields.iter()
+                    .map(|f| self.codegen_value(f, vars))
+                    .collect::<Result<Vec<_>>>()?;
+                let enum_ty = self.struct_type(ty)?;
+                let slot = self.entry_alloca(enum_ty.into(), "enumtmp");
+                let tag = self.builder.build_struct_gep(enum_ty, slot, 0, "tag").unwrap();
+                self.builder.build_store(tag, self.context.i32_type().const_int(*variant as u64, false));
+                if !fields.is_empty() {
+                    let payload = self.payload_ptr(ty, *variant, slot)?;
+                    let payload_ty = self.payload_type(ty, *variant)?;
+                    for (i, field) in fields.into_iter().enumerate() {
+                        let ptr = self.builder.build_struct_gep(payload_ty, payload, i as u32, "fieldptr").unwrap();
+                        self.builder.build_store(ptr, field);
+                    }
+                }
+                Ok(Some(self.builder.build_load(enum_ty, slot, "enumval")))
+            }
+            IrInst::TagOf(ty, ptr) => {
+                let ptr = self.codegen_value(ptr, vars)?.into_pointer_value();
+                let tag = self.builder.build_struct_gep(self.struct_type(ty)?, ptr, 0, "tagptr")
+                    .map_err(|_| anyhow::anyhow!("Unknown enum: {}", ty))?;
+                Ok(Some(self.builder.build_load(self.context.i32_type(), tag, "tag")))
+            }
+            IrInst::PayloadAddr(ty, variant, index, ptr) => {
+                let ptr = self.codegen_value(ptr, vars)?.into_pointer_value();
+                let payload = self.payload_ptr(ty, *variant, ptr)?;
+                let field = self.builder.build_struct_gep(self.payload_type(ty, *variant)?, payload, *index, "fieldptr")
+                    .map_err(|_| anyhow::anyhow!("Variant {} of '{}' has no field {}", variant, ty, index))?;
+                Ok(Some(field.into()))
+            }
+            IrInst::Local(name, ty) => {
+                let ty = self.llvm_type(ty)?;
+                let slot = self.entry_alloca(ty, name);
+                vars.insert(name.clone(), (slot, ty));
+                Ok(None)
+            }
+            IrInst::BindAddr(name, ty, addr) => {
+                let addr = self.codegen_value(addr, vars)?.into_pointer_value();
+                vars.insert(name.clone(), (addr, self.llvm_type(ty)?));
+                Ok(None)
+            }
+            IrInst::VarAddr(name) => match vars.get(name) {
+                Some(&(slot, _)) => Ok(Some(slot.into())),
+                None => Err(anyhow::anyhow!("Undefined variable: {}", name)),
+            },
+            IrInst::FieldAddr(ty, index, base) => {
+                let base = self.codegen_value(base, vars)?.into_pointer_value();
+                let field = self.builder.build_struct_gep(self.struct_type(ty)?, base, *index, "fieldptr")
+                    .map_err(|_| anyhow::anyhow!("Struct '{}' has no field {}", ty, index))?;
+                Ok(Some(field.into()))
+            }
+            IrInst::ArrayLit(elem_ty, elems) => {
+                let mut value = self.llvm_type(elem_ty)?.array_type(elems.len() as u32).get_undef();
+                for (i, elem) in elems.iter().enumerate() {
+                    let elem = self.codegen_value(elem, vars)?;
+                    value = self.builder.build_insert_value(value, elem, i as u32, "arraytmp")
+                        .unwrap()
+                        .into_array_value();
+                }
+                Ok(Some(value.into()))
+            }
+            IrInst::ElemAddr(elem_ty, base, index) => {
+                let elem_ty = self.llvm_type(elem_ty)?;
+                let base = self.codegen_value(base, vars)?.into_pointer_value();
+                let base = self.builder.build_pointer_cast(base, elem_ty.ptr_type(AddressSpace::default()), "elems");
+                let index = self.codegen_int(index, vars)?;
+                // Bounds are checked by `CheckIndex` and `CheckRange` beforehand.
+                Ok(Some(unsafe { self.builder.build_gep(elem_ty, base, &[index], "elemptr") }.into()))
+            }
+            IrInst::MakeSlice(elem_ty, ptr, len) => {
+                let slice_ty = self.llvm_type(&Type::Slice(Box::new(elem_ty.clone())))?.into_struct_type();
+                let ptr = self.codegen_value(ptr, vars)?;
+                let len = self.codegen_value(len, vars)?;
+                let mut value = slice_ty.get_undef();
+                for (i, field) in [ptr, len].into_iter().enumerate() {
+                    value = self.builder.build_insert_value(value, field, i as u32, "slicetmp")
+                        .unwrap()
+                        .into_struct_value();
+                }
+                Ok(Some(value.into()))
+            }
+            IrInst::CheckIndex(index, len) => {
+                let index = self.codegen_int(index, vars)?;
+                let len = self.codegen_int(len, vars)?;
+                if self.bounds_checks {
+                    // A negative index compares as a huge unsigned one.
+                    let ok = self.builder.build_int_compare(IntPredicate::ULT, index, len, "inbounds");
+                    self.build_check(ok, "yul_panic_index", &[index, len]);
+                }
+                Ok(None)
+            }
+            IrInst::CheckRange(start, end, len) => {
+                let start = self.codegen_int(start, vars)?;
+                let end = self.codegen_int(end, vars)?;
+                let len = self.codegen_int(len, vars)?;
+                if self.bounds_checks {
+                    let end_ok = self.builder.build_int_compare(IntPredicate::ULE, end, len, "endok");
+                    let start_ok = self.builder.build_int_compare(IntPredicate::ULE, start, end, "startok");
+                    let ok = self.builder.build_and(end_ok, start_ok, "inbounds");
+                    self.build_check(ok, "yul_panic_range", &[start, end, len]);
+                }
+                Ok(None)
+            }
+            IrInst::Alloc(ty, count) => {
+                let elem_ty = self.llvm_type(ty)?;
+                let count = self.codegen_int(count, vars)?;
+                let size = elem_ty.size_of().ok_or_else(|| anyhow::anyhow!("Type {} has no size", ty))?;
+                let bytes = self.builder.build_int_mul(count, size, "bytes");
+                let i8_ptr = self.context.i8_type().ptr_type(AddressSpace::default());
+                let func = self.runtime_fn("yul_alloc", i8_ptr.fn_type(&[self.context.i64_type().into()], false));
+                let memory = self.builder.build_call(func, &[bytes.into()], "alloc")
+                    .try_as_basic_value()
+                    .left()
+                    .unwrap()
+                    .into_pointer_value();
+                let ptr = self.builder.build_pointer_cast(memory, elem_ty.ptr_type(AddressSpace::default()), "allocptr");
+                Ok(Some(ptr.into()))
+            }
+            IrInst::Free(ptr) => {
+                let ptr = self.codegen_value(ptr, vars)?.into_pointer_value();
+                let i8_ptr = self.context.i8_type().ptr_type(AddressSpace::default());
+                let ptr = self.builder.build_pointer_cast(ptr, i8_ptr, "freeptr");
+                let func = self.runtime_fn("yul_free", self.context.void_type().fn_type(&[i8_ptr.into()], false));
+                self.builder.build_call(func, &[ptr.into()], "");
+                Ok(None)
+            }
+            IrInst::Deref(ty, ptr) => {
+                let ptr = self.codegen_value(ptr, vars)?.into_pointer_value();
+                Ok(Some(self.builder.build_load(self.llvm_type(ty)?, ptr, "loadtmp")))
+            }
+            IrInst::Write(ptr, value) => {
+                let ptr = self.codegen_value(ptr, vars)?.into_pointer_value();
+                let value = self.codegen_value(value, vars)?;
+                self.builder.build_store(ptr, value);
+                Ok(None)
+            }
+            IrInst::Print(ty, arg) => {
+                self.codegen_print(ty, arg, vars)?;
                 Ok(None)
             }
             IrInst::Call(name, args) => {
                 // Call an external or internal function
                 let func = self.module.get_function(name)
                     .ok_or_else(|| anyhow::anyhow!("Unknown function: {}", name))?;
-                let arg_vals: Vec<IntValue> = args.iter().map(|a| self.codegen_inst(a, vars).unwrap().unwrap()).collect();
-                let arg_vals_ref: Vec<BasicValueEnum> = arg_vals.iter().map(|&v| v.into()).collect();
-                let call = self.builder.build_call(func, &arg_vals_ref, "calltmp");
-                Ok(Some(call.try_as_basic_value().left().unwrap().into_int_value()))
-            }
-            IrInst::AsyncCall(_name, _args) => {
-                // For now, not implemented
-                Ok(Some(self.context.i64_type().const_zero()))
-            }
-            IrInst::Await(inner) => {
-                // Call the runtime's await function (assume it returns i64 for now)
-                // In a real system, this would lower to a call to a runtime await function
-                let awaited = self.codegen_inst(inner, vars)?.unwrap();
-                // Here, you would insert a call to the runtime's await function
-                // For demonstration, just return the awaited value
-                Ok(Some(awaited))
-            }
-            IrInst::Spawn(inner) => {
-                // Call the runtime's spawn function
-                let spawned = self.codegen_inst(inner, vars)?.unwrap();
-                // Here, you would insert a call to the runtime's spawn function
-                // For demonstration, just return zero
-                Ok(Some(self.context.i64_type().const_zero()))
+                if func.count_params() as usize != args.len() {
+                    return Err(anyhow::anyhow!(
+                        "Function '{}' expects {} arguments, found {}",
+                        name,
+                        func.count_params(),
+                        args.len()
+                    ));
+                }
+                let arg_vals = args.iter()
+                    .map(|a| Ok(self.codegen_value(a, vars)?.into()))
+                    .collect::<Result<Vec<BasicMetadataValueEnum>>>()?;
+                let call = self.builder.build_call(func, &arg_vals, "calltmp");
+                Ok(call.try_as_basic_value().left())
+            }
+            IrInst::CallGeneric(name, ..) => Err(anyhow::anyhow!("Generic function '{}' was not monomorphized", name)),
+            IrInst::CallMethod(trait_name, method, ..) => {
+                Err(anyhow::anyhow!("Call of '{}::{}' was not monomorphized", trait_name, method))
+            }
+            IrInst::MakeDyn(trait_name, ty, _) => {
+                Err(anyhow::anyhow!("Conversion of {} to dyn {} was not monomorphized", ty, trait_name))
+            }
+            IrInst::DynObject(vtable, ptr) => {
+                let ptr = self.codegen_value(ptr, vars)?.into_pointer_value();
+                let table = self.module.get_global(vtable)
+                    .ok_or_else(|| anyhow::anyhow!("Unknown vtable: {}", vtable))?
+                    .as_pointer_value();
+                Ok(Some(self.build_pair(ptr, table, "dyntmp")))
+            }
+            IrInst::FnAddr(name, _) => {
+                let function = self.module.get_function(name)
+                    .ok_or_else(|| anyhow::anyhow!("Unknown function: {}", name))?;
+                Ok(Some(function.as_global_value().as_pointer_value().into()))
+            }
+            IrInst::MakeClosure(name, _, env, on_heap) => {
+                let function = self.module.get_function(name)
+                    .ok_or_else(|| anyhow::anyhow!("Unknown function: {}", name))?;
+                let env = self.codegen_value(env, vars)?;
+                let env_ty = env.get_type();
+                let slot = if *on_heap {
+                    let i8_ptr = self.context.i8_type().ptr_type(AddressSpace::default());
+                    let alloc = self.runtime_fn("yul_alloc", i8_ptr.fn_type(&[self.context.i64_type().into()], false));
+                    let size = env_ty.size_of().ok_or_else(|| anyhow::anyhow!("Closure environment has no size"))?;
+                    let memory = self.builder.build_call(alloc, &[size.into()], "closure.env")
+                        .try_as_basic_value()
+                        .left()
+                        .unwrap()
+                        .into_pointer_value();
+                    self.builder.build_pointer_cast(memory, env_ty.ptr_type(AddressSpace::default()), "closure.env")
+                } else {
+                    self.entry_alloca(env_ty, "closure.env")
+                };
+                self.builder.build_store(slot, env);
+                Ok(Some(self.build_pair(function.as_global_value().as_pointer_value(), slot, "closure")))
+            }
+            IrInst::FnToClosure(ty, fn_ptr) => {
+                let thunk = self.closure_thunk(ty)?;
+                let fn_ptr = self.codegen_value(fn_ptr, vars)?.into_pointer_value();
+                Ok(Some(self.build_pair(thunk.as_global_value().as_pointer_value(), fn_ptr, "closure")))
+            }
+            IrInst::CallValue(ty, callee, args) => {
+                let (params, ret_ty) = match ty {
+                    Type::FnPtr(params, ret_ty) | Type::Closure(params, ret_ty) => (params, ret_ty),
+                    _ => return Err(anyhow::anyhow!("Cannot call a value of type {}", ty)),
+                };
+                let callee = self.codegen_value(callee, vars)?;
+                let mut arg_vals: Vec<BasicMetadataValueEnum> = Vec::new();
+                let (fn_type, function) = match ty {
+                    Type::Closure(..) => {
+                        let fn_type = self.function_type(true, params, ret_ty)?;
+                        let closure = callee.into_struct_value();
+                        let code = self.builder.build_extract_value(closure, 0, "closure.fn").unwrap();
+                        let env = self.builder.build_extract_value(closure, 1, "closure.env").unwrap();
+                        arg_vals.push(env.into());
+                        let fn_ptr_type = fn_type.ptr_type(AddressSpace::default());
+                        (fn_type, self.builder.build_pointer_cast(code.into_pointer_value(), fn_ptr_type, "closure.fn"))
+                    }
+                    _ => (self.function_type(false, params, ret_ty)?, callee.into_pointer_value()),
+                };
+                for arg in args {
+                    arg_vals.push(self.codegen_value(arg, vars)?.into());
+                }
+                let call = self.builder.build_indirect_call(fn_type, function, &arg_vals, "calltmp");
+                Ok(call.try_as_basic_value().left())
+            }
+            IrInst::CallDyn(trait_name, slot, object, args) => {
+                let methods = self.dyn_methods.get(trait_name)
+                    .ok_or_else(|| anyhow::anyhow!("Unknown trait: {}", trait_name))?;
+                let ptr_types: Vec<BasicTypeEnum> = methods.iter()
+                    .map(|m| m.ptr_type(AddressSpace::default()).into())
+                    .collect();
+                let table_ty = self.context.struct_type(&ptr_types, false);
+                let fn_type = methods[*slot as usize];
+
+                let object = self.codegen_value(object, vars)?.into_struct_value();
+                let data = self.builder.build_extract_value(object, 0, "dyn.data").unwrap();
+                let table = self.builder.build_extract_value(object, 1, "dyn.vtable").unwrap().into_pointer_value();
+                let table = self.builder.build_pointer_cast(table, table_ty.ptr_type(AddressSpace::default()), "vtable");
+                let entry = self.builder.build_struct_gep(table_ty, table, *slot, "method.ptr")
+                    .map_err(|_| anyhow::anyhow!("Trait '{}' has no method {}", trait_name, slot))?;
+                let function = self.builder.build_load(fn_type.ptr_type(AddressSpace::default()), entry, "method")
+                    .into_pointer_value();
+                let mut arg_vals: Vec<BasicMetadataValueEnum> = vec![data.into()];
+                for arg in args {
+                    arg_vals.push(self.codegen_value(arg, vars)?.into());
+                }
+                let call = self.builder.build_indirect_call(fn_type, function, &arg_vals, "calltmp");
+                Ok(call.try_as_basic_value().left())
+            }
+            IrInst::Binary(op, _, lhs, rhs) if op.is_logical() => {
+                Ok(Some(self.codegen_logical(*op, lhs, rhs, vars)?.into()))
+            }
+            IrInst::Binary(op, Type::Pointer(_), lhs, rhs) => {
+                let i64_ty = self.context.i64_type();
+                let l = self.codegen_value(lhs, vars)?.into_pointer_value();
+                let r = self.codegen_value(rhs, vars)?.into_pointer_value();
+                let l = self.builder.build_ptr_to_int(l, i64_ty, "ptrtmp");
+                let r = self.builder.build_ptr_to_int(r, i64_ty, "ptrtmp");
+                Ok(Some(self.codegen_int_binary(*op, false, l, r).into()))
+            }
+            IrInst::Binary(op, ty, lhs, rhs) if ty.is_float() => {
+                let l = self.codegen_value(lhs, vars)?.into_float_value();
+                let r = self.codegen_value(rhs, vars)?.into_float_value();
+                Ok(Some(self.codegen_float_binary(*op, l, r)))
+            }
+            IrInst::Binary(op, ty, lhs, rhs) => {
+                let l = self.codegen_int(lhs, vars)?;
+                let r = self.codegen_int(rhs, vars)?;
+                Ok(Some(self.codegen_int_binary(*op, ty.is_signed(), l, r).into()))
+            }
+            IrInst::Unary(op, inner) => {
+                let result = match (op, self.codegen_value(inner, vars)?) {
+                    (UnaryOp::Neg, BasicValueEnum::FloatValue(v)) => self.builder.build_float_neg(v, "negtmp").into(),
+                    (UnaryOp::Neg, BasicValueEnum::IntValue(v)) => self.builder.build_int_neg(v, "negtmp").into(),
+                    (UnaryOp::Not, BasicValueEnum::IntValue(v)) => self.builder.build_not(v, "nottmp").into(),
+                    (_, other) => {
+                        return Err(anyhow::anyhow!("Invalid operand for {:?}: {:?}", op, other.get_type()));
+                    }
+                };
+                Ok(Some(result))
+            }
+            // There is no scheduler yet, so a spawned future also runs to
+            // completion right away.
+            IrInst::Await(ty, future) => self.run_future(ty, future, vars),
+            IrInst::Spawn(ty, future) => {
+                self.run_future(ty, future, vars)?;
+                Ok(None)
             }
             IrInst::Ret(opt) => {
                 if let Some(val) = opt {
-                    let v = self.codegen_inst(val, vars)?.unwrap();
+                    let v = self.codegen_value(val, vars)?;
                     self.builder.build_return(Some(&v));
                 } else {
-                    self.builder.build_return(None);
+                    self.build_void_return();
                 }
                 Ok(None)
             }
+            IrInst::Jump(label) => {
+                self.builder.build_unconditional_branch(self.block(label)?);
+                Ok(None)
+            }
+            IrInst::Branch(cond, then_label, else_label) => {
+                let cond = self.codegen_int(cond, vars)?;
+                self.builder.build_conditional_branch(cond, self.block(then_label)?, self.block(else_label)?);
+                Ok(None)
+            }
+            IrInst::Switch(value, cases, default) => {
+                let value = self.codegen_int(value, vars)?;
+                let ty = value.get_type();
+                // Keys hold the value's bit pattern; drop the bits above its width.
+                let mask = u64::MAX >> (64 - ty.get_bit_width());
+                let cases = cases.iter()
+                    .map(|(key, label)| Ok((ty.const_int(key & mask, false), self.block(label)?)))
+                    .collect::<Result<Vec<_>>>()?;
+                self.builder.build_switch(value, self.block(default)?, &cases);
+                Ok(None)
+            }
+            IrInst::Unreachable => {
+                self.builder.build_unreachable();
+                Ok(None)
+            }
+        }
+    }
+
+    fn codegen_value(&self, inst: &IrInst, vars: &mut Vars<'ctx>) -> Result<BasicValueEnum<'ctx>> {
+        self.codegen_inst(inst, vars)?
+            .ok_or_else(|| anyhow::anyhow!("Expression does not produce a value: {:?}", inst))
+    }
+
+    fn codegen_int(&self, inst: &IrInst, vars: &mut Vars<'ctx>) -> Result<IntValue<'ctx>> {
+        match self.codegen_value(inst, vars)? {
+            BasicValueEnum::IntValue(v) => Ok(v),
+            other => Err(anyhow::anyhow!("Expected an integer value, found {:?}", other.get_type())),
+        }
+    }
+
+    /// Runs a future, a closure without parameters that runs the async
+    /// function's body, and then frees its environment, which the future
+    /// owns; see `IrInst::MakeClosure`.
+    fn run_future(&self, ty: &Type, future: &IrInst, vars: &mut Vars<'ctx>) -> Result<Option<BasicValueEnum<'ctx>>> {
+        let ret_ty = match ty {
+            Type::Closure(params, ret_ty) if params.is_empty() => ret_ty,
+            _ => return Err(anyhow::anyhow!("Cannot run a future of type {}", ty)),
+        };
+        let fn_type = self.function_type(true, &[], ret_ty)?;
+        let future = self.codegen_value(future, vars)?.into_struct_value();
+        let code = self.builder.build_extract_value(future, 0, "future.fn").unwrap().into_pointer_value();
+        let env = self.builder.build_extract_value(future, 1, "future.env").unwrap();
+        let function = self.builder.build_pointer_cast(code, fn_type.ptr_type(AddressSpace::default()), "future.fn");
+        let call = self.builder.build_indirect_call(fn_type, function, &[env.into()], "awaittmp");
+        let i8_ptr = self.context.i8_type().ptr_type(AddressSpace::default());
+        let free = self.runtime_fn("yul_free", self.context.void_type().fn_type(&[i8_ptr.into()], false));
+        self.builder.build_call(free, &[env.into()], "");
+        Ok(call.try_as_basic_value().left())
+    }
+
+    /// `print` dispatches on the argument's type to the matching runtime
+    /// support routine, widening narrow values first.
+    fn codegen_print(&self, ty: &Type, arg: &IrInst, vars: &mut Vars<'ctx>) -> Result<()> {
+        let value = self.codegen_value(arg, vars)?;
+        let void = self.context.void_type();
+        let i64_ty = self.context.i64_type();
+        let (name, args): (&str, Vec<BasicValueEnum>) = match ty.as_named().unwrap_or("") {
+            "string" => {
+                let v = value.into_struct_value();
+                let data = self.builder.build_extract_value(v, 0, "str.data").unwrap();
+                let len = self.builder.build_extract_value(v, 1, "str.len").unwrap();
+                ("yul_print_str", vec![data, len])
+            }
+            "bool" => {
+                let v = self.builder.build_int_z_extend(value.into_int_value(), self.context.i8_type(), "booltmp");
+                ("yul_print_bool", vec![v.into()])
+            }
+            "char" => ("yul_print_char", vec![value]),
+            "f32" | "f64" => {
+                let v = self.builder.build_float_ext(value.into_float_value(), self.context.f64_type(), "fexttmp");
+                ("yul_print_float", vec![v.into()])
+            }
+            _ if ty.is_signed() => {
+                let v = self.builder.build_int_s_extend(value.into_int_value(), i64_ty, "sexttmp");
+                ("yul_print_int", vec![v.into()])
+            }
+            _ if ty.is_integer() => {
+                let v = self.builder.build_int_z_extend(value.into_int_value(), i64_ty, "zexttmp");
+                ("yul_print_uint", vec![v.into()])
+            }
+            _ => return Err(anyhow::anyhow!("Cannot print a value of type {}", ty)),
+        };
+        let param_types: Vec<BasicMetadataTypeEnum> = args.iter().map(|a| a.get_type().into()).collect();
+        let args: Vec<BasicMetadataValueEnum> = args.into_iter().map(|a| a.into()).collect();
+        let func = self.runtime_fn(name, void.fn_type(&param_types, false));
+        self.builder.build_call(func, &args, "");
+        Ok(())
+    }
+
+    /// Continues if `ok` holds, and otherwise calls the runtime routine
+    /// `panic` with the `i64` arguments `args`, which reports the failed
+    /// check and aborts.
+    fn build_check(&self, ok: IntValue<'ctx>, panic: &str, args: &[IntValue<'ctx>]) {
+        let function = self.builder.get_insert_block().unwrap().get_parent().unwrap();
+        let fail_bb = self.context.append_basic_block(function, "check.fail");
+        let ok_bb = self.context.append_basic_block(function, "check.ok");
+        self.builder.build_conditional_branch(ok, ok_bb, fail_bb);
+
+        self.builder.position_at_end(fail_bb);
+        let param_types: Vec<BasicMetadataTypeEnum> = args.iter().map(|_| self.context.i64_type().into()).collect();
+        let func = self.runtime_fn(panic, self.context.void_type().fn_type(&param_types, false));
+        let args: Vec<BasicMetadataValueEnum> = args.iter().map(|&a| a.into()).collect();
+        self.builder.build_call(func, &args, "");
+        self.builder.build_unreachable();
+
+        self.builder.position_at_end(ok_bb);
+    }
+
+    /// Returns from a function without a declared return type; `main` still
+    /// hands a zero exit status back to the C runtime.
+    fn build_void_return(&self) {
+        let function = self.builder.get_insert_block().unwrap().get_parent().unwrap();
+        match function.get_type().get_return_type() {
+            Some(ty) => self.builder.build_return(Some(&ty.into_int_type().const_zero())),
+            None => self.builder.build_return(None),
+        };
+    }
+
+    fn block(&self, label: &str) -> Result<BasicBlock<'ctx>> {
+        self.blocks.get(label).copied()
+            .ok_or_else(|| anyhow::anyhow!("Unknown block: {}", label))
+    }
+
+    /// `&&` and `||` only evaluate their right operand when the left one does
+    /// not already decide the result.
+    fn codegen_logical(
+        &self,
+        op: BinOp,
+        lhs: &IrInst,
+        rhs: &IrInst,
+        vars: &mut Vars<'ctx>,
+    ) -> Result<IntValue<'ctx>> {
+        let l = self.codegen_int(lhs, vars)?;
+        let lhs_bb = self.builder.get_insert_block().unwrap();
+        let function = lhs_bb.get_parent().unwrap();
+        let rhs_bb = self.context.append_basic_block(function, "logic.rhs");
+        let end_bb = self.context.append_basic_block(function, "logic.end");
+        if op == BinOp::And {
+            self.builder.build_conditional_branch(l, rhs_bb, end_bb);
+        } else {
+            self.builder.build_conditional_branch(l, end_bb, rhs_bb);
         }
+
+        self.builder.position_at_end(rhs_bb);
+        let r = self.codegen_int(rhs, vars)?;
+        let rhs_end_bb = self.builder.get_insert_block().unwrap();
+        self.builder.build_unconditional_branch(end_bb);
+
+        self.builder.position_at_end(end_bb);
+        let bool_ty = self.context.bool_type();
+        let short = bool_ty.const_int((op == BinOp::Or) as u64, false);
+        let phi = self.builder.build_phi(bool_ty, "logictmp");
+        phi.add_incoming(&[(&short, lhs_bb), (&r, rhs_end_bb)]);
+        Ok(phi.as_basic_value().into_int_value())
+    }
+
+    fn codegen_int_binary(&self, op: BinOp, signed: bool, l: IntValue<'ctx>, r: IntValue<'ctx>) -> IntValue<'ctx> {
+        let predicate = match (op, signed) {
+            (BinOp::Add, _) => return self.builder.build_int_add(l, r, "addtmp"),
+            (BinOp::Sub, _) => return self.builder.build_int_sub(l, r, "subtmp"),
+            (BinOp::Mul, _) => return self.builder.build_int_mul(l, r, "multmp"),
+            (BinOp::Div, true) => return self.builder.build_int_signed_div(l, r, "divtmp"),
+            (BinOp::Div, false) => return self.builder.build_int_unsigned_div(l, r, "divtmp"),
+            (BinOp::Rem, true) => return self.builder.build_int_signed_rem(l, r, "remtmp"),
+            (BinOp::Rem, false) => return self.builder.build_int_unsigned_rem(l, r, "remtmp"),
+            (BinOp::And | BinOp::Or, _) => unreachable!("logical operators are lowered by codegen_logical"),
+            (BinOp::Eq, _) => IntPredicate::EQ,
+            (BinOp::Ne, _) => IntPredicate::NE,
+            (BinOp::Lt, true) => IntPredicate::SLT,
+            (BinOp::Le, true) => IntPredicate::SLE,
+            (BinOp::Gt, true) => IntPredicate::SGT,
+            (BinOp::Ge, true) => IntPredicate::SGE,
+            (BinOp::Lt, false) => IntPredicate::ULT,
+            (BinOp::Le, false) => IntPredicate::ULE,
+            (BinOp::Gt, false) => IntPredicate::UGT,
+            (BinOp::Ge, false) => IntPredicate::UGE,
+        };
+        self.builder.build_int_compare(predicate, l, r, "cmptmp")
+    }
+
+    fn codegen_float_binary(&self, op: BinOp, l: FloatValue<'ctx>, r: FloatValue<'ctx>) -> BasicValueEnum<'ctx> {
+        let predicate = match op {
+            BinOp::Add => return self.builder.build_float_add(l, r, "addtmp").into(),
+            BinOp::Sub => return self.builder.build_float_sub(l, r, "subtmp").into(),
+            BinOp::Mul => return self.builder.build_float_mul(l, r, "multmp").into(),
+            BinOp::Div => return self.builder.build_float_div(l, r, "divtmp").into(),
+            BinOp::Rem => return self.builder.build_float_rem(l, r, "remtmp").into(),
+            BinOp::And | BinOp::Or => unreachable!("logical operators are lowered by codegen_logical"),
+            BinOp::Eq => FloatPredicate::OEQ,
+            BinOp::Ne => FloatPredicate::UNE,
+            BinOp::Lt => FloatPredicate::OLT,
+            BinOp::Le => FloatPredicate::OLE,
+            BinOp::Gt => FloatPredicate::OGT,
+            BinOp::Ge => FloatPredicate::OGE,
+        };
+        self.builder.build_float_compare(predicate, l, r, "cmptmp").into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::lower;
+
+    /// The LLVM IR emitted for `source`, which must have no errors.
+    fn emit(source: &str) -> String {
+        let machine = crate::cli::target_machine("x86_64").unwrap();
+        let context = Context::create();
+        let mut codegen = Codegen::new(&context, "test", &machine);
+        codegen.codegen_module(&lower(source)).unwrap();
+        codegen.module.print_to_string().to_string()
+    }
+
+    #[test]
+    fn parameters_become_function_arguments() {
+        let ir = emit("fn add(a: i64, b: i64) -> i64 {\n    return a + b;\n}\nfn main() -> i64 {\n    return add(1, 2);\n}\n");
+        assert!(ir.contains("define i64 @add(i64 %a, i64 %b)"), "{}", ir);
+        assert!(ir.contains("call i64 @add(i64 1, i64 2)"), "{}", ir);
     }
 }
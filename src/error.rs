@@ -1,17 +1,112 @@
+use crate::ast::Span;
+use crate::source_map::{Location, SourceMap};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum YulcError {
-    #[error("Lexing error: {0}")]
-    Lex(String),
-    #[error("Parsing error: {0}")]
-    Parse(String),
+    #[error("{}", report(.0, "parsing error"))]
+    Parse(Vec<Diagnostic>),
+    #[error("{location}: semantic error: {message}{}", note_lines(.notes))]
+    Semantic { location: Location, message: String, notes: Vec<Diagnostic> },
     #[error("Codegen error: {0}")]
     Codegen(String),
+    #[error("Link error: {0}")]
+    Link(String),
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
     #[error("Other error: {0}")]
     Other(String),
 }
 
+impl YulcError {
+    pub fn parse(errors: &[SpannedError], sources: &SourceMap) -> Self {
+        let diagnostics = errors.iter()
+            .map(|err| Diagnostic { location: sources.location(err.span), message: err.message.clone() })
+            .collect();
+        YulcError::Parse(diagnostics)
+    }
+
+    pub fn semantic(err: anyhow::Error, sources: &SourceMap) -> Self {
+        let (location, message, notes) = locate(err, sources);
+        YulcError::Semantic { location, message, notes }
+    }
+}
+
+/// Resolves the spans of a `SpannedError` and its notes; other errors are
+/// reported at the start of the first file.
+fn locate(err: anyhow::Error, sources: &SourceMap) -> (Location, String, Vec<Diagnostic>) {
+    match err.downcast::<SpannedError>() {
+        Ok(spanned) => {
+            let notes = spanned.notes.into_iter()
+                .map(|(span, message)| Diagnostic { location: sources.location(span), message })
+                .collect();
+            (sources.location(spanned.span), spanned.message, notes)
+        }
+        Err(err) => (sources.lookup(0), err.to_string(), Vec::new()),
+    }
+}
+
 pub type YulcResult<T> = Result<T, YulcError>;
+
+/// One located message in a multi-error report.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub location: Location,
+    pub message: String,
+}
+
+/// Formats every diagnostic on its own line, followed by a count.
+fn report(diagnostics: &[Diagnostic], kind: &str) -> String {
+    let mut out = String::new();
+    for diag in diagnostics {
+        out.push_str(&format!("{}: {}: {}\n", diag.location, kind, diag.message));
+    }
+    let plural = if diagnostics.len() == 1 { "" } else { "s" };
+    out.push_str(&format!("aborting due to {} {}{}", diagnostics.len(), kind, plural));
+    out
+}
+
+/// Formats a warning, which unlike an error does not stop compilation, and
+/// its notes.
+pub fn format_warning(warning: &SpannedError, sources: &SourceMap) -> String {
+    let notes: Vec<Diagnostic> = warning.notes.iter()
+        .map(|(span, message)| Diagnostic { location: sources.location(*span), message: message.clone() })
+        .collect();
+    format!("{}: warning: {}{}", sources.location(warning.span), warning.message, note_lines(&notes))
+}
+
+/// Formats notes that follow an error, each on its own line.
+fn note_lines(notes: &[Diagnostic]) -> String {
+    notes.iter().map(|note| format!("\n{}: note: {}", note.location, note.message)).collect()
+}
+
+/// An error tied to a region of source text. Front-end passes return it
+/// inside `anyhow::Error` and the driver resolves the span for display.
+#[derive(Error, Debug, Clone)]
+#[error("{message}")]
+pub struct SpannedError {
+    pub span: Span,
+    pub message: String,
+    /// Other places the error involves, such as an earlier conflicting use,
+    /// each with a message of its own.
+    pub notes: Vec<(Span, String)>,
+}
+
+impl SpannedError {
+    pub fn new(span: Span, message: impl Into<String>) -> Self {
+        Self { span, message: message.into(), notes: Vec::new() }
+    }
+
+    pub fn with_note(mut self, span: Span, message: impl Into<String>) -> Self {
+        self.notes.push((span, message.into()));
+        self
+    }
+}
+
+/// Like `anyhow::bail!`, but records the span the error points at.
+#[macro_export]
+macro_rules! bail_at {
+    ($span:expr, $($arg:tt)*) => {
+        return Err($crate::error::SpannedError::new($span, format!($($arg)*)).into())
+    };
+}
@@ -0,0 +1,116 @@
+use crate::types::Type;
+
+/// A pattern reduced to what matters for exhaustiveness.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pat {
+    /// Matches anything: `_` or a binding.
+    Wild,
+    /// Constructor `index` of a type with finitely many constructors, applied
+    /// to patterns for its fields.
+    Ctor(usize, Vec<Pat>),
+    /// A single value of a type with too many values to list, such as an
+    /// integer or `char`.
+    Lit(i128),
+}
+
+/// One constructor of a type with finitely many: its name as written in a
+/// pattern and the types of its fields.
+#[derive(Debug, Clone)]
+pub struct Ctor {
+    pub name: String,
+    pub fields: Vec<Type>,
+}
+
+/// Lists the constructors of a type, or `None` if its values cannot be
+/// enumerated and only a wildcard covers them all.
+pub type Ctors<'a> = &'a dyn Fn(&Type) -> Option<Vec<Ctor>>;
+
+/// Whether some value matched by `row` is matched by none of `rows`. An arm
+/// for which this is false can never run. This is the usefulness check
+/// from Maranget's "Warnings for pattern matching".
+pub fn is_useful(rows: &[Vec<Pat>], row: &[Pat], tys: &[Type], ctors: Ctors) -> bool {
+    if row.is_empty() {
+        return rows.is_empty();
+    }
+    match &row[0] {
+        Pat::Ctor(ctor, fields) => {
+            let all = ctors(&tys[0]).expect("constructor pattern on a type without constructors");
+            let mut next = fields.clone();
+            next.extend_from_slice(&row[1..]);
+            let tys = with_fields(&all[*ctor].fields, &tys[1..]);
+            is_useful(&specialize(rows, *ctor, fields.len()), &next, &tys, ctors)
+        }
+        Pat::Lit(value) => {
+            let rows: Vec<Vec<Pat>> = rows.iter()
+                .filter(|r| r[0] == Pat::Wild || r[0] == Pat::Lit(*value))
+                .map(|r| r[1..].to_vec())
+                .collect();
+            is_useful(&rows, &row[1..], &tys[1..], ctors)
+        }
+        Pat::Wild => match ctors(&tys[0]) {
+            Some(all) => all.iter().enumerate().any(|(index, ctor)| {
+                let mut next = vec![Pat::Wild; ctor.fields.len()];
+                next.extend_from_slice(&row[1..]);
+                let tys = with_fields(&ctor.fields, &tys[1..]);
+                is_useful(&specialize(rows, index, ctor.fields.len()), &next, &tys, ctors)
+            }),
+            None => is_useful(&default_rows(rows), &row[1..], &tys[1..], ctors),
+        },
+    }
+}
+
+/// A value matched by none of `rows`, written as one pattern per column,
+/// or `None` if the rows are exhaustive.
+pub fn missing(rows: &[Vec<Pat>], tys: &[Type], ctors: Ctors) -> Option<Vec<String>> {
+    if tys.is_empty() {
+        return if rows.is_empty() { Some(Vec::new()) } else { None };
+    }
+    match ctors(&tys[0]) {
+        Some(all) => all.iter().enumerate().find_map(|(index, ctor)| {
+            let arity = ctor.fields.len();
+            let tys = with_fields(&ctor.fields, &tys[1..]);
+            let mut witness = missing(&specialize(rows, index, arity), &tys, ctors)?;
+            let fields: Vec<String> = witness.drain(..arity).collect();
+            let head = if arity == 0 {
+                ctor.name.clone()
+            } else {
+                format!("{}({})", ctor.name, fields.join(", "))
+            };
+            witness.insert(0, head);
+            Some(witness)
+        }),
+        None => {
+            let mut witness = missing(&default_rows(rows), &tys[1..], ctors)?;
+            witness.insert(0, "_".to_string());
+            Some(witness)
+        }
+    }
+}
+
+/// Rows that match constructor `ctor` in the first column, with that column
+/// replaced by the constructor's `arity` fields.
+fn specialize(rows: &[Vec<Pat>], ctor: usize, arity: usize) -> Vec<Vec<Pat>> {
+    rows.iter()
+        .filter_map(|row| {
+            let mut next = match &row[0] {
+                Pat::Ctor(c, fields) if *c == ctor => fields.clone(),
+                Pat::Wild => vec![Pat::Wild; arity],
+                _ => return None,
+            };
+            next.extend_from_slice(&row[1..]);
+            Some(next)
+        })
+        .collect()
+}
+
+/// Rows whose first column matches anything, without that column.
+fn default_rows(rows: &[Vec<Pat>]) -> Vec<Vec<Pat>> {
+    rows.iter()
+        .filter(|row| row[0] == Pat::Wild)
+        .map(|row| row[1..].to_vec())
+        .collect()
+}
+
+fn with_fields(fields: &[Type], rest: &[Type]) -> Vec<Type> {
+    fields.iter().chain(rest).cloned().collect()
+}
@@ -0,0 +1,317 @@
+use crate::ast::Span;
+use crate::bail_at;
+use crate::types::{self, Type};
+use anyhow::Result;
+
+/// What an inference variable may stand for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VarKind {
+    Any,
+    /// An integer type, like that of an integer literal without a suffix.
+    Int,
+    /// A signed integer type, like that of a negated `Int`.
+    Signed,
+    /// A float type, like that of a float literal without a suffix.
+    Float,
+}
+
+impl VarKind {
+    /// Whether a variable of this kind can stand for `ty`, which is not a
+    /// variable itself.
+    fn admits(self, ty: &Type) -> bool {
+        match self {
+            VarKind::Any => true,
+            VarKind::Int => ty.is_integer(),
+            VarKind::Signed => ty.is_signed(),
+            VarKind::Float => ty.is_float(),
+        }
+    }
+
+    /// The kind of a variable that has to be both kinds, if any.
+    fn meet(self, other: VarKind) -> Option<VarKind> {
+        match (self, other) {
+            (VarKind::Any, kind) | (kind, VarKind::Any) => Some(kind),
+            (VarKind::Int | VarKind::Signed, VarKind::Signed) | (VarKind::Signed, VarKind::Int) => {
+                Some(VarKind::Signed)
+            }
+            (a, b) if a == b => Some(a),
+            _ => None,
+        }
+    }
+
+    /// The type a variable of this kind stands for when nothing decides it.
+    fn default_type(self) -> Option<Type> {
+        match self {
+            VarKind::Any => None,
+            VarKind::Int | VarKind::Signed => Some(Type::named("i64")),
+            VarKind::Float => Some(Type::named("f64")),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Var {
+    value: Option<Type>,
+    kind: VarKind,
+    /// The value whose type this is, and a description of the type, for
+    /// errors.
+    origin: Span,
+    what: String,
+    /// How to spell out the type, for when nothing decides it.
+    advice: String,
+    /// The first variable declared without a type whose type mentions this
+    /// one, and that type.
+    holder: Option<(String, Type)>,
+}
+
+/// The types that inference is working out within one function body, as
+/// variables that unification binds to what they stand for.
+#[derive(Debug, Default)]
+pub struct Unifier {
+    vars: Vec<Var>,
+    /// Each variable a failing `unify` has to restore, with its value and
+    /// kind before.
+    trail: Vec<(u32, Option<Type>, VarKind)>,
+    /// Integer literals whose type is not known yet, with their values,
+    /// whether they are negated and their spans, checked to fit once it is.
+    literals: Vec<(Type, u64, bool, Span)>,
+}
+
+impl Unifier {
+    /// A new variable of kind `kind` for the type of the value at `origin`.
+    /// `what` describes the type and `advice` says how to give it in case
+    /// nothing else does, as in "Cannot infer {what}; {advice}".
+    pub fn fresh(&mut self, kind: VarKind, origin: Span, what: impl Into<String>, advice: impl Into<String>) -> Type {
+        let var = Var { value: None, kind, origin, what: what.into(), advice: advice.into(), holder: None };
+        self.vars.push(var);
+        Type::Var(self.vars.len() as u32 - 1)
+    }
+
+    /// Records that the integer literal `value` at `span`, `negated` if it is
+    /// the operand of `-`, has the type `ty`, a variable that has to end up
+    /// as an integer type it fits in.
+    pub fn literal(&mut self, ty: Type, value: u64, negated: bool, span: Span) {
+        self.literals.push((ty, value, negated, span));
+    }
+
+    /// Records that the variable `name` was declared without a type and has
+    /// the type `ty`, where a type annotation can decide its variables.
+    pub fn hold(&mut self, name: &str, ty: &Type) {
+        let ty = self.apply(ty);
+        for var in unbound(&ty) {
+            let var = &mut self.vars[var as usize];
+            if var.holder.is_none() {
+                var.holder = Some((name.to_string(), ty.clone()));
+            }
+        }
+    }
+
+    /// Kind of `ty` if it is a variable nothing has decided yet.
+    pub fn kind(&self, ty: &Type) -> Option<VarKind> {
+        match self.shallow(ty) {
+            Type::Var(var) => Some(self.vars[var as usize].kind),
+            _ => None,
+        }
+    }
+
+    /// Narrows the kind of `ty`, if it is an undecided variable, to `kind`.
+    /// Fails if no type is of both kinds.
+    pub fn restrict(&mut self, ty: &Type, kind: VarKind) -> bool {
+        let Type::Var(var) = self.shallow(ty) else {
+            return true;
+        };
+        match self.vars[var as usize].kind.meet(kind) {
+            Some(kind) => {
+                self.vars[var as usize].kind = kind;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// `ty` with every variable that has a value replaced by it.
+    pub fn apply(&self, ty: &Type) -> Type {
+        self.fill(ty, false)
+    }
+
+    /// `ty` as far as it is known, for error messages: like `apply`, but
+    /// showing a numeric variable as its default type.
+    pub fn describe(&self, ty: &Type) -> Type {
+        self.fill(ty, true)
+    }
+
+    fn fill(&self, ty: &Type, defaults: bool) -> Type {
+        let fill = |ty: &Type| self.fill(ty, defaults);
+        match ty {
+            Type::Var(var) => {
+                let var = &self.vars[*var as usize];
+                match (&var.value, var.kind.default_type()) {
+                    (Some(value), _) => fill(value),
+                    (None, Some(default)) if defaults => default,
+                    (None, _) => ty.clone(),
+                }
+            }
+            Type::Generic(name, args) => Type::Generic(name.clone(), args.iter().map(fill).collect()),
+            Type::Pointer(pointee) => Type::pointer(fill(pointee)),
+            Type::Ref(pointee, mutable) => Type::Ref(Box::new(fill(pointee)), *mutable),
+            Type::Array(elem, len) => Type::Array(Box::new(fill(elem)), *len),
+            Type::Slice(elem) => Type::Slice(Box::new(fill(elem))),
+            Type::FnPtr(params, ret) => Type::FnPtr(params.iter().map(fill).collect(), Box::new(fill(ret))),
+            Type::Closure(params, ret) => Type::Closure(params.iter().map(fill).collect(), Box::new(fill(ret))),
+            Type::Named(_) | Type::Dyn(_) | Type::Void => ty.clone(),
+        }
+    }
+
+    /// Binds variables so that `a` and `b` become the same type, if that is
+    /// possible; otherwise binds nothing and returns false.
+    pub fn unify(&mut self, a: &Type, b: &Type) -> bool {
+        let mark = self.trail.len();
+        let unified = self.unify_parts(a, b);
+        for (var, value, kind) in self.trail.drain(mark..).rev() {
+            if !unified {
+                let var = &mut self.vars[var as usize];
+                var.value = value;
+                var.kind = kind;
+            }
+        }
+        unified
+    }
+
+    /// The type of a value whose type has to be known at `span`: `ty` with
+    /// its variables applied, and the numeric variables in it, at any depth,
+    /// given their defaults.
+    pub fn settle(&mut self, ty: &Type, span: Span) -> Result<Type> {
+        let ty = self.apply(ty);
+        if let Type::Var(var) = ty {
+            if self.vars[var as usize].kind.default_type().is_none() {
+                bail_at!(span, "Cannot infer {} here; {}", self.vars[var as usize].what, self.advice(var));
+            }
+        }
+        self.default_numeric(&ty);
+        Ok(self.apply(&ty))
+    }
+
+    /// Gives the numeric variables in `ty`, whose variables are applied,
+    /// their default types.
+    fn default_numeric(&mut self, ty: &Type) {
+        match ty {
+            Type::Var(var) => {
+                let var = &mut self.vars[*var as usize];
+                if var.value.is_none() {
+                    var.value = var.kind.default_type();
+                }
+            }
+            Type::Generic(_, args) => args.iter().for_each(|arg| self.default_numeric(arg)),
+            Type::Pointer(inner) | Type::Ref(inner, _) | Type::Array(inner, _) | Type::Slice(inner) => {
+                self.default_numeric(inner)
+            }
+            Type::FnPtr(params, ret) | Type::Closure(params, ret) => {
+                params.iter().for_each(|param| self.default_numeric(param));
+                self.default_numeric(ret);
+            }
+            Type::Named(_) | Type::Dyn(_) | Type::Void => {}
+        }
+    }
+
+    /// Gives numeric variables that nothing decided their default type and
+    /// checks that every integer literal fits in its type. Fails for the
+    /// first other variable that nothing decided.
+    pub fn finish(&mut self) -> Result<()> {
+        for var in &mut self.vars {
+            if var.value.is_none() {
+                var.value = var.kind.default_type();
+            }
+        }
+        if let Some(i) = self.vars.iter().position(|var| var.value.is_none()) {
+            let var = &self.vars[i];
+            bail_at!(var.origin, "Cannot infer {}; {}", var.what, self.advice(i as u32));
+        }
+        for (ty, value, negated, span) in &self.literals {
+            let ty = self.apply(ty);
+            if !types::int_fits(*value, *negated, ty.as_named().expect("integer literal of a non-integer type")) {
+                bail_at!(*span, "Integer literal {}{} does not fit in {}", if *negated { "-" } else { "" }, value, ty);
+            }
+        }
+        Ok(())
+    }
+
+    fn advice(&self, var: u32) -> String {
+        let var = &self.vars[var as usize];
+        match &var.holder {
+            Some((name, ty)) => format!("give `{}` a type, as in `let {}: {} = ...`", name, name, self.describe(ty)),
+            None => var.advice.clone(),
+        }
+    }
+
+    /// `ty`, or what it stands for if it is a variable with a value.
+    fn shallow(&self, ty: &Type) -> Type {
+        match ty {
+            Type::Var(var) => match &self.vars[*var as usize].value {
+                Some(value) => self.shallow(value),
+                None => ty.clone(),
+            },
+            _ => ty.clone(),
+        }
+    }
+
+    fn unify_parts(&mut self, a: &Type, b: &Type) -> bool {
+        let (a, b) = (self.shallow(a), self.shallow(b));
+        match (&a, &b) {
+            (Type::Var(x), Type::Var(y)) if x == y => true,
+            (Type::Var(x), Type::Var(y)) => {
+                let (x_kind, y_kind) = (self.vars[*x as usize].kind, self.vars[*y as usize].kind);
+                match x_kind.meet(y_kind) {
+                    Some(kind) => {
+                        self.set(*y, None, kind);
+                        self.set(*x, Some(b.clone()), x_kind);
+                        true
+                    }
+                    None => false,
+                }
+            }
+            (Type::Var(var), ty) | (ty, Type::Var(var)) => {
+                let var = *var;
+                let kind = self.vars[var as usize].kind;
+                if !kind.admits(ty) || unbound(&self.apply(ty)).contains(&var) {
+                    return false;
+                }
+                self.set(var, Some(ty.clone()), kind);
+                true
+            }
+            (Type::Generic(name, args), Type::Generic(other_name, other_args)) => {
+                name == other_name
+                    && args.len() == other_args.len()
+                    && args.iter().zip(other_args).all(|(a, b)| self.unify_parts(a, b))
+            }
+            (Type::Pointer(a), Type::Pointer(b)) | (Type::Slice(a), Type::Slice(b)) => self.unify_parts(a, b),
+            (Type::Ref(a, a_mut), Type::Ref(b, b_mut)) => a_mut == b_mut && self.unify_parts(a, b),
+            (Type::Array(a, a_len), Type::Array(b, b_len)) => a_len == b_len && self.unify_parts(a, b),
+            (Type::FnPtr(a_params, a_ret), Type::FnPtr(b_params, b_ret))
+            | (Type::Closure(a_params, a_ret), Type::Closure(b_params, b_ret)) => {
+                a_params.len() == b_params.len()
+                    && a_params.iter().zip(b_params).all(|(a, b)| self.unify_parts(a, b))
+                    && self.unify_parts(a_ret, b_ret)
+            }
+            _ => a == b,
+        }
+    }
+
+    /// Gives `var` a new value and kind, remembering the old ones.
+    fn set(&mut self, var: u32, value: Option<Type>, kind: VarKind) {
+        let old = &mut self.vars[var as usize];
+        self.trail.push((var, std::mem::replace(&mut old.value, value), std::mem::replace(&mut old.kind, kind)));
+    }
+}
+
+/// The variables in `ty` that have no value, if `ty` has been applied.
+fn unbound(ty: &Type) -> Vec<u32> {
+    match ty {
+        Type::Var(var) => vec![*var],
+        Type::Generic(_, args) => args.iter().flat_map(unbound).collect(),
+        Type::Pointer(inner) | Type::Ref(inner, _) | Type::Array(inner, _) | Type::Slice(inner) => unbound(inner),
+        Type::FnPtr(params, ret) | Type::Closure(params, ret) => {
+            params.iter().chain(std::iter::once(&**ret)).flat_map(unbound).collect()
+        }
+        Type::Named(_) | Type::Dyn(_) | Type::Void => Vec::new(),
+    }
+}
@@ -1,30 +1,237 @@
+use crate::ast::{BinOp, Span, UnaryOp};
+use crate::types::Type;
+
 #[derive(Debug, Clone)]
 pub enum IrInst {
-    Const(i64),
+    /// Integer constant of the given integer type.
+    Const(u64, Type),
+    Float(f64, Type),
+    Bool(bool),
+    Char(char),
+    Str(String),
+    /// Value of a local variable.
     Load(String),
+    /// Binds a new local variable to a value.
     Store(String, Box<IrInst>),
     Call(String, Vec<IrInst>),
+    /// Call of a generic function with the given type arguments, which
+    /// monomorphization turns into a `Call` of the matching instance.
+    CallGeneric(String, Vec<Type>, Vec<IrInst>),
+    /// Call of the named method of the named trait for the given type of
+    /// `self`, the first argument. Monomorphization turns it into a `Call`
+    /// of the impl's method or of an instance of the trait's default.
+    CallMethod(String, String, Type, Vec<IrInst>),
+    /// Call of a method of the named trait, by vtable slot, on a `dyn` value.
+    CallDyn(String, u32, Box<IrInst>, Vec<IrInst>),
+    /// `dyn` value of the named trait made from a pointer to a value of the
+    /// given type, which monomorphization turns into a `DynObject`.
+    MakeDyn(String, Type, Box<IrInst>),
+    /// `dyn` value made from the named vtable and a pointer.
+    DynObject(String, Box<IrInst>),
+    /// Address of the named function, as a function pointer. Type arguments
+    /// select an instance of a generic function, like `CallGeneric`'s.
+    FnAddr(String, Vec<Type>),
+    /// Closure calling the named function (with type arguments as for
+    /// `FnAddr`) with a pointer to a copy of the environment, a struct value
+    /// holding what the closure captured. The copy is on the heap if the
+    /// flag is set, and in the frame of the current function otherwise.
+    ///
+    /// A future's environment is on the heap and belongs to the future, which
+    /// is never copied: `Await` or `Spawn` frees it after running the future,
+    /// and a future that never runs keeps it. Closures are copied freely, so
+    /// the heap environment of a `move` closure that outlives its function
+    /// belongs to no copy of it and is never freed; every other closure's is
+    /// in the frame.
+    MakeClosure(String, Vec<Type>, Box<IrInst>, bool),
+    /// Closure that calls a function pointer of the given type.
+    FnToClosure(Type, Box<IrInst>),
+    /// Call through a function pointer or closure of the given type.
+    CallValue(Type, Box<IrInst>, Vec<IrInst>),
+    /// Binary operation; the type is that of the operands, which decides
+    /// between signed, unsigned and floating-point instructions.
+    Binary(BinOp, Type, Box<IrInst>, Box<IrInst>),
+    Unary(UnaryOp, Box<IrInst>),
+    Print(Type, Box<IrInst>),
+    /// Value of the given struct type built from its field values in
+    /// declaration order.
+    StructLit(Type, Vec<IrInst>),
+    /// Field of a struct value, by index.
+    Extract(Box<IrInst>, u32),
+    /// Value of the given enum type: variant index and payload values.
+    EnumLit(Type, u32, Vec<IrInst>),
+    /// Variant index (an `u32`) of the enum of the given type behind a pointer.
+    TagOf(Type, Box<IrInst>),
+    /// Address of payload field `field` of variant `variant` of the enum of
+    /// the given type behind a pointer.
+    PayloadAddr(Type, u32, u32, Box<IrInst>),
+    /// Declares an uninitialized local variable of the given type.
+    Local(String, Type),
+    /// Makes a local variable of the given type refer to the storage at an
+    /// address, as a closure does with the variables it captured.
+    BindAddr(String, Type, Box<IrInst>),
+    /// Address of a local variable.
+    VarAddr(String),
+    /// Address of field `index` of the struct of the given type behind a pointer.
+    FieldAddr(Type, u32, Box<IrInst>),
+    /// Array of elements of the given type, from their values in order.
+    ArrayLit(Type, Vec<IrInst>),
+    /// Address of element `index` (an `i64`) of the array of elements of
+    /// the given type that starts at a pointer.
+    ElemAddr(Type, Box<IrInst>, Box<IrInst>),
+    /// Slice of elements of the given type from a pointer to the first one
+    /// and an `i64` length.
+    MakeSlice(Type, Box<IrInst>, Box<IrInst>),
+    /// Aborts unless `0 <= index < len`, when bounds checks are enabled.
+    CheckIndex(Box<IrInst>, Box<IrInst>),
+    /// Aborts unless `0 <= start <= end <= len`, when bounds checks are enabled.
+    CheckRange(Box<IrInst>, Box<IrInst>, Box<IrInst>),
+    /// Pointer to zeroed heap memory for the given number (an `i64`)
+    /// of values of the given type.
+    Alloc(Type, Box<IrInst>),
+    /// Releases memory `Alloc` returned.
+    Free(Box<IrInst>),
+    /// Value of the given type read through a pointer.
+    Deref(Type, Box<IrInst>),
+    /// Writes a value through a pointer.
+    Write(Box<IrInst>, Box<IrInst>),
     Ret(Option<Box<IrInst>>),
-    AsyncCall(String, Vec<IrInst>),
-    Await(Box<IrInst>),
-    Spawn(Box<IrInst>),
+    /// Runs a future of the given type and gives its result, consuming the
+    /// future.
+    Await(Type, Box<IrInst>),
+    /// Starts running a future of the given type, consuming it.
+    Spawn(Type, Box<IrInst>),
+    Jump(String),
+    Branch(Box<IrInst>, String, String),
+    /// Jumps to the label paired with the integer value, or to the default label.
+    Switch(Box<IrInst>, Vec<(u64, String)>, String),
+    Unreachable,
+}
+
+impl IrInst {
+    pub fn is_terminator(&self) -> bool {
+        matches!(
+            self,
+            IrInst::Ret(_) | IrInst::Jump(_) | IrInst::Branch(..) | IrInst::Switch(..) | IrInst::Unreachable
+        )
+    }
+}
+
+/// A straight-line run of instructions. Every block except possibly the last
+/// one of a function ends in a terminator (`Ret`, `Jump`, `Branch`, `Switch`
+/// or `Unreachable`).
+#[derive(Debug, Clone)]
+pub struct IrBlock {
+    pub label: String,
+    pub body: Vec<IrInst>,
 }
 
 #[derive(Debug, Clone)]
 pub struct IrFunction {
     pub name: String,
-    pub body: Vec<IrInst>,
+    /// The function or closure it was lowered from; the default span for
+    /// functions the compiler generates, such as `dyn` shims.
+    pub span: Span,
+    /// Type parameters of a generic function; empty once monomorphized.
+    pub type_params: Vec<String>,
+    pub params: Vec<IrParam>,
+    pub ret_ty: Option<Type>,
+    pub blocks: Vec<IrBlock>,
+}
+
+#[derive(Debug, Clone)]
+pub struct IrParam {
+    pub name: String,
+    pub ty: Type,
 }
 
 #[derive(Debug, Clone)]
 pub struct IrModule {
+    pub structs: Vec<IrStruct>,
+    pub enums: Vec<IrEnum>,
     pub functions: Vec<IrFunction>,
     pub externs: Vec<IrExtern>,
+    /// Traits; once monomorphized, only those used as `dyn`.
+    pub traits: Vec<IrTrait>,
+    /// Trait impls, which monomorphization resolves method calls with and
+    /// then drops.
+    pub impls: Vec<IrImpl>,
+    /// Filled in by monomorphization.
+    pub vtables: Vec<IrVtable>,
 }
 
 #[derive(Debug, Clone)]
 pub struct IrExtern {
     pub name: String,
-    pub params: Vec<String>,
-    pub ret_ty: String,
+    pub params: Vec<Type>,
+    pub ret_ty: Type,
+}
+
+/// A struct's field types in declaration order, which is also their order in memory.
+#[derive(Debug, Clone)]
+pub struct IrStruct {
+    pub name: String,
+    /// Type parameters of a generic struct; empty once monomorphized.
+    pub type_params: Vec<String>,
+    pub fields: Vec<Type>,
+    pub packed: bool,
+}
+
+/// An enum's payload types, one list per variant in declaration order; a
+/// variant's index is its tag.
+#[derive(Debug, Clone)]
+pub struct IrEnum {
+    pub name: String,
+    /// Type parameters of a generic enum; empty once monomorphized.
+    pub type_params: Vec<String>,
+    pub variants: Vec<Vec<Type>>,
+}
+
+/// A trait's methods in declaration order, which is also their order in its
+/// vtables.
+#[derive(Debug, Clone)]
+pub struct IrTrait {
+    pub name: String,
+    pub methods: Vec<IrMethod>,
+}
+
+/// Signature of a trait method, without its `self` parameter.
+#[derive(Debug, Clone)]
+pub struct IrMethod {
+    pub name: String,
+    pub params: Vec<Type>,
+    pub ret_ty: Option<Type>,
+}
+
+/// An impl of a trait for a type, with the names of the methods it defines;
+/// the trait's defaults provide the rest.
+#[derive(Debug, Clone)]
+pub struct IrImpl {
+    pub trait_name: String,
+    pub ty: Type,
+    pub methods: Vec<String>,
+}
+
+/// The functions through which `dyn` values of a trait call the methods of
+/// one type, in the order of the trait's methods. Each takes `self` by
+/// pointer, as `dyn` values hold it.
+#[derive(Debug, Clone)]
+pub struct IrVtable {
+    pub name: String,
+    pub trait_name: String,
+    pub functions: Vec<String>,
+}
+
+/// Symbol of the method an impl block gives type `ty`, such as
+/// `Circle::Shape::area`, or `Circle::area` for an inherent impl.
+pub fn method_symbol(ty: &Type, trait_name: Option<&str>, method: &str) -> String {
+    match trait_name {
+        Some(trait_name) => format!("{}::{}::{}", ty, trait_name, method),
+        None => format!("{}::{}", ty, method),
+    }
+}
+
+/// Symbol of a trait's default for `method`, a generic function whose type
+/// parameter `Self` is the implementing type.
+pub fn default_symbol(trait_name: &str, method: &str) -> String {
+    format!("{}::{}", trait_name, method)
 }
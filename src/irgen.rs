@@ -1,12 +1,36 @@
 use crate::ast::*;
 use crate::ir::*;
+use crate::semantic::TypeTable;
+use std::collections::HashMap;
 
-pub fn lower_program(prog: &Program) -> IrModule {
-    let mut functions = Vec::new();
+/// Lowers the program to IR. Generic functions, structs and enums, and the
+/// default methods of traits, are lowered once, in terms of their type
+/// parameters; `mono::monomorphize` then makes the concrete copies codegen
+/// needs and resolves method calls.
+pub fn lower_program(prog: &Program, types: &TypeTable) -> IrModule {
+    let mut lifted = Lifted::default();
     let mut externs = Vec::new();
+    let decls = Decls {
+        structs: prog.structs.iter().map(|s| (s.name.as_str(), s)).collect(),
+        enums: prog.enums.iter().map(|e| (e.name.as_str(), e)).collect(),
+        types,
+    };
     for func in &prog.functions {
-        functions.push(lower_function(func));
+        lower_function(func, func.name.clone(), func.type_params.clone(), None, &decls, &mut lifted);
     }
+    for imp in &prog.impls {
+        for method in &imp.methods {
+            let name = method_symbol(&imp.ty, imp.trait_name.as_deref(), &method.name);
+            lower_function(method, name, Vec::new(), Some(imp), &decls, &mut lifted);
+        }
+    }
+    for def in &prog.traits {
+        for method in def.methods.iter().filter(|m| m.has_default) {
+            let name = default_symbol(&def.name, &method.func.name);
+            lower_function(&method.func, name, vec!["Self".to_string()], None, &decls, &mut lifted);
+        }
+    }
+    let Lifted { functions, envs } = lifted;
     for ext in &prog.externs {
         externs.push(IrExtern {
             name: ext.name.clone(),
@@ -14,47 +38,931 @@ pub fn lower_program(prog: &Program) -> IrModule {
             ret_ty: ext.ret_ty.clone(),
         });
     }
-    IrModule { functions, externs }
+    let structs = prog.structs.iter()
+        .map(|s| IrStruct {
+            name: s.name.clone(),
+            type_params: s.type_params.clone(),
+            fields: s.fields.iter().map(|f| f.ty.clone()).collect(),
+            packed: s.packed,
+        })
+        .chain(envs)
+        .collect();
+    let enums = prog.enums.iter()
+        .map(|e| IrEnum {
+            name: e.name.clone(),
+            type_params: e.type_params.clone(),
+            variants: e.variants.iter().map(|v| v.fields.clone()).collect(),
+        })
+        .collect();
+    let traits = prog.traits.iter()
+        .map(|t| IrTrait {
+            name: t.name.clone(),
+            methods: t.methods.iter()
+                .map(|m| IrMethod {
+                    name: m.func.name.clone(),
+                    params: m.func.params[1..].iter().map(|p| p.ty.clone()).collect(),
+                    ret_ty: m.func.ret_ty.clone(),
+                })
+                .collect(),
+        })
+        .collect();
+    let impls = prog.impls.iter()
+        .filter_map(|imp| {
+            Some(IrImpl {
+                trait_name: imp.trait_name.clone()?,
+                ty: imp.ty.clone(),
+                methods: imp.methods.iter().map(|f| f.name.clone()).collect(),
+            })
+        })
+        .collect();
+    IrModule { structs, enums, functions, externs, traits, impls, vtables: Vec::new() }
+}
+
+/// Program-wide declarations that lowering a function body consults.
+struct Decls<'a> {
+    structs: HashMap<&'a str, &'a StructDef>,
+    enums: HashMap<&'a str, &'a EnumDef>,
+    types: &'a TypeTable,
 }
 
-fn lower_function(func: &Function) -> IrFunction {
-    let mut body = Vec::new();
-    for stmt in &func.body {
-        lower_stmt(stmt, &mut body);
+/// Functions lowered so far, and the environment structs of their closures.
+#[derive(Default)]
+struct Lifted {
+    functions: Vec<IrFunction>,
+    envs: Vec<IrStruct>,
+}
+
+/// Accumulates the basic blocks of one function while its body is lowered.
+struct FnBuilder<'a> {
+    /// Symbol of the function, which its closures are named after.
+    name: String,
+    /// The function or closure being lowered.
+    span: Span,
+    /// Type parameters of the function, which its closures share.
+    type_params: Vec<String>,
+    blocks: Vec<IrBlock>,
+    current: usize,
+    /// `(continue target, break target)` for each enclosing loop.
+    loops: Vec<(String, String)>,
+    next_label: usize,
+    /// Declared return type of the function, `Void` if it has none.
+    ret_ty: Type,
+    decls: &'a Decls<'a>,
+    /// Functions and environment structs made from the closures in the body.
+    lifted: Lifted,
+}
+
+impl<'a> FnBuilder<'a> {
+    fn new(name: String, span: Span, type_params: Vec<String>, ret_ty: Type, decls: &'a Decls<'a>) -> Self {
+        Self {
+            name,
+            span,
+            type_params,
+            blocks: vec![IrBlock { label: "entry".to_string(), body: Vec::new() }],
+            current: 0,
+            loops: Vec::new(),
+            next_label: 0,
+            ret_ty,
+            decls,
+            lifted: Lifted::default(),
+        }
+    }
+
+    /// Adds the function this builder built, followed by those of its
+    /// closures, to `lifted`.
+    fn finish(self, params: Vec<IrParam>, ret_ty: Option<Type>, lifted: &mut Lifted) {
+        lifted.functions.push(IrFunction {
+            name: self.name,
+            span: self.span,
+            params,
+            type_params: self.type_params,
+            ret_ty,
+            blocks: self.blocks,
+        });
+        lifted.functions.extend(self.lifted.functions);
+        lifted.envs.extend(self.lifted.envs);
+    }
+
+    fn new_block(&mut self, prefix: &str) -> usize {
+        let label = format!("{}{}", prefix, self.next_label);
+        self.next_label += 1;
+        self.blocks.push(IrBlock { label, body: Vec::new() });
+        self.blocks.len() - 1
+    }
+
+    /// Name for a compiler-generated local; the `.` keeps it apart from
+    /// every identifier the program can declare.
+    fn temp(&mut self, prefix: &str) -> String {
+        let name = format!("{}.{}", prefix, self.next_label);
+        self.next_label += 1;
+        name
+    }
+
+    fn label(&self, block: usize) -> String {
+        self.blocks[block].label.clone()
+    }
+
+    fn switch_to(&mut self, block: usize) {
+        self.current = block;
+    }
+
+    fn is_terminated(&self) -> bool {
+        self.blocks[self.current].body.last().is_some_and(IrInst::is_terminator)
     }
-    IrFunction {
-        name: func.name.clone(),
-        body,
+
+    fn push(&mut self, inst: IrInst) {
+        if self.is_terminated() {
+            // Code after `break`/`continue` is unreachable; give it its own block.
+            let dead = self.new_block("dead");
+            self.switch_to(dead);
+        }
+        self.blocks[self.current].body.push(inst);
+    }
+
+    fn jump_to(&mut self, block: usize) {
+        if !self.is_terminated() {
+            let label = self.label(block);
+            self.push(IrInst::Jump(label));
+        }
+    }
+
+    /// Type of an expression, as semantic analysis worked it out.
+    fn expr_type(&self, expr: &Expr) -> Type {
+        self.decls.types.exprs.get(&expr.span).cloned().expect("expression was not type-checked")
+    }
+
+    /// Struct type and index of `field` on a value of type `ty`, which is a
+    /// struct or a reference or pointer to one.
+    fn field(&self, ty: &Type, field: &str) -> (Type, u32) {
+        let ty = ty.pointee().unwrap_or(ty);
+        let def = ty.decl_name().and_then(|name| self.decls.structs.get(name)).expect("field access on a non-struct");
+        let index = def.fields.iter().position(|f| f.name == field).expect("unknown field");
+        (ty.clone(), index as u32)
+    }
+
+    /// Index of `variant` in the named enum, which is also its tag.
+    fn variant_index(&self, enum_name: &str, variant: &str) -> u32 {
+        let def = self.decls.enums.get(enum_name).expect("unknown enum");
+        def.variants.iter().position(|v| v.name == variant).expect("unknown variant") as u32
+    }
+
+    /// Index of `variant`, and the address and type of each of its payload
+    /// fields, for the value of the enum type `ty` stored at `addr`.
+    fn payload(&self, ty: &Type, variant: &str, addr: &IrInst) -> (u32, Vec<(IrInst, Type)>) {
+        let def = self.decls.enums[ty.decl_name().expect("not an enum")];
+        let index = self.variant_index(&def.name, variant);
+        let fields = def.variants[index as usize].fields.iter()
+            .enumerate()
+            .map(|(i, field)| {
+                let field_addr = IrInst::PayloadAddr(ty.clone(), index, i as u32, Box::new(addr.clone()));
+                (field_addr, field.substitute(&def.type_params, ty.type_args()))
+            })
+            .collect();
+        (index, fields)
     }
 }
 
-fn lower_stmt(stmt: &Stmt, body: &mut Vec<IrInst>) {
-    match stmt {
-        Stmt::Let { name, expr } => {
-            let value = lower_expr(expr);
-            body.push(IrInst::Store(name.clone(), Box::new(value)));
+/// Lowers `func` to the function `name` with the given type parameters,
+/// adding it to `lifted`; `imp` is the impl block of a method, whose type
+/// `Self` stands for.
+fn lower_function(
+    func: &Function,
+    name: String,
+    type_params: Vec<String>,
+    imp: Option<&ImplDef>,
+    decls: &Decls,
+    lifted: &mut Lifted,
+) {
+    let resolve = |ty: &Type| imp.map_or_else(|| ty.clone(), |imp| imp.resolve_self(ty));
+    let ret_ty = func.ret_ty.as_ref().map(resolve);
+    let params = func.params.iter()
+        .map(|p| IrParam { name: p.name.clone(), ty: resolve(&p.ty) })
+        .collect();
+    if func.is_async {
+        return lower_async_function(func, name, type_params, params, ret_ty, decls, lifted);
+    }
+    let mut builder = FnBuilder::new(name, func.span, type_params, ret_ty.clone().unwrap_or(Type::Void), decls);
+    lower_block(&func.body, &mut builder);
+    builder.finish(params, ret_ty, lifted);
+}
+
+/// Lowers the async function `func` to the function `name`, which returns a
+/// future of its result: a closure whose environment holds a copy of the
+/// arguments, and whose function runs the body when the future is awaited
+/// or spawned. The environment is on the heap even without arguments, as
+/// running the future frees it.
+fn lower_async_function(
+    func: &Function,
+    name: String,
+    type_params: Vec<String>,
+    params: Vec<IrParam>,
+    ret_ty: Option<Type>,
+    decls: &Decls,
+    lifted: &mut Lifted,
+) {
+    let body_name = format!("{}.future", name);
+    let type_args: Vec<Type> = type_params.iter().map(|p| Type::named(p.as_str())).collect();
+    let output = ret_ty.clone().unwrap_or(Type::Void);
+    let mut body = FnBuilder::new(body_name.clone(), func.span, type_params.clone(), output.clone(), decls);
+    let env_name = format!("{}.env", body_name);
+    let env_ty = Type::instance(&env_name, type_args.clone());
+    let fields = params.iter().map(|p| p.ty.clone()).collect();
+    lifted.envs.push(IrStruct { name: env_name, type_params: type_params.clone(), fields, packed: false });
+    let body_params = vec![IrParam { name: "closure.env".to_string(), ty: Type::pointer(env_ty.clone()) }];
+    for (i, param) in params.iter().enumerate() {
+        let field = IrInst::FieldAddr(env_ty.clone(), i as u32, Box::new(IrInst::Load("closure.env".to_string())));
+        body.push(IrInst::BindAddr(param.name.clone(), param.ty.clone(), Box::new(field)));
+    }
+    let args = params.iter().map(|p| IrInst::Load(p.name.clone())).collect();
+    let future = IrInst::MakeClosure(body_name, type_args, Box::new(IrInst::StructLit(env_ty, args)), true);
+    lower_block(&func.body, &mut body);
+    body.finish(body_params, ret_ty, lifted);
+    let future_ty = Type::future(output);
+    let mut builder = FnBuilder::new(name, func.span, type_params, future_ty.clone(), decls);
+    builder.push(IrInst::Ret(Some(Box::new(future))));
+    builder.finish(params, Some(future_ty), lifted);
+}
+
+/// Lowers a closure to a function of its own named after the enclosing one,
+/// and returns the value that refers to it: the function's address if the
+/// closure captures nothing, or else a closure whose environment holds the
+/// captured variables, or their addresses when they are captured by
+/// reference. The function takes a pointer to the environment first and
+/// binds the captured names to the storage it refers to.
+fn lower_lambda(expr: &Expr, lambda: &Lambda, builder: &mut FnBuilder<'_>) -> IrInst {
+    let (params, ret_ty) = match builder.expr_type(expr) {
+        Type::FnPtr(params, ret_ty) | Type::Closure(params, ret_ty) => (params, *ret_ty),
+        _ => unreachable!("closures have function types"),
+    };
+    let decls = builder.decls;
+    let captures = &decls.types.captures[&expr.span];
+    let name = builder.temp(&format!("{}.closure", builder.name));
+    let type_args: Vec<Type> = builder.type_params.iter().map(|p| Type::named(p.as_str())).collect();
+    let mut inner = FnBuilder::new(name.clone(), expr.span, builder.type_params.clone(), ret_ty.clone(), decls);
+    let mut ir_params = Vec::new();
+    let mut env = None;
+    if !captures.is_empty() {
+        let env_name = format!("{}.env", name);
+        let env_ty = Type::instance(&env_name, type_args.clone());
+        let fields = captures.iter()
+            .map(|(_, ty)| if lambda.by_value { ty.clone() } else { Type::pointer(ty.clone()) })
+            .collect();
+        let type_params = builder.type_params.clone();
+        builder.lifted.envs.push(IrStruct { name: env_name, type_params, fields, packed: false });
+        ir_params.push(IrParam { name: "closure.env".to_string(), ty: Type::pointer(env_ty.clone()) });
+        for (i, (var, ty)) in captures.iter().enumerate() {
+            let field = IrInst::FieldAddr(env_ty.clone(), i as u32, Box::new(IrInst::Load("closure.env".to_string())));
+            let addr = if lambda.by_value { field } else { IrInst::Deref(Type::pointer(ty.clone()), Box::new(field)) };
+            inner.push(IrInst::BindAddr(var.clone(), ty.clone(), Box::new(addr)));
+        }
+        let values = captures.iter()
+            .map(|(var, _)| if lambda.by_value { IrInst::Load(var.clone()) } else { IrInst::VarAddr(var.clone()) })
+            .collect();
+        env = Some(IrInst::StructLit(env_ty, values));
+    }
+    for (param, ty) in lambda.params.iter().zip(params) {
+        ir_params.push(IrParam { name: param.name.clone(), ty });
+    }
+    match &lambda.body {
+        LambdaBody::Expr(body) if ret_ty == Type::Void => lower_expr_stmt(body, &mut inner),
+        LambdaBody::Expr(body) => {
+            let value = lower_expr(body, &mut inner);
+            inner.push(IrInst::Ret(Some(Box::new(value))));
         }
-        Stmt::Expr(expr) => {
-            let _ = lower_expr(expr);
+        LambdaBody::Block(body) => lower_block(body, &mut inner),
+    }
+    let ret_ty = (ret_ty != Type::Void).then_some(ret_ty);
+    inner.finish(ir_params, ret_ty, &mut builder.lifted);
+    match env {
+        Some(env) => {
+            let on_heap = decls.types.escaping.contains(&expr.span);
+            IrInst::MakeClosure(name, type_args, Box::new(env), on_heap)
         }
+        None => IrInst::FnAddr(name, type_args),
     }
 }
 
-fn lower_expr(expr: &Expr) -> IrInst {
-    match expr {
-        Expr::Int(i) => IrInst::Const(*i),
-        Expr::Ident(name) => IrInst::Load(name.clone()),
-        Expr::Call { name, args } => {
-            let lowered_args = args.iter().map(lower_expr).collect();
-            IrInst::Call(name.clone(), lowered_args)
+fn lower_block(stmts: &[Stmt], builder: &mut FnBuilder<'_>) {
+    for stmt in stmts {
+        lower_stmt(stmt, builder);
+    }
+}
+
+fn lower_stmt(stmt: &Stmt, builder: &mut FnBuilder<'_>) {
+    match &stmt.kind {
+        StmtKind::Let { name, expr, .. } => {
+            let value = lower_expr(expr, builder);
+            builder.push(IrInst::Store(name.clone(), Box::new(value)));
+        }
+        StmtKind::Expr(expr) => lower_expr_stmt(expr, builder),
+        StmtKind::Assign { target, value } => {
+            let addr = lower_place(target, builder).expect("assignment to a non-place");
+            let value = lower_expr(value, builder);
+            builder.push(IrInst::Write(Box::new(addr), Box::new(value)));
+        }
+        StmtKind::If { cond, then_body, else_body } => {
+            let cond = lower_expr(cond, builder);
+            let then_bb = builder.new_block("then");
+            let else_bb = else_body.as_ref().map(|_| builder.new_block("else"));
+            let merge_bb = builder.new_block("ifcont");
+            let else_label = builder.label(else_bb.unwrap_or(merge_bb));
+            builder.push(IrInst::Branch(Box::new(cond), builder.label(then_bb), else_label));
+
+            builder.switch_to(then_bb);
+            lower_block(then_body, builder);
+            builder.jump_to(merge_bb);
+
+            if let (Some(else_bb), Some(else_body)) = (else_bb, else_body) {
+                builder.switch_to(else_bb);
+                lower_block(else_body, builder);
+                builder.jump_to(merge_bb);
+            }
+            builder.switch_to(merge_bb);
+        }
+        StmtKind::While { cond, body } => {
+            let cond_bb = builder.new_block("while.cond");
+            let body_bb = builder.new_block("while.body");
+            let end_bb = builder.new_block("while.end");
+            builder.jump_to(cond_bb);
+
+            builder.switch_to(cond_bb);
+            let cond = lower_expr(cond, builder);
+            builder.push(IrInst::Branch(Box::new(cond), builder.label(body_bb), builder.label(end_bb)));
+
+            builder.switch_to(body_bb);
+            builder.loops.push((builder.label(cond_bb), builder.label(end_bb)));
+            lower_block(body, builder);
+            builder.loops.pop();
+            builder.jump_to(cond_bb);
+            builder.switch_to(end_bb);
+        }
+        StmtKind::Unsafe(body) | StmtKind::Block(body) => lower_block(body, builder),
+        StmtKind::Loop { body } => {
+            let body_bb = builder.new_block("loop.body");
+            let end_bb = builder.new_block("loop.end");
+            builder.jump_to(body_bb);
+
+            builder.switch_to(body_bb);
+            builder.loops.push((builder.label(body_bb), builder.label(end_bb)));
+            lower_block(body, builder);
+            builder.loops.pop();
+            builder.jump_to(body_bb);
+            builder.switch_to(end_bb);
+        }
+        StmtKind::Break => {
+            let (_, target) = builder.loops.last().cloned().expect("break outside of loop");
+            builder.push(IrInst::Jump(target));
+        }
+        StmtKind::Continue => {
+            let (target, _) = builder.loops.last().cloned().expect("continue outside of loop");
+            builder.push(IrInst::Jump(target));
+        }
+        StmtKind::Return(value) => {
+            let value = value.as_ref().map(|v| Box::new(lower_expr(v, builder)));
+            builder.push(IrInst::Ret(value));
+        }
+        StmtKind::MacroInvoke { .. } => unreachable!("semantic analysis rejects invocations of undefined macros"),
+        StmtKind::Error => unreachable!("syntax errors stop compilation before lowering"),
+    }
+}
+
+/// Lowers an expression evaluated only for its effects, which may produce no value.
+fn lower_expr_stmt(expr: &Expr, builder: &mut FnBuilder<'_>) {
+    let value = match &expr.kind {
+        ExprKind::Match { scrutinee, arms } => lower_match(expr, scrutinee, arms, builder),
+        _ => Some(lower_expr(expr, builder)),
+    };
+    if let Some(value) = value {
+        builder.push(value);
+    }
+}
+
+fn lower_expr(expr: &Expr, builder: &mut FnBuilder<'_>) -> IrInst {
+    match builder.decls.types.coercions.get(&expr.span) {
+        Some(Type::Dyn(trait_name)) => {
+            let ty = builder.expr_type(expr);
+            let pointee = ty.pointee().cloned().expect("only references and pointers become dyn values");
+            let ptr = lower_uncoerced(expr, builder);
+            IrInst::MakeDyn(trait_name.clone(), pointee, Box::new(ptr))
+        }
+        Some(Type::Closure(..)) => {
+            let fn_ptr = lower_uncoerced(expr, builder);
+            IrInst::FnToClosure(builder.expr_type(expr), Box::new(fn_ptr))
+        }
+        _ => lower_uncoerced(expr, builder),
+    }
+}
+
+/// Lowers `expr` to a value of the type it has before any implicit conversion.
+fn lower_uncoerced(expr: &Expr, builder: &mut FnBuilder<'_>) -> IrInst {
+    match &expr.kind {
+        ExprKind::Int { value, .. } => IrInst::Const(*value, builder.expr_type(expr)),
+        ExprKind::Float { value, .. } => IrInst::Float(*value, builder.expr_type(expr)),
+        ExprKind::Bool(b) => IrInst::Bool(*b),
+        ExprKind::Char(c) => IrInst::Char(*c),
+        ExprKind::Str(s) => IrInst::Str(s.clone()),
+        ExprKind::Ident(name) if builder.decls.types.fn_values.contains(&expr.span) => {
+            IrInst::FnAddr(name.clone(), Vec::new())
+        }
+        ExprKind::Ident(name) => IrInst::Load(name.clone()),
+        ExprKind::Call { name, args, .. } if name == "print" => {
+            let arg = lower_expr(&args[0], builder);
+            IrInst::Print(builder.expr_type(&args[0]), Box::new(arg))
+        }
+        ExprKind::Call { name, type_args, args } if name == "alloc" => {
+            IrInst::Alloc(type_args[0].clone(), Box::new(lower_expr(&args[0], builder)))
+        }
+        ExprKind::Call { name, args, .. } if name == "free" => IrInst::Free(Box::new(lower_expr(&args[0], builder))),
+        ExprKind::Call { name, args, .. } => {
+            let lowered_args = args.iter().map(|a| lower_expr(a, builder)).collect();
+            if let Some(callee_ty) = builder.decls.types.indirect_calls.get(&expr.span) {
+                return IrInst::CallValue(callee_ty.clone(), Box::new(IrInst::Load(name.clone())), lowered_args);
+            }
+            match builder.decls.types.type_args.get(&expr.span) {
+                Some(type_args) => IrInst::CallGeneric(name.clone(), type_args.clone(), lowered_args),
+                None => IrInst::Call(name.clone(), lowered_args),
+            }
+        }
+        ExprKind::CallValue { callee, args } => {
+            let callee_ty = builder.decls.types.indirect_calls[&expr.span].clone();
+            let callee = lower_expr(callee, builder);
+            let lowered_args = args.iter().map(|a| lower_expr(a, builder)).collect();
+            IrInst::CallValue(callee_ty, Box::new(callee), lowered_args)
+        }
+        ExprKind::MethodCall { receiver, method, args } if !builder.decls.types.methods.contains_key(&expr.span) => {
+            // The built-in `offset(n)` of raw pointers and `len()` of arrays and slices.
+            if method == "offset" {
+                let elem_ty = builder.expr_type(receiver).pointee().cloned().expect("offset of a non-pointer");
+                let ptr = lower_expr(receiver, builder);
+                let count = lower_expr(&args[0], builder);
+                return IrInst::ElemAddr(elem_ty, Box::new(ptr), Box::new(count));
+            }
+            lower_elements(receiver, builder).1
+        }
+        ExprKind::MethodCall { receiver, method, args } => {
+            let resolved = builder.decls.types.methods[&expr.span].clone();
+            let mut receiver_value = lower_expr(receiver, builder);
+            if builder.expr_type(receiver).pointee().is_some() {
+                receiver_value = IrInst::Deref(resolved.self_ty.clone(), Box::new(receiver_value));
+            }
+            let values: Vec<IrInst> = args.iter().map(|a| lower_expr(a, builder)).collect();
+            match (resolved.trait_name, resolved.slot) {
+                (Some(trait_name), Some(slot)) => IrInst::CallDyn(trait_name, slot, Box::new(receiver_value), values),
+                (Some(trait_name), None) => {
+                    let values = std::iter::once(receiver_value).chain(values).collect();
+                    IrInst::CallMethod(trait_name, method.clone(), resolved.self_ty, values)
+                }
+                (None, _) => {
+                    let values = std::iter::once(receiver_value).chain(values).collect();
+                    IrInst::Call(method_symbol(&resolved.self_ty, None, method), values)
+                }
+            }
+        }
+        ExprKind::Binary { op, lhs, rhs } => {
+            let l = lower_expr(lhs, builder);
+            let r = lower_expr(rhs, builder);
+            IrInst::Binary(*op, builder.expr_type(lhs), Box::new(l), Box::new(r))
+        }
+        ExprKind::StructLit { name, fields } => {
+            let def = builder.decls.structs[name.as_str()];
+            let values = def.fields.iter()
+                .map(|decl| {
+                    let (_, value) = fields.iter().find(|(field, _)| *field == decl.name).expect("missing field");
+                    lower_expr(value, builder)
+                })
+                .collect();
+            IrInst::StructLit(builder.expr_type(expr), values)
+        }
+        ExprKind::Field { expr: base, field } => {
+            let (_, index) = builder.field(&builder.expr_type(base), field);
+            match lower_place(expr, builder) {
+                Some(addr) => IrInst::Deref(builder.expr_type(expr), Box::new(addr)),
+                None => IrInst::Extract(Box::new(lower_expr(base, builder)), index),
+            }
         }
-        Expr::Await(inner) => {
-            let inner_ir = lower_expr(inner);
-            IrInst::Await(Box::new(inner_ir))
+        ExprKind::Variant { enum_name, variant, args } => {
+            let index = builder.variant_index(enum_name, variant);
+            let values = args.iter().map(|a| lower_expr(a, builder)).collect();
+            IrInst::EnumLit(builder.expr_type(expr), index, values)
         }
-        Expr::Spawn(inner) => {
-            let inner_ir = lower_expr(inner);
-            IrInst::Spawn(Box::new(inner_ir))
+        ExprKind::Match { scrutinee, arms } => {
+            lower_match(expr, scrutinee, arms, builder).expect("void match used as a value")
         }
+        ExprKind::Try(operand) => lower_try(operand, builder),
+        ExprKind::Lambda(lambda) => lower_lambda(expr, lambda, builder),
+        ExprKind::AddrOf { expr: place, .. } => lower_place(place, builder).expect("address of a non-place"),
+        ExprKind::Deref(ptr) => IrInst::Deref(builder.expr_type(expr), Box::new(lower_expr(ptr, builder))),
+        ExprKind::ArrayLit(elems) => {
+            let elem_ty = match builder.expr_type(expr) {
+                Type::Array(elem, _) => *elem,
+                _ => unreachable!("array literals have array types"),
+            };
+            let values = elems.iter().map(|e| lower_expr(e, builder)).collect();
+            IrInst::ArrayLit(elem_ty, values)
+        }
+        ExprKind::ArrayRepeat { value, count } => lower_array_repeat(expr, value, *count, builder),
+        ExprKind::Index { expr: base, index } => {
+            let addr = lower_elem_addr(base, index, builder);
+            IrInst::Deref(builder.expr_type(expr), Box::new(addr))
+        }
+        ExprKind::Slice { expr: base, start, end } => lower_slice(base, start.as_deref(), end.as_deref(), builder),
+        ExprKind::Unary { op, expr } => IrInst::Unary(*op, Box::new(lower_expr(expr, builder))),
+        ExprKind::Await(future) => IrInst::Await(builder.expr_type(future), Box::new(lower_expr(future, builder))),
+        ExprKind::Spawn(future) => IrInst::Spawn(builder.expr_type(future), Box::new(lower_expr(future, builder))),
+    }
+}
+
+/// Address of the storage `expr` denotes, or `None` if it is a temporary.
+fn lower_place(expr: &Expr, builder: &mut FnBuilder<'_>) -> Option<IrInst> {
+    match &expr.kind {
+        ExprKind::Ident(name) => Some(IrInst::VarAddr(name.clone())),
+        ExprKind::Deref(ptr) => Some(lower_expr(ptr, builder)),
+        ExprKind::Field { expr: base, field } => {
+            let base_ty = builder.expr_type(base);
+            let (struct_ty, index) = builder.field(&base_ty, field);
+            let base_addr = match base_ty.pointee() {
+                Some(_) => lower_expr(base, builder),
+                None => lower_place(base, builder)?,
+            };
+            Some(IrInst::FieldAddr(struct_ty, index, Box::new(base_addr)))
+        }
+        ExprKind::Index { expr: base, index } => Some(lower_elem_addr(base, index, builder)),
+        _ => None,
+    }
+}
+
+/// `value` if it is a plain variable address, or else a load of a temporary
+/// holding it, so that it is evaluated once however often it is used.
+fn reuse(value: IrInst, builder: &mut FnBuilder<'_>) -> IrInst {
+    match value {
+        IrInst::VarAddr(_) | IrInst::Const(..) => value,
+        _ => {
+            let temp = builder.temp("tmp");
+            builder.push(IrInst::Store(temp.clone(), Box::new(value)));
+            IrInst::Load(temp)
+        }
+    }
+}
+
+/// Address of the first element of the array or slice `base` (which a
+/// temporary array is stored for), and its length.
+fn lower_elements(base: &Expr, builder: &mut FnBuilder<'_>) -> (IrInst, IrInst) {
+    let i64_ty = Type::named("i64");
+    match builder.expr_type(base) {
+        Type::Array(_, len) => {
+            let addr = match lower_place(base, builder) {
+                Some(addr) => addr,
+                None => {
+                    let value = lower_expr(base, builder);
+                    let temp = builder.temp("array");
+                    builder.push(IrInst::Store(temp.clone(), Box::new(value)));
+                    IrInst::VarAddr(temp)
+                }
+            };
+            (reuse(addr, builder), IrInst::Const(len, i64_ty))
+        }
+        Type::Pointer(pointee) | Type::Ref(pointee, _) => match *pointee {
+            Type::Array(_, len) => {
+                let addr = lower_expr(base, builder);
+                (reuse(addr, builder), IrInst::Const(len, i64_ty))
+            }
+            _ => unreachable!("only pointers to arrays have elements"),
+        },
+        Type::Slice(_) => {
+            let slice = lower_expr(base, builder);
+            let slice = reuse(slice, builder);
+            (IrInst::Extract(Box::new(slice.clone()), 0), IrInst::Extract(Box::new(slice), 1))
+        }
+        _ => unreachable!("only arrays and slices have elements"),
+    }
+}
+
+/// Element type of the array or slice `base`, also behind a reference or pointer.
+fn elem_type(base: &Expr, builder: &FnBuilder<'_>) -> Type {
+    match builder.expr_type(base) {
+        Type::Array(elem, _) | Type::Slice(elem) => *elem,
+        Type::Pointer(pointee) | Type::Ref(pointee, _) => match *pointee {
+            Type::Array(elem, _) => *elem,
+            _ => unreachable!("only pointers to arrays have elements"),
+        },
+        _ => unreachable!("only arrays and slices have elements"),
+    }
+}
+
+/// Whether `base`'s length is known at compile time, so that semantic
+/// analysis already checked literal indices into it.
+fn has_static_len(base: &Expr, builder: &FnBuilder<'_>) -> bool {
+    match builder.expr_type(base) {
+        Type::Array(..) => true,
+        Type::Pointer(pointee) | Type::Ref(pointee, _) => matches!(*pointee, Type::Array(..)),
+        _ => false,
+    }
+}
+
+/// Address of `base[index]`, checking the index against the length unless
+/// it is a literal into an array.
+fn lower_elem_addr(base: &Expr, index: &Expr, builder: &mut FnBuilder<'_>) -> IrInst {
+    let elem_ty = elem_type(base, builder);
+    let checked = !(has_static_len(base, builder) && matches!(index.kind, ExprKind::Int { .. }));
+    let (addr, len) = lower_elements(base, builder);
+    let index = lower_expr(index, builder);
+    let index = if checked {
+        let index = reuse(index, builder);
+        builder.push(IrInst::CheckIndex(Box::new(index.clone()), Box::new(len)));
+        index
+    } else {
+        index
+    };
+    IrInst::ElemAddr(elem_ty, Box::new(addr), Box::new(index))
+}
+
+/// Lowers `base[start..end]` to a slice from the address of element `start`
+/// and the length `end - start`, checking the bounds unless they are
+/// literals (or left out) on an array.
+fn lower_slice(base: &Expr, start: Option<&Expr>, end: Option<&Expr>, builder: &mut FnBuilder<'_>) -> IrInst {
+    let elem_ty = elem_type(base, builder);
+    let is_literal = |bound: Option<&Expr>| matches!(bound.map(|b| &b.kind), None | Some(ExprKind::Int { .. }));
+    let checked = !(has_static_len(base, builder) && is_literal(start) && is_literal(end));
+    let (addr, len) = lower_elements(base, builder);
+    let i64_ty = Type::named("i64");
+    let start = match start {
+        Some(start) => lower_expr(start, builder),
+        None => IrInst::Const(0, i64_ty.clone()),
+    };
+    let start = reuse(start, builder);
+    let end = match end {
+        Some(end) => lower_expr(end, builder),
+        None => len.clone(),
+    };
+    let end = reuse(end, builder);
+    if checked {
+        builder.push(IrInst::CheckRange(Box::new(start.clone()), Box::new(end.clone()), Box::new(len)));
+    }
+    let first = IrInst::ElemAddr(elem_ty.clone(), Box::new(addr), Box::new(start.clone()));
+    let len = IrInst::Binary(BinOp::Sub, i64_ty, Box::new(end), Box::new(start));
+    IrInst::MakeSlice(elem_ty, Box::new(first), Box::new(len))
+}
+
+/// Lowers `[value; count]` to a loop storing the value, evaluated once, into
+/// each element of a new array.
+fn lower_array_repeat(expr: &Expr, value: &Expr, count: u64, builder: &mut FnBuilder<'_>) -> IrInst {
+    let elem_ty = builder.expr_type(value);
+    let value = lower_expr(value, builder);
+    if count == 0 {
+        builder.push(value);
+        return IrInst::ArrayLit(elem_ty, Vec::new());
+    }
+    let value = reuse(value, builder);
+    let i64_ty = Type::named("i64");
+    let array = builder.temp("array");
+    builder.push(IrInst::Local(array.clone(), builder.expr_type(expr)));
+    let counter = builder.temp("i");
+    builder.push(IrInst::Store(counter.clone(), Box::new(IrInst::Const(0, i64_ty.clone()))));
+    let cond_bb = builder.new_block("repeat.cond");
+    let body_bb = builder.new_block("repeat.body");
+    let end_bb = builder.new_block("repeat.end");
+    builder.jump_to(cond_bb);
+
+    builder.switch_to(cond_bb);
+    let i = IrInst::Load(counter.clone());
+    let more = IrInst::Binary(BinOp::Lt, i64_ty.clone(), Box::new(i.clone()), Box::new(IrInst::Const(count, i64_ty.clone())));
+    builder.push(IrInst::Branch(Box::new(more), builder.label(body_bb), builder.label(end_bb)));
+
+    builder.switch_to(body_bb);
+    let elem = IrInst::ElemAddr(elem_ty, Box::new(IrInst::VarAddr(array.clone())), Box::new(i.clone()));
+    builder.push(IrInst::Write(Box::new(elem), Box::new(value)));
+    let next = IrInst::Binary(BinOp::Add, i64_ty.clone(), Box::new(i), Box::new(IrInst::Const(1, i64_ty)));
+    builder.push(IrInst::Write(Box::new(IrInst::VarAddr(counter)), Box::new(next)));
+    builder.jump_to(cond_bb);
+
+    builder.switch_to(end_bb);
+    IrInst::Load(array)
+}
+
+/// Lowers a `match` to a `Switch` on the scrutinee's variant (or its value,
+/// for literal patterns). Each case tries the arms that can apply in order,
+/// testing their nested patterns and falling through to the next arm on a
+/// mismatch. Returns a load of the result slot if the match has a value.
+fn lower_match(expr: &Expr, scrutinee: &Expr, arms: &[MatchArm], builder: &mut FnBuilder<'_>) -> Option<IrInst> {
+    let ty = builder.expr_type(scrutinee);
+    let value = lower_expr(scrutinee, builder);
+    let subject = builder.temp("match");
+    builder.push(IrInst::Store(subject.clone(), Box::new(value)));
+    let addr = IrInst::VarAddr(subject.clone());
+
+    let result_ty = builder.expr_type(expr);
+    let slot = (result_ty != Type::Void).then(|| builder.temp("match.result"));
+    if let Some(slot) = &slot {
+        builder.push(IrInst::Local(slot.clone(), result_ty));
+    }
+
+    // Arms whose pattern starts with a literal or variant select a switch
+    // case; the rest apply to every case.
+    let keys: Vec<Option<u64>> = arms.iter().map(|arm| switch_key(&arm.pattern, builder)).collect();
+    let arm_bbs: Vec<usize> = arms.iter().map(|_| builder.new_block("match.arm")).collect();
+    let merge_bb = builder.new_block("match.end");
+    let mut cases: Vec<(u64, usize)> = Vec::new();
+    for key in keys.iter().flatten() {
+        if !cases.iter().any(|(k, _)| k == key) {
+            cases.push((*key, builder.new_block("match.case")));
+        }
+    }
+    let default_bb = builder.new_block("match.default");
+    if cases.is_empty() {
+        builder.jump_to(default_bb);
+    } else {
+        let tag = match ty.decl_name() {
+            Some(name) if builder.decls.enums.contains_key(name) => IrInst::TagOf(ty.clone(), Box::new(addr.clone())),
+            _ => IrInst::Load(subject.clone()),
+        };
+        let labels = cases.iter().map(|&(key, bb)| (key, builder.label(bb))).collect();
+        builder.push(IrInst::Switch(Box::new(tag), labels, builder.label(default_bb)));
+    }
+
+    for (key, case_bb) in cases.iter().map(|&(key, bb)| (Some(key), bb)).chain([(None, default_bb)]) {
+        builder.switch_to(case_bb);
+        for (i, arm) in arms.iter().enumerate() {
+            if keys[i].is_some() && keys[i] != key {
+                continue;
+            }
+            let (variant, fields) = match &arm.pattern.kind {
+                PatternKind::Variant { variant, fields, .. } if fields.iter().any(is_refutable) => (variant, fields),
+                _ => {
+                    // The switch already decided that this arm matches.
+                    builder.jump_to(arm_bbs[i]);
+                    break;
+                }
+            };
+            let next_bb = builder.new_block("match.next");
+            let next = builder.label(next_bb);
+            let (_, payload) = builder.payload(&ty, variant, &addr);
+            for (pattern, (field_addr, field_ty)) in fields.iter().zip(payload) {
+                pattern_test(pattern, field_addr, &field_ty, &next, builder);
+            }
+            builder.jump_to(arm_bbs[i]);
+            builder.switch_to(next_bb);
+        }
+        if !builder.is_terminated() {
+            // Exhaustiveness checking guarantees some arm always matches.
+            builder.push(IrInst::Unreachable);
+        }
+    }
+
+    for (arm, &arm_bb) in arms.iter().zip(&arm_bbs) {
+        builder.switch_to(arm_bb);
+        bind_pattern(&arm.pattern, addr.clone(), &ty, builder);
+        match (&arm.body, &slot) {
+            (ArmBody::Expr(body), Some(slot)) => {
+                let value = lower_expr(body, builder);
+                builder.push(IrInst::Write(Box::new(IrInst::VarAddr(slot.clone())), Box::new(value)));
+            }
+            (ArmBody::Expr(body), None) => lower_expr_stmt(body, builder),
+            (ArmBody::Block(body), _) => lower_block(body, builder),
+        }
+        builder.jump_to(merge_bb);
+    }
+    builder.switch_to(merge_bb);
+    slot.map(IrInst::Load)
+}
+
+/// Switch case selected by the outermost part of `pattern`, if it has one.
+fn switch_key(pattern: &Pattern, builder: &FnBuilder<'_>) -> Option<u64> {
+    match &pattern.kind {
+        PatternKind::Variant { enum_name, variant, .. } => Some(builder.variant_index(enum_name, variant) as u64),
+        PatternKind::Literal(lit) => Some(literal_key(lit)),
+        PatternKind::Wildcard | PatternKind::Binding(_) => None,
+    }
+}
+
+/// Bit pattern of a literal pattern's value.
+fn literal_key(lit: &Expr) -> u64 {
+    match &lit.kind {
+        ExprKind::Bool(b) => *b as u64,
+        ExprKind::Char(c) => *c as u64,
+        ExprKind::Int { value, .. } => *value,
+        ExprKind::Unary { op: UnaryOp::Neg, expr } => literal_key(expr).wrapping_neg(),
+        _ => unreachable!("not a literal pattern"),
+    }
+}
+
+/// A literal pattern's value as a constant of the matched type `ty`.
+fn literal_value(lit: &Expr, ty: &Type) -> IrInst {
+    match &lit.kind {
+        ExprKind::Bool(b) => IrInst::Bool(*b),
+        ExprKind::Char(c) => IrInst::Char(*c),
+        ExprKind::Int { value, .. } => IrInst::Const(*value, ty.clone()),
+        ExprKind::Unary { op: UnaryOp::Neg, expr } => IrInst::Unary(UnaryOp::Neg, Box::new(literal_value(expr, ty))),
+        _ => unreachable!("not a literal pattern"),
+    }
+}
+
+/// Emits branches to `fail` unless the value of type `ty` at `addr` matches
+/// `pattern`, leaving the builder in the block reached on success.
+fn pattern_test(pattern: &Pattern, addr: IrInst, ty: &Type, fail: &str, builder: &mut FnBuilder<'_>) {
+    let branch = |cond: IrInst, builder: &mut FnBuilder<'_>| {
+        let ok_bb = builder.new_block("match.test");
+        builder.push(IrInst::Branch(Box::new(cond), builder.label(ok_bb), fail.to_string()));
+        builder.switch_to(ok_bb);
+    };
+    match &pattern.kind {
+        PatternKind::Wildcard | PatternKind::Binding(_) => {}
+        PatternKind::Literal(lit) => {
+            let value = IrInst::Deref(ty.clone(), Box::new(addr));
+            let expected = literal_value(lit, ty);
+            branch(IrInst::Binary(BinOp::Eq, ty.clone(), Box::new(value), Box::new(expected)), builder);
+        }
+        PatternKind::Variant { variant, fields, .. } => {
+            let (index, payload) = builder.payload(ty, variant, &addr);
+            let tag = IrInst::TagOf(ty.clone(), Box::new(addr));
+            let expected = IrInst::Const(index as u64, Type::named("u32"));
+            branch(IrInst::Binary(BinOp::Eq, Type::named("u32"), Box::new(tag), Box::new(expected)), builder);
+            for (pattern, (field_addr, field_ty)) in fields.iter().zip(payload) {
+                pattern_test(pattern, field_addr, &field_ty, fail, builder);
+            }
+        }
+    }
+}
+
+/// Whether some value of the matched type fails to match `pattern`.
+fn is_refutable(pattern: &Pattern) -> bool {
+    !matches!(pattern.kind, PatternKind::Wildcard | PatternKind::Binding(_))
+}
+
+/// Binds the variables of a pattern known to match the value of type `ty` at `addr`.
+fn bind_pattern(pattern: &Pattern, addr: IrInst, ty: &Type, builder: &mut FnBuilder<'_>) {
+    match &pattern.kind {
+        PatternKind::Binding(name) => {
+            builder.push(IrInst::Store(name.clone(), Box::new(IrInst::Deref(ty.clone(), Box::new(addr)))));
+        }
+        PatternKind::Variant { variant, fields, .. } => {
+            let (_, payload) = builder.payload(ty, variant, &addr);
+            for (pattern, (field_addr, field_ty)) in fields.iter().zip(payload) {
+                bind_pattern(pattern, field_addr, &field_ty, builder);
+            }
+        }
+        PatternKind::Wildcard | PatternKind::Literal(_) => {}
+    }
+}
+
+/// Lowers `operand?`, where the operand is an `Option` or `Result`. The
+/// prelude declares the variant that `?` unwraps first; for the other one,
+/// the function returns `None`, or `Err` with the same error value.
+fn lower_try(operand: &Expr, builder: &mut FnBuilder<'_>) -> IrInst {
+    let ty = builder.expr_type(operand);
+    let value = lower_expr(operand, builder);
+    let subject = builder.temp("try");
+    builder.push(IrInst::Store(subject.clone(), Box::new(value)));
+    let addr = IrInst::VarAddr(subject);
+    let (_, payload) = builder.payload(&ty, if ty.decl_name() == Some("Option") { "Some" } else { "Ok" }, &addr);
+
+    let ok_bb = builder.new_block("try.ok");
+    let fail_bb = builder.new_block("try.fail");
+    let tag = IrInst::TagOf(ty.clone(), Box::new(addr.clone()));
+    let is_ok = IrInst::Binary(BinOp::Eq, Type::named("u32"), Box::new(tag), Box::new(IrInst::Const(0, Type::named("u32"))));
+    builder.push(IrInst::Branch(Box::new(is_ok), builder.label(ok_bb), builder.label(fail_bb)));
+
+    builder.switch_to(fail_bb);
+    let error = match ty.decl_name() {
+        Some("Result") => {
+            let (_, err) = builder.payload(&ty, "Err", &addr);
+            err.into_iter().map(|(addr, ty)| IrInst::Deref(ty, Box::new(addr))).collect()
+        }
+        _ => Vec::new(),
+    };
+    let early = IrInst::EnumLit(builder.ret_ty.clone(), 1, error);
+    builder.push(IrInst::Ret(Some(Box::new(early))));
+
+    builder.switch_to(ok_bb);
+    let (value_addr, value_ty) = payload.into_iter().next().expect("`?` unwraps a variant with one field");
+    IrInst::Deref(value_ty, Box::new(value_addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ir::IrInst;
+    use crate::test_util::lower;
+
+    /// How many `CheckIndex` and `CheckRange` instructions `main` has.
+    fn checks(source: &str) -> (usize, usize) {
+        let module = lower(source);
+        let main = module.functions.iter().find(|f| f.name == "main").unwrap();
+        let insts = || main.blocks.iter().flat_map(|b| &b.body);
+        (
+            insts().filter(|inst| matches!(inst, IrInst::CheckIndex(..))).count(),
+            insts().filter(|inst| matches!(inst, IrInst::CheckRange(..))).count(),
+        )
+    }
+
+    #[test]
+    fn literal_indices_into_arrays_are_not_checked_at_run_time() {
+        assert_eq!(checks("fn main() {\n    let a = [1, 2, 3]\n    let s = a[..2]\n    print(s[1])\n    print(a[2])\n}\n"), (1, 0));
+    }
+
+    #[test]
+    fn computed_indices_and_bounds_are_checked() {
+        assert_eq!(
+            checks("fn main() {\n    let a = [1, 2, 3]\n    let i = 1\n    let s = a[i..]\n    print(s[0])\n    print(a[i])\n}\n"),
+            (2, 1)
+        );
     }
 }
@@ -2,11 +2,18 @@ use crate::ir::*;
 use crate::codegen::Codegen;
 use inkwell::context::Context;
 use inkwell::execution_engine::JitFunction;
+use inkwell::targets::{CodeModel, InitializationConfig, RelocMode, Target, TargetMachine};
 use anyhow::Result;
 
 pub fn run_jit(ir: &IrModule, entry: &str) -> Result<i64> {
+    Target::initialize_native(&InitializationConfig::default()).map_err(anyhow::Error::msg)?;
+    let triple = TargetMachine::get_default_triple();
+    let machine = Target::from_triple(&triple)
+        .map_err(|e| anyhow::anyhow!("{}", e))?
+        .create_target_machine(&triple, "", "", inkwell::OptimizationLevel::None, RelocMode::Default, CodeModel::JITDefault)
+        .ok_or_else(|| anyhow::anyhow!("Cannot generate code for {}", triple))?;
     let context = Context::create();
-    let mut codegen = Codegen::new(&context, "yuljit");
+    let mut codegen = Codegen::new(&context, "yuljit", &machine);
     codegen.codegen_module(ir)?;
     let engine = codegen.module.create_jit_execution_engine(inkwell::OptimizationLevel::None)?;
     unsafe {
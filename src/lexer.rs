@@ -4,39 +4,390 @@ use logos::Logos;
 pub enum Token {
     #[token("fn")] Fn,
     #[token("let")] Let,
+    #[token("if")] If,
+    #[token("else")] Else,
+    #[token("while")] While,
+    #[token("loop")] Loop,
+    #[token("break")] Break,
+    #[token("continue")] Continue,
+    #[token("return")] Return,
+    #[token("true")] True,
+    #[token("false")] False,
+    #[token("struct")] Struct,
+    #[token("enum")] Enum,
+    #[token("trait")] Trait,
+    #[token("impl")] Impl,
+    #[token("match")] Match,
     #[token("=>")] Arrow,
+    #[token("->")] ThinArrow,
     #[token("(")] LParen,
     #[token(")")] RParen,
     #[token("{")] LBrace,
     #[token("}")] RBrace,
+    #[token("[")] LBracket,
+    #[token("]")] RBracket,
     #[token(",")] Comma,
     #[token(":")] Colon,
+    #[token("::")] ColonColon,
     #[token(";")] Semicolon,
+    #[token(".")] Dot,
+    #[token("..")] DotDot,
     #[token("=")] Eq,
+    #[token("+")] Plus,
+    #[token("-")] Minus,
+    #[token("*")] Star,
+    #[token("/")] Slash,
+    #[token("%")] Percent,
+    #[token("==")] EqEq,
+    #[token("!=")] NotEq,
+    #[token("<")] Lt,
+    #[token("<=")] Le,
+    #[token(">")] Gt,
+    #[token(">=")] Ge,
+    #[token("&&")] AndAnd,
+    #[token("||")] OrOr,
+    #[token("|")] Pipe,
+    #[token("!")] Bang,
+    #[token("&")] Amp,
+    #[token("?")] Question,
     #[regex(r"[a-zA-Z_][a-zA-Z0-9_]*", |lex| lex.slice().to_string())]
     Ident(String),
-    #[regex(r"[0-9]+", |lex| lex.slice().parse())]
-    Int(i64),
-    #[regex(r"[ \t\n\r]+", logos::skip)] Whitespace,
+    #[regex(r"[0-9][0-9_]*(i8|i16|i32|i64|u8|u16|u32|u64)?", |lex| int_literal(lex.slice(), 10))]
+    #[regex(r"0x[0-9a-fA-F_]+(i8|i16|i32|i64|u8|u16|u32|u64)?", |lex| int_literal(&lex.slice()[2..], 16))]
+    #[regex(r"0b[01_]+(i8|i16|i32|i64|u8|u16|u32|u64)?", |lex| int_literal(&lex.slice()[2..], 2))]
+    Int((u64, Option<String>)),
+    #[regex(r"[0-9][0-9_]*\.[0-9][0-9_]*([eE][+-]?[0-9_]+)?(f32|f64)?", |lex| float_literal(lex.slice()))]
+    #[regex(r"[0-9][0-9_]*[eE][+-]?[0-9_]+(f32|f64)?", |lex| float_literal(lex.slice()))]
+    #[regex(r"[0-9][0-9_]*(f32|f64)", |lex| float_literal(lex.slice()))]
+    Float((f64, Option<String>)),
+    #[regex(r#""([^"\\]|\\.)*""#, |lex| unescape(lex.slice()))]
+    Str(String),
+    #[regex(r"'([^'\\]|\\.)+'", char_literal)]
+    Char(char),
+    #[regex(r"///[^\n]*", doc_comment)]
+    DocComment(String),
+    #[regex(r"//[^\n]*", logos::skip)] LineComment,
+    #[token("/*", block_comment)] BlockComment,
+    #[regex(r"[ \t\r]+", logos::skip)] Whitespace,
+    /// A line break plus any blank lines after it. Never reaches the parser:
+    /// `lex` turns it into a `Semicolon` or drops it.
+    #[regex(r"\n[ \t\r\n]*")] Newline,
     #[error]
     Error,
 }
 
+/// Text of a `///` comment without the marker and its conventional leading space.
+fn doc_comment(lex: &mut logos::Lexer<Token>) -> String {
+    let text = &lex.slice()[3..];
+    text.strip_prefix(' ').unwrap_or(text).trim_end().to_string()
+}
+
+/// Skips a block comment, honouring nested `/* */` pairs. An unterminated
+/// comment becomes `Token::Error`.
+fn block_comment(lex: &mut logos::Lexer<Token>) -> logos::FilterResult<()> {
+    let bytes = lex.remainder().as_bytes();
+    let mut depth = 1;
+    let mut i = 0;
+    while i + 1 < bytes.len() {
+        match (bytes[i], bytes[i + 1]) {
+            (b'/', b'*') => {
+                depth += 1;
+                i += 2;
+            }
+            (b'*', b'/') => {
+                depth -= 1;
+                i += 2;
+                if depth == 0 {
+                    lex.bump(i);
+                    return logos::FilterResult::Skip;
+                }
+            }
+            _ => i += 1,
+        }
+    }
+    let len = bytes.len();
+    lex.bump(len);
+    logos::FilterResult::Error
+}
+
+const INT_SUFFIXES: [&str; 8] = ["i8", "i16", "i32", "i64", "u8", "u16", "u32", "u64"];
+
+/// Splits a trailing type suffix such as `u8` or `f32` off a numeric literal.
+fn split_suffix<'s>(text: &'s str, suffixes: &[&str]) -> (&'s str, Option<String>) {
+    for suffix in suffixes {
+        if let Some(digits) = text.strip_suffix(suffix) {
+            return (digits, Some(suffix.to_string()));
+        }
+    }
+    (text, None)
+}
+
+/// Parses the digits of an integer literal (prefix already removed). Values
+/// that overflow `u64` turn the literal into `Token::Error`.
+fn int_literal(text: &str, radix: u32) -> Option<(u64, Option<String>)> {
+    let (digits, suffix) = split_suffix(text, &INT_SUFFIXES);
+    let digits: String = digits.chars().filter(|&c| c != '_').collect();
+    let value = u64::from_str_radix(&digits, radix).ok()?;
+    Some((value, suffix))
+}
+
+fn float_literal(text: &str) -> Option<(f64, Option<String>)> {
+    let (digits, suffix) = split_suffix(text, &["f32", "f64"]);
+    let digits: String = digits.chars().filter(|&c| c != '_').collect();
+    Some((digits.parse().ok()?, suffix))
+}
+
+/// The one character between the quotes. Literals holding more than one
+/// character turn into `Token::Error`.
+fn char_literal(lex: &mut logos::Lexer<Token>) -> Option<char> {
+    let text = unescape(lex.slice())?;
+    let mut chars = text.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Some(c),
+        _ => None,
+    }
+}
+
+/// Strips the quotes from a string or char literal and resolves its escape
+/// sequences. Unknown escapes turn the literal into `Token::Error`.
+fn unescape(slice: &str) -> Option<String> {
+    let mut out = String::with_capacity(slice.len());
+    let mut chars = slice[1..slice.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        out.push(match chars.next()? {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '0' => '\0',
+            '\\' => '\\',
+            '"' => '"',
+            '\'' => '\'',
+            _ => return None,
+        });
+    }
+    Some(out)
+}
+
 #[derive(Debug, Clone)]
 pub struct SpannedToken {
     pub token: Token,
     pub span: std::ops::Range<usize>,
 }
 
+#[cfg(test)]
 pub fn lex(source: &str) -> Vec<SpannedToken> {
+    lex_at(source, 0)
+}
+
+/// Lexes a file whose spans start at `start` in a `SourceMap` holding
+/// several files.
+pub fn lex_at(source: &str, start: usize) -> Vec<SpannedToken> {
     let mut lexer = Token::lexer(source);
     let mut tokens = Vec::new();
     while let Some(token) = lexer.next() {
         let span = lexer.span();
         tokens.push(SpannedToken {
             token,
-            span,
+            span: span.start + start..span.end + start,
         });
     }
-    tokens
+    insert_semicolons(tokens)
+}
+
+/// Terminates statements at line breaks. A newline becomes a `;` when the
+/// line's last token can end a statement, no parenthesis or bracket opened
+/// in the current block is open (so closure bodies passed as arguments
+/// still end their statements at line breaks), and the next line that is
+/// not blank or a comment does not start with a token that continues the
+/// statement or closes the block (the parser allows `}` to end a
+/// statement); every other newline is dropped.
+fn insert_semicolons(tokens: Vec<SpannedToken>) -> Vec<SpannedToken> {
+    let mut out: Vec<SpannedToken> = Vec::with_capacity(tokens.len());
+    let mut parens = 0usize;
+    let mut outer_parens = Vec::new();
+    for (i, tok) in tokens.iter().enumerate() {
+        match tok.token {
+            Token::LParen | Token::LBracket => parens += 1,
+            Token::RParen | Token::RBracket => parens = parens.saturating_sub(1),
+            Token::LBrace => outer_parens.push(std::mem::take(&mut parens)),
+            Token::RBrace => parens = outer_parens.pop().unwrap_or(0),
+            Token::Newline => {
+                let ends = out.last().is_some_and(|prev| ends_statement(&prev.token));
+                // Lines holding only comments lex as further newlines.
+                let next = i + tokens[i..].iter().take_while(|t| t.token == Token::Newline).count();
+                let continues = tokens.get(next).is_some_and(|next_tok| {
+                    continues_statement(&next_tok.token) && !starts_deref(next_tok, tokens.get(next + 1))
+                });
+                if parens == 0 && ends && !continues {
+                    let at = tok.span.start;
+                    out.push(SpannedToken { token: Token::Semicolon, span: at..at });
+                }
+                continue;
+            }
+            _ => {}
+        }
+        out.push(tok.clone());
+    }
+    out
+}
+
+/// Whether `tok` is a `*` written directly against the token after it,
+/// which at the start of a line is a dereference, as in `*p = 1`, rather
+/// than the rest of a product.
+fn starts_deref(tok: &SpannedToken, after: Option<&SpannedToken>) -> bool {
+    tok.token == Token::Star && matches!(after, Some(after) if after.span.start == tok.span.end)
+}
+
+/// Tokens that may be the last one of a statement.
+fn ends_statement(token: &Token) -> bool {
+    matches!(
+        token,
+        Token::Ident(_) | Token::Int(_) | Token::Float(_) | Token::Str(_) | Token::Char(_)
+            | Token::True | Token::False | Token::Break | Token::Continue | Token::Return
+            | Token::RParen | Token::RBracket | Token::RBrace | Token::Question
+    )
+}
+
+/// Tokens that, at the start of a line, continue the previous one: binary
+/// operators, field access, `else`, and the `}` closing a block or struct
+/// literal. A `{` starting a line ends the previous statement, as it may
+/// start a block statement; the parser skips that end when the previous
+/// line was the header of the block.
+fn continues_statement(token: &Token) -> bool {
+    matches!(
+        token,
+        Token::Plus | Token::Minus | Token::Star | Token::Slash | Token::Percent
+            | Token::EqEq | Token::NotEq | Token::Lt | Token::Le | Token::Gt | Token::Ge
+            | Token::AndAnd | Token::OrOr | Token::Dot | Token::Else | Token::RBrace
+            | Token::ThinArrow
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(source: &str) -> Vec<Token> {
+        lex(source).into_iter().map(|t| t.token).collect()
+    }
+
+    fn int(value: u64, suffix: Option<&str>) -> Token {
+        Token::Int((value, suffix.map(String::from)))
+    }
+
+    fn float(value: f64, suffix: Option<&str>) -> Token {
+        Token::Float((value, suffix.map(String::from)))
+    }
+
+    #[test]
+    fn integer_literals_in_every_radix() {
+        assert_eq!(
+            tokens("1_000 0xFF 0xdead_BEEF 0b1010 0b1111_0000"),
+            vec![int(1000, None), int(255, None), int(0xdead_beef, None), int(10, None), int(0xf0, None)]
+        );
+    }
+
+    #[test]
+    fn literal_suffixes() {
+        assert_eq!(
+            tokens("255u8 0x7Fi8 0b1u64 7i32 1.5f32 2f64 3.0"),
+            vec![
+                int(255, Some("u8")),
+                int(127, Some("i8")),
+                int(1, Some("u64")),
+                int(7, Some("i32")),
+                float(1.5, Some("f32")),
+                float(2.0, Some("f64")),
+                float(3.0, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn integers_beyond_u64_are_errors() {
+        assert_eq!(tokens("18446744073709551615"), vec![int(u64::MAX, None)]);
+        assert_eq!(tokens("18446744073709551616"), vec![Token::Error]);
+        assert_eq!(tokens("0x1_0000_0000_0000_0000"), vec![Token::Error]);
+    }
+
+    #[test]
+    fn float_exponents() {
+        assert_eq!(
+            tokens("2.5e-3 1e3 6.02E+23f64 1_0.5e1_0"),
+            vec![float(2.5e-3, None), float(1e3, None), float(6.02e23, Some("f64")), float(10.5e10, None)]
+        );
+    }
+
+    #[test]
+    fn char_and_string_escapes() {
+        assert_eq!(
+            tokens(r"'a' '\n' '\t' '\0' '\\' '\'' '\x'"),
+            vec![
+                Token::Char('a'),
+                Token::Char('\n'),
+                Token::Char('\t'),
+                Token::Char('\0'),
+                Token::Char('\\'),
+                Token::Char('\''),
+                Token::Error,
+            ]
+        );
+        assert_eq!(tokens(r#""a\"b\r\n""#), vec![Token::Str("a\"b\r\n".to_string())]);
+    }
+
+    #[test]
+    fn char_literals_hold_one_character() {
+        assert_eq!(tokens("'é' '€' '🦀'"), vec![Token::Char('é'), Token::Char('€'), Token::Char('🦀')]);
+        assert_eq!(tokens("'ab' 'é\\n'"), vec![Token::Error, Token::Error]);
+    }
+
+    #[test]
+    fn string_escapes() {
+        assert_eq!(
+            tokens(r#""tab\there" "\"quoted\" \\ \0" "line\r\n""#),
+            vec![
+                Token::Str("tab\there".to_string()),
+                Token::Str("\"quoted\" \\ \0".to_string()),
+                Token::Str("line\r\n".to_string()),
+            ]
+        );
+        assert_eq!(tokens(r#""\q""#), vec![Token::Error]);
+    }
+
+    #[test]
+    fn block_comments_nest() {
+        let a_b = vec![Token::Ident("a".into()), Token::Ident("b".into())];
+        assert_eq!(tokens("a /* one /* two */ still one */ b"), a_b);
+        assert_eq!(tokens("a /**/ b // rest"), a_b);
+    }
+
+    #[test]
+    fn unterminated_block_comments_are_errors() {
+        assert_eq!(tokens("a /* one /* two */"), vec![Token::Ident("a".into()), Token::Error]);
+        assert_eq!(tokens("/*"), vec![Token::Error]);
+    }
+
+    #[test]
+    fn doc_comment_text() {
+        assert_eq!(
+            tokens("///  Adds two numbers.  \n///x\nfn"),
+            vec![
+                Token::DocComment(" Adds two numbers.".into()),
+                Token::DocComment("x".into()),
+                Token::Fn,
+            ]
+        );
+    }
+
+    #[test]
+    fn comment_lines_do_not_end_statements() {
+        let chained = tokens("x\n// note\n/* more */\n.foo()");
+        assert!(!chained.contains(&Token::Semicolon), "{:?}", chained);
+        let separate = tokens("x\n// note\nfoo()");
+        assert_eq!(separate.iter().filter(|t| **t == Token::Semicolon).count(), 1);
+    }
 }
@@ -0,0 +1,27 @@
+use crate::error::{YulcError, YulcResult};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// C source of the runtime support library (`print` and friends).
+const RUNTIME_SOURCE: &str = include_str!("../runtime/yulrt.c");
+
+/// Links a compiled object file together with the runtime support library
+/// into a native executable using the system C compiler (`$CC`, default `cc`).
+pub fn link_executable(object: &Path, output: &Path) -> YulcResult<()> {
+    let runtime = object.with_extension("yulrt.c");
+    fs::write(&runtime, RUNTIME_SOURCE)?;
+    let cc = std::env::var("CC").unwrap_or_else(|_| "cc".to_string());
+    let status = Command::new(&cc)
+        .arg(object)
+        .arg(&runtime)
+        .arg("-o")
+        .arg(output)
+        .status();
+    let _ = fs::remove_file(&runtime);
+    let status = status.map_err(|e| YulcError::Link(format!("failed to run {}: {}", cc, e)))?;
+    if !status.success() {
+        return Err(YulcError::Link(format!("{} exited with {}", cc, status)));
+    }
+    Ok(())
+}
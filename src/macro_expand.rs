@@ -1,27 +1,417 @@
 use crate::ast::*;
+use crate::bail_at;
+use crate::source_map::SourceMap;
+use anyhow::Result;
+use std::collections::HashMap;
 
-pub fn expand_macros(program: &mut Program) {
-    let macro_map = program.macros.iter().map(|m| (m.name.clone(), m)).collect::<std::collections::HashMap<_, _>>();
+/// How deeply macros may invoke other macros, which stops one that invokes
+/// itself.
+const MAX_DEPTH: usize = 64;
+
+/// Replaces each `invoke name(args)` in the bodies of functions and methods
+/// by a block holding the body of the macro `name`, in which every use of a
+/// parameter is a copy of its argument expression. The variables the body
+/// declares are renamed for each expansion, so they never hide the
+/// variables its arguments use. Invocations of undefined macros are left
+/// for semantic analysis to report.
+///
+/// Expanded code gets spans of its own, in a copy of its file added to
+/// `sources`, so errors in it point at the macro's text and each copy can
+/// have its own types. `program.expansions` records where each copy stands.
+pub fn expand_macros(program: &mut Program, sources: &mut SourceMap) -> Result<()> {
+    let macros = program.macros.iter().map(|mac| (mac.name.as_str(), mac)).collect();
+    let mut expander = Expander { macros: &macros, sources, expansions: Vec::new(), depth: 0 };
     for func in &mut program.functions {
-        expand_stmts(&macro_map, &mut func.body);
+        expander.block(&mut func.body)?;
+    }
+    for imp in &mut program.impls {
+        for method in &mut imp.methods {
+            expander.block(&mut method.body)?;
+        }
     }
+    for def in &mut program.traits {
+        for method in &mut def.methods {
+            expander.block(&mut method.func.body)?;
+        }
+    }
+    program.expansions = expander.expansions;
+    Ok(())
+}
+
+struct Expander<'a> {
+    macros: &'a HashMap<&'a str, &'a MacroDef>,
+    sources: &'a mut SourceMap,
+    expansions: Vec<Expansion>,
+    /// Number of expansions around the code being expanded.
+    depth: usize,
+}
+
+/// How the code of one copy is rewritten.
+struct Rewrite<'a> {
+    /// Distance from the spans of the code copied to those of the copy.
+    shift: usize,
+    /// Where the copy starts, which tells it apart from the other copies.
+    copy: usize,
+    /// The macro's parameters and the arguments they stand for; empty when
+    /// copying an argument.
+    params: &'a [String],
+    args: &'a [Expr],
+    /// The macro's variables in scope, innermost last: each name and what
+    /// it is renamed to.
+    locals: Vec<(String, String)>,
 }
 
-fn expand_stmts<'a>(macro_map: &std::collections::HashMap<String, &'a MacroDef>, stmts: &mut Vec<Stmt>) {
-    let mut i = 0;
-    while i < stmts.len() {
-        match &stmts[i] {
-            Stmt::MacroInvoke { name, args } => {
-                if let Some(mac) = macro_map.get(name) {
-                    let mut expanded = mac.body.clone();
-                    // TODO: parameter substitution for macro args
-                    stmts.splice(i..=i, expanded);
-                    // Do not increment i, as we want to process the newly inserted stmts
-                    continue;
+impl Expander<'_> {
+    fn block(&mut self, stmts: &mut [Stmt]) -> Result<()> {
+        for stmt in stmts {
+            self.stmt(stmt)?;
+        }
+        Ok(())
+    }
+
+    fn stmt(&mut self, stmt: &mut Stmt) -> Result<()> {
+        match &mut stmt.kind {
+            StmtKind::MacroInvoke { name, args } => match self.macros.get(name.as_str()) {
+                Some(mac) => {
+                    let body = self.expand(mac, args, stmt.span)?;
+                    stmt.kind = StmtKind::Block(body);
+                }
+                None => {
+                    for arg in args {
+                        self.expr(arg)?;
+                    }
+                }
+            },
+            StmtKind::Let { expr, .. } | StmtKind::Expr(expr) | StmtKind::Return(Some(expr)) => self.expr(expr)?,
+            StmtKind::Assign { target, value } => {
+                self.expr(target)?;
+                self.expr(value)?;
+            }
+            StmtKind::If { cond, then_body, else_body } => {
+                self.expr(cond)?;
+                self.block(then_body)?;
+                if let Some(else_body) = else_body {
+                    self.block(else_body)?;
+                }
+            }
+            StmtKind::While { cond, body } => {
+                self.expr(cond)?;
+                self.block(body)?;
+            }
+            StmtKind::Loop { body } | StmtKind::Unsafe(body) | StmtKind::Block(body) => self.block(body)?,
+            StmtKind::Break | StmtKind::Continue | StmtKind::Return(None) | StmtKind::Error => {}
+        }
+        Ok(())
+    }
+
+    /// Expands the invocations in the closures and `match` arms of `expr`.
+    fn expr(&mut self, expr: &mut Expr) -> Result<()> {
+        match &mut expr.kind {
+            ExprKind::Lambda(lambda) => match &mut lambda.body {
+                LambdaBody::Expr(body) => self.expr(body),
+                LambdaBody::Block(body) => self.block(body),
+            },
+            ExprKind::Match { scrutinee, arms } => {
+                self.expr(scrutinee)?;
+                for arm in arms {
+                    match &mut arm.body {
+                        ArmBody::Expr(body) => self.expr(body)?,
+                        ArmBody::Block(body) => self.block(body)?,
+                    }
+                }
+                Ok(())
+            }
+            _ => children_mut(expr).into_iter().try_for_each(|child| self.expr(child)),
+        }
+    }
+
+    /// The body of `mac` for the invocation at `span`, with its own
+    /// invocations expanded.
+    fn expand(&mut self, mac: &MacroDef, args: &[Expr], span: Span) -> Result<Vec<Stmt>> {
+        if args.len() != mac.params.len() {
+            bail_at!(span, "Macro '{}' expects {} arguments, found {}", mac.name, mac.params.len(), args.len());
+        }
+        if self.depth == MAX_DEPTH {
+            bail_at!(span, "Macro '{}' is invoked more than {} levels deep; does it invoke itself?", mac.name, MAX_DEPTH);
+        }
+        let (shift, copy) = self.copy_file(mac.span.start, span.start);
+        let mut rewrite = Rewrite { shift, copy, params: &mac.params, args, locals: Vec::new() };
+        let mut body = mac.body.clone();
+        self.rewrite_block(&mut rewrite, &mut body);
+        self.depth += 1;
+        let expanded = self.block(&mut body);
+        self.depth -= 1;
+        expanded?;
+        Ok(body)
+    }
+
+    /// Adds a copy of the file holding `offset` to the source map, for code
+    /// that stands at the position `at`, and gives the distance from the
+    /// file's spans to the copy's, and where the copy starts.
+    fn copy_file(&mut self, offset: usize, at: usize) -> (usize, usize) {
+        let file = self.sources.file(offset).expect("spans lie in a file of the source map");
+        let (name, source, start) = (file.name.clone(), file.source.clone(), file.start);
+        let len = source.len();
+        let copy = self.sources.add_file(name, source);
+        self.expansions.push(Expansion { region: Span::new(copy, copy + len), at });
+        (copy - start, copy)
+    }
+
+    /// A copy of `arg` with spans of its own, for the parameter used at
+    /// `at`.
+    fn copy_arg(&mut self, arg: &Expr, at: usize) -> Expr {
+        let (shift, copy) = self.copy_file(arg.span.start, at);
+        let mut arg = arg.clone();
+        self.rewrite_expr(&mut Rewrite { shift, copy, params: &[], args: &[], locals: Vec::new() }, &mut arg);
+        arg
+    }
+
+    fn rewrite_block(&mut self, rw: &mut Rewrite, stmts: &mut [Stmt]) {
+        let outer = rw.locals.len();
+        for stmt in stmts {
+            self.rewrite_stmt(rw, stmt);
+        }
+        rw.locals.truncate(outer);
+    }
+
+    fn rewrite_stmt(&mut self, rw: &mut Rewrite, stmt: &mut Stmt) {
+        stmt.span = rw.shifted(stmt.span);
+        match &mut stmt.kind {
+            StmtKind::Let { name, expr, .. } => {
+                self.rewrite_expr(rw, expr);
+                rw.bind(name);
+            }
+            StmtKind::Expr(expr) | StmtKind::Return(Some(expr)) => self.rewrite_expr(rw, expr),
+            StmtKind::Assign { target, value } => {
+                self.rewrite_expr(rw, target);
+                self.rewrite_expr(rw, value);
+            }
+            StmtKind::MacroInvoke { args, .. } => {
+                for arg in args {
+                    self.rewrite_expr(rw, arg);
+                }
+            }
+            StmtKind::If { cond, then_body, else_body } => {
+                self.rewrite_expr(rw, cond);
+                self.rewrite_block(rw, then_body);
+                if let Some(else_body) = else_body {
+                    self.rewrite_block(rw, else_body);
+                }
+            }
+            StmtKind::While { cond, body } => {
+                self.rewrite_expr(rw, cond);
+                self.rewrite_block(rw, body);
+            }
+            StmtKind::Loop { body } | StmtKind::Unsafe(body) | StmtKind::Block(body) => self.rewrite_block(rw, body),
+            StmtKind::Break | StmtKind::Continue | StmtKind::Return(None) | StmtKind::Error => {}
+        }
+    }
+
+    fn rewrite_expr(&mut self, rw: &mut Rewrite, expr: &mut Expr) {
+        expr.span = rw.shifted(expr.span);
+        match &mut expr.kind {
+            ExprKind::Ident(name) => match rw.arg(name) {
+                Some(arg) => *expr = self.copy_arg(arg, expr.span.start),
+                None => rw.rename(name),
+            },
+            ExprKind::Call { name, args, .. } => {
+                for arg in args.iter_mut() {
+                    self.rewrite_expr(rw, arg);
+                }
+                match rw.arg(name) {
+                    Some(Expr { kind: ExprKind::Ident(callee), .. }) => *name = callee.clone(),
+                    Some(callee) => {
+                        let callee = Box::new(self.copy_arg(callee, expr.span.start));
+                        expr.kind = ExprKind::CallValue { callee, args: std::mem::take(args) };
+                    }
+                    None => rw.rename(name),
+                }
+            }
+            ExprKind::Match { scrutinee, arms } => {
+                self.rewrite_expr(rw, scrutinee);
+                for arm in arms {
+                    arm.span = rw.shifted(arm.span);
+                    let outer = rw.locals.len();
+                    self.rewrite_pattern(rw, &mut arm.pattern);
+                    match &mut arm.body {
+                        ArmBody::Expr(body) => self.rewrite_expr(rw, body),
+                        ArmBody::Block(body) => self.rewrite_block(rw, body),
+                    }
+                    rw.locals.truncate(outer);
+                }
+            }
+            ExprKind::Lambda(lambda) => {
+                let outer = rw.locals.len();
+                for param in &mut lambda.params {
+                    param.span = rw.shifted(param.span);
+                    rw.bind(&mut param.name);
+                }
+                match &mut lambda.body {
+                    LambdaBody::Expr(body) => self.rewrite_expr(rw, body),
+                    LambdaBody::Block(body) => self.rewrite_block(rw, body),
+                }
+                rw.locals.truncate(outer);
+            }
+            _ => {
+                for child in children_mut(expr) {
+                    self.rewrite_expr(rw, child);
                 }
             }
-            Stmt::Expr(_) | Stmt::Let { .. } => {}
         }
-        i += 1;
+    }
+
+    fn rewrite_pattern(&mut self, rw: &mut Rewrite, pattern: &mut Pattern) {
+        pattern.span = rw.shifted(pattern.span);
+        match &mut pattern.kind {
+            PatternKind::Wildcard => {}
+            PatternKind::Binding(name) => rw.bind(name),
+            PatternKind::Literal(lit) => self.rewrite_expr(rw, lit),
+            PatternKind::Variant { fields, .. } => {
+                for field in fields {
+                    self.rewrite_pattern(rw, field);
+                }
+            }
+        }
+    }
+}
+
+impl Rewrite<'_> {
+    fn shifted(&self, span: Span) -> Span {
+        Span::new(span.start + self.shift, span.end + self.shift)
+    }
+
+    /// The argument the variable `name` stands for, if it is a parameter
+    /// that no variable of the macro hides.
+    fn arg(&self, name: &str) -> Option<&Expr> {
+        if self.locals.iter().any(|(local, _)| local == name) {
+            return None;
+        }
+        self.params.iter().position(|param| param == name).map(|i| &self.args[i])
+    }
+
+    /// Renames a variable the macro declares, to a name unique to this
+    /// expansion, and brings it into scope.
+    fn bind(&mut self, name: &mut String) {
+        let renamed = format!("{}#macro{}", name, self.copy);
+        self.locals.push((std::mem::replace(name, renamed.clone()), renamed));
+    }
+
+    /// Renames a use of a variable the macro declares.
+    fn rename(&self, name: &mut String) {
+        if let Some((_, renamed)) = self.locals.iter().rev().find(|(local, _)| local == name) {
+            *name = renamed.clone();
+        }
+    }
+}
+
+/// The operands of an expression, except for the bodies of closures and
+/// `match` arms and the patterns of the arms.
+fn children_mut(expr: &mut Expr) -> Vec<&mut Expr> {
+    match &mut expr.kind {
+        ExprKind::Int { .. }
+        | ExprKind::Float { .. }
+        | ExprKind::Bool(_)
+        | ExprKind::Char(_)
+        | ExprKind::Str(_)
+        | ExprKind::Ident(_)
+        | ExprKind::Lambda(_) => Vec::new(),
+        ExprKind::Call { args, .. } | ExprKind::Variant { args, .. } | ExprKind::ArrayLit(args) => args.iter_mut().collect(),
+        ExprKind::CallValue { callee, args } => std::iter::once(&mut **callee).chain(args.iter_mut()).collect(),
+        ExprKind::MethodCall { receiver, args, .. } => std::iter::once(&mut **receiver).chain(args.iter_mut()).collect(),
+        ExprKind::StructLit { fields, .. } => fields.iter_mut().map(|(_, value)| value).collect(),
+        ExprKind::Match { scrutinee, .. } => vec![&mut **scrutinee],
+        ExprKind::Index { expr, index } => vec![&mut **expr, &mut **index],
+        ExprKind::Slice { expr, start, end } => {
+            std::iter::once(&mut **expr).chain(start.as_deref_mut()).chain(end.as_deref_mut()).collect()
+        }
+        ExprKind::Binary { lhs, rhs, .. } => vec![&mut **lhs, &mut **rhs],
+        ExprKind::Field { expr, .. }
+        | ExprKind::AddrOf { expr, .. }
+        | ExprKind::Deref(expr)
+        | ExprKind::ArrayRepeat { value: expr, .. }
+        | ExprKind::Unary { expr, .. }
+        | ExprKind::Try(expr)
+        | ExprKind::Await(expr)
+        | ExprKind::Spawn(expr) => vec![&mut **expr],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{analyze_package, check, error, run};
+
+    const TWICE: &str = "macro twice(x) {\n    let y = x + x\n    print(y)\n}\n";
+
+    #[test]
+    fn parameters_stand_for_their_arguments() {
+        let source = format!("{TWICE}fn main() {{\n    let result = 2 + 3\n    invoke twice(result)\n    invoke twice(1.25)\n}}\n");
+        assert_eq!(run(&source), "10\n2.5\n");
+    }
+
+    #[test]
+    fn macro_variables_do_not_hide_arguments() {
+        let source = format!("{TWICE}fn main() {{\n    let y = 7\n    invoke twice(y)\n    print(y)\n}}\n");
+        assert_eq!(run(&source), "14\n7\n");
+    }
+
+    #[test]
+    fn invocations_expand_in_closures_and_match_arms() {
+        check(&format!(
+            "{TWICE}fn main() {{
+    let f = |n: i64| {{ invoke twice(n) }}
+    match Option::Some(f) {{
+        Option::Some(g) => {{ invoke twice(1) }}
+        Option::None => {{}}
+    }}
+}}
+"
+        ));
+    }
+
+    #[test]
+    fn macro_bodies_use_the_names_of_their_module() {
+        let files = [
+            ("", "mod a\nimport a::twice\nfn main() {\n    invoke twice(1)\n}\n"),
+            ("a", "pub macro twice(x) {\n    print(double(x))\n}\nfn double(n: i64) -> i64 { return n * 2 }\n"),
+        ];
+        analyze_package(&files, false).unwrap();
+    }
+
+    #[test]
+    fn errors_point_into_the_macro_or_its_arguments() {
+        assert_eq!(
+            error("macro flag(x) {\n    let y: bool = x\n    let z: bool = y + 1\n}\nfn main() {\n    invoke flag(true)\n}\n"),
+            "main.yul:3:19: semantic error: Mismatched operand types for Add: bool and i64"
+        );
+        assert_eq!(
+            error("macro flag(x) {\n    let y: bool = x\n}\nfn main() {\n    invoke flag(1)\n}\n"),
+            "main.yul:5:17: semantic error: Mismatched types in `let`: expected bool, found i64"
+        );
+    }
+
+    #[test]
+    fn invocations_are_checked() {
+        assert_eq!(
+            error(&format!("{TWICE}fn main() {{\n    invoke twice(1, 2)\n}}\n")),
+            "main.yul:6:5: semantic error: Macro 'twice' expects 1 arguments, found 2"
+        );
+        assert_eq!(
+            error("fn main() {\n    invoke twice(1)\n}\n"),
+            "main.yul:2:5: semantic error: Undefined macro: twice"
+        );
+        assert_eq!(
+            error("macro forever(x) {\n    invoke forever(x)\n}\nfn main() {\n    invoke forever(1)\n}\n"),
+            "main.yul:2:5: semantic error: Macro 'forever' is invoked more than 64 levels deep; does it invoke itself?"
+        );
+    }
+
+    #[test]
+    fn expanded_code_is_borrow_checked_where_it_is_invoked() {
+        let show = "macro show(v) {\n    print(v)\n}\n";
+        check(&format!("{show}fn main() {{\n    let mut a = 1\n    let r = &a\n    invoke show(*r)\n    a = 2\n}}\n"));
+        assert_eq!(
+            error(&format!("{show}fn main() {{\n    let mut a = 1\n    let r = &mut a\n    invoke show(a)\n    print(*r)\n}}\n")),
+            "main.yul:7:17: semantic error: Cannot use `a` while it is mutably borrowed\nmain.yul:6:13: note: `a` is mutably borrowed here\nmain.yul:8:12: note: and the borrow is used again here"
+        );
     }
 }
@@ -1,3 +1,4 @@
+mod ai_hints;
 mod cli;
 mod lexer;
 mod parser;
@@ -6,13 +7,27 @@ mod ir;
 mod codegen;
 mod error;
 mod semantic;
+mod borrowck;
+mod infer;
+mod types;
 mod irgen;
-mod runtime;
+mod mono;
+mod linker;
+mod source_map;
+mod exhaustiveness;
+mod prelude;
+mod resolve;
+mod macro_expand;
+#[cfg(test)]
+mod test_util;
 
+use clap::Parser;
 use cli::YulcCli;
-use error::YulcResult;
 
-fn main() -> YulcResult<()> {
+fn main() {
     let cli = YulcCli::parse();
-    cli.run()
+    if let Err(err) = cli.run() {
+        eprintln!("{err}");
+        std::process::exit(1);
+    }
 }
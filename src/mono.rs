@@ -0,0 +1,460 @@
+use crate::ir::*;
+use crate::types::{self, Type};
+use anyhow::Result;
+use std::collections::{HashMap, HashSet};
+
+/// Deepest nesting of type arguments an instance may have. Only generic
+/// code that uses itself with ever larger types, like `f<T>` calling
+/// `f<Option<T>>`, gets this deep, and it would never stop instantiating.
+const MAX_TYPE_DEPTH: usize = 32;
+
+/// Replaces the generic functions, structs and enums of `module` with one
+/// copy per combination of type arguments the program uses them with, and
+/// drops the ones it never uses. Each copy is named by `mangle`, and every
+/// type and call in the result refers to copies, so the module no longer
+/// mentions type parameters or `Type::Generic`. Trait method calls go to the
+/// method of the receiver's type, and each conversion to `dyn` gets the
+/// vtable of the converted type.
+pub fn monomorphize(module: &IrModule) -> Result<IrModule> {
+    let mut mono = Mono {
+        generic_fns: module.functions.iter()
+            .filter(|f| !f.type_params.is_empty())
+            .map(|f| (f.name.as_str(), f))
+            .collect(),
+        generic_structs: module.structs.iter()
+            .filter(|s| !s.type_params.is_empty())
+            .map(|s| (s.name.as_str(), s))
+            .collect(),
+        generic_enums: module.enums.iter()
+            .filter(|e| !e.type_params.is_empty())
+            .map(|e| (e.name.as_str(), e))
+            .collect(),
+        traits: module.traits.iter().map(|t| (t.name.as_str(), t)).collect(),
+        impls: &module.impls,
+        params: Vec::new(),
+        args: Vec::new(),
+        types: HashSet::new(),
+        fns: HashSet::new(),
+        queue: Vec::new(),
+        structs: Vec::new(),
+        enums: Vec::new(),
+        dyn_traits: HashSet::new(),
+        vtables: Vec::new(),
+        shims: Vec::new(),
+    };
+    for def in module.structs.iter().filter(|s| s.type_params.is_empty()) {
+        let fields = def.fields.iter().map(|ty| mono.ty(ty)).collect::<Result<_>>()?;
+        mono.structs.push(IrStruct { fields, ..def.clone() });
+    }
+    for def in module.enums.iter().filter(|e| e.type_params.is_empty()) {
+        let variants = def.variants.iter()
+            .map(|fields| fields.iter().map(|ty| mono.ty(ty)).collect())
+            .collect::<Result<_>>()?;
+        mono.enums.push(IrEnum { variants, ..def.clone() });
+    }
+    let mut externs = Vec::new();
+    for ext in &module.externs {
+        let params = ext.params.iter().map(|ty| mono.ty(ty)).collect::<Result<_>>()?;
+        externs.push(IrExtern { name: ext.name.clone(), params, ret_ty: mono.ty(&ext.ret_ty)? });
+    }
+    let mut functions = Vec::new();
+    for func in module.functions.iter().filter(|f| f.type_params.is_empty()) {
+        functions.push(mono.function(func, func.name.clone(), Vec::new())?);
+    }
+    while let Some((name, generic, args)) = mono.queue.pop() {
+        let func = mono.generic_fns[generic.as_str()];
+        functions.push(mono.function(func, name, args)?);
+    }
+    functions.append(&mut mono.shims);
+    let dyn_traits = std::mem::take(&mut mono.dyn_traits);
+    let mut traits = Vec::new();
+    for def in module.traits.iter().filter(|t| dyn_traits.contains(&t.name)) {
+        let methods = def.methods.iter()
+            .map(|m| {
+                let params = m.params.iter().map(|ty| mono.concrete(ty)).collect::<Result<_>>()?;
+                let ret_ty = m.ret_ty.as_ref().map(|ty| mono.concrete(ty)).transpose()?;
+                Ok(IrMethod { name: m.name.clone(), params, ret_ty })
+            })
+            .collect::<Result<_>>()?;
+        traits.push(IrTrait { name: def.name.clone(), methods });
+    }
+    Ok(IrModule {
+        structs: mono.structs,
+        enums: mono.enums,
+        functions,
+        externs,
+        traits,
+        impls: Vec::new(),
+        vtables: mono.vtables,
+    })
+}
+
+/// Symbol name of the instance of `name` with the given type arguments, such
+/// as `max$3i64` for `max<i64>`. Every name in an argument is prefixed with
+/// its length, so different arguments never give the same symbol, and the
+/// `$` keeps instances apart from every name a program can declare.
+pub fn mangle(name: &str, args: &[Type]) -> String {
+    let mut symbol = format!("{}$", name);
+    for arg in args {
+        mangle_type(arg, &mut symbol);
+    }
+    symbol
+}
+
+fn mangle_type(ty: &Type, out: &mut String) {
+    match ty {
+        Type::Named(name) => out.push_str(&format!("{}{}", name.len(), name)),
+        Type::Generic(name, args) => {
+            out.push_str(&format!("{}{}I", name.len(), name));
+            for arg in args {
+                mangle_type(arg, out);
+            }
+            out.push('E');
+        }
+        Type::Pointer(pointee) => {
+            out.push('P');
+            mangle_type(pointee, out);
+        }
+        Type::Ref(pointee, mutable) => {
+            out.push(if *mutable { 'M' } else { 'B' });
+            mangle_type(pointee, out);
+        }
+        Type::Array(elem, len) => {
+            out.push_str(&format!("A{}", len));
+            mangle_type(elem, out);
+        }
+        Type::Slice(elem) => {
+            out.push('S');
+            mangle_type(elem, out);
+        }
+        Type::Dyn(name) => out.push_str(&format!("D{}{}", name.len(), name)),
+        Type::FnPtr(params, ret) | Type::Closure(params, ret) => {
+            out.push(if matches!(ty, Type::FnPtr(..)) { 'F' } else { 'C' });
+            for param in params {
+                mangle_type(param, out);
+            }
+            out.push('R');
+            mangle_type(ret, out);
+        }
+        Type::Void => out.push('v'),
+        Type::Var(_) => unreachable!("semantic analysis resolves inference variables"),
+    }
+}
+
+/// How deeply type arguments nest in `ty`.
+fn depth(ty: &Type) -> usize {
+    match ty {
+        Type::Generic(_, args) => 1 + args.iter().map(depth).max().unwrap_or(0),
+        Type::Pointer(elem) | Type::Ref(elem, _) | Type::Array(elem, _) | Type::Slice(elem) => 1 + depth(elem),
+        Type::FnPtr(params, ret) | Type::Closure(params, ret) => {
+            1 + params.iter().chain(std::iter::once(&**ret)).map(depth).max().unwrap_or(0)
+        }
+        Type::Named(_) | Type::Dyn(_) | Type::Void | Type::Var(_) => 1,
+    }
+}
+
+struct Mono<'a> {
+    generic_fns: HashMap<&'a str, &'a IrFunction>,
+    generic_structs: HashMap<&'a str, &'a IrStruct>,
+    generic_enums: HashMap<&'a str, &'a IrEnum>,
+    traits: HashMap<&'a str, &'a IrTrait>,
+    impls: &'a [IrImpl],
+    /// Type parameters of the function being copied and the types that
+    /// stand for them in this copy.
+    params: Vec<String>,
+    args: Vec<Type>,
+    /// Names of the struct and enum instances made so far.
+    types: HashSet<String>,
+    /// Names of the function instances made or queued so far.
+    fns: HashSet<String>,
+    /// Function instances still to make: name, generic function and type arguments.
+    queue: Vec<(String, String, Vec<Type>)>,
+    structs: Vec<IrStruct>,
+    enums: Vec<IrEnum>,
+    /// Traits used as `dyn`, whose signatures codegen needs for vtable calls.
+    dyn_traits: HashSet<String>,
+    vtables: Vec<IrVtable>,
+    /// Functions the vtables point to.
+    shims: Vec<IrFunction>,
+}
+
+impl<'a> Mono<'a> {
+    /// Copy of `func` named `name`, with `args` standing for its type parameters.
+    fn function(&mut self, func: &IrFunction, name: String, args: Vec<Type>) -> Result<IrFunction> {
+        self.params = func.type_params.clone();
+        self.args = args;
+        let params = func.params.iter()
+            .map(|p| Ok(IrParam { name: p.name.clone(), ty: self.ty(&p.ty)? }))
+            .collect::<Result<_>>()?;
+        let ret_ty = func.ret_ty.as_ref().map(|ty| self.ty(ty)).transpose()?;
+        let blocks = func.blocks.iter()
+            .map(|b| Ok(IrBlock { label: b.label.clone(), body: self.all(&b.body)? }))
+            .collect::<Result<_>>()?;
+        Ok(IrFunction { name, span: func.span, type_params: Vec::new(), params, ret_ty, blocks })
+    }
+
+    /// `ty` in the function being copied, with type parameters replaced and
+    /// generic instances referred to by the names of their copies.
+    fn ty(&mut self, ty: &Type) -> Result<Type> {
+        let ty = ty.substitute(&self.params, &self.args);
+        self.concrete(&ty)
+    }
+
+    /// `ty`, a type without type parameters, with each generic instance in it
+    /// replaced by the named type of its copy, which is made on first use.
+    fn concrete(&mut self, ty: &Type) -> Result<Type> {
+        match ty {
+            // A future is a closure that runs the body of an async function.
+            Type::Generic(name, args) if name == types::FUTURE => {
+                Ok(Type::Closure(Vec::new(), Box::new(self.concrete(&args[0])?)))
+            }
+            Type::Generic(name, args) => {
+                if depth(ty) > MAX_TYPE_DEPTH {
+                    anyhow::bail!("Type '{}' uses itself with ever larger type arguments and cannot be instantiated", name);
+                }
+                let symbol = mangle(name, args);
+                if self.types.insert(symbol.clone()) {
+                    if let Some(def) = self.generic_structs.get(name.as_str()).copied() {
+                        let fields = def.fields.iter()
+                            .map(|f| self.concrete(&f.substitute(&def.type_params, args)))
+                            .collect::<Result<_>>()?;
+                        self.structs.push(IrStruct { name: symbol.clone(), type_params: Vec::new(), fields, packed: def.packed });
+                    } else {
+                        let def = self.generic_enums[name.as_str()];
+                        let variants = def.variants.iter()
+                            .map(|fields| fields.iter().map(|f| self.concrete(&f.substitute(&def.type_params, args))).collect())
+                            .collect::<Result<_>>()?;
+                        self.enums.push(IrEnum { name: symbol.clone(), type_params: Vec::new(), variants });
+                    }
+                }
+                Ok(Type::Named(symbol))
+            }
+            Type::Pointer(pointee) => Ok(Type::pointer(self.concrete(pointee)?)),
+            Type::Ref(pointee, mutable) => Ok(Type::Ref(Box::new(self.concrete(pointee)?), *mutable)),
+            Type::Array(elem, len) => Ok(Type::Array(Box::new(self.concrete(elem)?), *len)),
+            Type::Slice(elem) => Ok(Type::Slice(Box::new(self.concrete(elem)?))),
+            Type::FnPtr(params, ret) | Type::Closure(params, ret) => {
+                let params = params.iter().map(|ty| self.concrete(ty)).collect::<Result<_>>()?;
+                let ret = Box::new(self.concrete(ret)?);
+                Ok(match ty {
+                    Type::FnPtr(..) => Type::FnPtr(params, ret),
+                    _ => Type::Closure(params, ret),
+                })
+            }
+            Type::Dyn(name) => {
+                self.dyn_traits.insert(name.clone());
+                Ok(ty.clone())
+            }
+            Type::Named(_) | Type::Void | Type::Var(_) => Ok(ty.clone()),
+        }
+    }
+
+    /// Name of the instance of generic function `name` with the type
+    /// arguments `args`, which have no type parameters, queueing the
+    /// instance on first use.
+    fn instance(&mut self, name: &str, args: Vec<Type>) -> Result<String> {
+        if args.iter().any(|ty| depth(ty) > MAX_TYPE_DEPTH) {
+            anyhow::bail!("Function '{}' uses itself with ever larger type arguments and cannot be instantiated", name);
+        }
+        let symbol = mangle(name, &args);
+        if self.fns.insert(symbol.clone()) {
+            self.queue.push((symbol.clone(), name.to_string(), args));
+        }
+        Ok(symbol)
+    }
+
+    /// Name of the function `name` refers to with the type arguments
+    /// `type_args` in the function being copied: itself if there are none,
+    /// or else its instance for them.
+    fn fn_symbol(&mut self, name: &str, type_args: &[Type]) -> Result<String> {
+        if type_args.is_empty() {
+            return Ok(name.to_string());
+        }
+        let type_args = type_args.iter().map(|ty| ty.substitute(&self.params, &self.args)).collect();
+        self.instance(name, type_args)
+    }
+
+    /// Name of the function that runs `method` of the named trait for `ty`,
+    /// a type without type parameters: the method its impl defines, or an
+    /// instance of the trait's default.
+    fn method(&mut self, trait_name: &str, method: &str, ty: &Type) -> Result<String> {
+        let imp = self.impls.iter()
+            .find(|imp| imp.trait_name == trait_name && imp.ty == *ty)
+            .ok_or_else(|| anyhow::anyhow!("Type {} does not implement trait '{}'", ty, trait_name))?;
+        if imp.methods.iter().any(|m| m == method) {
+            Ok(method_symbol(ty, Some(trait_name), method))
+        } else {
+            self.instance(&default_symbol(trait_name, method), vec![ty.clone()])
+        }
+    }
+
+    /// Name of the vtable through which `dyn` values of the named trait call
+    /// the methods of `ty`, a type without type parameters, made on first
+    /// use. Its entries are shims that take `self` by pointer and pass the
+    /// value on to the method.
+    fn vtable(&mut self, trait_name: &str, ty: &Type) -> Result<String> {
+        let name = format!("{}::{}$vtable", ty, trait_name);
+        if self.vtables.iter().any(|v| v.name == name) {
+            return Ok(name);
+        }
+        let def = self.traits[trait_name];
+        let self_ty = self.concrete(ty)?;
+        let mut functions = Vec::new();
+        for method in &def.methods {
+            let target = self.method(trait_name, &method.name, ty)?;
+            let mut params = vec![IrParam { name: "self".to_string(), ty: Type::pointer(self_ty.clone()) }];
+            let mut args = vec![IrInst::Deref(self_ty.clone(), Box::new(IrInst::Load("self".to_string())))];
+            for (i, param_ty) in method.params.iter().enumerate() {
+                let param = format!("arg{}", i);
+                params.push(IrParam { name: param.clone(), ty: self.concrete(param_ty)? });
+                args.push(IrInst::Load(param));
+            }
+            let ret_ty = method.ret_ty.as_ref().map(|ty| self.concrete(ty)).transpose()?;
+            let call = IrInst::Call(target, args);
+            let body = match ret_ty {
+                Some(_) => vec![IrInst::Ret(Some(Box::new(call)))],
+                None => vec![call, IrInst::Ret(None)],
+            };
+            let shim = format!("{}$dyn", method_symbol(ty, Some(trait_name), &method.name));
+            self.shims.push(IrFunction {
+                name: shim.clone(),
+                span: Default::default(),
+                type_params: Vec::new(),
+                params,
+                ret_ty,
+                blocks: vec![IrBlock { label: "entry".to_string(), body }],
+            });
+            functions.push(shim);
+        }
+        self.vtables.push(IrVtable { name: name.clone(), trait_name: trait_name.to_string(), functions });
+        Ok(name)
+    }
+
+    fn all(&mut self, insts: &[IrInst]) -> Result<Vec<IrInst>> {
+        insts.iter().map(|inst| self.inst(inst)).collect()
+    }
+
+    fn boxed(&mut self, inst: &IrInst) -> Result<Box<IrInst>> {
+        Ok(Box::new(self.inst(inst)?))
+    }
+
+    /// `inst` in the function being copied, with its types made concrete,
+    /// calls of generic functions directed to their instances and trait
+    /// methods to their implementations.
+    fn inst(&mut self, inst: &IrInst) -> Result<IrInst> {
+        Ok(match inst {
+            IrInst::Const(value, ty) => IrInst::Const(*value, self.ty(ty)?),
+            IrInst::Float(value, ty) => IrInst::Float(*value, self.ty(ty)?),
+            IrInst::Bool(_)
+            | IrInst::Char(_)
+            | IrInst::Str(_)
+            | IrInst::Load(_)
+            | IrInst::VarAddr(_)
+            | IrInst::Jump(_)
+            | IrInst::Unreachable => inst.clone(),
+            IrInst::Store(name, value) => IrInst::Store(name.clone(), self.boxed(value)?),
+            IrInst::Call(name, args) => IrInst::Call(name.clone(), self.all(args)?),
+            IrInst::CallGeneric(name, type_args, args) => {
+                let type_args = type_args.iter().map(|ty| ty.substitute(&self.params, &self.args)).collect();
+                IrInst::Call(self.instance(name, type_args)?, self.all(args)?)
+            }
+            IrInst::CallMethod(trait_name, method, ty, args) => {
+                let ty = ty.substitute(&self.params, &self.args);
+                IrInst::Call(self.method(trait_name, method, &ty)?, self.all(args)?)
+            }
+            IrInst::CallDyn(trait_name, slot, object, args) => {
+                IrInst::CallDyn(trait_name.clone(), *slot, self.boxed(object)?, self.all(args)?)
+            }
+            IrInst::MakeDyn(trait_name, ty, ptr) => {
+                let ty = ty.substitute(&self.params, &self.args);
+                IrInst::DynObject(self.vtable(trait_name, &ty)?, self.boxed(ptr)?)
+            }
+            IrInst::DynObject(vtable, ptr) => IrInst::DynObject(vtable.clone(), self.boxed(ptr)?),
+            IrInst::FnAddr(name, type_args) => IrInst::FnAddr(self.fn_symbol(name, type_args)?, Vec::new()),
+            IrInst::MakeClosure(name, type_args, env, on_heap) => {
+                IrInst::MakeClosure(self.fn_symbol(name, type_args)?, Vec::new(), self.boxed(env)?, *on_heap)
+            }
+            IrInst::FnToClosure(ty, fn_ptr) => IrInst::FnToClosure(self.ty(ty)?, self.boxed(fn_ptr)?),
+            IrInst::CallValue(ty, callee, args) => IrInst::CallValue(self.ty(ty)?, self.boxed(callee)?, self.all(args)?),
+            IrInst::Binary(op, ty, lhs, rhs) => IrInst::Binary(*op, self.ty(ty)?, self.boxed(lhs)?, self.boxed(rhs)?),
+            IrInst::Unary(op, operand) => IrInst::Unary(*op, self.boxed(operand)?),
+            IrInst::Print(ty, value) => IrInst::Print(self.ty(ty)?, self.boxed(value)?),
+            IrInst::StructLit(ty, fields) => IrInst::StructLit(self.ty(ty)?, self.all(fields)?),
+            IrInst::Extract(base, index) => IrInst::Extract(self.boxed(base)?, *index),
+            IrInst::EnumLit(ty, variant, fields) => IrInst::EnumLit(self.ty(ty)?, *variant, self.all(fields)?),
+            IrInst::TagOf(ty, ptr) => IrInst::TagOf(self.ty(ty)?, self.boxed(ptr)?),
+            IrInst::PayloadAddr(ty, variant, field, ptr) => {
+                IrInst::PayloadAddr(self.ty(ty)?, *variant, *field, self.boxed(ptr)?)
+            }
+            IrInst::Local(name, ty) => IrInst::Local(name.clone(), self.ty(ty)?),
+            IrInst::BindAddr(name, ty, addr) => IrInst::BindAddr(name.clone(), self.ty(ty)?, self.boxed(addr)?),
+            IrInst::FieldAddr(ty, index, base) => IrInst::FieldAddr(self.ty(ty)?, *index, self.boxed(base)?),
+            IrInst::ArrayLit(ty, elems) => IrInst::ArrayLit(self.ty(ty)?, self.all(elems)?),
+            IrInst::ElemAddr(ty, base, index) => IrInst::ElemAddr(self.ty(ty)?, self.boxed(base)?, self.boxed(index)?),
+            IrInst::MakeSlice(ty, ptr, len) => IrInst::MakeSlice(self.ty(ty)?, self.boxed(ptr)?, self.boxed(len)?),
+            IrInst::CheckIndex(index, len) => IrInst::CheckIndex(self.boxed(index)?, self.boxed(len)?),
+            IrInst::CheckRange(start, end, len) => {
+                IrInst::CheckRange(self.boxed(start)?, self.boxed(end)?, self.boxed(len)?)
+            }
+            IrInst::Alloc(ty, count) => IrInst::Alloc(self.ty(ty)?, self.boxed(count)?),
+            IrInst::Free(ptr) => IrInst::Free(self.boxed(ptr)?),
+            IrInst::Deref(ty, ptr) => IrInst::Deref(self.ty(ty)?, self.boxed(ptr)?),
+            IrInst::Write(ptr, value) => IrInst::Write(self.boxed(ptr)?, self.boxed(value)?),
+            IrInst::Ret(value) => IrInst::Ret(value.as_deref().map(|v| self.boxed(v)).transpose()?),
+            IrInst::Await(ty, future) => IrInst::Await(self.ty(ty)?, self.boxed(future)?),
+            IrInst::Spawn(ty, future) => IrInst::Spawn(self.ty(ty)?, self.boxed(future)?),
+            IrInst::Branch(cond, then_label, else_label) => {
+                IrInst::Branch(self.boxed(cond)?, then_label.clone(), else_label.clone())
+            }
+            IrInst::Switch(value, cases, default) => IrInst::Switch(self.boxed(value)?, cases.clone(), default.clone()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ir::IrModule;
+    use crate::test_util::lower;
+
+    fn function_names(module: &IrModule) -> Vec<&str> {
+        let mut names: Vec<_> = module.functions.iter().map(|f| f.name.as_str()).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn generic_structs_get_one_copy_per_argument_list() {
+        let module = lower(
+            "struct Pair<A, B> { first: A, second: B }
+fn main() {
+    let a = Pair { first: 1, second: true }
+    let b = Pair { first: false, second: 2 }
+    let c = Pair { first: 3, second: false }
+    print(a.first + b.second + c.first)
+}
+",
+        );
+        let mut structs: Vec<_> = module.structs.iter().map(|s| s.name.as_str()).collect();
+        structs.sort();
+        assert_eq!(structs, ["Pair$3i644bool", "Pair$4bool3i64"]);
+        assert_eq!(function_names(&module), ["main"]);
+    }
+
+    #[test]
+    fn generic_functions_instantiate_the_generics_they_call() {
+        let module = lower(
+            "struct Pair<A, B> { first: A, second: B }
+fn id<T>(x: T) -> T { return x }
+fn pair<A, B>(a: A, b: B) -> Pair<A, B> { return Pair { first: id(a), second: id(b) } }
+fn unused<T>(x: T) -> T { return id(x) }
+fn main() {
+    let p = pair(1, true)
+    print(p.first + id(2))
+}
+",
+        );
+        assert_eq!(function_names(&module), ["id$3i64", "id$4bool", "main", "pair$3i644bool"]);
+        let structs: Vec<_> = module.structs.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(structs, ["Pair$3i644bool"]);
+    }
+}
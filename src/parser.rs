@@ -1,71 +1,293 @@
 use crate::lexer::{Token, SpannedToken};
 use crate::ast::*;
+use crate::error::SpannedError;
 use anyhow::Result;
 
 pub struct Parser<'a> {
     tokens: &'a [SpannedToken],
     pos: usize,
+    /// Syntax errors recovered from so far, in source order.
+    errors: Vec<SpannedError>,
+    /// Set while parsing `if`/`while` conditions, where `Name {` opens the
+    /// body rather than a struct literal.
+    no_struct_literal: bool,
 }
 
 impl<'a> Parser<'a> {
     pub fn new(tokens: &'a [SpannedToken]) -> Self {
-        Self { tokens, pos: 0 }
+        Self { tokens, pos: 0, errors: Vec::new(), no_struct_literal: false }
     }
 
-    pub fn parse_program(&mut self) -> Result<Program> {
+    /// Syntax errors reported while parsing. When this is non-empty the
+    /// program returned by `parse_program` may contain `StmtKind::Error`
+    /// nodes and must not be compiled further.
+    pub fn errors(&self) -> &[SpannedError] {
+        &self.errors
+    }
+
+    /// Parses the whole token stream, recovering from syntax errors at item
+    /// and statement boundaries; see `errors` for what went wrong.
+    pub fn parse_program(&mut self) -> Program {
+        let mut structs = Vec::new();
+        let mut enums = Vec::new();
         let mut functions = Vec::new();
         let mut externs = Vec::new();
         let mut macros = Vec::new();
+        let mut traits = Vec::new();
+        let mut impls = Vec::new();
+        let mut mods = Vec::new();
+        let mut imports = Vec::new();
         while self.pos < self.tokens.len() {
-            if self.check(&Token::Ident("extern".to_string())) {
-                externs.push(self.parse_extern_function()?);
+            self.skip_semicolons();
+            let doc = self.parse_doc_comments();
+            if self.peek().is_none() {
+                break;
+            }
+            let item_start = self.pos;
+            let is_pub = self.check(&Token::Ident("pub".to_string()));
+            if is_pub {
+                self.advance();
+            }
+            let result = if is_pub && (self.check(&Token::Impl) || self.check(&Token::Ident("import".to_string()))) {
+                Err(self.error("Impl blocks and imports cannot be `pub`"))
+            } else if self.check(&Token::Ident("mod".to_string())) {
+                self.parse_mod_decl().map(|mut decl| {
+                    decl.is_pub = is_pub;
+                    mods.push(decl);
+                })
+            } else if self.check(&Token::Ident("import".to_string())) {
+                self.parse_import().map(|import| imports.push(import))
+            } else if self.check(&Token::Struct) || self.check(&Token::Ident("packed".to_string())) {
+                self.parse_struct_def().map(|mut def| {
+                    def.doc = doc;
+                    def.is_pub = is_pub;
+                    structs.push(def);
+                })
+            } else if self.check(&Token::Enum) {
+                self.parse_enum_def().map(|mut def| {
+                    def.doc = doc;
+                    def.is_pub = is_pub;
+                    enums.push(def);
+                })
+            } else if self.check(&Token::Trait) {
+                self.parse_trait_def().map(|mut def| {
+                    def.doc = doc;
+                    def.is_pub = is_pub;
+                    traits.push(def);
+                })
+            } else if self.check(&Token::Impl) {
+                self.parse_impl_def().map(|mut imp| {
+                    imp.doc = doc;
+                    impls.push(imp);
+                })
+            } else if self.check(&Token::Ident("extern".to_string())) {
+                self.parse_extern_function().map(|mut ext| {
+                    ext.doc = doc;
+                    ext.is_pub = is_pub;
+                    externs.push(ext);
+                })
             } else if self.check(&Token::Ident("macro".to_string())) {
-                macros.push(self.parse_macro_def()?);
+                self.parse_macro_def().map(|mut mac| {
+                    mac.doc = doc;
+                    mac.is_pub = is_pub;
+                    macros.push(mac);
+                })
             } else {
-                functions.push(self.parse_function()?);
+                self.parse_function().map(|mut func| {
+                    func.doc = doc;
+                    func.is_pub = is_pub;
+                    functions.push(func);
+                })
+            };
+            if let Err(err) = result {
+                self.report(err);
+                if self.pos == item_start {
+                    self.advance();
+                }
+                self.synchronize_item();
             }
         }
-        Ok(Program { functions, externs, macros })
+        Program { structs, enums, functions, externs, macros, traits, impls, mods, imports, expansions: Vec::new() }
     }
 
-    fn parse_macro_def(&mut self) -> Result<MacroDef> {
-        self.expect(Token::Ident("macro".to_string()))?;
+    fn parse_mod_decl(&mut self) -> Result<ModDecl> {
+        let start = self.start();
+        self.expect(Token::Ident("mod".to_string()))?;
         let name = self.parse_ident()?;
-        self.expect(Token::LParen)?;
-        let mut params = Vec::new();
-        if !self.check(Token::RParen) {
-            loop {
-                params.push(self.parse_ident()?);
-                if self.check(Token::Comma) {
+        Ok(ModDecl { name, is_pub: false, span: self.span_from(start) })
+    }
+
+    fn parse_import(&mut self) -> Result<Import> {
+        let start = self.start();
+        self.expect(Token::Ident("import".to_string()))?;
+        let path = self.parse_path()?.split("::").map(String::from).collect();
+        Ok(Import { path, span: self.span_from(start) })
+    }
+
+    fn parse_struct_def(&mut self) -> Result<StructDef> {
+        let start = self.start();
+        let packed = self.check(&Token::Ident("packed".to_string()));
+        if packed {
+            self.advance();
+        }
+        self.expect(Token::Struct)?;
+        let name = self.parse_ident()?;
+        let type_params = self.parse_type_params(None)?;
+        self.expect_block_start()?;
+        let mut fields = Vec::new();
+        while !self.check(&Token::RBrace) {
+            let field_start = self.start();
+            let field_name = self.parse_ident()?;
+            self.expect(Token::Colon)?;
+            let ty = self.parse_type()?;
+            fields.push(Field { name: field_name, ty, span: self.span_from(field_start) });
+            if self.check(&Token::Comma) {
+                self.advance();
+            } else {
+                break;
+            }
+        }
+        self.expect(Token::RBrace)?;
+        Ok(StructDef { name, type_params, fields, packed, is_pub: false, doc: None, span: self.span_from(start) })
+    }
+
+    fn parse_enum_def(&mut self) -> Result<EnumDef> {
+        let start = self.start();
+        self.expect(Token::Enum)?;
+        let name = self.parse_ident()?;
+        let type_params = self.parse_type_params(None)?;
+        self.expect_block_start()?;
+        let mut variants = Vec::new();
+        while !self.check(&Token::RBrace) {
+            let variant_start = self.start();
+            let variant_name = self.parse_ident()?;
+            let mut fields = Vec::new();
+            if self.check(&Token::LParen) {
+                self.advance();
+                while !self.check(&Token::RParen) {
+                    fields.push(self.parse_type()?);
+                    if self.check(&Token::Comma) {
+                        self.advance();
+                    } else {
+                        break;
+                    }
+                }
+                self.expect(Token::RParen)?;
+            }
+            variants.push(Variant { name: variant_name, fields, span: self.span_from(variant_start) });
+            if self.check(&Token::Comma) {
+                self.advance();
+            } else {
+                break;
+            }
+        }
+        self.expect(Token::RBrace)?;
+        Ok(EnumDef { name, type_params, variants, is_pub: false, doc: None, span: self.span_from(start) })
+    }
+
+    /// Parses the optional `<T, U, ...>` after the name of a generic item.
+    /// `T: Trait + ...` bounds are added to `bounds` as (parameter, trait)
+    /// pairs, and are an error where it is `None`.
+    fn parse_type_params(&mut self, mut bounds: Option<&mut Vec<(String, String)>>) -> Result<Vec<String>> {
+        let mut type_params = Vec::new();
+        if self.check(&Token::Lt) {
+            self.advance();
+            while !self.check(&Token::Gt) {
+                let param = self.parse_ident()?;
+                if self.check(&Token::Colon) {
+                    let bounds = match bounds.as_deref_mut() {
+                        Some(bounds) => bounds,
+                        None => return Err(self.error("Trait bounds are only allowed on the type parameters of functions")),
+                    };
+                    self.advance();
+                    loop {
+                        bounds.push((param.clone(), self.parse_path()?));
+                        if self.check(&Token::Plus) {
+                            self.advance();
+                        } else {
+                            break;
+                        }
+                    }
+                }
+                type_params.push(param);
+                if self.check(&Token::Comma) {
                     self.advance();
                 } else {
                     break;
                 }
             }
+            self.expect(Token::Gt)?;
         }
-        self.expect(Token::RParen)?;
-        self.expect(Token::LBrace)?;
-        let mut body = Vec::new();
-        while !self.check(Token::RBrace) {
-            body.push(self.parse_stmt()?);
+        Ok(type_params)
+    }
+
+    fn parse_trait_def(&mut self) -> Result<TraitDef> {
+        let start = self.start();
+        self.expect(Token::Trait)?;
+        let name = self.parse_ident()?;
+        self.expect_block_start()?;
+        let mut methods = Vec::new();
+        loop {
+            self.skip_semicolons();
+            let doc = self.parse_doc_comments();
+            if self.check(&Token::RBrace) {
+                break;
+            }
+            let mut func = self.parse_signature()?;
+            self.skip_line_end_before_block();
+            let has_default = self.check(&Token::LBrace);
+            if has_default {
+                func.body = self.parse_block()?;
+                func.span = self.span_from(func.span.start);
+            } else {
+                self.expect_terminator()?;
+            }
+            func.doc = doc;
+            methods.push(TraitMethod { func, has_default });
         }
         self.expect(Token::RBrace)?;
-        Ok(MacroDef { name, params, body })
+        Ok(TraitDef { name, methods, is_pub: false, doc: None, span: self.span_from(start) })
     }
 
-    fn parse_extern_function(&mut self) -> Result<ExternFunction> {
-        self.expect(Token::Ident("extern".to_string()))?;
-        self.expect(Token::Fn)?;
+    fn parse_impl_def(&mut self) -> Result<ImplDef> {
+        let start = self.start();
+        self.expect(Token::Impl)?;
+        let mut ty = self.parse_type()?;
+        let mut trait_name = None;
+        if self.check(&Token::Ident("for".to_string())) {
+            match ty {
+                Type::Named(name) => trait_name = Some(name),
+                _ => return Err(self.error("Expected a trait name before `for`")),
+            }
+            self.advance();
+            ty = self.parse_type()?;
+        }
+        self.expect_block_start()?;
+        let mut methods = Vec::new();
+        loop {
+            self.skip_semicolons();
+            let doc = self.parse_doc_comments();
+            if self.check(&Token::RBrace) {
+                break;
+            }
+            let mut func = self.parse_function()?;
+            func.doc = doc;
+            methods.push(func);
+        }
+        self.expect(Token::RBrace)?;
+        Ok(ImplDef { trait_name, ty, methods, doc: None, span: self.span_from(start) })
+    }
+
+    fn parse_macro_def(&mut self) -> Result<MacroDef> {
+        let start = self.start();
+        self.expect(Token::Ident("macro".to_string()))?;
         let name = self.parse_ident()?;
         self.expect(Token::LParen)?;
         let mut params = Vec::new();
-        if !self.check(Token::RParen) {
+        if !self.check(&Token::RParen) {
             loop {
-                let param_name = self.parse_ident()?;
-                self.expect(Token::Colon)?;
-                let param_ty = self.parse_ident()?;
-                params.push(Param { name: param_name, ty: param_ty });
-                if self.check(Token::Comma) {
+                params.push(self.parse_ident()?);
+                if self.check(&Token::Comma) {
                     self.advance();
                 } else {
                     break;
@@ -73,29 +295,86 @@ impl<'a> Parser<'a> {
             }
         }
         self.expect(Token::RParen)?;
-        self.expect(Token::Arrow)?;
-        let ret_ty = self.parse_ident()?;
-        Ok(ExternFunction { name, params, ret_ty })
+        let body = self.parse_block()?;
+        Ok(MacroDef { name, params, body, is_pub: false, doc: None, span: self.span_from(start) })
+    }
+
+    fn parse_extern_function(&mut self) -> Result<ExternFunction> {
+        let start = self.start();
+        self.expect(Token::Ident("extern".to_string()))?;
+        self.expect(Token::Fn)?;
+        let name = self.parse_ident()?;
+        let params = self.parse_params()?;
+        self.expect(Token::ThinArrow)?;
+        let ret_ty = self.parse_type()?;
+        Ok(ExternFunction { name, params, ret_ty, is_pub: false, doc: None, span: self.span_from(start) })
     }
 
     fn parse_function(&mut self) -> Result<Function> {
-        let is_async = if self.check(&Token::Ident("async".to_string())) {
+        let mut func = self.parse_signature()?;
+        func.body = self.parse_block()?;
+        func.span = self.span_from(func.span.start);
+        Ok(func)
+    }
+
+    /// Parses a function up to its body, which is left empty.
+    fn parse_signature(&mut self) -> Result<Function> {
+        let start = self.start();
+        // `async` and `unsafe` may come in either order, each at most once.
+        let (mut is_async, mut is_unsafe) = (false, false);
+        loop {
+            if !is_async && self.check(&Token::Ident("async".to_string())) {
+                is_async = true;
+            } else if !is_unsafe && self.check(&Token::Ident("unsafe".to_string())) {
+                is_unsafe = true;
+            } else {
+                break;
+            }
             self.advance();
-            true
-        } else {
-            false
-        };
+        }
         self.expect(Token::Fn)?;
         let name = self.parse_ident()?;
+        let mut bounds = Vec::new();
+        let type_params = self.parse_type_params(Some(&mut bounds))?;
+        let params = self.parse_params()?;
+        let ret_ty = if self.check(&Token::ThinArrow) {
+            self.advance();
+            Some(self.parse_type()?)
+        } else {
+            None
+        };
+        Ok(Function {
+            name,
+            type_params,
+            bounds,
+            params,
+            ret_ty,
+            body: Vec::new(),
+            is_async,
+            is_unsafe,
+            is_pub: false,
+            doc: None,
+            span: self.span_from(start),
+        })
+    }
+
+    /// Parses a parenthesized parameter list. The first parameter may be a
+    /// bare `self`, which has the type `Self`.
+    fn parse_params(&mut self) -> Result<Vec<Param>> {
         self.expect(Token::LParen)?;
         let mut params = Vec::new();
-        if !self.check(Token::RParen) {
+        if !self.check(&Token::RParen) {
             loop {
+                let start = self.start();
                 let param_name = self.parse_ident()?;
-                self.expect(Token::Colon)?;
-                let param_ty = self.parse_ident()?;
-                params.push(Param { name: param_name, ty: param_ty });
-                if self.check(Token::Comma) {
+                let param_ty = if param_name == "self" && params.is_empty() && !self.check(&Token::Colon) {
+                    Type::named("Self")
+                } else {
+                    self.expect(Token::Colon)?;
+                    self.parse_type()?
+                };
+                params.push(Param { name: param_name, ty: param_ty, span: self.span_from(start) });
+                if self.check(&Token::Comma) {
                     self.advance();
                 } else {
                     break;
@@ -103,113 +382,664 @@ impl<'a> Parser<'a> {
             }
         }
         self.expect(Token::RParen)?;
-        self.expect(Token::LBrace)?;
+        Ok(params)
+    }
+
+    fn parse_block(&mut self) -> Result<Vec<Stmt>> {
+        self.expect_block_start()?;
         let mut body = Vec::new();
-        while !self.check(Token::RBrace) {
-            body.push(self.parse_stmt()?);
+        loop {
+            // Doc comments only document items; inside a block they are ignored.
+            self.parse_doc_comments();
+            if self.check(&Token::Semicolon) {
+                self.advance();
+                continue;
+            }
+            if self.check(&Token::RBrace) {
+                break;
+            }
+            if self.peek().is_none() {
+                return Err(self.error("Unexpected end of input, expected '}'"));
+            }
+            let stmt_start = self.pos;
+            let start = self.start();
+            match self.parse_stmt() {
+                Ok(stmt) => body.push(stmt),
+                Err(err) => {
+                    self.report(err);
+                    if self.pos == stmt_start && !self.check(&Token::LBrace) {
+                        self.advance();
+                    }
+                    self.synchronize_stmt();
+                    body.push(Stmt::new(StmtKind::Error, self.span_from(start)));
+                }
+            }
         }
         self.expect(Token::RBrace)?;
-        Ok(Function { name, params, body, is_async })
+        Ok(body)
     }
 
     fn parse_stmt(&mut self) -> Result<Stmt> {
-        if self.check(Token::Let) {
+        let start = self.start();
+        let kind = if self.check(&Token::Let) {
             self.advance();
+            let mutable = self.check(&Token::Ident("mut".to_string()));
+            if mutable {
+                self.advance();
+            }
             let name = self.parse_ident()?;
+            let ty = if self.check(&Token::Colon) {
+                self.advance();
+                Some(self.parse_type()?)
+            } else {
+                None
+            };
             self.expect(Token::Eq)?;
             let expr = self.parse_expr()?;
-            self.expect(Token::Semicolon)?;
-            Ok(Stmt::Let { name, expr })
+            self.expect_terminator()?;
+            StmtKind::Let { name, mutable, ty, expr }
+        } else if self.check(&Token::If) {
+            return self.parse_if();
+        } else if self.check(&Token::While) {
+            self.advance();
+            let cond = self.parse_cond()?;
+            let body = self.parse_block()?;
+            StmtKind::While { cond, body }
+        } else if self.check(&Token::Loop) {
+            self.advance();
+            let body = self.parse_block()?;
+            StmtKind::Loop { body }
+        } else if self.check(&Token::Break) {
+            self.advance();
+            self.expect_terminator()?;
+            StmtKind::Break
+        } else if self.check(&Token::Continue) {
+            self.advance();
+            self.expect_terminator()?;
+            StmtKind::Continue
+        } else if self.check(&Token::Return) {
+            self.advance();
+            let value = if self.check(&Token::Semicolon) || self.check(&Token::RBrace) {
+                None
+            } else {
+                Some(self.parse_expr()?)
+            };
+            self.expect_terminator()?;
+            StmtKind::Return(value)
+        } else if self.check(&Token::Ident("unsafe".to_string()))
+            && matches!(self.tokens.get(self.pos + 1).map(|t| &t.token), Some(Token::LBrace))
+        {
+            self.advance();
+            StmtKind::Unsafe(self.parse_block()?)
+        } else if self.check(&Token::LBrace) {
+            StmtKind::Block(self.parse_block()?)
         } else if self.check(&Token::Ident("invoke".to_string())) {
             self.advance();
             let name = self.parse_ident()?;
-            self.expect(Token::LParen)?;
-            let mut args = Vec::new();
-            if !self.check(Token::RParen) {
-                loop {
-                    args.push(self.parse_expr()?);
-                    if self.check(Token::Comma) {
-                        self.advance();
-                    } else {
-                        break;
-                    }
+            let args = self.parse_args()?;
+            self.expect_terminator()?;
+            StmtKind::MacroInvoke { name, args }
+        } else {
+            let expr = self.parse_expr()?;
+            if self.check(&Token::Eq) {
+                self.advance();
+                let value = self.parse_expr()?;
+                self.expect_terminator()?;
+                StmtKind::Assign { target: expr, value }
+            } else if let ExprKind::Match { .. } = expr.kind {
+                // Like `if`, a `match` statement ends with its closing brace.
+                if self.check(&Token::Semicolon) {
+                    self.advance();
                 }
+                StmtKind::Expr(expr)
+            } else {
+                self.expect_terminator()?;
+                StmtKind::Expr(expr)
+            }
+        };
+        Ok(Stmt::new(kind, self.span_from(start)))
+    }
+
+    fn parse_if(&mut self) -> Result<Stmt> {
+        let start = self.start();
+        self.expect(Token::If)?;
+        let cond = self.parse_cond()?;
+        let then_body = self.parse_block()?;
+        let else_body = if self.check(&Token::Else) {
+            self.advance();
+            if self.check(&Token::If) {
+                Some(vec![self.parse_if()?])
+            } else {
+                Some(self.parse_block()?)
             }
-            self.expect(Token::RParen)?;
-            self.expect(Token::Semicolon)?;
-            Ok(Stmt::MacroInvoke { name, args })
         } else {
-            let expr = self.parse_expr()?;
-            self.expect(Token::Semicolon)?;
-            Ok(Stmt::Expr(expr))
-        }
+            None
+        };
+        Ok(Stmt::new(StmtKind::If { cond, then_body, else_body }, self.span_from(start)))
     }
 
     fn parse_expr(&mut self) -> Result<Expr> {
-        if let Some(Token::Int) = self.peek_token() {
-            let value = self.parse_int()?;
-            Ok(Expr::Int(value))
-        } else if let Some(Token::Ident) = self.peek_token() {
-            // Check for 'await' and 'spawn' keywords
-            if let Some(SpannedToken { token: Token::Ident(ref s), .. }) = self.peek() {
-                if s == "await" {
+        self.parse_binary(0)
+    }
+
+    /// An `if` or `while` condition, which may not be a bare struct literal.
+    fn parse_cond(&mut self) -> Result<Expr> {
+        let saved = std::mem::replace(&mut self.no_struct_literal, true);
+        let cond = self.parse_expr();
+        self.no_struct_literal = saved;
+        cond
+    }
+
+    /// Precedence climbing over the binary operators; `min_prec` is the
+    /// loosest operator allowed to bind at this level.
+    fn parse_binary(&mut self, min_prec: u8) -> Result<Expr> {
+        let mut lhs = self.parse_unary()?;
+        while let Some((op, prec)) = self.peek_token().and_then(binary_op) {
+            if prec < min_prec {
+                break;
+            }
+            self.advance();
+            let rhs = self.parse_binary(prec + 1)?;
+            let span = lhs.span.to(rhs.span);
+            lhs = Expr::new(ExprKind::Binary { op, lhs: Box::new(lhs), rhs: Box::new(rhs) }, span);
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr> {
+        let start = self.start();
+        let op = match self.peek_token() {
+            Some(Token::Minus) => UnaryOp::Neg,
+            Some(Token::Bang) => UnaryOp::Not,
+            Some(Token::Amp) => {
+                self.advance();
+                let mutable = self.check(&Token::Ident("mut".to_string()));
+                if mutable {
+                    self.advance();
+                }
+                let expr = Box::new(self.parse_unary()?);
+                return Ok(Expr::new(ExprKind::AddrOf { expr, mutable }, self.span_from(start)));
+            }
+            Some(Token::Star) => {
+                self.advance();
+                let expr = self.parse_unary()?;
+                return Ok(Expr::new(ExprKind::Deref(Box::new(expr)), self.span_from(start)));
+            }
+            _ => return self.parse_postfix(),
+        };
+        self.advance();
+        let expr = self.parse_unary()?;
+        Ok(Expr::new(ExprKind::Unary { op, expr: Box::new(expr) }, self.span_from(start)))
+    }
+
+    /// A primary expression followed by any number of `.field` accesses,
+    /// `.method(args)` calls, `(args)` calls of the value, `[index]` or
+    /// `[start..end]` and `?`s.
+    fn parse_postfix(&mut self) -> Result<Expr> {
+        let start = self.start();
+        let mut expr = self.parse_primary()?;
+        loop {
+            let kind = if self.check(&Token::Dot) {
+                self.advance();
+                let field = self.parse_ident()?;
+                if self.check(&Token::LParen) {
+                    let args = self.parse_args()?;
+                    ExprKind::MethodCall { receiver: Box::new(expr), method: field, args }
+                } else {
+                    ExprKind::Field { expr: Box::new(expr), field }
+                }
+            } else if self.check(&Token::Question) {
+                self.advance();
+                ExprKind::Try(Box::new(expr))
+            } else if self.check(&Token::LBracket) {
+                self.parse_index(expr)?
+            } else if self.check(&Token::LParen) {
+                let args = self.parse_args()?;
+                ExprKind::CallValue { callee: Box::new(expr), args }
+            } else {
+                break;
+            };
+            expr = Expr::new(kind, self.span_from(start));
+        }
+        Ok(expr)
+    }
+
+    /// Parses `[index]` or `[start..end]` after `expr`; both bounds of a
+    /// range are optional.
+    fn parse_index(&mut self, expr: Expr) -> Result<ExprKind> {
+        self.expect(Token::LBracket)?;
+        let saved = std::mem::replace(&mut self.no_struct_literal, false);
+        let result = self.parse_index_contents(expr);
+        self.no_struct_literal = saved;
+        let kind = result?;
+        self.expect(Token::RBracket)?;
+        Ok(kind)
+    }
+
+    fn parse_index_contents(&mut self, expr: Expr) -> Result<ExprKind> {
+        let start = if self.check(&Token::DotDot) { None } else { Some(Box::new(self.parse_expr()?)) };
+        if !self.check(&Token::DotDot) {
+            let index = start.expect("an index without `..` was parsed");
+            return Ok(ExprKind::Index { expr: Box::new(expr), index });
+        }
+        self.advance();
+        let end = if self.check(&Token::RBracket) { None } else { Some(Box::new(self.parse_expr()?)) };
+        Ok(ExprKind::Slice { expr: Box::new(expr), start, end })
+    }
+
+    /// Parses `[a, b, ...]` or `[value; count]`.
+    fn parse_array(&mut self) -> Result<ExprKind> {
+        self.expect(Token::LBracket)?;
+        let saved = std::mem::replace(&mut self.no_struct_literal, false);
+        let result = self.parse_array_contents();
+        self.no_struct_literal = saved;
+        let kind = result?;
+        self.expect(Token::RBracket)?;
+        Ok(kind)
+    }
+
+    fn parse_array_contents(&mut self) -> Result<ExprKind> {
+        let mut elems = Vec::new();
+        while !self.check(&Token::RBracket) {
+            elems.push(self.parse_expr()?);
+            if elems.len() == 1 && self.check(&Token::Semicolon) {
+                self.advance();
+                let count = self.parse_array_len()?;
+                let value = Box::new(elems.pop().expect("the repeated value was parsed"));
+                return Ok(ExprKind::ArrayRepeat { value, count });
+            }
+            if self.check(&Token::Comma) {
+                self.advance();
+            } else {
+                break;
+            }
+        }
+        Ok(ExprKind::ArrayLit(elems))
+    }
+
+    /// The length of an array type or repeat expression, an integer literal.
+    fn parse_array_len(&mut self) -> Result<u64> {
+        match self.peek_token() {
+            Some(Token::Int((value, None))) => {
+                let value = *value;
+                self.advance();
+                Ok(value)
+            }
+            _ => Err(self.error("Expected an array length, an integer literal without a suffix")),
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr> {
+        let start = self.start();
+        let kind = match self.peek_token() {
+            Some(Token::Int((value, ty))) => {
+                let kind = ExprKind::Int { value: *value, ty: ty.clone() };
+                self.advance();
+                kind
+            }
+            Some(Token::Float((value, ty))) => {
+                let kind = ExprKind::Float { value: *value, ty: ty.clone() };
+                self.advance();
+                kind
+            }
+            Some(Token::True) => {
+                self.advance();
+                ExprKind::Bool(true)
+            }
+            Some(Token::False) => {
+                self.advance();
+                ExprKind::Bool(false)
+            }
+            Some(Token::Char(c)) => {
+                let value = *c;
+                self.advance();
+                ExprKind::Char(value)
+            }
+            Some(Token::Str(s)) => {
+                let value = s.clone();
+                self.advance();
+                ExprKind::Str(value)
+            }
+            Some(Token::LParen) => {
+                self.advance();
+                let saved = std::mem::replace(&mut self.no_struct_literal, false);
+                let expr = self.parse_expr();
+                self.no_struct_literal = saved;
+                let expr = expr?;
+                self.expect(Token::RParen)?;
+                return Ok(expr);
+            }
+            Some(Token::LBracket) => self.parse_array()?,
+            Some(Token::Match) => {
+                self.advance();
+                let scrutinee = self.parse_cond()?;
+                let arms = self.parse_arms()?;
+                ExprKind::Match { scrutinee: Box::new(scrutinee), arms }
+            }
+            Some(Token::Ident(s)) if s == "await" => {
+                self.advance();
+                let expr = self.parse_unary()?;
+                ExprKind::Await(Box::new(expr))
+            }
+            Some(Token::Ident(s)) if s == "spawn" => {
+                self.advance();
+                let expr = self.parse_unary()?;
+                ExprKind::Spawn(Box::new(expr))
+            }
+            Some(Token::Pipe | Token::OrOr) => ExprKind::Lambda(Box::new(self.parse_lambda()?)),
+            Some(Token::Ident(s))
+                if s == "move"
+                    && matches!(
+                        self.tokens.get(self.pos + 1).map(|t| &t.token),
+                        Some(Token::Pipe | Token::OrOr)
+                    ) =>
+            {
+                ExprKind::Lambda(Box::new(self.parse_lambda()?))
+            }
+            Some(Token::Ident(_)) => {
+                let ident = self.parse_path()?;
+                let type_args = self.parse_call_type_args();
+                if self.check(&Token::LParen) || !type_args.is_empty() {
+                    let args = self.parse_args()?;
+                    ExprKind::Call { name: ident, type_args, args }
+                } else if self.check(&Token::LBrace) && !self.no_struct_literal {
+                    let fields = self.parse_field_inits()?;
+                    ExprKind::StructLit { name: ident, fields }
+                } else {
+                    ExprKind::Ident(ident)
+                }
+            }
+            _ => return Err(self.error("Unexpected token in expression")),
+        };
+        Ok(Expr::new(kind, self.span_from(start)))
+    }
+
+    /// Parses the `<T, ...>` of a call such as `alloc<i64>(n)`, if the
+    /// tokens after the callee's name read as type arguments followed by
+    /// `(`; otherwise consumes nothing, leaving a `<` comparison.
+    fn parse_call_type_args(&mut self) -> Vec<Type> {
+        if !self.check(&Token::Lt) {
+            return Vec::new();
+        }
+        let saved = self.pos;
+        match self.parse_type_args() {
+            Ok(args) if self.check(&Token::LParen) => args,
+            _ => {
+                self.pos = saved;
+                Vec::new()
+            }
+        }
+    }
+
+    /// Parses `<T, ...>`.
+    fn parse_type_args(&mut self) -> Result<Vec<Type>> {
+        self.expect(Token::Lt)?;
+        let mut args = Vec::new();
+        while !self.check(&Token::Gt) {
+            args.push(self.parse_type()?);
+            if self.check(&Token::Comma) {
+                self.advance();
+            } else {
+                break;
+            }
+        }
+        self.expect(Token::Gt)?;
+        Ok(args)
+    }
+
+    /// Parses `|params| expr` or `|params| -> Type { ... }`, optionally
+    /// preceded by `move`; `||` starts a closure without parameters.
+    fn parse_lambda(&mut self) -> Result<Lambda> {
+        let by_value = self.check(&Token::Ident("move".to_string()));
+        if by_value {
+            self.advance();
+        }
+        let mut params = Vec::new();
+        if self.check(&Token::OrOr) {
+            self.advance();
+        } else {
+            self.expect(Token::Pipe)?;
+            while !self.check(&Token::Pipe) {
+                let start = self.start();
+                let name = self.parse_ident()?;
+                let ty = if self.check(&Token::Colon) {
                     self.advance();
-                    let expr = self.parse_expr()?;
-                    return Ok(Expr::Await(Box::new(expr)));
-                } else if s == "spawn" {
+                    Some(self.parse_type()?)
+                } else {
+                    None
+                };
+                params.push(LambdaParam { name, ty, span: self.span_from(start) });
+                if self.check(&Token::Comma) {
                     self.advance();
-                    let expr = self.parse_expr()?;
-                    return Ok(Expr::Spawn(Box::new(expr)));
+                } else {
+                    break;
                 }
             }
-            let ident = self.parse_ident()?;
-            if self.check(&Token::LParen) {
+            self.expect(Token::Pipe)?;
+        }
+        let (ret_ty, body) = if self.check(&Token::ThinArrow) {
+            self.advance();
+            let ret_ty = self.parse_type()?;
+            (Some(ret_ty), LambdaBody::Block(self.parse_block()?))
+        } else if self.check(&Token::LBrace) {
+            (None, LambdaBody::Block(self.parse_block()?))
+        } else {
+            (None, LambdaBody::Expr(Box::new(self.parse_expr()?)))
+        };
+        Ok(Lambda { params, ret_ty, body, by_value })
+    }
+
+    /// Parses a parenthesized, comma-separated argument list.
+    fn parse_args(&mut self) -> Result<Vec<Expr>> {
+        self.expect(Token::LParen)?;
+        let mut args = Vec::new();
+        if !self.check(&Token::RParen) {
+            loop {
+                args.push(self.parse_expr()?);
+                if self.check(&Token::Comma) {
+                    self.advance();
+                } else {
+                    break;
+                }
+            }
+        }
+        self.expect(Token::RParen)?;
+        Ok(args)
+    }
+
+    /// Parses the `{ pattern => body, ... }` part of a `match`. Arms are
+    /// separated by commas or, when written one per line, by line breaks.
+    fn parse_arms(&mut self) -> Result<Vec<MatchArm>> {
+        self.expect_block_start()?;
+        let mut arms = Vec::new();
+        while !self.check(&Token::RBrace) {
+            let start = self.start();
+            let pattern = self.parse_pattern()?;
+            self.expect(Token::Arrow)?;
+            let body = if self.check(&Token::LBrace) {
+                ArmBody::Block(self.parse_block()?)
+            } else {
+                ArmBody::Expr(self.parse_expr()?)
+            };
+            arms.push(MatchArm { pattern, body, span: self.span_from(start) });
+            if self.check(&Token::Comma) || self.check(&Token::Semicolon) {
                 self.advance();
-                let mut args = Vec::new();
-                if !self.check(&Token::RParen) {
-                    loop {
-                        args.push(self.parse_expr()?);
-                        if self.check(&Token::Comma) {
-                            self.advance();
-                        } else {
-                            break;
+            } else {
+                break;
+            }
+        }
+        self.expect(Token::RBrace)?;
+        Ok(arms)
+    }
+
+    fn parse_pattern(&mut self) -> Result<Pattern> {
+        let start = self.start();
+        let kind = match self.peek_token() {
+            Some(Token::Ident(s)) if s == "_" => {
+                self.advance();
+                PatternKind::Wildcard
+            }
+            Some(Token::Ident(_)) => {
+                let name = self.parse_path()?;
+                if let Some((enum_name, variant)) = name.rsplit_once("::") {
+                    let mut fields = Vec::new();
+                    if self.check(&Token::LParen) {
+                        self.advance();
+                        while !self.check(&Token::RParen) {
+                            fields.push(self.parse_pattern()?);
+                            if self.check(&Token::Comma) {
+                                self.advance();
+                            } else {
+                                break;
+                            }
                         }
+                        self.expect(Token::RParen)?;
                     }
+                    PatternKind::Variant { enum_name: enum_name.to_string(), variant: variant.to_string(), fields }
+                } else if self.check(&Token::LParen) {
+                    return Err(self.error(format!("Variant patterns name their enum, as in `Enum::{}(...)`", name)));
+                } else {
+                    PatternKind::Binding(name)
                 }
-                self.expect(Token::RParen)?;
-                Ok(Expr::Call { name: ident, args })
+            }
+            Some(Token::Int(_) | Token::Char(_) | Token::True | Token::False | Token::Minus) => {
+                PatternKind::Literal(self.parse_unary()?)
+            }
+            _ => return Err(self.error("Expected a pattern")),
+        };
+        Ok(Pattern { kind, span: self.span_from(start) })
+    }
+
+    /// Parses the `{ field: value, ... }` part of a struct literal.
+    fn parse_field_inits(&mut self) -> Result<Vec<(String, Expr)>> {
+        self.expect_block_start()?;
+        let mut fields = Vec::new();
+        while !self.check(&Token::RBrace) {
+            let name = self.parse_ident()?;
+            self.expect(Token::Colon)?;
+            fields.push((name, self.parse_expr()?));
+            if self.check(&Token::Comma) {
+                self.advance();
             } else {
-                Ok(Expr::Ident(ident))
+                break;
             }
+        }
+        self.expect(Token::RBrace)?;
+        Ok(fields)
+    }
+
+    /// Collects consecutive `///` comments into a single doc string.
+    fn parse_doc_comments(&mut self) -> Option<String> {
+        let mut lines = Vec::new();
+        while let Some(Token::DocComment(line)) = self.peek_token() {
+            lines.push(line.clone());
+            self.advance();
+        }
+        if lines.is_empty() {
+            None
         } else {
-            Err(anyhow::anyhow!("Unexpected token in expression"))
+            Some(lines.join("\n"))
+        }
+    }
+
+    // --- Error recovery ---
+    fn report(&mut self, err: anyhow::Error) {
+        let err = match err.downcast::<SpannedError>() {
+            Ok(err) => err,
+            Err(err) => SpannedError::new(self.current_span(), err.to_string()),
+        };
+        self.errors.push(err);
+    }
+    /// Skips the rest of a broken statement: through the next `;` or
+    /// balanced `{ ... }`, or up to the next statement keyword or the `}`
+    /// closing the enclosing block.
+    fn synchronize_stmt(&mut self) {
+        let mut depth = 0usize;
+        while let Some(token) = self.peek_token() {
+            match token {
+                Token::Semicolon if depth == 0 => {
+                    self.advance();
+                    return;
+                }
+                Token::RBrace if depth == 0 => return,
+                Token::Let | Token::If | Token::While | Token::Loop | Token::Return
+                | Token::Break | Token::Continue if depth == 0 => return,
+                Token::LBrace => depth += 1,
+                Token::RBrace => {
+                    depth -= 1;
+                    if depth == 0 {
+                        self.advance();
+                        return;
+                    }
+                }
+                _ => {}
+            }
+            self.advance();
+        }
+    }
+    /// Skips to the next token that can start an item at the top level.
+    fn synchronize_item(&mut self) {
+        let mut depth = 0usize;
+        while let Some(token) = self.peek_token() {
+            match token {
+                Token::Fn | Token::Struct | Token::Enum | Token::Trait | Token::Impl if depth == 0 => return,
+                Token::Ident(s)
+                    if depth == 0
+                        && matches!(
+                            s.as_str(),
+                            "extern" | "macro" | "async" | "unsafe" | "packed" | "pub" | "mod" | "import"
+                        ) =>
+                {
+                    return
+                }
+                Token::LBrace => depth += 1,
+                Token::RBrace => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+            self.advance();
         }
     }
 
     // --- Utility methods ---
+    /// Skips empty statements, such as the `;` inserted after an item's
+    /// closing brace at the end of a line.
+    fn skip_semicolons(&mut self) {
+        while self.check(&Token::Semicolon) {
+            self.advance();
+        }
+    }
+    /// The `{` opening a block, which may start the line after its header.
+    fn expect_block_start(&mut self) -> Result<()> {
+        self.skip_line_end_before_block();
+        self.expect(Token::LBrace)
+    }
+    /// Skips the `;` the lexer puts at the end of a line when the next line
+    /// starts with `{`, as in `fn main()\n{`.
+    fn skip_line_end_before_block(&mut self) {
+        let inserted = self.peek().is_some_and(|tok| tok.token == Token::Semicolon && tok.span.is_empty());
+        if inserted && matches!(self.tokens.get(self.pos + 1).map(|t| &t.token), Some(Token::LBrace)) {
+            self.advance();
+        }
+    }
+    /// Ends a statement: a `;`, or nothing when the enclosing block closes
+    /// right after it.
+    fn expect_terminator(&mut self) -> Result<()> {
+        if self.check(&Token::RBrace) {
+            Ok(())
+        } else {
+            self.expect(Token::Semicolon)
+        }
+    }
     fn expect(&mut self, expected: Token) -> Result<()> {
         if self.check(&expected) {
             self.advance();
             Ok(())
         } else {
-            Err(anyhow::anyhow!("Expected {:?}, found {:?}", expected, self.peek_token()))
+            Err(self.error(format!("Expected {:?}, found {:?}", expected, self.peek_token())))
         }
     }
     fn check(&self, expected: &Token) -> bool {
-        match (self.peek_token(), expected) {
-            (Some(Token::Fn), Token::Fn)
-            | (Some(Token::Let), Token::Let)
-            | (Some(Token::Arrow), Token::Arrow)
-            | (Some(Token::LParen), Token::LParen)
-            | (Some(Token::RParen), Token::RParen)
-            | (Some(Token::LBrace), Token::LBrace)
-            | (Some(Token::RBrace), Token::RBrace)
-            | (Some(Token::Comma), Token::Comma)
-            | (Some(Token::Colon), Token::Colon)
-            | (Some(Token::Semicolon), Token::Semicolon)
-            | (Some(Token::Eq), Token::Eq) => true,
-            _ => false,
-        }
+        self.peek_token() == Some(expected)
     }
     fn advance(&mut self) {
         self.pos += 1;
@@ -220,22 +1050,263 @@ impl<'a> Parser<'a> {
     fn peek_token(&self) -> Option<&Token> {
         self.peek().map(|st| &st.token)
     }
+    /// Span of the current token, or an empty span at the end of input.
+    fn current_span(&self) -> Span {
+        match self.peek() {
+            Some(tok) => Span::new(tok.span.start, tok.span.end),
+            None => {
+                let end = self.tokens.last().map_or(0, |t| t.span.end);
+                Span::new(end, end)
+            }
+        }
+    }
+    fn start(&self) -> usize {
+        self.current_span().start
+    }
+    /// Span from `start` to the end of the last consumed token.
+    fn span_from(&self, start: usize) -> Span {
+        let end = match self.pos {
+            0 => start,
+            pos => self.tokens[pos - 1].span.end,
+        };
+        Span::new(start, end.max(start))
+    }
+    fn error(&self, message: impl Into<String>) -> anyhow::Error {
+        SpannedError::new(self.current_span(), message).into()
+    }
     fn parse_ident(&mut self) -> Result<String> {
         if let Some(SpannedToken { token: Token::Ident(ref s), .. }) = self.peek() {
             let ident = s.clone();
             self.advance();
             Ok(ident)
         } else {
-            Err(anyhow::anyhow!("Expected identifier"))
+            Err(self.error("Expected identifier"))
         }
     }
-    fn parse_int(&mut self) -> Result<i64> {
-        if let Some(SpannedToken { token: Token::Int(i), .. }) = self.peek() {
-            let value = *i;
+    /// Parses a name that may be qualified with a path, such as `a::b::name`,
+    /// into a single string.
+    fn parse_path(&mut self) -> Result<String> {
+        let mut path = self.parse_ident()?;
+        while self.check(&Token::ColonColon) {
             self.advance();
-            Ok(value)
-        } else {
-            Err(anyhow::anyhow!("Expected integer"))
+            path.push_str("::");
+            path.push_str(&self.parse_ident()?);
+        }
+        Ok(path)
+    }
+    /// Parses a type: a name or path, `Name<T, ...>`, `*T`, `&T`, `&mut T`,
+    /// `[T; N]`, `[T]`, `dyn Trait`, or `fn(T, ...) -> R` and `Fn(T, ...) -> R`.
+    /// The `int` and `float` aliases resolve to the sized types they stand for.
+    fn parse_type(&mut self) -> Result<Type> {
+        if self.check(&Token::Star) {
+            self.advance();
+            return Ok(Type::pointer(self.parse_type()?));
+        }
+        if self.check(&Token::Amp) {
+            self.advance();
+            let mutable = self.check(&Token::Ident("mut".to_string()));
+            if mutable {
+                self.advance();
+            }
+            return Ok(Type::Ref(Box::new(self.parse_type()?), mutable));
+        }
+        if self.check(&Token::LBracket) {
+            self.advance();
+            let elem = Box::new(self.parse_type()?);
+            let ty = if self.check(&Token::Semicolon) {
+                self.advance();
+                Type::Array(elem, self.parse_array_len()?)
+            } else {
+                Type::Slice(elem)
+            };
+            self.expect(Token::RBracket)?;
+            return Ok(ty);
         }
+        let is_closure = self.check(&Token::Ident("Fn".to_string()))
+            && matches!(self.tokens.get(self.pos + 1).map(|t| &t.token), Some(Token::LParen));
+        if is_closure || self.check(&Token::Fn) {
+            self.advance();
+            self.expect(Token::LParen)?;
+            let mut params = Vec::new();
+            while !self.check(&Token::RParen) {
+                params.push(self.parse_type()?);
+                if self.check(&Token::Comma) {
+                    self.advance();
+                } else {
+                    break;
+                }
+            }
+            self.expect(Token::RParen)?;
+            let ret_ty = if self.check(&Token::ThinArrow) {
+                self.advance();
+                self.parse_type()?
+            } else {
+                Type::Void
+            };
+            return Ok(if is_closure {
+                Type::Closure(params, Box::new(ret_ty))
+            } else {
+                Type::FnPtr(params, Box::new(ret_ty))
+            });
+        }
+        if self.check(&Token::Ident("dyn".to_string())) {
+            self.advance();
+            return Ok(Type::Dyn(self.parse_path()?));
+        }
+        let name = self.parse_path()?;
+        if self.check(&Token::Lt) {
+            return Ok(Type::Generic(name, self.parse_type_args()?));
+        }
+        Ok(match name.as_str() {
+            "int" => Type::named("i64"),
+            "float" => Type::named("f64"),
+            _ => Type::Named(name),
+        })
+    }
+}
+
+/// Binding power of each binary operator token; higher binds tighter.
+fn binary_op(token: &Token) -> Option<(BinOp, u8)> {
+    let op = match token {
+        Token::OrOr => (BinOp::Or, 1),
+        Token::AndAnd => (BinOp::And, 2),
+        Token::EqEq => (BinOp::Eq, 3),
+        Token::NotEq => (BinOp::Ne, 3),
+        Token::Lt => (BinOp::Lt, 4),
+        Token::Le => (BinOp::Le, 4),
+        Token::Gt => (BinOp::Gt, 4),
+        Token::Ge => (BinOp::Ge, 4),
+        Token::Plus => (BinOp::Add, 5),
+        Token::Minus => (BinOp::Sub, 5),
+        Token::Star => (BinOp::Mul, 6),
+        Token::Slash => (BinOp::Div, 6),
+        Token::Percent => (BinOp::Rem, 6),
+        _ => return None,
+    };
+    Some(op)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lex;
+
+    /// The program parsed from `source`, and each diagnostic with the
+    /// source text its span covers.
+    fn parse_with_errors(source: &str) -> (Program, Vec<(String, String)>) {
+        let tokens = lex(source);
+        let mut parser = Parser::new(&tokens);
+        let program = parser.parse_program();
+        let errors = parser.errors().iter()
+            .map(|err| (err.message.clone(), source[err.span.start..err.span.end].to_string()))
+            .collect();
+        (program, errors)
+    }
+
+    #[test]
+    fn parsing_resumes_at_the_next_statement() {
+        let source = "fn main() {\n    let x = ;\n    print(x);\n    let = 2;\n    print(1);\n}\n";
+        let (program, errors) = parse_with_errors(source);
+        let expected = [("Unexpected token in expression", ";"), ("Expected identifier", "=")];
+        assert_eq!(errors, expected.map(|(message, at)| (message.to_string(), at.to_string())));
+        let body = &program.functions[0].body;
+        assert_eq!(body.len(), 4);
+        assert!(matches!(body[0].kind, StmtKind::Error) && matches!(body[2].kind, StmtKind::Error));
+        assert!(matches!(&body[1].kind, StmtKind::Expr(Expr { kind: ExprKind::Call { name, .. }, .. }) if name == "print"));
+        assert!(matches!(&body[3].kind, StmtKind::Expr(Expr { kind: ExprKind::Call { name, .. }, .. }) if name == "print"));
+    }
+
+    #[test]
+    fn parsing_resumes_at_the_next_item() {
+        let source = "fn a( {}\nfn (x: i32) {}\nfn b() {}\n";
+        let (program, errors) = parse_with_errors(source);
+        let expected = [("Expected identifier", "{"), ("Expected identifier", "(")];
+        assert_eq!(errors, expected.map(|(message, at)| (message.to_string(), at.to_string())));
+        let names: Vec<&str> = program.functions.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["b"]);
+    }
+
+    /// Debug dump of the parsed program with every span removed, so sources
+    /// that differ only in layout compare equal.
+    fn parse_shape(source: &str) -> String {
+        let tokens = lex(source);
+        let mut parser = Parser::new(&tokens);
+        let program = parser.parse_program();
+        assert!(parser.errors().is_empty(), "{:?}", parser.errors());
+        let dump = format!("{:?}", program);
+        let mut shape = String::new();
+        let mut rest = dump.as_str();
+        while let Some(at) = rest.find("span: Span {") {
+            shape.push_str(&rest[..at]);
+            let end = rest[at..].find('}').unwrap();
+            rest = &rest[at + end + 1..];
+        }
+        shape.push_str(rest);
+        shape
+    }
+
+    /// Asserts that the expression `expr` parses like `grouped`, which
+    /// spells its grouping out with parentheses.
+    fn assert_groups(expr: &str, grouped: &str) {
+        let program = |expr: &str| format!("fn main() {{ let x = {}; }}", expr);
+        assert_eq!(parse_shape(&program(expr)), parse_shape(&program(grouped)), "{}", expr);
+    }
+
+    #[test]
+    fn tighter_operators_bind_first() {
+        assert_groups("1 + 2 * 3", "1 + (2 * 3)");
+        assert_groups("a || b && c", "a || (b && c)");
+        assert_groups("a == b < c + d % e", "a == (b < (c + (d % e)))");
+        assert_groups("x < y && y != z", "(x < y) && (y != z)");
+    }
+
+    #[test]
+    fn unary_operators_bind_tighter_than_binary_ones() {
+        assert_groups("-a * b", "(-a) * b");
+        assert_groups("!a && !b", "(!a) && (!b)");
+        assert_groups("--a - -b", "(-(-a)) - (-b)");
+    }
+
+    #[test]
+    fn binary_operators_associate_left() {
+        assert_groups("a - b - c", "(a - b) - c");
+        assert_groups("a / b * c % d", "((a / b) * c) % d");
+        assert_groups("a || b || c", "(a || b) || c");
+    }
+
+    #[test]
+    fn newlines_terminate_statements() {
+        let with = "fn main() {\n    let x = 1;\n    print(x);\n    return;\n}\n";
+        let without = "fn main() {\n    let x = 1\n    print(x)\n    return\n}\n";
+        assert_eq!(parse_shape(with), parse_shape(without));
+    }
+
+    #[test]
+    fn control_flow_without_semicolons() {
+        let with = "fn f(n: int) -> int {\n    while n > 0 { n; break; }\n    loop { continue; }\n    if n == 1 { return 1; } else { return 2; }\n}\n";
+        let without = "fn f(n: int) -> int {\n    while n > 0 {\n        n\n        break\n    }\n    loop {\n        continue\n    }\n    if n == 1 {\n        return 1\n    }\n    else {\n        return 2\n    }\n}\n";
+        assert_eq!(parse_shape(with), parse_shape(without));
+    }
+
+    #[test]
+    fn continuation_lines_do_not_terminate() {
+        let with = "fn main()\n{\n    let x = 1 + 2 * 3;\n    print(add(x, 4));\n}\n";
+        let without = "fn main()\n{\n    let x = 1 +\n        2\n        * 3\n    print(add(\n        x,\n        4\n    ))\n}\n";
+        assert_eq!(parse_shape(with), parse_shape(without));
+    }
+
+    #[test]
+    fn blocks_start_statements_at_line_breaks() {
+        let with = "fn main() {\n    let x = 1;\n    { let x = 2; print(x); }\n    if x > 0 { print(x); }\n}\n";
+        let without = "fn main() {\n    let x = 1\n    {\n        let x = 2\n        print(x)\n    }\n    if x > 0\n    {\n        print(x)\n    }\n}\n";
+        assert_eq!(parse_shape(with), parse_shape(without));
+        assert!(parse_shape(without).contains("Block([Stmt { kind: Let { name: \"x\""));
+    }
+
+    #[test]
+    fn items_and_comments_between_lines() {
+        let with = "/// Doc.\nextern fn puts(s: string) -> i32\nfn main() { puts(\"hi\"); } // done\n";
+        let without = "/// Doc.\nextern fn puts(s: string) -> i32\n\nfn main() {\n    puts(\"hi\") // call\n}\n";
+        assert_eq!(parse_shape(with), parse_shape(without));
     }
 }
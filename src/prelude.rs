@@ -0,0 +1,32 @@
+use crate::ast::Program;
+use crate::lexer::lex_at;
+use crate::parser::Parser;
+use crate::source_map::SourceMap;
+
+/// Declarations every program can use without defining them. `?` relies on
+/// the variant order: the one it unwraps comes first.
+pub const SOURCE: &str = "\
+/// An optional value: `Some` holds one, `None` marks its absence.
+enum Option<T> {
+    Some(T),
+    None,
+}
+
+/// The outcome of an operation that can fail: `Ok` holds its value and
+/// `Err` the reason it failed.
+enum Result<T, E> {
+    Ok(T),
+    Err(E),
+}
+";
+
+/// Adds the prelude's declarations to `program` and its source to `sources`,
+/// so diagnostics about them can point into it.
+pub fn add_to(program: &mut Program, sources: &mut SourceMap) {
+    let start = sources.add_file("<prelude>", SOURCE);
+    let tokens = lex_at(SOURCE, start);
+    let mut parser = Parser::new(&tokens);
+    let prelude = parser.parse_program();
+    assert!(parser.errors().is_empty(), "the prelude does not parse: {:?}", parser.errors());
+    program.enums.splice(0..0, prelude.enums);
+}
@@ -0,0 +1,672 @@
+use crate::ast::*;
+use crate::bail_at;
+use anyhow::Result;
+use std::collections::HashMap;
+
+/// The items of one source file and the path of the module it defines,
+/// which is empty for the package's root file.
+pub struct Module {
+    pub path: Vec<String>,
+    pub program: Program,
+}
+
+/// Merges the modules of a package into one program. Items are renamed to
+/// their path (`math::sqrt`; the root module's keep their names), every name
+/// an item uses is replaced by the path of the item it refers to, and the
+/// `Ident` and `Call` paths that name enum variants become `Variant`s.
+/// Extern functions keep their names, which are the symbols they link to.
+///
+/// Names that refer to nothing a module declares or imports are left alone
+/// for semantic analysis, which knows the primitive types and the prelude.
+pub fn resolve(modules: Vec<Module>) -> Result<Program> {
+    let mut resolver = Resolver { declared: HashMap::new() };
+    for module in &modules {
+        resolver.declare(module)?;
+    }
+    let mut program = Program::default();
+    for module in modules {
+        let mut scope = resolver.declared[&module.path].clone();
+        for import in &module.program.imports {
+            resolver.import(&module.path, import, &mut scope)?;
+        }
+        resolver.merge(module, &scope, &mut program)?;
+    }
+    Ok(program)
+}
+
+/// The kinds of items, each with names of its own.
+#[derive(Clone, Copy)]
+enum Ns {
+    Value,
+    Type,
+    Trait,
+    Macro,
+    Module,
+}
+
+impl Ns {
+    const ALL: [Ns; 5] = [Ns::Value, Ns::Type, Ns::Trait, Ns::Macro, Ns::Module];
+
+    fn kind(self) -> &'static str {
+        match self {
+            Ns::Value => "function",
+            Ns::Type => "type",
+            Ns::Trait => "trait",
+            Ns::Macro => "macro",
+            Ns::Module => "module",
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Item {
+    /// The item's name in the merged program; for a module, its path.
+    full_name: String,
+    /// The module that declares the item. It and its submodules may use the
+    /// item even when it is not `pub`.
+    owner: Vec<String>,
+    is_pub: bool,
+}
+
+impl Item {
+    fn visible_from(&self, module: &[String]) -> bool {
+        self.is_pub || module.starts_with(&self.owner)
+    }
+}
+
+/// The items a module can refer to by a single name, per kind.
+#[derive(Clone, Default)]
+struct Names([HashMap<String, Item>; 5]);
+
+impl Names {
+    fn get(&self, ns: Ns, name: &str) -> Option<&Item> {
+        self.0[ns as usize].get(name)
+    }
+
+    fn define(&mut self, ns: Ns, name: &str, item: Item, span: Span) -> Result<()> {
+        if self.0[ns as usize].insert(name.to_string(), item).is_some() {
+            bail_at!(span, "The {} '{}' is defined more than once", ns.kind(), name);
+        }
+        Ok(())
+    }
+}
+
+/// `name` as an item of the module at `path`.
+fn qualify(path: &[String], name: &str) -> String {
+    if path.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}::{}", path.join("::"), name)
+    }
+}
+
+fn describe(path: &[String]) -> String {
+    if path.is_empty() {
+        "the root module".to_string()
+    } else {
+        format!("module '{}'", path.join("::"))
+    }
+}
+
+/// Where the names being resolved are written.
+struct Ctx<'a> {
+    module: &'a [String],
+    scope: &'a Names,
+    /// Type parameters of the enclosing items, and `Self` in traits and impls.
+    type_params: Vec<String>,
+    /// Variables in scope at this point of a body, innermost last: the name
+    /// each is written with and the name it resolves to.
+    locals: Vec<(String, String)>,
+    /// How many variables of each name the body has declared so far.
+    declared: HashMap<String, usize>,
+}
+
+impl<'a> Ctx<'a> {
+    fn new(module: &'a [String], scope: &'a Names, type_params: &[String]) -> Self {
+        Ctx { module, scope, type_params: type_params.to_vec(), locals: Vec::new(), declared: HashMap::new() }
+    }
+
+    /// Brings the variable `name` into scope. One that reuses the name of
+    /// an earlier variable of the body, as when it shadows it, is renamed
+    /// `name#n`, so later passes can tell every variable of a body apart.
+    fn bind(&mut self, name: &mut String) {
+        let written = name.clone();
+        let count = self.declared.entry(written.clone()).or_default();
+        if *count > 0 {
+            *name = format!("{}#{}", written, count);
+        }
+        *count += 1;
+        self.locals.push((written, name.clone()));
+    }
+
+    /// Starts a new body whose only variables are `params`.
+    fn enter_body<'p>(&mut self, params: impl IntoIterator<Item = &'p mut String>) {
+        self.locals.clear();
+        self.declared.clear();
+        for param in params {
+            self.bind(param);
+        }
+    }
+}
+
+/// What a path used as a value refers to.
+enum Value {
+    /// A variable, by the name it resolves to.
+    Local(String),
+    Item(String),
+    Variant(String, String),
+}
+
+struct Resolver {
+    /// The items each module declares, by module path.
+    declared: HashMap<Vec<String>, Names>,
+}
+
+impl Resolver {
+    fn declare(&mut self, module: &Module) -> Result<()> {
+        let path = &module.path;
+        let item = |full_name: String, is_pub: bool| Item { full_name, owner: path.clone(), is_pub };
+        let program = &module.program;
+        let mut names = Names::default();
+        for func in &program.functions {
+            names.define(Ns::Value, &func.name, item(qualify(path, &func.name), func.is_pub), func.span)?;
+        }
+        for ext in &program.externs {
+            names.define(Ns::Value, &ext.name, item(ext.name.clone(), ext.is_pub), ext.span)?;
+        }
+        for def in &program.structs {
+            names.define(Ns::Type, &def.name, item(qualify(path, &def.name), def.is_pub), def.span)?;
+        }
+        for def in &program.enums {
+            names.define(Ns::Type, &def.name, item(qualify(path, &def.name), def.is_pub), def.span)?;
+        }
+        for def in &program.traits {
+            names.define(Ns::Trait, &def.name, item(qualify(path, &def.name), def.is_pub), def.span)?;
+        }
+        for mac in &program.macros {
+            names.define(Ns::Macro, &mac.name, item(qualify(path, &mac.name), mac.is_pub), mac.span)?;
+        }
+        for decl in &program.mods {
+            names.define(Ns::Module, &decl.name, item(qualify(path, &decl.name), decl.is_pub), decl.span)?;
+        }
+        self.declared.insert(path.clone(), names);
+        Ok(())
+    }
+
+    fn root(&self) -> &Names {
+        &self.declared[&Vec::new()]
+    }
+
+    /// Adds what `import` names to `scope`, the names of the module at
+    /// `module`. A path of one segment imports an item of the root module.
+    fn import(&self, module: &[String], import: &Import, scope: &mut Names) -> Result<()> {
+        let (name, prefix) = import.path.split_last().expect("an import names at least one item");
+        let from = if prefix.is_empty() {
+            Vec::new()
+        } else {
+            let segments: Vec<&str> = prefix.iter().map(String::as_str).collect();
+            match self.module_path(&self.declared[module], module, &segments, import.span)? {
+                Some(path) => path,
+                None => bail_at!(import.span, "Cannot find module '{}'", prefix.join("::")),
+            }
+        };
+        let mut found = false;
+        for ns in Ns::ALL {
+            let Some(item) = self.declared[&from].get(ns, name) else { continue };
+            if !item.visible_from(module) {
+                bail_at!(import.span, "The {} '{}' is private to {}", ns.kind(), name, describe(&from));
+            }
+            if scope.get(ns, name).is_some() {
+                bail_at!(import.span, "The import of '{}' conflicts with a {} of that name", name, ns.kind());
+            }
+            scope.define(ns, name, item.clone(), import.span)?;
+            found = true;
+        }
+        if !found {
+            bail_at!(import.span, "Cannot find '{}' in {}", name, describe(&from));
+        }
+        Ok(())
+    }
+
+    /// Follows `segments`, written in the module `from` whose names are
+    /// `scope`, through nested modules. The first segment is a module `from`
+    /// declares or imports, or else one the root declares. `None` means the
+    /// path does not name a module.
+    fn module_path(&self, scope: &Names, from: &[String], segments: &[&str], span: Span) -> Result<Option<Vec<String>>> {
+        let first = scope.get(Ns::Module, segments[0]).or_else(|| self.root().get(Ns::Module, segments[0]));
+        let Some(first) = first else { return Ok(None) };
+        let mut path: Vec<String> = first.full_name.split("::").map(String::from).collect();
+        for &segment in &segments[1..] {
+            match self.declared[&path].get(Ns::Module, segment) {
+                Some(item) if item.visible_from(from) => path.push(segment.to_string()),
+                Some(_) => bail_at!(span, "The module '{}' is private to {}", segment, describe(&path)),
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(path))
+    }
+
+    /// The full name of the item of kind `ns` that `name`, which may be a
+    /// path, refers to; `None` if it refers to nothing the module knows.
+    fn lookup(&self, ctx: &Ctx, name: &str, ns: Ns, span: Span) -> Result<Option<String>> {
+        let segments: Vec<&str> = name.split("::").collect();
+        let (last, prefix) = segments.split_last().expect("split yields at least one segment");
+        if prefix.is_empty() {
+            if let Some(item) = ctx.scope.get(ns, last) {
+                return Ok(Some(item.full_name.clone()));
+            }
+            if !ctx.module.is_empty() && self.root().get(ns, last).is_some() {
+                bail_at!(span, "'{}' is defined in the root module; import it to use it in {}", last, describe(ctx.module));
+            }
+            return Ok(None);
+        }
+        let Some(module) = self.module_path(ctx.scope, ctx.module, prefix, span)? else { return Ok(None) };
+        match self.declared[&module].get(ns, last) {
+            Some(item) if item.visible_from(ctx.module) => Ok(Some(item.full_name.clone())),
+            Some(_) => bail_at!(span, "The {} '{}' is private to {}", ns.kind(), last, describe(&module)),
+            None => bail_at!(span, "Cannot find {} '{}' in {}", ns.kind(), last, describe(&module)),
+        }
+    }
+
+    fn type_name(&self, ctx: &Ctx, name: &str, span: Span) -> Result<String> {
+        if ctx.type_params.iter().any(|p| p == name) {
+            return Ok(name.to_string());
+        }
+        Ok(self.lookup(ctx, name, Ns::Type, span)?.unwrap_or_else(|| name.to_string()))
+    }
+
+    fn trait_name(&self, ctx: &Ctx, name: &str, span: Span) -> Result<String> {
+        Ok(self.lookup(ctx, name, Ns::Trait, span)?.unwrap_or_else(|| name.to_string()))
+    }
+
+    /// What the name of an `Ident` or `Call` refers to: a variable, a
+    /// function, or the variant `Enum::Variant` when the part before the
+    /// last `::` is not a module.
+    fn value(&self, ctx: &Ctx, name: &str, span: Span) -> Result<Option<Value>> {
+        if let Some((_, local)) = ctx.locals.iter().rev().find(|(written, _)| written == name) {
+            return Ok(Some(Value::Local(local.clone())));
+        }
+        if let Some(full_name) = self.lookup(ctx, name, Ns::Value, span)? {
+            return Ok(Some(Value::Item(full_name)));
+        }
+        match name.rsplit_once("::") {
+            Some((enum_name, variant)) => {
+                Ok(Some(Value::Variant(self.type_name(ctx, enum_name, span)?, variant.to_string())))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn merge(&self, module: Module, scope: &Names, out: &mut Program) -> Result<()> {
+        let path = &module.path;
+        let program = module.program;
+        for mut def in program.structs {
+            let ctx = Ctx::new(path, scope, &def.type_params);
+            for field in &mut def.fields {
+                self.resolve_type(&ctx, &mut field.ty, field.span)?;
+            }
+            def.name = qualify(path, &def.name);
+            out.structs.push(def);
+        }
+        for mut def in program.enums {
+            let ctx = Ctx::new(path, scope, &def.type_params);
+            for variant in &mut def.variants {
+                for ty in &mut variant.fields {
+                    self.resolve_type(&ctx, ty, variant.span)?;
+                }
+            }
+            def.name = qualify(path, &def.name);
+            out.enums.push(def);
+        }
+        let self_param = ["Self".to_string()];
+        for mut def in program.traits {
+            let mut ctx = Ctx::new(path, scope, &self_param);
+            for method in &mut def.methods {
+                self.function(&mut ctx, &mut method.func)?;
+            }
+            def.name = qualify(path, &def.name);
+            out.traits.push(def);
+        }
+        for mut imp in program.impls {
+            let mut ctx = Ctx::new(path, scope, &[]);
+            if let Some(trait_name) = &mut imp.trait_name {
+                *trait_name = self.trait_name(&ctx, trait_name, imp.span)?;
+            }
+            self.resolve_type(&ctx, &mut imp.ty, imp.span)?;
+            ctx.type_params = self_param.to_vec();
+            for method in &mut imp.methods {
+                self.function(&mut ctx, method)?;
+            }
+            out.impls.push(imp);
+        }
+        for mut ext in program.externs {
+            let ctx = Ctx::new(path, scope, &[]);
+            for param in &mut ext.params {
+                self.resolve_type(&ctx, &mut param.ty, param.span)?;
+            }
+            self.resolve_type(&ctx, &mut ext.ret_ty, ext.span)?;
+            // Modules may each declare the same C function.
+            if let Some(prev) = out.externs.iter().find(|prev| prev.name == ext.name) {
+                if prev.ret_ty != ext.ret_ty || !prev.params.iter().map(|p| &p.ty).eq(ext.params.iter().map(|p| &p.ty)) {
+                    bail_at!(ext.span, "Extern function '{}' is declared elsewhere with a different signature", ext.name);
+                }
+                continue;
+            }
+            out.externs.push(ext);
+        }
+        for mut mac in program.macros {
+            let mut ctx = Ctx::new(path, scope, &[]);
+            ctx.enter_body(&mut mac.params);
+            self.block(&mut ctx, &mut mac.body)?;
+            mac.name = qualify(path, &mac.name);
+            out.macros.push(mac);
+        }
+        for mut func in program.functions {
+            let mut ctx = Ctx::new(path, scope, &[]);
+            self.function(&mut ctx, &mut func)?;
+            func.name = qualify(path, &func.name);
+            out.functions.push(func);
+        }
+        Ok(())
+    }
+
+    fn function(&self, ctx: &mut Ctx, func: &mut Function) -> Result<()> {
+        let outer = ctx.type_params.len();
+        ctx.type_params.extend(func.type_params.iter().cloned());
+        for (_, bound) in &mut func.bounds {
+            *bound = self.trait_name(ctx, bound, func.span)?;
+        }
+        for param in &mut func.params {
+            self.resolve_type(ctx, &mut param.ty, param.span)?;
+        }
+        if let Some(ret_ty) = &mut func.ret_ty {
+            self.resolve_type(ctx, ret_ty, func.span)?;
+        }
+        ctx.enter_body(func.params.iter_mut().map(|p| &mut p.name));
+        self.block(ctx, &mut func.body)?;
+        ctx.type_params.truncate(outer);
+        Ok(())
+    }
+
+    fn resolve_type(&self, ctx: &Ctx, ty: &mut Type, span: Span) -> Result<()> {
+        match ty {
+            Type::Named(name) => *name = self.type_name(ctx, name, span)?,
+            Type::Generic(name, args) => {
+                *name = self.type_name(ctx, name, span)?;
+                for arg in args {
+                    self.resolve_type(ctx, arg, span)?;
+                }
+            }
+            Type::Pointer(inner) | Type::Ref(inner, _) | Type::Array(inner, _) | Type::Slice(inner) => {
+                self.resolve_type(ctx, inner, span)?
+            }
+            Type::Dyn(trait_name) => *trait_name = self.trait_name(ctx, trait_name, span)?,
+            Type::FnPtr(params, ret_ty) | Type::Closure(params, ret_ty) => {
+                for param in params {
+                    self.resolve_type(ctx, param, span)?;
+                }
+                self.resolve_type(ctx, ret_ty, span)?;
+            }
+            Type::Void | Type::Var(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Resolves a block's statements; its variables go out of scope at its end.
+    fn block(&self, ctx: &mut Ctx, stmts: &mut [Stmt]) -> Result<()> {
+        let outer = ctx.locals.len();
+        for stmt in stmts {
+            self.stmt(ctx, stmt)?;
+        }
+        ctx.locals.truncate(outer);
+        Ok(())
+    }
+
+    fn stmt(&self, ctx: &mut Ctx, stmt: &mut Stmt) -> Result<()> {
+        match &mut stmt.kind {
+            StmtKind::Let { name, ty, expr, .. } => {
+                if let Some(ty) = ty {
+                    self.resolve_type(ctx, ty, stmt.span)?;
+                }
+                self.expr(ctx, expr)?;
+                ctx.bind(name);
+            }
+            StmtKind::Expr(expr) | StmtKind::Return(Some(expr)) => self.expr(ctx, expr)?,
+            StmtKind::Assign { target, value } => {
+                self.expr(ctx, target)?;
+                self.expr(ctx, value)?;
+            }
+            StmtKind::MacroInvoke { name, args } => {
+                if let Some(full_name) = self.lookup(ctx, name, Ns::Macro, stmt.span)? {
+                    *name = full_name;
+                }
+                for arg in args {
+                    self.expr(ctx, arg)?;
+                }
+            }
+            StmtKind::If { cond, then_body, else_body } => {
+                self.expr(ctx, cond)?;
+                self.block(ctx, then_body)?;
+                if let Some(else_body) = else_body {
+                    self.block(ctx, else_body)?;
+                }
+            }
+            StmtKind::While { cond, body } => {
+                self.expr(ctx, cond)?;
+                self.block(ctx, body)?;
+            }
+            StmtKind::Loop { body } | StmtKind::Unsafe(body) | StmtKind::Block(body) => {
+                self.block(ctx, body)?
+            }
+            StmtKind::Break | StmtKind::Continue | StmtKind::Return(None) | StmtKind::Error => {}
+        }
+        Ok(())
+    }
+
+    fn expr(&self, ctx: &mut Ctx, expr: &mut Expr) -> Result<()> {
+        let span = expr.span;
+        match &mut expr.kind {
+            ExprKind::Int { .. }
+            | ExprKind::Float { .. }
+            | ExprKind::Bool(_)
+            | ExprKind::Char(_)
+            | ExprKind::Str(_) => {}
+            ExprKind::Ident(name) => match self.value(ctx, name, span)? {
+                Some(Value::Local(resolved) | Value::Item(resolved)) => *name = resolved,
+                Some(Value::Variant(enum_name, variant)) => {
+                    expr.kind = ExprKind::Variant { enum_name, variant, args: Vec::new() };
+                }
+                None => {}
+            },
+            ExprKind::Call { name, type_args, args } => {
+                for ty in type_args.iter_mut() {
+                    self.resolve_type(ctx, ty, span)?;
+                }
+                for arg in args.iter_mut() {
+                    self.expr(ctx, arg)?;
+                }
+                match self.value(ctx, name, span)? {
+                    Some(Value::Local(resolved) | Value::Item(resolved)) => *name = resolved,
+                    Some(Value::Variant(..)) if !type_args.is_empty() => {
+                        bail_at!(span, "Type arguments can only be given to function calls, not to variant '{}'", name)
+                    }
+                    Some(Value::Variant(enum_name, variant)) => {
+                        let args = std::mem::take(args);
+                        expr.kind = ExprKind::Variant { enum_name, variant, args };
+                    }
+                    None => {}
+                }
+            }
+            ExprKind::CallValue { callee, args } => {
+                self.expr(ctx, callee)?;
+                for arg in args {
+                    self.expr(ctx, arg)?;
+                }
+            }
+            ExprKind::MethodCall { receiver, args, .. } => {
+                self.expr(ctx, receiver)?;
+                for arg in args {
+                    self.expr(ctx, arg)?;
+                }
+            }
+            ExprKind::StructLit { name, fields } => {
+                *name = self.type_name(ctx, name, span)?;
+                for (_, value) in fields {
+                    self.expr(ctx, value)?;
+                }
+            }
+            ExprKind::Variant { enum_name, args, .. } => {
+                *enum_name = self.type_name(ctx, enum_name, span)?;
+                for arg in args {
+                    self.expr(ctx, arg)?;
+                }
+            }
+            ExprKind::Match { scrutinee, arms } => {
+                self.expr(ctx, scrutinee)?;
+                for arm in arms {
+                    let outer = ctx.locals.len();
+                    self.pattern(ctx, &mut arm.pattern)?;
+                    match &mut arm.body {
+                        ArmBody::Expr(body) => self.expr(ctx, body)?,
+                        ArmBody::Block(body) => self.block(ctx, body)?,
+                    }
+                    ctx.locals.truncate(outer);
+                }
+            }
+            ExprKind::ArrayLit(elems) => {
+                for elem in elems {
+                    self.expr(ctx, elem)?;
+                }
+            }
+            ExprKind::Index { expr: base, index } => {
+                self.expr(ctx, base)?;
+                self.expr(ctx, index)?;
+            }
+            ExprKind::Slice { expr: base, start, end } => {
+                self.expr(ctx, base)?;
+                for bound in [start, end].into_iter().flatten() {
+                    self.expr(ctx, bound)?;
+                }
+            }
+            ExprKind::Binary { lhs, rhs, .. } => {
+                self.expr(ctx, lhs)?;
+                self.expr(ctx, rhs)?;
+            }
+            ExprKind::Field { expr: inner, .. }
+            | ExprKind::AddrOf { expr: inner, .. }
+            | ExprKind::Deref(inner)
+            | ExprKind::ArrayRepeat { value: inner, .. }
+            | ExprKind::Unary { expr: inner, .. }
+            | ExprKind::Try(inner)
+            | ExprKind::Await(inner)
+            | ExprKind::Spawn(inner) => self.expr(ctx, inner)?,
+            ExprKind::Lambda(lambda) => {
+                let outer = ctx.locals.len();
+                for param in &mut lambda.params {
+                    if let Some(ty) = &mut param.ty {
+                        self.resolve_type(ctx, ty, param.span)?;
+                    }
+                    ctx.bind(&mut param.name);
+                }
+                if let Some(ret_ty) = &mut lambda.ret_ty {
+                    self.resolve_type(ctx, ret_ty, span)?;
+                }
+                match &mut lambda.body {
+                    LambdaBody::Expr(body) => self.expr(ctx, body)?,
+                    LambdaBody::Block(body) => self.block(ctx, body)?,
+                }
+                ctx.locals.truncate(outer);
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolves the enums a pattern names and brings its bindings into scope.
+    fn pattern(&self, ctx: &mut Ctx, pattern: &mut Pattern) -> Result<()> {
+        match &mut pattern.kind {
+            PatternKind::Wildcard | PatternKind::Literal(_) => {}
+            PatternKind::Binding(name) => ctx.bind(name),
+            PatternKind::Variant { enum_name, fields, .. } => {
+                *enum_name = self.type_name(ctx, enum_name, pattern.span)?;
+                for field in fields {
+                    self.pattern(ctx, field)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::analyze_package;
+
+    /// The first error in the package, which must have one.
+    fn error(files: &[(&str, &str)]) -> String {
+        match analyze_package(files, false) {
+            Ok(_) => panic!("expected an error"),
+            Err(err) => err,
+        }
+    }
+
+    const SHAPES: &str = "pub struct Circle { r: i64 }
+pub fn area(c: Circle) -> i64 { return c.r * c.r * 3 }
+fn secret() -> i64 { return 7 }
+pub mod round
+";
+
+    #[test]
+    fn public_items_are_reachable_by_path_and_import() {
+        let main = "mod shapes
+import shapes::Circle
+import shapes::round::unit
+fn main() { print(shapes::area(Circle { r: unit() })) }
+";
+        let round = "pub fn unit() -> i64 { return super_secret() }\nfn super_secret() -> i64 { return 1 }\n";
+        analyze_package(&[("", main), ("shapes", SHAPES), ("shapes::round", round)], false).unwrap();
+    }
+
+    #[test]
+    fn private_items_stay_in_their_module() {
+        let round = "pub fn unit() -> i64 { return 1 }\n";
+        assert_eq!(
+            error(&[("", "mod shapes\nfn main() { print(shapes::secret()) }\n"), ("shapes", SHAPES), ("shapes::round", round)]),
+            "main.yul:2:19: semantic error: The function 'secret' is private to module 'shapes'"
+        );
+        assert_eq!(
+            error(&[("", "mod shapes\nimport shapes::secret\nfn main() {}\n"), ("shapes", SHAPES), ("shapes::round", round)]),
+            "main.yul:2:1: semantic error: The function 'secret' is private to module 'shapes'"
+        );
+    }
+
+    #[test]
+    fn submodules_see_private_items_of_their_ancestors() {
+        let round = "import shapes::secret\npub fn unit() -> i64 { return secret() }\n";
+        analyze_package(&[("", "mod shapes\nfn main() {}\n"), ("shapes", SHAPES), ("shapes::round", round)], false).unwrap();
+    }
+
+    #[test]
+    fn items_are_defined_once_per_module() {
+        assert_eq!(
+            error(&[("", "fn f() {}\nstruct S { x: i64 }\nfn f() {}\nfn main() {}\n")]),
+            "main.yul:3:1: semantic error: The function 'f' is defined more than once"
+        );
+        assert_eq!(
+            error(&[("", "mod shapes\nimport shapes::area\nfn area() {}\nfn main() {}\n"), ("shapes", "pub fn area() {}\n")]),
+            "main.yul:2:1: semantic error: The import of 'area' conflicts with a function of that name"
+        );
+    }
+
+    #[test]
+    fn missing_items_are_reported() {
+        let round = "pub fn unit() -> i64 { return 1 }\n";
+        assert_eq!(
+            error(&[("", "mod shapes\nimport shapes::square\nfn main() {}\n"), ("shapes", SHAPES), ("shapes::round", round)]),
+            "main.yul:2:1: semantic error: Cannot find 'square' in module 'shapes'"
+        );
+        assert_eq!(
+            error(&[("", "mod shapes\nfn main() { print(shapes::square()) }\n"), ("shapes", SHAPES), ("shapes::round", round)]),
+            "main.yul:2:19: semantic error: Cannot find function 'square' in module 'shapes'"
+        );
+    }
+}
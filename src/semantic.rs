@@ -1,78 +1,2649 @@
 use crate::ast::*;
-use std::collections::HashMap;
-use anyhow::{Result, bail};
+use crate::bail_at;
+use crate::error::SpannedError;
+use crate::borrowck;
+use crate::exhaustiveness::{self, Ctor, Pat};
+use crate::infer::{Unifier, VarKind};
+use crate::types;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use anyhow::Result;
 
 #[derive(Debug, Clone)]
 pub struct Symbol {
-    pub name: String,
-    pub ty: String,
+    pub ty: Type,
+    /// Declared with `let mut`, so that it may be assigned to.
+    pub mutable: bool,
 }
 
+/// The variables visible at a point of a function body: one frame for the
+/// body and one for each block around the point, innermost last. A
+/// variable hides those of the same name declared before it.
 #[derive(Debug, Clone)]
 pub struct Scope {
-    pub symbols: HashMap<String, Symbol>,
+    frames: Vec<HashMap<String, Symbol>>,
 }
 
 impl Scope {
     pub fn new() -> Self {
-        Self { symbols: HashMap::new() }
+        Self { frames: vec![HashMap::new()] }
     }
-    pub fn insert(&mut self, name: String, ty: String) {
-        self.symbols.insert(name.clone(), Symbol { name, ty });
+    /// Declares a variable in the innermost block.
+    pub fn insert(&mut self, name: String, ty: Type, mutable: bool) {
+        let frame = self.frames.last_mut().expect("a scope has at least one frame");
+        frame.insert(name, Symbol { ty, mutable });
     }
     pub fn get(&self, name: &str) -> Option<&Symbol> {
-        self.symbols.get(name)
+        self.frames.iter().rev().find_map(|frame| frame.get(name))
     }
+    /// Enters a block, whose variables go out of scope at the matching `pop`.
+    pub fn push(&mut self) {
+        self.frames.push(HashMap::new());
+    }
+    pub fn pop(&mut self) {
+        self.frames.pop();
+    }
+}
+
+/// What type checking worked out about function bodies. Lowering reads it
+/// instead of working types out again.
+#[derive(Debug, Default)]
+pub struct TypeTable {
+    /// Type of every expression, by span.
+    pub exprs: HashMap<Span, Type>,
+    /// Type arguments inferred for each call of a generic function, by the
+    /// span of the call.
+    pub type_args: HashMap<Span, Vec<Type>>,
+    /// How each method call reaches its method, by the span of the call.
+    pub methods: HashMap<Span, MethodRef>,
+    /// The type each value converts to implicitly, by the span of the value:
+    /// `dyn` for a reference or pointer that becomes a `dyn` value, `Fn` for
+    /// a function pointer that becomes a closure, or the pointer or shared
+    /// reference a reference becomes. `exprs` keeps the original type.
+    pub coercions: HashMap<Span, Type>,
+    /// Identifiers that name a function rather than a variable, by span.
+    pub fn_values: HashSet<Span>,
+    /// Type of the function pointer or closure each call through one calls,
+    /// by the span of the call.
+    pub indirect_calls: HashMap<Span, Type>,
+    /// Variables each closure captures and their types, in order of first
+    /// use, by the span of the closure.
+    pub captures: HashMap<Span, Vec<(String, Type)>>,
+    /// Type of the value each binding in a `match` pattern binds, by the
+    /// span of the binding.
+    pub bindings: HashMap<Span, Type>,
+    /// `move` closures whose environments go on the heap rather than in the
+    /// frame of the function creating them, by span; see
+    /// `borrowck::check_function`.
+    pub escaping: HashSet<Span>,
+    /// Problems that do not stop compilation, such as futures that never run.
+    pub warnings: Vec<SpannedError>,
+}
+
+impl TypeTable {
+    /// Replaces the inference variables in every recorded type with what
+    /// they stand for.
+    fn apply(&mut self, infer: &Unifier) {
+        let types = self.exprs.values_mut()
+            .chain(self.coercions.values_mut())
+            .chain(self.indirect_calls.values_mut())
+            .chain(self.bindings.values_mut())
+            .chain(self.type_args.values_mut().flatten())
+            .chain(self.methods.values_mut().map(|method| &mut method.self_ty))
+            .chain(self.captures.values_mut().flatten().map(|(_, ty)| ty));
+        for ty in types {
+            *ty = infer.apply(ty);
+        }
+    }
+
+    /// Adds what type checking worked out about another function.
+    fn extend(&mut self, other: TypeTable) {
+        let TypeTable {
+            exprs,
+            type_args,
+            methods,
+            coercions,
+            fn_values,
+            indirect_calls,
+            captures,
+            bindings,
+            escaping,
+            warnings,
+        } = other;
+        self.exprs.extend(exprs);
+        self.type_args.extend(type_args);
+        self.methods.extend(methods);
+        self.coercions.extend(coercions);
+        self.fn_values.extend(fn_values);
+        self.indirect_calls.extend(indirect_calls);
+        self.captures.extend(captures);
+        self.bindings.extend(bindings);
+        self.escaping.extend(escaping);
+        self.warnings.extend(warnings);
+    }
+}
+
+/// A method call as type checking resolved it.
+#[derive(Debug, Clone)]
+pub struct MethodRef {
+    /// Trait that declares the method, or `None` for a method of an inherent impl.
+    pub trait_name: Option<String>,
+    /// Type of `self`: that of the receiver, or what the receiver points to.
+    pub self_ty: Type,
+    /// Index of the method in the vtable, if `self_ty` is `dyn`.
+    pub slot: Option<u32>,
+}
+
+/// Program-wide declarations that every function body can refer to.
+struct Globals<'a> {
+    structs: HashMap<&'a str, &'a StructDef>,
+    enums: HashMap<&'a str, &'a EnumDef>,
+    functions: HashMap<&'a str, &'a Function>,
+    traits: HashMap<&'a str, &'a TraitDef>,
+    impls: Vec<&'a ImplDef>,
+    /// Signatures of the non-generic functions and of the externs, which
+    /// their calls are checked against.
+    prototypes: HashMap<&'a str, Prototype<'a>>,
+    /// Reject `unsafe` blocks and functions, for auditing dependencies.
+    forbid_unsafe: bool,
+    /// The code macro expansion made, which borrow checking orders by where
+    /// it stands.
+    expansions: &'a [Expansion],
+}
+
+/// Parameters and return type of a function or extern, as its calls see them.
+struct Prototype<'a> {
+    params: &'a [Param],
+    /// `Void` for a function without a return type.
+    ret_ty: Type,
+    /// Why calls need an `unsafe` block, if they do.
+    unsafe_reason: Option<&'static str>,
 }
 
-pub fn analyze_program(prog: &Program) -> Result<()> {
+/// Type checks the program. With `forbid_unsafe`, it may not contain
+/// `unsafe` blocks or functions.
+pub fn analyze_program(prog: &Program, forbid_unsafe: bool) -> Result<TypeTable> {
+    let mut structs = HashMap::new();
+    let mut enums = HashMap::new();
+    for def in &prog.structs {
+        if types::is_builtin(&def.name) || structs.insert(def.name.as_str(), def).is_some() {
+            bail_at!(def.span, "Type '{}' is already defined", def.name);
+        }
+    }
+    for def in &prog.enums {
+        if types::is_builtin(&def.name)
+            || structs.contains_key(def.name.as_str())
+            || enums.insert(def.name.as_str(), def).is_some()
+        {
+            bail_at!(def.span, "Type '{}' is already defined", def.name);
+        }
+    }
+    let mut traits = HashMap::new();
+    for def in &prog.traits {
+        if traits.insert(def.name.as_str(), def).is_some() {
+            bail_at!(def.span, "Trait '{}' is already defined", def.name);
+        }
+    }
+    let functions = prog.functions.iter().map(|f| (f.name.as_str(), f)).collect();
+    let impls = prog.impls.iter().collect();
+    let mut globals = Globals { structs, enums, functions, traits, impls, prototypes: HashMap::new(), forbid_unsafe, expansions: &prog.expansions };
+    for def in &prog.structs {
+        check_struct(def, &globals)?;
+    }
+    for def in &prog.enums {
+        check_enum(def, &globals)?;
+    }
+    for def in &prog.traits {
+        for (i, method) in def.methods.iter().enumerate() {
+            if def.methods[..i].iter().any(|m| m.func.name == method.func.name) {
+                bail_at!(method.func.span, "Method '{}' is declared more than once in '{}'", method.func.name, def.name);
+            }
+            check_signature(&method.func, Owner::Trait(def), &globals)?;
+        }
+    }
+    for (i, imp) in prog.impls.iter().enumerate() {
+        check_impl(imp, &prog.impls[..i], &globals)?;
+    }
+    for ext in &prog.externs {
+        for param in &ext.params {
+            check_type(&param.ty, param.span, &[], &globals)?;
+        }
+        check_type(&ext.ret_ty, ext.span, &[], &globals)?;
+        let proto = Prototype {
+            params: &ext.params,
+            ret_ty: ext.ret_ty.clone(),
+            unsafe_reason: Some("the compiler cannot check what an extern function does"),
+        };
+        globals.prototypes.insert(&ext.name, proto);
+    }
+    for func in prog.functions.iter().filter(|f| f.type_params.is_empty()) {
+        let ret_ty = call_result(func, func.ret_ty.clone().unwrap_or(Type::Void));
+        let unsafe_reason = func.is_unsafe.then_some(UNSAFE_FN);
+        globals.prototypes.insert(&func.name, Prototype { params: &func.params, ret_ty, unsafe_reason });
+    }
+    let mut types = TypeTable::default();
     for func in &prog.functions {
-        analyze_function(func)?;
+        analyze_function(func, Owner::Free, &globals, &mut types)?;
+    }
+    for def in &prog.traits {
+        for method in def.methods.iter().filter(|m| m.has_default) {
+            analyze_function(&method.func, Owner::Trait(def), &globals, &mut types)?;
+        }
+    }
+    for imp in &prog.impls {
+        for method in &imp.methods {
+            analyze_function(method, Owner::Impl(imp), &globals, &mut types)?;
+        }
+    }
+    Ok(types)
+}
+
+fn check_struct(def: &StructDef, globals: &Globals) -> Result<()> {
+    check_type_params(&def.type_params, def.span, &def.name)?;
+    for (i, field) in def.fields.iter().enumerate() {
+        if def.fields[..i].iter().any(|f| f.name == field.name) {
+            bail_at!(field.span, "Field '{}' is declared more than once in '{}'", field.name, def.name);
+        }
+        check_type(&field.ty, field.span, &def.type_params, globals)?;
+        if contains_by_value(&field.ty, &def.name, globals, &mut Vec::new()) {
+            bail_at!(
+                field.span,
+                "Struct '{}' contains itself by value and would have infinite size; use a pointer",
+                def.name
+            );
+        }
     }
     Ok(())
 }
 
-fn analyze_function(func: &Function) -> Result<()> {
-    let mut scope = Scope::new();
+fn check_enum(def: &EnumDef, globals: &Globals) -> Result<()> {
+    check_type_params(&def.type_params, def.span, &def.name)?;
+    for (i, variant) in def.variants.iter().enumerate() {
+        if def.variants[..i].iter().any(|v| v.name == variant.name) {
+            bail_at!(variant.span, "Variant '{}' is declared more than once in '{}'", variant.name, def.name);
+        }
+        for ty in &variant.fields {
+            check_type(ty, variant.span, &def.type_params, globals)?;
+            if contains_by_value(ty, &def.name, globals, &mut Vec::new()) {
+                bail_at!(
+                    variant.span,
+                    "Enum '{}' contains itself by value and would have infinite size; use a pointer",
+                    def.name
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks an impl block, given the ones before it. A trait impl must give
+/// each method without a default, and may give the others, with the
+/// signature the trait declares.
+fn check_impl(imp: &ImplDef, earlier: &[ImplDef], globals: &Globals) -> Result<()> {
+    check_type(&imp.ty, imp.span, &[], globals)?;
+    if matches!(imp.ty, Type::Pointer(_) | Type::Ref(..) | Type::Dyn(_)) {
+        bail_at!(imp.span, "Cannot implement methods for {}", imp.ty);
+    }
+    for (i, method) in imp.methods.iter().enumerate() {
+        if imp.methods[..i].iter().any(|m| m.name == method.name) {
+            bail_at!(method.span, "Method '{}' is declared more than once in this impl", method.name);
+        }
+    }
+    let trait_name = match &imp.trait_name {
+        Some(trait_name) => trait_name,
+        None => {
+            let inherent = earlier.iter().filter(|other| other.trait_name.is_none() && other.ty == imp.ty);
+            for other in inherent {
+                if let Some(method) = imp.methods.iter().find(|m| other.methods.iter().any(|o| o.name == m.name)) {
+                    bail_at!(method.span, "Method '{}' is already defined for {}", method.name, imp.ty);
+                }
+            }
+            return Ok(());
+        }
+    };
+    let def = match globals.traits.get(trait_name.as_str()) {
+        Some(def) => *def,
+        None => bail_at!(imp.span, "Unknown trait: {}", trait_name),
+    };
+    if earlier.iter().any(|other| other.trait_name == imp.trait_name && other.ty == imp.ty) {
+        bail_at!(imp.span, "Trait '{}' is already implemented for {}", trait_name, imp.ty);
+    }
+    for method in &imp.methods {
+        let decl = match def.methods.iter().find(|m| m.func.name == method.name) {
+            Some(decl) => &decl.func,
+            None => bail_at!(method.span, "Method '{}' is not a member of trait '{}'", method.name, trait_name),
+        };
+        let signature = |func: &Function| {
+            let params: Vec<Type> = func.params.iter().map(|p| imp.resolve_self(&p.ty)).collect();
+            (params, func.ret_ty.as_ref().map(|ty| imp.resolve_self(ty)), func.is_async, func.is_unsafe)
+        };
+        if signature(method) != signature(decl) {
+            bail_at!(method.span, "Method '{}' does not match its declaration in trait '{}'", method.name, trait_name);
+        }
+    }
+    let missing = def.methods.iter().find(|m| !m.has_default && !imp.methods.iter().any(|f| f.name == m.func.name));
+    if let Some(missing) = missing {
+        bail_at!(imp.span, "Missing method '{}' in the impl of '{}' for {}", missing.func.name, trait_name, imp.ty);
+    }
+    Ok(())
+}
+
+/// Checks that a trait's methods only use `Self` as the type of `self`, so
+/// that a `dyn` value, whose type is unknown, can call them.
+fn check_object_safe(def: &TraitDef, span: Span) -> Result<()> {
+    let self_param = ["Self".to_string()];
+    for method in &def.methods {
+        let func = &method.func;
+        if func.params.iter().skip(1).map(|p| &p.ty).chain(&func.ret_ty).any(|ty| ty.mentions(&self_param)) {
+            bail_at!(
+                span,
+                "Trait '{}' cannot be used as `dyn` because method '{}' uses `Self` other than as `self`",
+                def.name,
+                func.name
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the type parameters of the generic item `owner` are declared
+/// once each. They shadow structs and enums of the same name, but not the
+/// built-in types.
+fn check_type_params(params: &[String], span: Span, owner: &str) -> Result<()> {
+    for (i, param) in params.iter().enumerate() {
+        if params[..i].contains(param) {
+            bail_at!(span, "Type parameter '{}' is declared more than once in '{}'", param, owner);
+        }
+        if types::is_primitive(param) {
+            bail_at!(span, "Type parameter '{}' of '{}' has the name of a built-in type", param, owner);
+        }
+    }
+    Ok(())
+}
+
+/// Whether a value of type `ty` embeds the type `target`, directly or
+/// through array elements and the by-value fields of structs and enum payloads.
+fn contains_by_value(ty: &Type, target: &str, globals: &Globals, seen: &mut Vec<Type>) -> bool {
+    if let Type::Array(elem, _) = ty {
+        return contains_by_value(elem, target, globals, seen);
+    }
+    let name = match ty.decl_name() {
+        Some(name) => name,
+        None => return false,
+    };
+    if name == target {
+        return true;
+    }
+    if seen.contains(ty) {
+        return false;
+    }
+    seen.push(ty.clone());
+    let (params, fields): (&[String], Vec<&Type>) = if let Some(def) = globals.structs.get(name) {
+        (&def.type_params, def.fields.iter().map(|f| &f.ty).collect())
+    } else if let Some(def) = globals.enums.get(name) {
+        (&def.type_params, def.variants.iter().flat_map(|v| &v.fields).collect())
+    } else {
+        return false;
+    };
+    let args = ty.type_args();
+    // Instances with the wrong number of arguments are reported by `check_type`.
+    args.len() == params.len()
+        && fields.into_iter().any(|field| contains_by_value(&field.substitute(params, args), target, globals, seen))
+}
+
+/// Per-function facts that statements need while being checked.
+#[derive(Clone, Copy)]
+struct FnCtx<'a> {
+    ret_ty: Option<&'a Type>,
+    in_loop: bool,
+    /// Set inside an `unsafe` block or function.
+    in_unsafe: bool,
+    /// Set in the body of an async function, outside its closures.
+    in_async: bool,
+    /// Type parameters in scope and the traits they are bounded by.
+    type_params: &'a [String],
+    bounds: &'a [(String, String)],
+    globals: &'a Globals<'a>,
+    types: &'a RefCell<TypeTable>,
+    /// The types being inferred in the function body.
+    infer: &'a RefCell<Unifier>,
+    /// Set inside the body of a closure.
+    captures: Option<&'a Captures<'a>>,
+    /// Variables, by name, and expression statements, without one, whose
+    /// values have not been used yet, for warning about futures that never
+    /// run.
+    unused: &'a RefCell<Vec<(Option<String>, Span, Type)>>,
+}
+
+impl FnCtx<'_> {
+    fn fresh(&self, kind: VarKind, origin: Span, what: impl Into<String>, advice: impl Into<String>) -> Type {
+        self.infer.borrow_mut().fresh(kind, origin, what, advice)
+    }
+
+    /// Makes `a` and `b` the same type, if they can be; see `Unifier::unify`.
+    fn unify(&self, a: &Type, b: &Type) -> bool {
+        self.infer.borrow_mut().unify(a, b)
+    }
+
+    fn apply(&self, ty: &Type) -> Type {
+        self.infer.borrow().apply(ty)
+    }
+
+    /// `ty` as error messages show it; see `Unifier::describe`.
+    fn show(&self, ty: &Type) -> Type {
+        self.infer.borrow().describe(ty)
+    }
+
+    /// `ty` as far as it is known, which has to be enough to tell what kind
+    /// of type it is; see `Unifier::settle`.
+    fn settle(&self, ty: &Type, span: Span) -> Result<Type> {
+        self.infer.borrow_mut().settle(ty, span)
+    }
+
+    /// Whether `ty` is a numeric type or will be one.
+    fn is_numeric(&self, ty: &Type) -> bool {
+        ty.is_numeric() || matches!(self.infer.borrow().kind(ty), Some(VarKind::Int | VarKind::Signed | VarKind::Float))
+    }
+}
+
+/// The variables around a closure body, and those of them it uses.
+struct Captures<'a> {
+    /// Scope the closure is written in.
+    scope: &'a Scope,
+    /// Set when that scope is itself the body of a closure, which then has
+    /// to capture whatever this one captures from further out.
+    outer: Option<&'a Captures<'a>>,
+    vars: RefCell<Vec<(String, Type)>>,
+}
+
+impl Captures<'_> {
+    /// The variable `name` from around the closure, which the closure then
+    /// captures.
+    fn capture(&self, name: &str) -> Option<Symbol> {
+        let sym = match self.scope.get(name) {
+            Some(sym) => sym.clone(),
+            None => self.outer?.capture(name)?,
+        };
+        let mut vars = self.vars.borrow_mut();
+        if !vars.iter().any(|(var, _)| var == name) {
+            vars.push((name.to_string(), sym.ty.clone()));
+        }
+        Some(sym)
+    }
+}
+
+/// What declares a function, which decides whether it takes `self` and
+/// what `Self` stands for.
+#[derive(Clone, Copy)]
+enum Owner<'a> {
+    Free,
+    /// A method of an impl block, where `Self` is the implementing type.
+    Impl(&'a ImplDef),
+    /// A method a trait declares, where `Self` is a type parameter bounded
+    /// by the trait.
+    Trait(&'a TraitDef),
+}
+
+/// A function's signature as its body sees it, with `Self` resolved.
+struct Signature {
+    type_params: Vec<String>,
+    bounds: Vec<(String, String)>,
+    params: Vec<Type>,
+    ret_ty: Option<Type>,
+}
+
+fn check_signature(func: &Function, owner: Owner, globals: &Globals) -> Result<Signature> {
+    check_type_params(&func.type_params, func.span, &func.name)?;
+    if func.is_unsafe && globals.forbid_unsafe {
+        bail_at!(func.span, "Unsafe function '{}' is not allowed with --forbid-unsafe", func.name);
+    }
+    let mut type_params = func.type_params.clone();
+    let mut bounds = func.bounds.clone();
+    match owner {
+        Owner::Free => {
+            if let Some(param) = func.params.iter().find(|p| p.name == "self") {
+                bail_at!(param.span, "Only methods can take `self`");
+            }
+            if func.is_async && func.name == "main" {
+                bail_at!(func.span, "`main` cannot be async, as nothing would await its future");
+            }
+        }
+        _ if !func.type_params.is_empty() => bail_at!(func.span, "Method '{}' cannot have type parameters", func.name),
+        _ if !func.params.first().is_some_and(|p| p.name == "self" && p.ty.is("Self")) => {
+            bail_at!(func.span, "Method '{}' must take `self` as its first parameter", func.name)
+        }
+        Owner::Impl(_) => {}
+        Owner::Trait(def) => {
+            type_params.push("Self".to_string());
+            bounds.push(("Self".to_string(), def.name.clone()));
+        }
+    }
+    for (_, trait_name) in &func.bounds {
+        if !globals.traits.contains_key(trait_name.as_str()) {
+            bail_at!(func.span, "Unknown trait: {}", trait_name);
+        }
+    }
+    let resolve = |ty: &Type| match owner {
+        Owner::Impl(imp) => imp.resolve_self(ty),
+        _ => ty.clone(),
+    };
+    let mut params = Vec::new();
     for param in &func.params {
-        scope.insert(param.name.clone(), param.ty.clone());
+        let ty = resolve(&param.ty);
+        check_type(&ty, param.span, &type_params, globals)?;
+        params.push(ty);
+    }
+    let ret_ty = func.ret_ty.as_ref().map(resolve);
+    if let Some(ret_ty) = &ret_ty {
+        check_type(ret_ty, func.span, &type_params, globals)?;
+    }
+    // Calls infer type arguments from the signature alone.
+    for param in &func.type_params {
+        let used = params.iter()
+            .chain(&ret_ty)
+            .any(|ty| ty.mentions(std::slice::from_ref(param)));
+        if !used {
+            bail_at!(func.span, "Type parameter '{}' of '{}' is not used by its parameters or return type", param, func.name);
+        }
+    }
+    Ok(Signature { type_params, bounds, params, ret_ty })
+}
+
+/// Type checks a function body, inferring the types it leaves out, and
+/// adds what it worked out to `types`.
+fn analyze_function(func: &Function, owner: Owner, globals: &Globals, types: &mut TypeTable) -> Result<()> {
+    let sig = check_signature(func, owner, globals)?;
+    let mut scope = Scope::new();
+    for (param, ty) in func.params.iter().zip(&sig.params) {
+        scope.insert(param.name.clone(), ty.clone(), false);
+    }
+    let local = RefCell::new(TypeTable::default());
+    let infer = RefCell::new(Unifier::default());
+    let unused = RefCell::new(Vec::new());
+    let ctx = FnCtx {
+        ret_ty: sig.ret_ty.as_ref(),
+        in_loop: false,
+        in_unsafe: func.is_unsafe,
+        in_async: func.is_async,
+        type_params: &sig.type_params,
+        bounds: &sig.bounds,
+        globals,
+        types: &local,
+        infer: &infer,
+        captures: None,
+        unused: &unused,
+    };
+    analyze_block(&func.body, &mut scope, ctx)?;
+    if func.ret_ty.is_some() && !block_returns(&func.body) {
+        bail_at!(func.span, "Function '{}' does not return a value on all paths", func.name);
+    }
+    let mut infer = infer.into_inner();
+    infer.finish()?;
+    let mut local = local.into_inner();
+    local.apply(&infer);
+    for (name, span, ty) in unused.into_inner() {
+        if infer.apply(&ty).future_output().is_some() {
+            let message = match name {
+                Some(name) => format!("Future `{}` is never awaited or spawned, so it never runs", source_name(&name)),
+                None => "This future is never awaited or spawned, so it never runs".to_string(),
+            };
+            local.warnings.push(SpannedError::new(span, message));
+        }
+    }
+    let holds = |ty: &Type| holds_references(ty, &sig.type_params, globals);
+    local.escaping = borrowck::check_function(&func.body, &local, &holds, globals.expansions)?;
+    types.extend(local);
+    Ok(())
+}
+
+/// Whether a value of type `ty` can hold a reference, a slice, a `dyn` value
+/// or a closure, which borrow what they refer to, directly or in its array
+/// elements, fields or payloads. A type parameter may stand for any of them.
+fn holds_references(ty: &Type, type_params: &[String], globals: &Globals) -> bool {
+    let name = match ty {
+        Type::Ref(..) | Type::Slice(_) | Type::Dyn(_) | Type::Closure(..) => return true,
+        Type::Array(elem, _) => return holds_references(elem, type_params, globals),
+        Type::Named(name) if type_params.contains(name) => return true,
+        // A future holds the arguments of its call.
+        Type::Generic(name, _) if name == types::FUTURE => return true,
+        Type::Named(name) | Type::Generic(name, _) => name,
+        Type::Pointer(_) | Type::FnPtr(..) | Type::Void => return false,
+        Type::Var(_) => unreachable!("inference variables are replaced before borrow checking"),
+    };
+    let (params, fields): (&[String], Vec<&Type>) = if let Some(def) = globals.structs.get(name.as_str()) {
+        (&def.type_params, def.fields.iter().map(|f| &f.ty).collect())
+    } else if let Some(def) = globals.enums.get(name.as_str()) {
+        (&def.type_params, def.variants.iter().flat_map(|v| &v.fields).collect())
+    } else {
+        return false;
+    };
+    // Only raw pointers lead back to a struct or enum itself, so this ends.
+    fields.into_iter().any(|field| holds_references(&field.substitute(params, ty.type_args()), type_params, globals))
+}
+
+/// Type checks the statements of a block, whose variables go out of scope
+/// at its end.
+fn analyze_block(stmts: &[Stmt], scope: &mut Scope, ctx: FnCtx) -> Result<()> {
+    scope.push();
+    for stmt in stmts {
+        analyze_stmt(stmt, scope, ctx)?;
     }
-    for stmt in &func.body {
-        analyze_stmt(stmt, &mut scope)?;
+    scope.pop();
+    Ok(())
+}
+
+fn analyze_cond(cond: &Expr, scope: &Scope, ctx: FnCtx) -> Result<()> {
+    let bool_ty = Type::named("bool");
+    let ty = analyze_expr_expecting(cond, Some(&bool_ty), scope, ctx)?;
+    if !ctx.unify(&ty, &bool_ty) {
+        bail_at!(cond.span, "Condition must be bool, found {}", ctx.show(&ty));
     }
     Ok(())
 }
 
-fn analyze_stmt(stmt: &Stmt, scope: &mut Scope) -> Result<()> {
-    match stmt {
-        Stmt::Let { name, expr } => {
-            let ty = analyze_expr(expr, scope)?;
-            scope.insert(name.clone(), ty);
+fn analyze_stmt(stmt: &Stmt, scope: &mut Scope, ctx: FnCtx) -> Result<()> {
+    match &stmt.kind {
+        StmtKind::Let { name, mutable, ty: None, expr } => {
+            let ty = analyze_expr(expr, scope, ctx)?;
+            if ty == Type::Void {
+                bail_at!(expr.span, "Expression does not produce a value");
+            }
+            ctx.infer.borrow_mut().hold(source_name(name), &ty);
+            ctx.unused.borrow_mut().push((Some(name.clone()), stmt.span, ty.clone()));
+            scope.insert(name.clone(), ty, *mutable);
+            Ok(())
+        }
+        StmtKind::Let { name, mutable, ty: Some(declared), expr } => {
+            check_type(declared, stmt.span, ctx.type_params, ctx.globals)?;
+            let ty = analyze_expr_expecting(expr, Some(declared), scope, ctx)?;
+            if !ctx.unify(&ty, declared) {
+                bail_at!(expr.span, "Mismatched types in `let`: expected {}, found {}", declared, ctx.show(&ty));
+            }
+            ctx.unused.borrow_mut().push((Some(name.clone()), stmt.span, declared.clone()));
+            scope.insert(name.clone(), declared.clone(), *mutable);
+            Ok(())
+        }
+        StmtKind::Expr(expr) => {
+            let ty = analyze_expr(expr, scope, ctx)?;
+            ctx.unused.borrow_mut().push((None, expr.span, ty));
+            Ok(())
+        }
+        StmtKind::Assign { target, value } => {
+            let assignable = matches!(
+                target.kind,
+                ExprKind::Ident(_) | ExprKind::Field { .. } | ExprKind::Index { .. } | ExprKind::Deref(_)
+            );
+            if !assignable || !is_place(target, scope, ctx)? {
+                bail_at!(
+                    target.span,
+                    "Cannot assign to this expression; only variables, fields and elements of variables, of referenced values and of slices, and `*` of references, are assignable"
+                );
+            }
+            let expected = analyze_expr(target, scope, ctx)?;
+            check_mutable(target, scope, ctx)?;
+            let ty = analyze_expr_expecting(value, Some(&expected), scope, ctx)?;
+            if !ctx.unify(&ty, &expected) {
+                bail_at!(
+                    value.span,
+                    "Mismatched types in assignment: expected {}, found {}",
+                    ctx.show(&expected),
+                    ctx.show(&ty)
+                );
+            }
             Ok(())
         }
-        Stmt::Expr(expr) => {
-            analyze_expr(expr, scope)?;
+        StmtKind::If { cond, then_body, else_body } => {
+            analyze_cond(cond, scope, ctx)?;
+            analyze_block(then_body, scope, ctx)?;
+            if let Some(else_body) = else_body {
+                analyze_block(else_body, scope, ctx)?;
+            }
             Ok(())
         }
+        StmtKind::While { cond, body } => {
+            analyze_cond(cond, scope, ctx)?;
+            analyze_block(body, scope, FnCtx { in_loop: true, ..ctx })
+        }
+        StmtKind::Loop { body } => analyze_block(body, scope, FnCtx { in_loop: true, ..ctx }),
+        StmtKind::Unsafe(_) if ctx.globals.forbid_unsafe => {
+            bail_at!(stmt.span, "`unsafe` blocks are not allowed with --forbid-unsafe")
+        }
+        StmtKind::Unsafe(body) => analyze_block(body, scope, FnCtx { in_unsafe: true, ..ctx }),
+        StmtKind::Block(body) => analyze_block(body, scope, ctx),
+        StmtKind::Break | StmtKind::Continue if !ctx.in_loop => {
+            bail_at!(stmt.span, "`break` and `continue` are only allowed inside a loop")
+        }
+        StmtKind::Break | StmtKind::Continue => Ok(()),
+        StmtKind::Return(value) => match (value, ctx.ret_ty) {
+            (Some(expr), Some(expected)) => {
+                let ty = analyze_expr_expecting(expr, Some(expected), scope, ctx)?;
+                if !ctx.unify(&ty, expected) {
+                    bail_at!(expr.span, "Mismatched return type: expected {}, found {}", expected, ctx.show(&ty));
+                }
+                Ok(())
+            }
+            (None, None) => Ok(()),
+            (Some(expr), None) => {
+                bail_at!(expr.span, "Cannot return a value from a function without a return type")
+            }
+            (None, Some(expected)) => bail_at!(stmt.span, "Missing return value of type {}", expected),
+        },
+        // Expansion replaces the invocations of every macro the program defines.
+        StmtKind::MacroInvoke { name, .. } => bail_at!(stmt.span, "Undefined macro: {}", name),
+        StmtKind::Error => Ok(()),
+    }
+}
+
+/// Whether control can never fall off the end of `stmts`.
+fn block_returns(stmts: &[Stmt]) -> bool {
+    stmts.iter().any(|stmt| match &stmt.kind {
+        StmtKind::Return(_) => true,
+        StmtKind::If { then_body, else_body: Some(else_body), .. } => {
+            block_returns(then_body) && block_returns(else_body)
+        }
+        StmtKind::Loop { body } => !loop_breaks(body),
+        StmtKind::Unsafe(body) | StmtKind::Block(body) => block_returns(body),
+        StmtKind::Expr(Expr { kind: ExprKind::Match { arms, .. }, .. }) => {
+            !arms.is_empty() && arms.iter().all(|arm| matches!(&arm.body, ArmBody::Block(b) if block_returns(b)))
+        }
+        _ => false,
+    })
+}
+
+/// Whether a `break` in `stmts` exits the innermost enclosing loop.
+fn loop_breaks(stmts: &[Stmt]) -> bool {
+    stmts.iter().any(|stmt| match &stmt.kind {
+        StmtKind::Break => true,
+        StmtKind::If { then_body, else_body, .. } => {
+            loop_breaks(then_body) || else_body.as_deref().is_some_and(loop_breaks)
+        }
+        StmtKind::Unsafe(body) | StmtKind::Block(body) => loop_breaks(body),
+        StmtKind::Expr(Expr { kind: ExprKind::Match { arms, .. }, .. }) => {
+            arms.iter().any(|arm| matches!(&arm.body, ArmBody::Block(b) if loop_breaks(b)))
+        }
+        _ => false,
+    })
+}
+
+/// Checks that `ty` names existing types, where `type_params` are the type
+/// parameters in scope, and that generic structs and enums get all their
+/// arguments.
+fn check_type(ty: &Type, span: Span, type_params: &[String], globals: &Globals) -> Result<()> {
+    let params = ty.decl_name().and_then(|name| {
+        globals.structs.get(name).map(|def| &def.type_params)
+            .or_else(|| globals.enums.get(name).map(|def| &def.type_params))
+    });
+    match (ty, params) {
+        (Type::Named(name), _) if types::is_primitive(name) || type_params.contains(name) => Ok(()),
+        (Type::Named(name) | Type::Generic(name, _), None) if name == types::FUTURE => {
+            let args = ty.type_args();
+            if args.len() != 1 {
+                bail_at!(span, "Type '{}' expects 1 type arguments, found {}", name, args.len());
+            }
+            check_type(&args[0], span, type_params, globals)
+        }
+        (Type::Named(name) | Type::Generic(name, _), Some(params)) => {
+            let args = ty.type_args();
+            if args.len() != params.len() {
+                bail_at!(span, "Type '{}' expects {} type arguments, found {}", name, params.len(), args.len());
+            }
+            args.iter().try_for_each(|arg| check_type(arg, span, type_params, globals))
+        }
+        (Type::Pointer(pointee) | Type::Ref(pointee, _) | Type::Array(pointee, _) | Type::Slice(pointee), _) => {
+            check_type(pointee, span, type_params, globals)
+        }
+        (Type::FnPtr(params, ret) | Type::Closure(params, ret), _) => {
+            params.iter().try_for_each(|param| check_type(param, span, type_params, globals))?;
+            match **ret {
+                Type::Void => Ok(()),
+                ref ret => check_type(ret, span, type_params, globals),
+            }
+        }
+        (Type::Dyn(name), _) => match globals.traits.get(name.as_str()) {
+            Some(def) => check_object_safe(def, span),
+            None => bail_at!(span, "Unknown trait: {}", name),
+        },
+        _ => bail_at!(span, "Unknown type: {}", ty),
+    }
+}
+
+/// Payload types of variant `index` of the enum instance `ty`.
+fn variant_fields(def: &EnumDef, index: usize, ty: &Type) -> Vec<Type> {
+    def.variants[index].fields.iter()
+        .map(|field| field.substitute(&def.type_params, ty.type_args()))
+        .collect()
+}
+
+/// The type of the struct a field access on a value of type `ty` reads
+/// from, and its declaration; references and pointers to structs are
+/// dereferenced implicitly.
+fn struct_of<'a, 't>(ty: &'t Type, globals: &Globals<'a>) -> Option<(&'t Type, &'a StructDef)> {
+    let ty = ty.pointee().unwrap_or(ty);
+    globals.structs.get(ty.decl_name()?).map(|def| (ty, *def))
+}
+
+/// What a call of `func` gives, where `ret_ty` is its return type: a future
+/// of it if `func` is async.
+fn call_result(func: &Function, ret_ty: Type) -> Type {
+    if func.is_async {
+        Type::future(ret_ty)
+    } else {
+        ret_ty
+    }
+}
+
+/// The type a generic declaration refers to itself by, like `Option<T>`.
+fn declared_type(name: &str, params: &[String]) -> Type {
+    Type::instance(name, params.iter().map(|p| Type::named(p.as_str())).collect())
+}
+
+/// Works out the type arguments of one use of a generic item. Each type
+/// parameter stands for an inference variable, which the type the context
+/// expects and the types of the values given to the item decide.
+struct Inference<'a> {
+    params: &'a [String],
+    args: Vec<Type>,
+}
+
+impl<'a> Inference<'a> {
+    /// `ty` is the type of the use in terms of the parameters, such as
+    /// `Option<T>` for a variant or the return type for a call, and `item`
+    /// names the item in errors.
+    fn new(params: &'a [String], ty: &Type, expected: Option<&Type>, span: Span, item: &str, ctx: FnCtx) -> Self {
+        let args = params.iter()
+            .map(|param| {
+                let what = format!("type parameter '{}' of '{}'", param, item);
+                ctx.fresh(VarKind::Any, span, what, "use it where its type is known, such as a return value")
+            })
+            .collect();
+        let inference = Self { params, args };
+        // The expected type is only a hint: it may be one the result
+        // converts to instead.
+        if let Some(expected) = expected {
+            ctx.unify(&inference.show(ty, ctx), expected);
+        }
+        inference
+    }
+
+    /// Types `value` where a `ty` is wanted, which decides the parameters
+    /// `ty` mentions. Returns the value's type and whether it fits.
+    fn check(&self, value: &Expr, ty: &Type, scope: &Scope, ctx: FnCtx) -> Result<(Type, bool)> {
+        let wanted = self.show(ty, ctx);
+        let found = analyze_expr_expecting(value, Some(&wanted), scope, ctx)?;
+        // A function pointer passed for a closure whose signature is not
+        // known yet converts once the signature is.
+        if let (Type::Closure(..), Type::FnPtr(params, ret)) = (&wanted, &found) {
+            let closure = Type::Closure(params.clone(), ret.clone());
+            if ctx.unify(&closure, &wanted) {
+                ctx.types.borrow_mut().coercions.insert(value.span, closure.clone());
+                return Ok((closure, true));
+            }
+        }
+        let fits = ctx.unify(&found, &wanted);
+        Ok((found, fits))
+    }
+
+    /// `ty` with each parameter replaced by what is known of its argument,
+    /// for error messages.
+    fn show(&self, ty: &Type, ctx: FnCtx) -> Type {
+        ctx.show(&ty.substitute(self.params, &self.args))
+    }
+
+    fn finish(&self, ctx: FnCtx) -> Vec<Type> {
+        self.args.iter().map(|arg| ctx.apply(arg)).collect()
+    }
+}
+
+/// Constructors of types with finitely many values, for exhaustiveness checking.
+fn constructors(ty: &Type, globals: &Globals) -> Option<Vec<Ctor>> {
+    if ty.is("bool") {
+        let ctor = |name: &str| Ctor { name: name.to_string(), fields: Vec::new() };
+        return Some(vec![ctor("false"), ctor("true")]);
+    }
+    let def = globals.enums.get(ty.decl_name()?)?;
+    let ctors = def.variants.iter()
+        .enumerate()
+        .map(|(i, v)| Ctor { name: format!("{}::{}", def.name, v.name), fields: variant_fields(def, i, ty) })
+        .collect();
+    Some(ctors)
+}
+
+/// Checks `pattern` against the type of the value it matches, adding its
+/// bindings to `scope`.
+fn check_pattern(pattern: &Pattern, ty: &Type, scope: &mut Scope, ctx: FnCtx) -> Result<Pat> {
+    match &pattern.kind {
+        PatternKind::Wildcard => Ok(Pat::Wild),
+        PatternKind::Binding(name) => {
+            // A bare variant name would bind the value, not match the variant.
+            let matched = ctx.apply(ty);
+            if let Some(def) = matched.decl_name().and_then(|name| ctx.globals.enums.get(name)) {
+                if def.variants.iter().any(|v| v.name == source_name(name)) {
+                    bail_at!(
+                        pattern.span,
+                        "Pattern `{0}` binds a new variable that matches any value; write `{1}::{0}` to match the variant",
+                        source_name(name),
+                        def.name
+                    );
+                }
+            }
+            scope.insert(name.clone(), ty.clone(), false);
+            ctx.types.borrow_mut().bindings.insert(pattern.span, ty.clone());
+            Ok(Pat::Wild)
+        }
+        PatternKind::Literal(lit) => match (&lit.kind, int_pattern(lit), &ctx.settle(ty, pattern.span)?) {
+            (ExprKind::Bool(b), _, ty) if ty.is("bool") => Ok(Pat::Ctor(*b as usize, Vec::new())),
+            (ExprKind::Char(c), _, ty) if ty.is("char") => Ok(Pat::Lit(*c as i128)),
+            (_, Some((value, suffix)), ty) if ty.is_integer() => {
+                let name = ty.as_named().unwrap();
+                if suffix.is_some_and(|suffix| suffix != name) {
+                    bail_at!(pattern.span, "Mismatched pattern type: expected {}, found {}", ty, suffix.unwrap());
+                }
+                let max = types::int_max(name) as i128;
+                let min = if types::is_signed(name) { -max - 1 } else { 0 };
+                if value < min || value > max {
+                    bail_at!(pattern.span, "Integer literal {} does not fit in {}", value, ty);
+                }
+                Ok(Pat::Lit(value))
+            }
+            (.., ty) => bail_at!(pattern.span, "This literal pattern cannot match a value of type {}", ty),
+        },
+        PatternKind::Variant { enum_name, variant, fields } => {
+            let ty = &ctx.settle(ty, pattern.span)?;
+            let def = match ctx.globals.enums.get(enum_name.as_str()) {
+                Some(def) => *def,
+                None => bail_at!(pattern.span, "Unknown enum: {}", enum_name),
+            };
+            if ty.decl_name() != Some(enum_name.as_str()) {
+                bail_at!(pattern.span, "Mismatched pattern type: expected {}, found {}", ty, enum_name);
+            }
+            let index = match def.variants.iter().position(|v| v.name == *variant) {
+                Some(index) => index,
+                None => bail_at!(pattern.span, "Enum '{}' has no variant '{}'", enum_name, variant),
+            };
+            let field_types = variant_fields(def, index, ty);
+            if fields.len() != field_types.len() {
+                bail_at!(
+                    pattern.span,
+                    "Variant '{}::{}' has {} fields, but the pattern has {}",
+                    enum_name,
+                    variant,
+                    field_types.len(),
+                    fields.len()
+                );
+            }
+            let fields = fields.iter()
+                .zip(&field_types)
+                .map(|(field, ty)| check_pattern(field, ty, scope, ctx))
+                .collect::<Result<Vec<_>>>()?;
+            Ok(Pat::Ctor(index, fields))
+        }
+    }
+}
+
+/// Value and suffix of an integer literal pattern such as `3`, `-1` or `7u8`.
+fn int_pattern(lit: &Expr) -> Option<(i128, Option<&str>)> {
+    match &lit.kind {
+        ExprKind::Int { value, ty } => Some((*value as i128, ty.as_deref())),
+        ExprKind::Unary { op: UnaryOp::Neg, expr } => {
+            let (value, ty) = int_pattern(expr)?;
+            Some((-value, ty))
+        }
+        _ => None,
+    }
+}
+
+fn analyze_match(
+    expr: &Expr,
+    scrutinee: &Expr,
+    arms: &[MatchArm],
+    expected: Option<&Type>,
+    scope: &Scope,
+    ctx: FnCtx,
+) -> Result<Type> {
+    let ty = analyze_expr(scrutinee, scope, ctx)?;
+    // Patterns decide the types of payloads that are not known yet.
+    let ctors = |ty: &Type| constructors(&ctx.apply(ty), ctx.globals);
+    let tys = [ty.clone()];
+    let mut rows: Vec<Vec<Pat>> = Vec::new();
+    let mut result: Option<Type> = None;
+    let mut has_block = false;
+    for arm in arms {
+        let mut arm_scope = scope.clone();
+        let pat = check_pattern(&arm.pattern, &ty, &mut arm_scope, ctx)?;
+        if !exhaustiveness::is_useful(&rows, std::slice::from_ref(&pat), &tys, &ctors) {
+            bail_at!(arm.span, "Unreachable match arm: earlier arms already cover this pattern");
+        }
+        rows.push(vec![pat]);
+        match &arm.body {
+            ArmBody::Expr(body) => {
+                let body_ty = analyze_expr_expecting(body, result.as_ref().or(expected), &arm_scope, ctx)?;
+                match &result {
+                    Some(expected) if !ctx.unify(expected, &body_ty) => bail_at!(
+                        body.span,
+                        "Mismatched match arm types: expected {}, found {}",
+                        ctx.show(expected),
+                        ctx.show(&body_ty)
+                    ),
+                    _ => result = Some(body_ty),
+                }
+            }
+            ArmBody::Block(body) => {
+                has_block = true;
+                analyze_block(body, &mut arm_scope, ctx)?;
+            }
+        }
+    }
+    if let Some(witness) = exhaustiveness::missing(&rows, &tys, &ctors) {
+        bail_at!(expr.span, "Non-exhaustive match: pattern `{}` is not covered", witness.join(", "));
+    }
+    match result {
+        Some(ty) if !has_block => Ok(ty),
+        _ => Ok(Type::Void),
+    }
+}
+
+/// Whether `expr` denotes storage that can be assigned to or have its
+/// address taken: a variable, what a reference or pointer refers to, a
+/// field of a place or of a referenced struct, or an element of an array
+/// place, of a referenced array or of a slice.
+fn is_place(expr: &Expr, scope: &Scope, ctx: FnCtx) -> Result<bool> {
+    Ok(match &expr.kind {
+        ExprKind::Ident(_) | ExprKind::Deref(_) => true,
+        ExprKind::Field { expr: base, .. } => {
+            analyze_expr(base, scope, ctx)?.pointee().is_some() || is_place(base, scope, ctx)?
+        }
+        ExprKind::Index { expr: base, .. } => {
+            !matches!(analyze_expr(base, scope, ctx)?, Type::Array(..)) || is_place(base, scope, ctx)?
+        }
+        _ => false,
+    })
+}
+
+/// Rejects writing to the place `place`, or borrowing it with `&mut`, when
+/// it is reached through a shared reference or is part of a variable not
+/// declared `mut`.
+fn check_mutable(place: &Expr, scope: &Scope, ctx: FnCtx) -> Result<()> {
+    let base = match &place.kind {
+        ExprKind::Field { expr: base, .. } | ExprKind::Index { expr: base, .. } | ExprKind::Deref(base) => base,
+        ExprKind::Ident(name) => match lookup(name, scope, ctx) {
+            Some(sym) if sym.mutable => return Ok(()),
+            Some(_) => {
+                let name = source_name(name);
+                bail_at!(place.span, "Cannot modify `{}`, which is not declared mutable; use `let mut {}`", name, name)
+            }
+            None => bail_at!(place.span, "Cannot modify `{}`, which is not a variable", name),
+        },
+        _ => return Ok(()),
+    };
+    match analyze_expr(base, scope, ctx)? {
+        ty @ Type::Ref(_, false) => {
+            bail_at!(
+                place.span,
+                "Cannot modify a value through the shared reference type {}; use `&mut`",
+                ctx.show(&ty)
+            )
+        }
+        Type::Ref(_, true) | Type::Pointer(_) | Type::Slice(_) => Ok(()),
+        _ => check_mutable(base, scope, ctx),
+    }
+}
+
+/// Rejects reading or writing through a value of type `ty` outside an
+/// `unsafe` block if it is a raw pointer.
+fn check_raw_access(ty: &Type, span: Span, ctx: FnCtx) -> Result<()> {
+    if matches!(ty, Type::Pointer(_)) && !ctx.in_unsafe {
+        bail_at!(span, "Dereferencing the raw pointer type {} requires an `unsafe` block", ty);
+    }
+    Ok(())
+}
+
+/// Element type of an array or slice, also behind a reference or pointer to
+/// an array, and the length of an array.
+fn elements(ty: &Type) -> Option<(&Type, Option<u64>)> {
+    match ty.pointee().unwrap_or(ty) {
+        Type::Array(elem, len) => Some((elem, Some(*len))),
+        Type::Slice(elem) if ty.pointee().is_none() => Some((elem, None)),
+        _ => None,
+    }
+}
+
+/// Types an index or a bound of a range, which must be an `i64`. A literal
+/// one into an array of length `len` is checked now instead of when the
+/// program runs; the end of a range may equal the length.
+fn analyze_bound(bound: &Expr, len: Option<u64>, is_range: bool, scope: &Scope, ctx: FnCtx) -> Result<()> {
+    let i64_ty = Type::named("i64");
+    let ty = analyze_expr_expecting(bound, Some(&i64_ty), scope, ctx)?;
+    if !ctx.unify(&ty, &i64_ty) {
+        bail_at!(bound.span, "Index must be i64, found {}", ctx.show(&ty));
+    }
+    if let (ExprKind::Int { value, .. }, Some(len)) = (&bound.kind, len) {
+        if *value > len || (*value == len && !is_range) {
+            let what = if is_range { "Slice bound" } else { "Index" };
+            bail_at!(bound.span, "{} {} is out of bounds for an array of length {}", what, value, len);
+        }
+    }
+    Ok(())
+}
+
+/// Types `base[start..end]`, a slice of the elements of an array place or
+/// of a slice.
+fn analyze_slice(
+    expr: &Expr,
+    base: &Expr,
+    start: Option<&Expr>,
+    end: Option<&Expr>,
+    scope: &Scope,
+    ctx: FnCtx,
+) -> Result<Type> {
+    let base_ty = ctx.settle(&analyze_expr(base, scope, ctx)?, base.span)?;
+    let Some((elem, len)) = elements(&base_ty) else {
+        bail_at!(expr.span, "Cannot slice a value of type {}", base_ty)
+    };
+    check_raw_access(&base_ty, expr.span, ctx)?;
+    if matches!(base_ty, Type::Array(..)) && !is_place(base, scope, ctx)? {
+        bail_at!(base.span, "Cannot slice a temporary array; store it in a variable first");
+    }
+    for bound in [start, end].into_iter().flatten() {
+        analyze_bound(bound, len, true, scope, ctx)?;
+    }
+    if let (Some(ExprKind::Int { value: start, .. }), Some(ExprKind::Int { value: end, .. })) =
+        (start.map(|e| &e.kind), end.map(|e| &e.kind))
+    {
+        if start > end {
+            bail_at!(expr.span, "Slice starts at {} but ends at {}", start, end);
+        }
+    }
+    Ok(Type::Slice(Box::new(elem.clone())))
+}
+
+/// Types `[a, b, ...]`. The element type is the first element's, which an
+/// expected array type can fill in; that of an empty array is inferred
+/// from how it is used.
+fn analyze_array(expr: &Expr, elems: &[Expr], expected: Option<&Type>, scope: &Scope, ctx: FnCtx) -> Result<Type> {
+    let hint = match expected {
+        Some(Type::Array(elem, _)) => Some(&**elem),
+        _ => None,
+    };
+    let Some((first, rest)) = elems.split_first() else {
+        let elem = match hint {
+            Some(elem) => elem.clone(),
+            None => ctx.fresh(
+                VarKind::Any,
+                expr.span,
+                "the element type of an empty array",
+                "annotate where it is used",
+            ),
+        };
+        return Ok(Type::Array(Box::new(elem), 0));
+    };
+    let elem_ty = analyze_expr_expecting(first, hint, scope, ctx)?;
+    if elem_ty == Type::Void {
+        bail_at!(first.span, "Expression does not produce a value");
+    }
+    for elem in rest {
+        let ty = analyze_expr_expecting(elem, Some(&elem_ty), scope, ctx)?;
+        if !ctx.unify(&ty, &elem_ty) {
+            bail_at!(elem.span, "Mismatched types in array: expected {}, found {}", ctx.show(&elem_ty), ctx.show(&ty));
+        }
+    }
+    Ok(Type::Array(Box::new(elem_ty), elems.len() as u64))
+}
+
+/// Types `Enum::Variant(args)`. The enum's type arguments come from the
+/// types of `args` and, for parameters they leave open, from `expected`.
+fn analyze_variant(
+    expr: &Expr,
+    enum_name: &str,
+    variant: &str,
+    args: &[Expr],
+    expected: Option<&Type>,
+    scope: &Scope,
+    ctx: FnCtx,
+) -> Result<Type> {
+    let def = match ctx.globals.enums.get(enum_name) {
+        Some(def) => *def,
+        None => bail_at!(expr.span, "Unknown enum: {}", enum_name),
+    };
+    let decl = match def.variants.iter().find(|v| v.name == variant) {
+        Some(decl) => decl,
+        None => bail_at!(expr.span, "Enum '{}' has no variant '{}'", enum_name, variant),
+    };
+    if args.len() != decl.fields.len() {
+        bail_at!(
+            expr.span,
+            "Variant '{}::{}' expects {} values, found {}",
+            enum_name,
+            variant,
+            decl.fields.len(),
+            args.len()
+        );
+    }
+    let item = format!("{}::{}", enum_name, variant);
+    let declared = declared_type(enum_name, &def.type_params);
+    let inference = Inference::new(&def.type_params, &declared, expected, expr.span, &item, ctx);
+    for (arg, field) in args.iter().zip(&decl.fields) {
+        let (ty, fits) = inference.check(arg, field, scope, ctx)?;
+        if !fits {
+            bail_at!(
+                arg.span,
+                "Mismatched types for '{}': expected {}, found {}",
+                item,
+                inference.show(field, ctx),
+                ctx.show(&ty)
+            );
+        }
     }
+    Ok(Type::instance(enum_name, inference.finish(ctx)))
 }
 
-fn analyze_expr(expr: &Expr, scope: &Scope) -> Result<String> {
-    match expr {
-        Expr::Int(_) => Ok("int".to_string()),
-        Expr::Ident(name) => {
-            if let Some(sym) = scope.get(name) {
-                Ok(sym.ty.clone())
+/// Types the struct literal `name { fields }`, inferring the type arguments
+/// of a generic struct like `analyze_variant` does.
+fn analyze_struct_lit(
+    expr: &Expr,
+    name: &str,
+    fields: &[(String, Expr)],
+    expected: Option<&Type>,
+    scope: &Scope,
+    ctx: FnCtx,
+) -> Result<Type> {
+    let def = match ctx.globals.structs.get(name) {
+        Some(def) => *def,
+        None => bail_at!(expr.span, "Unknown struct: {}", name),
+    };
+    let declared = declared_type(name, &def.type_params);
+    let inference = Inference::new(&def.type_params, &declared, expected, expr.span, name, ctx);
+    for (i, (field, value)) in fields.iter().enumerate() {
+        let decl = match def.fields.iter().find(|f| f.name == *field) {
+            Some(decl) => decl,
+            None => bail_at!(value.span, "Struct '{}' has no field '{}'", name, field),
+        };
+        if fields[..i].iter().any(|(f, _)| f == field) {
+            bail_at!(value.span, "Field '{}' is initialized more than once", field);
+        }
+        let (ty, fits) = inference.check(value, &decl.ty, scope, ctx)?;
+        if !fits {
+            bail_at!(
+                value.span,
+                "Mismatched types for field '{}': expected {}, found {}",
+                field,
+                inference.show(&decl.ty, ctx),
+                ctx.show(&ty)
+            );
+        }
+    }
+    if let Some(missing) = def.fields.iter().find(|f| !fields.iter().any(|(name, _)| *name == f.name)) {
+        bail_at!(expr.span, "Missing field '{}' in initializer of '{}'", missing.name, name);
+    }
+    Ok(Type::instance(name, inference.finish(ctx)))
+}
+
+/// Types a call of a generic function, inferring its type arguments like
+/// `analyze_variant` does, and records them for lowering.
+fn analyze_generic_call(
+    expr: &Expr,
+    func: &Function,
+    type_args: &[Type],
+    args: &[Expr],
+    expected: Option<&Type>,
+    scope: &Scope,
+    ctx: FnCtx,
+) -> Result<Type> {
+    if args.len() != func.params.len() {
+        bail_at!(expr.span, "Function '{}' expects {} arguments, found {}", func.name, func.params.len(), args.len());
+    }
+    if func.is_unsafe {
+        check_unsafe_call(&func.name, UNSAFE_FN, expr.span, ctx)?;
+    }
+    let ret_ty = call_result(func, func.ret_ty.clone().unwrap_or(Type::Void));
+    let inference = if type_args.is_empty() {
+        Inference::new(&func.type_params, &ret_ty, expected, expr.span, &func.name, ctx)
+    } else {
+        if type_args.len() != func.type_params.len() {
+            bail_at!(
+                expr.span,
+                "Function '{}' expects {} type arguments, found {}",
+                func.name,
+                func.type_params.len(),
+                type_args.len()
+            );
+        }
+        for ty in type_args {
+            check_type(ty, expr.span, ctx.type_params, ctx.globals)?;
+        }
+        Inference { params: &func.type_params, args: type_args.to_vec() }
+    };
+    for (arg, param) in args.iter().zip(&func.params) {
+        let (ty, fits) = inference.check(arg, &param.ty, scope, ctx)?;
+        if !fits {
+            bail_at!(
+                arg.span,
+                "Mismatched types for parameter '{}' of '{}': expected {}, found {}",
+                param.name,
+                func.name,
+                inference.show(&param.ty, ctx),
+                ctx.show(&ty)
+            );
+        }
+    }
+    let type_args = inference.finish(ctx);
+    for (param, trait_name) in &func.bounds {
+        let arg = &type_args[func.type_params.iter().position(|p| p == param).unwrap()];
+        let arg = &ctx.settle(arg, expr.span)?;
+        if !implements(arg, trait_name, ctx) {
+            bail_at!(
+                expr.span,
+                "Type {} does not implement trait '{}', which '{}' requires of '{}'",
+                arg,
+                trait_name,
+                func.name,
+                param
+            );
+        }
+    }
+    let ty = ret_ty.substitute(&func.type_params, &type_args);
+    ctx.types.borrow_mut().type_args.insert(expr.span, type_args);
+    Ok(ty)
+}
+
+/// Whether `name` calls a generic function rather than a variable.
+fn is_generic_fn(name: &str, scope: &Scope, ctx: FnCtx) -> bool {
+    lookup(name, scope, ctx).is_none()
+        && matches!(ctx.globals.functions.get(name), Some(func) if !func.type_params.is_empty())
+}
+
+/// Types `alloc<T>(count)`, which allocates room for `count` values of type
+/// `T` on the heap and gives a raw pointer to the first.
+fn analyze_alloc(expr: &Expr, type_args: &[Type], args: &[Expr], scope: &Scope, ctx: FnCtx) -> Result<Type> {
+    let [elem_ty] = type_args else {
+        bail_at!(expr.span, "alloc expects 1 type argument, as in alloc<i64>(n), found {}", type_args.len())
+    };
+    check_type(elem_ty, expr.span, ctx.type_params, ctx.globals)?;
+    if args.len() != 1 {
+        bail_at!(expr.span, "alloc expects 1 argument, found {}", args.len());
+    }
+    let i64_ty = Type::named("i64");
+    let ty = analyze_expr_expecting(&args[0], Some(&i64_ty), scope, ctx)?;
+    if !ctx.unify(&ty, &i64_ty) {
+        bail_at!(args[0].span, "The element count of alloc must be i64, found {}", ctx.show(&ty));
+    }
+    Ok(Type::pointer(elem_ty.clone()))
+}
+
+/// Whether values of type `ty` have the methods of the named trait: it has
+/// an impl of the trait, or is a type parameter bounded by it.
+fn implements(ty: &Type, trait_name: &str, ctx: FnCtx) -> bool {
+    match ty {
+        Type::Named(name) if ctx.type_params.contains(name) => {
+            ctx.bounds.iter().any(|(param, bound)| param == name && bound == trait_name)
+        }
+        _ => ctx.globals.impls.iter().any(|imp| imp.trait_name.as_deref() == Some(trait_name) && imp.ty == *ty),
+    }
+}
+
+/// Finds the method `method` of values of type `ty`: one of an inherent
+/// impl, or else one declared by a trait that `ty` implements, is bounded
+/// by or, for `dyn`, stands for. Returns its declaration and its trait.
+fn find_method<'a>(
+    ty: &Type,
+    method: &str,
+    span: Span,
+    ctx: FnCtx<'a>,
+) -> Result<(&'a Function, Option<&'a TraitDef>)> {
+    let globals = ctx.globals;
+    let traits: Vec<&TraitDef> = match ty {
+        Type::Dyn(name) => vec![globals.traits[name.as_str()]],
+        Type::Named(name) if ctx.type_params.contains(name) => ctx.bounds.iter()
+            .filter(|(param, _)| param == name)
+            .map(|(_, bound)| globals.traits[bound.as_str()])
+            .collect(),
+        _ => {
+            let inherent = globals.impls.iter()
+                .filter(|imp| imp.trait_name.is_none() && imp.ty == *ty)
+                .flat_map(|imp| &imp.methods)
+                .find(|f| f.name == method);
+            if let Some(func) = inherent {
+                return Ok((func, None));
+            }
+            globals.impls.iter()
+                .filter(|imp| imp.ty == *ty)
+                .filter_map(|imp| imp.trait_name.as_deref())
+                .map(|name| globals.traits[name])
+                .collect()
+        }
+    };
+    let mut found = traits.into_iter()
+        .filter_map(|def| def.methods.iter().find(|m| m.func.name == method).map(|m| (&m.func, def)));
+    match (found.next(), found.next()) {
+        (Some((func, def)), None) => Ok((func, Some(def))),
+        (Some((_, first)), Some((_, second))) => bail_at!(
+            span,
+            "Method '{}' of {} is ambiguous: traits '{}' and '{}' both declare it",
+            method,
+            ty,
+            first.name,
+            second.name
+        ),
+        (None, _) => bail_at!(span, "Type {} has no method '{}'", ty, method),
+    }
+}
+
+/// Types `receiver.method(args)` and records how the call reaches the method.
+/// Arrays and slices have a built-in `len()`, and raw pointers `offset(n)`,
+/// the address `n` elements further on; neither is recorded.
+fn analyze_method_call(
+    expr: &Expr,
+    receiver: &Expr,
+    method: &str,
+    args: &[Expr],
+    scope: &Scope,
+    ctx: FnCtx,
+) -> Result<Type> {
+    let receiver_ty = ctx.settle(&analyze_expr(receiver, scope, ctx)?, receiver.span)?;
+    if method == "len" && elements(&receiver_ty).is_some() {
+        if !args.is_empty() {
+            bail_at!(expr.span, "Method 'len' expects 0 arguments, found {}", args.len());
+        }
+        return Ok(Type::named("i64"));
+    }
+    if method == "offset" && matches!(receiver_ty, Type::Pointer(_)) {
+        if args.len() != 1 {
+            bail_at!(expr.span, "Method 'offset' expects 1 argument, found {}", args.len());
+        }
+        let i64_ty = Type::named("i64");
+        let ty = analyze_expr_expecting(&args[0], Some(&i64_ty), scope, ctx)?;
+        if !ctx.unify(&ty, &i64_ty) {
+            bail_at!(args[0].span, "Offset must be i64, found {}", ctx.show(&ty));
+        }
+        return Ok(receiver_ty);
+    }
+    check_raw_access(&receiver_ty, expr.span, ctx)?;
+    let self_ty = receiver_ty.pointee().cloned().unwrap_or(receiver_ty);
+    let (decl, def) = find_method(&self_ty, method, expr.span, ctx)?;
+    let params = &decl.params[1..];
+    if args.len() != params.len() {
+        bail_at!(expr.span, "Method '{}' expects {} arguments, found {}", method, params.len(), args.len());
+    }
+    let self_param = ["Self".to_string()];
+    let resolve = |ty: &Type| ty.substitute(&self_param, std::slice::from_ref(&self_ty));
+    for (arg, param) in args.iter().zip(params) {
+        let expected = resolve(&param.ty);
+        let ty = analyze_expr_expecting(arg, Some(&expected), scope, ctx)?;
+        if !ctx.unify(&ty, &expected) {
+            bail_at!(
+                arg.span,
+                "Mismatched types for parameter '{}' of '{}': expected {}, found {}",
+                param.name,
+                method,
+                ctx.show(&expected),
+                ctx.show(&ty)
+            );
+        }
+    }
+    if decl.is_unsafe {
+        check_unsafe_call(method, UNSAFE_FN, expr.span, ctx)?;
+    }
+    let ret_ty = call_result(decl, decl.ret_ty.as_ref().map_or(Type::Void, resolve));
+    let slot = match (&self_ty, def) {
+        (Type::Dyn(_), Some(def)) => def.methods.iter().position(|m| m.func.name == method).map(|i| i as u32),
+        _ => None,
+    };
+    let resolved = MethodRef { trait_name: def.map(|def| def.name.clone()), self_ty, slot };
+    ctx.types.borrow_mut().methods.insert(expr.span, resolved);
+    Ok(ret_ty)
+}
+
+/// Types the operand of `?` and returns the type it unwraps to.
+fn analyze_try(expr: &Expr, operand: &Expr, scope: &Scope, ctx: FnCtx) -> Result<Type> {
+    let ty = ctx.settle(&analyze_expr(operand, scope, ctx)?, operand.span)?;
+    let ret_ty = ctx.ret_ty.cloned().unwrap_or(Type::Void);
+    let (name, args) = match &ty {
+        Type::Generic(name, args) if name == "Option" || name == "Result" => (name, args),
+        _ => bail_at!(operand.span, "The `?` operator applies to Option and Result values, found {}", ctx.show(&ty)),
+    };
+    if ret_ty.decl_name() != Some(name.as_str()) {
+        bail_at!(
+            expr.span,
+            "The `?` operator on {} can only be used in a function that returns {}, but this one returns {}",
+            ctx.show(&ty),
+            name,
+            ret_ty
+        );
+    }
+    if name == "Result" && !ctx.unify(&args[1], &ret_ty.type_args()[1]) {
+        bail_at!(
+            expr.span,
+            "The `?` operator cannot return an error of type {} from a function returning {}",
+            ctx.show(&args[1]),
+            ret_ty
+        );
+    }
+    Ok(args[0].clone())
+}
+
+fn analyze_expr(expr: &Expr, scope: &Scope, ctx: FnCtx) -> Result<Type> {
+    analyze_expr_expecting(expr, None, scope, ctx)
+}
+
+/// Types `expr` and records the result for lowering. `expected` is the type
+/// the context requires, if known; it only fills in type arguments the
+/// expression leaves open, as in `Option::None`, gives closure parameters
+/// their types, and converts values as `coerces` allows, so callers still
+/// compare the result against it.
+fn analyze_expr_expecting(expr: &Expr, expected: Option<&Type>, scope: &Scope, ctx: FnCtx) -> Result<Type> {
+    let ty = ctx.apply(&expr_type(expr, expected, scope, ctx)?);
+    ctx.types.borrow_mut().exprs.insert(expr.span, ty.clone());
+    if let Some(target) = expected.filter(|target| coerces(&ty, target, ctx)) {
+        ctx.types.borrow_mut().coercions.insert(expr.span, target.clone());
+        return Ok(target.clone());
+    }
+    Ok(ty)
+}
+
+/// Whether a value of type `ty` converts implicitly to `target`: a reference
+/// or pointer to a `dyn` of a trait its pointee implements, a reference to
+/// a raw pointer, a `&mut` to a shared reference, or a function pointer to
+/// a closure with the same signature.
+fn coerces(ty: &Type, target: &Type, ctx: FnCtx) -> bool {
+    match (ty, target) {
+        (Type::Pointer(pointee) | Type::Ref(pointee, _), Type::Dyn(trait_name)) => {
+            implements(pointee, trait_name, ctx)
+        }
+        (Type::Ref(pointee, _), Type::Pointer(target)) | (Type::Ref(pointee, true), Type::Ref(target, false)) => {
+            pointee == target
+        }
+        (Type::FnPtr(params, ret), Type::Closure(target_params, target_ret)) => {
+            params == target_params && ret == target_ret
+        }
+        _ => false,
+    }
+}
+
+/// The variable `name`, which may be one a closure captures.
+fn lookup(name: &str, scope: &Scope, ctx: FnCtx) -> Option<Symbol> {
+    ctx.unused.borrow_mut().retain(|(var, ..)| var.as_deref() != Some(name));
+    match scope.get(name) {
+        Some(sym) => Some(sym.clone()),
+        None => ctx.captures?.capture(name),
+    }
+}
+
+/// Why calls of an `unsafe fn` need an `unsafe` block.
+const UNSAFE_FN: &str = "it is declared `unsafe`";
+
+/// Checks that a call of `callee`, which needs an `unsafe` block for
+/// `reason`, is inside one.
+fn check_unsafe_call(callee: &str, reason: &str, span: Span, ctx: FnCtx) -> Result<()> {
+    if !ctx.in_unsafe {
+        bail_at!(span, "Calling '{}' requires an `unsafe` block, as {}", callee, reason);
+    }
+    Ok(())
+}
+
+/// Types a call through the function pointer or closure of type `callee_ty`
+/// and records it for lowering.
+fn analyze_value_call(expr: &Expr, callee_ty: Type, args: &[Expr], scope: &Scope, ctx: FnCtx) -> Result<Type> {
+    let callee_ty = ctx.settle(&callee_ty, expr.span)?;
+    let (params, ret_ty) = match &callee_ty {
+        Type::FnPtr(params, ret_ty) | Type::Closure(params, ret_ty) => (params, (**ret_ty).clone()),
+        _ => bail_at!(expr.span, "Cannot call a value of type {}", callee_ty),
+    };
+    if args.len() != params.len() {
+        bail_at!(expr.span, "Function of type {} expects {} arguments, found {}", callee_ty, params.len(), args.len());
+    }
+    for (i, (arg, param)) in args.iter().zip(params).enumerate() {
+        let ty = analyze_expr_expecting(arg, Some(param), scope, ctx)?;
+        if !ctx.unify(&ty, param) {
+            bail_at!(
+                arg.span,
+                "Mismatched types for argument {} of {}: expected {}, found {}",
+                i + 1,
+                ctx.show(&callee_ty),
+                ctx.show(param),
+                ctx.show(&ty)
+            );
+        }
+    }
+    ctx.types.borrow_mut().indirect_calls.insert(expr.span, callee_ty);
+    Ok(ret_ty)
+}
+
+/// Types a closure. Parameters without a type take it from `expected`, and
+/// the closure is a plain function pointer if it captures nothing.
+fn analyze_lambda(expr: &Expr, lambda: &Lambda, expected: Option<&Type>, scope: &Scope, ctx: FnCtx) -> Result<Type> {
+    let (hinted_params, hinted_ret) = match expected {
+        Some(Type::FnPtr(params, ret) | Type::Closure(params, ret)) if params.len() == lambda.params.len() => {
+            (Some(params), Some(&**ret))
+        }
+        _ => (None, None),
+    };
+    let mut inner = Scope::new();
+    let mut params = Vec::new();
+    for (i, param) in lambda.params.iter().enumerate() {
+        let ty = match (&param.ty, hinted_params) {
+            (Some(ty), _) => {
+                check_type(ty, param.span, ctx.type_params, ctx.globals)?;
+                ty.clone()
+            }
+            (None, Some(hinted)) => hinted[i].clone(),
+            (None, None) => {
+                let what = format!("the type of closure parameter '{}'", source_name(&param.name));
+                ctx.fresh(VarKind::Any, param.span, what, "annotate it")
+            }
+        };
+        inner.insert(param.name.clone(), ty.clone(), false);
+        params.push(ty);
+    }
+    if let Some(ret_ty) = &lambda.ret_ty {
+        check_type(ret_ty, expr.span, ctx.type_params, ctx.globals)?;
+    }
+    let captures = Captures { scope, outer: ctx.captures, vars: RefCell::new(Vec::new()) };
+    let inner_ctx = FnCtx { ret_ty: lambda.ret_ty.as_ref(), in_loop: false, in_async: false, captures: Some(&captures), ..ctx };
+    let ret_ty = match &lambda.body {
+        LambdaBody::Expr(body) => {
+            let hint = hinted_ret.filter(|ty| **ty != Type::Void);
+            analyze_expr_expecting(body, hint, &inner, inner_ctx)?
+        }
+        LambdaBody::Block(body) => {
+            analyze_block(body, &mut inner, inner_ctx)?;
+            if lambda.ret_ty.is_some() && !block_returns(body) {
+                bail_at!(expr.span, "Closure does not return a value on all paths");
+            }
+            lambda.ret_ty.clone().unwrap_or(Type::Void)
+        }
+    };
+    let captures = captures.vars.into_inner();
+    let ty = if captures.is_empty() {
+        Type::FnPtr(params, Box::new(ret_ty))
+    } else {
+        Type::Closure(params, Box::new(ret_ty))
+    };
+    ctx.types.borrow_mut().captures.insert(expr.span, captures);
+    Ok(ty)
+}
+
+fn expr_type(expr: &Expr, expected: Option<&Type>, scope: &Scope, ctx: FnCtx) -> Result<Type> {
+    match &expr.kind {
+        ExprKind::Int { value, ty } => int_literal_type(*value, ty.as_deref(), false, expr.span, expected, ctx),
+        ExprKind::Float { ty, .. } => match (ty, expected.map(|ty| ctx.apply(ty))) {
+            (Some(suffix), _) => Ok(Type::named(suffix.as_str())),
+            (None, Some(expected)) if expected.is_float() => Ok(expected),
+            (None, _) => Ok(ctx.fresh(VarKind::Float, expr.span, "the type of this float literal", "")),
+        },
+        ExprKind::Bool(_) => Ok(Type::named("bool")),
+        ExprKind::Char(_) => Ok(Type::named("char")),
+        ExprKind::Str(_) => Ok(Type::named("string")),
+        ExprKind::Ident(name) => {
+            if let Some(sym) = lookup(name, scope, ctx) {
+                return Ok(sym.ty);
+            }
+            match ctx.globals.functions.get(name.as_str()) {
+                Some(func) if func.is_unsafe => {
+                    bail_at!(expr.span, "Unsafe function '{}' can only be called, not used as a value", name)
+                }
+                Some(func) if func.type_params.is_empty() => {
+                    ctx.types.borrow_mut().fn_values.insert(expr.span);
+                    let params = func.params.iter().map(|p| p.ty.clone()).collect();
+                    let ret_ty = call_result(func, func.ret_ty.clone().unwrap_or(Type::Void));
+                    Ok(Type::FnPtr(params, Box::new(ret_ty)))
+                }
+                Some(_) => bail_at!(expr.span, "Generic function '{}' can only be called, not used as a value", name),
+                None => bail_at!(expr.span, "Undefined variable: {}", name),
+            }
+        }
+        ExprKind::Call { name, type_args, args } if name == "alloc" => analyze_alloc(expr, type_args, args, scope, ctx),
+        ExprKind::Call { name, args, .. } if name == "free" => {
+            if args.len() != 1 {
+                bail_at!(expr.span, "free expects 1 argument, found {}", args.len());
+            }
+            let ty = analyze_expr(&args[0], scope, ctx)?;
+            if !matches!(ty, Type::Pointer(_)) {
+                bail_at!(args[0].span, "free expects a raw pointer from alloc, found {}", ctx.show(&ty));
+            }
+            if !ctx.in_unsafe {
+                bail_at!(expr.span, "Calling free requires an `unsafe` block");
+            }
+            Ok(Type::Void)
+        }
+        ExprKind::Call { name, type_args, .. } if !type_args.is_empty() && !is_generic_fn(name, scope, ctx) => {
+            bail_at!(expr.span, "'{}' is not a generic function and takes no type arguments", name)
+        }
+        ExprKind::Call { name, args, .. } if name == "print" => {
+            if args.len() != 1 {
+                bail_at!(expr.span, "print expects 1 argument, found {}", args.len());
+            }
+            let ty = analyze_expr(&args[0], scope, ctx)?;
+            if !ty.is_primitive() && !ctx.is_numeric(&ty) {
+                bail_at!(args[0].span, "Cannot print a value of type {}", ctx.show(&ty));
+            }
+            Ok(Type::Void)
+        }
+        ExprKind::Call { name, type_args, args } => {
+            if let Some(Symbol { ty: callee_ty, .. }) = lookup(name, scope, ctx) {
+                return analyze_value_call(expr, callee_ty, args, scope, ctx);
+            }
+            if let Some(func) = ctx.globals.functions.get(name.as_str()).filter(|f| !f.type_params.is_empty()) {
+                return analyze_generic_call(expr, func, type_args, args, expected, scope, ctx);
+            }
+            let Some(proto) = ctx.globals.prototypes.get(name.as_str()) else {
+                bail_at!(expr.span, "Undefined function: {}", name)
+            };
+            if args.len() != proto.params.len() {
+                bail_at!(expr.span, "Function '{}' expects {} arguments, found {}", name, proto.params.len(), args.len());
+            }
+            if let Some(reason) = proto.unsafe_reason {
+                check_unsafe_call(name, reason, expr.span, ctx)?;
+            }
+            for (arg, param) in args.iter().zip(proto.params) {
+                let ty = analyze_expr_expecting(arg, Some(&param.ty), scope, ctx)?;
+                if !ctx.unify(&ty, &param.ty) {
+                    bail_at!(
+                        arg.span,
+                        "Mismatched types for parameter '{}' of '{}': expected {}, found {}",
+                        param.name,
+                        name,
+                        param.ty,
+                        ctx.show(&ty)
+                    );
+                }
+            }
+            Ok(proto.ret_ty.clone())
+        }
+        ExprKind::CallValue { callee, args } => {
+            let callee_ty = analyze_expr(callee, scope, ctx)?;
+            analyze_value_call(expr, callee_ty, args, scope, ctx)
+        }
+        ExprKind::MethodCall { receiver, method, args } => analyze_method_call(expr, receiver, method, args, scope, ctx),
+        ExprKind::StructLit { name, fields } => analyze_struct_lit(expr, name, fields, expected, scope, ctx),
+        ExprKind::Field { expr: base, field } => {
+            let base_ty = ctx.settle(&analyze_expr(base, scope, ctx)?, base.span)?;
+            let (struct_ty, def) = match struct_of(&base_ty, ctx.globals) {
+                Some(found) => found,
+                None => bail_at!(expr.span, "Type {} has no fields", base_ty),
+            };
+            check_raw_access(&base_ty, expr.span, ctx)?;
+            match def.fields.iter().find(|f| f.name == *field) {
+                Some(decl) => Ok(decl.ty.substitute(&def.type_params, struct_ty.type_args())),
+                None => bail_at!(expr.span, "Struct '{}' has no field '{}'", def.name, field),
+            }
+        }
+        ExprKind::Variant { enum_name, variant, args } => {
+            analyze_variant(expr, enum_name, variant, args, expected, scope, ctx)
+        }
+        ExprKind::Match { scrutinee, arms } => analyze_match(expr, scrutinee, arms, expected, scope, ctx),
+        ExprKind::Try(operand) => analyze_try(expr, operand, scope, ctx),
+        ExprKind::Await(_) if !ctx.in_async => {
+            bail_at!(expr.span, "`await` is only allowed inside an async function")
+        }
+        ExprKind::Await(operand) => {
+            let hint = expected.map(|ty| Type::future(ty.clone()));
+            let ty = analyze_expr_expecting(operand, hint.as_ref(), scope, ctx)?;
+            match ctx.settle(&ty, operand.span)?.future_output() {
+                Some(output) => Ok(output.clone()),
+                None => bail_at!(operand.span, "Cannot await a value of type {}, which is not a future", ctx.show(&ty)),
+            }
+        }
+        ExprKind::Spawn(operand) => {
+            let ty = ctx.settle(&analyze_expr(operand, scope, ctx)?, operand.span)?;
+            if ty.future_output().is_none() {
+                bail_at!(
+                    operand.span,
+                    "Cannot spawn a value of type {}; `spawn` takes a future, such as a call of an async function",
+                    ty
+                );
+            }
+            Ok(Type::Void)
+        }
+        ExprKind::Lambda(lambda) => analyze_lambda(expr, lambda, expected, scope, ctx),
+        ExprKind::ArrayLit(elems) => analyze_array(expr, elems, expected, scope, ctx),
+        ExprKind::ArrayRepeat { value, count } => {
+            let hint = match expected {
+                Some(Type::Array(elem, _)) => Some(&**elem),
+                _ => None,
+            };
+            let ty = analyze_expr_expecting(value, hint, scope, ctx)?;
+            if ty == Type::Void {
+                bail_at!(value.span, "Expression does not produce a value");
+            }
+            Ok(Type::Array(Box::new(ty), *count))
+        }
+        ExprKind::Index { expr: base, index } => {
+            let base_ty = ctx.settle(&analyze_expr(base, scope, ctx)?, base.span)?;
+            let Some((elem, len)) = elements(&base_ty) else {
+                bail_at!(expr.span, "Cannot index a value of type {}", base_ty)
+            };
+            check_raw_access(&base_ty, expr.span, ctx)?;
+            analyze_bound(index, len, false, scope, ctx)?;
+            Ok(elem.clone())
+        }
+        ExprKind::Slice { expr: base, start, end } => {
+            analyze_slice(expr, base, start.as_deref(), end.as_deref(), scope, ctx)
+        }
+        ExprKind::AddrOf { expr: place, mutable } => {
+            if !is_place(place, scope, ctx)? {
+                bail_at!(place.span, "Cannot take the address of a temporary value");
+            }
+            let ty = analyze_expr(place, scope, ctx)?;
+            if *mutable {
+                check_mutable(place, scope, ctx)?;
+            }
+            Ok(Type::Ref(Box::new(ty), *mutable))
+        }
+        ExprKind::Deref(operand) => {
+            let ty = ctx.settle(&analyze_expr(operand, scope, ctx)?, operand.span)?;
+            let Some(pointee) = ty.pointee() else {
+                bail_at!(expr.span, "Cannot dereference a value of type {}", ty)
+            };
+            check_raw_access(&ty, expr.span, ctx)?;
+            Ok(pointee.clone())
+        }
+        ExprKind::Binary { op, lhs, rhs } => {
+            let lhs_ty = analyze_expr(lhs, scope, ctx)?;
+            let rhs_ty = analyze_expr_expecting(rhs, Some(&lhs_ty), scope, ctx)?;
+            if !ctx.unify(&lhs_ty, &rhs_ty) {
+                bail_at!(
+                    expr.span,
+                    "Mismatched operand types for {:?}: {} and {}",
+                    op,
+                    ctx.show(&lhs_ty),
+                    ctx.show(&rhs_ty)
+                );
+            }
+            let lhs_ty = ctx.apply(&lhs_ty);
+            let valid = match op {
+                _ if op.is_logical() => ctx.unify(&lhs_ty, &Type::named("bool")),
+                BinOp::Eq | BinOp::Ne => {
+                    (lhs_ty.is_primitive() && !lhs_ty.is("string"))
+                        || ctx.is_numeric(&lhs_ty)
+                        || matches!(lhs_ty, Type::Pointer(_))
+                }
+                _ if op.is_comparison() => ctx.is_numeric(&lhs_ty) || lhs_ty.is("char"),
+                _ => ctx.is_numeric(&lhs_ty),
+            };
+            if !valid {
+                bail_at!(expr.span, "Operator {:?} is not defined for {}", op, ctx.show(&lhs_ty));
+            }
+            if op.is_logical() || op.is_comparison() {
+                Ok(Type::named("bool"))
             } else {
-                bail!("Undefined variable: {}", name)
+                Ok(lhs_ty)
             }
         }
-        Expr::Call { name, args } => {
-            // For now, assume all calls return int
-            for arg in args {
-                analyze_expr(arg, scope)?;
+        ExprKind::Unary { op, expr: operand } => {
+            let ty = match &operand.kind {
+                ExprKind::Int { value, ty } if *op == UnaryOp::Neg => {
+                    let ty = ctx.apply(&int_literal_type(*value, ty.as_deref(), true, operand.span, expected, ctx)?);
+                    ctx.types.borrow_mut().exprs.insert(operand.span, ty.clone());
+                    ty
+                }
+                _ => analyze_expr_expecting(operand, expected, scope, ctx)?,
+            };
+            let float = ty.is_float() || ctx.infer.borrow().kind(&ty) == Some(VarKind::Float);
+            match op {
+                // A type still being inferred becomes a signed integer type.
+                UnaryOp::Neg if matches!(ty, Type::Var(_)) && ctx.infer.borrow_mut().restrict(&ty, VarKind::Signed) => {
+                    Ok(ty)
+                }
+                UnaryOp::Neg if !ty.is_signed() && !float => {
+                    bail_at!(expr.span, "Cannot negate a value of type {}", ctx.show(&ty))
+                }
+                UnaryOp::Not if !ctx.unify(&ty, &Type::named("bool")) => {
+                    bail_at!(expr.span, "Operator ! expects a bool operand, found {}", ctx.show(&ty))
+                }
+                _ => Ok(ty),
             }
-            Ok("int".to_string())
         }
     }
 }
+
+/// The type of the integer literal `value` at `span`, checking that it fits.
+/// A `negated` literal, the operand of `-`, may be one more than the largest
+/// value of its type, as in `-128i8`.
+fn int_literal_type(
+    value: u64,
+    suffix: Option<&str>,
+    negated: bool,
+    span: Span,
+    expected: Option<&Type>,
+    ctx: FnCtx,
+) -> Result<Type> {
+    let ty = match (suffix, expected.map(|ty| ctx.apply(ty))) {
+        (Some(suffix), _) => Type::named(suffix),
+        (None, Some(expected)) if expected.is_integer() => expected,
+        // Numeric variables default to `i64` or `f64` rather than going
+        // uninferred, so they need no advice.
+        (None, _) => {
+            let var = ctx.fresh(VarKind::Int, span, "the type of this integer literal", "");
+            ctx.infer.borrow_mut().literal(var.clone(), value, negated, span);
+            return Ok(var);
+        }
+    };
+    if !types::int_fits(value, negated, ty.as_named().unwrap()) {
+        bail_at!(span, "Integer literal {}{} does not fit in {}", if negated { "-" } else { "" }, value, ty);
+    }
+    Ok(ty)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::source_name;
+    use crate::ast::Span;
+    use crate::test_util::{analyze_package, check, error};
+
+    const MAYBE: &str = "enum Maybe { Some(i64), None }\nenum Nested { Some(Maybe), None }\n";
+
+    #[test]
+    fn break_and_continue_are_allowed_in_loops() {
+        check(
+            "fn main() {
+    let n = 0;
+    while n < 10 {
+        if n == 5 { break; } else { continue; }
+    }
+    loop {
+        while n > 0 { break; }
+        break;
+    }
+}
+",
+        );
+    }
+
+    #[test]
+    fn break_outside_a_loop_is_rejected() {
+        let message = "semantic error: `break` and `continue` are only allowed inside a loop";
+        assert_eq!(error("fn main() {\n    break;\n}\n"), format!("main.yul:2:5: {}", message));
+        assert_eq!(error("fn main() {\n    if 1 < 2 { continue; }\n}\n"), format!("main.yul:2:16: {}", message));
+        assert_eq!(error("fn main() {\n    loop { break; }\n    break;\n}\n"), format!("main.yul:3:5: {}", message));
+    }
+
+    #[test]
+    fn conditions_must_be_bool() {
+        assert_eq!(
+            error("fn main() {\n    while 1 { break; }\n}\n"),
+            "main.yul:2:11: semantic error: Condition must be bool, found i64"
+        );
+        assert_eq!(
+            error("fn main() {\n    if 1 + 2 {}\n}\n"),
+            "main.yul:2:8: semantic error: Condition must be bool, found i64"
+        );
+    }
+
+    #[test]
+    fn every_path_must_return_a_value() {
+        check(
+            "fn sign(n: i64) -> i64 {
+    if n < 0 { return -1; } else if n == 0 { return 0; } else { return 1; }
+}
+fn spin() -> i64 {
+    loop {}
+}
+",
+        );
+        let expected = "main.yul:1:1: semantic error: Function 'f' does not return a value on all paths";
+        assert_eq!(error("fn f(n: i64) -> i64 {\n    if n > 0 { return 1; }\n}\n"), expected);
+        assert_eq!(error("fn f() -> i64 {\n    loop { break; }\n}\n"), expected);
+    }
+
+    #[test]
+    fn returns_must_match_the_declared_type() {
+        assert_eq!(
+            error("fn f() -> i64 {\n    return 1 < 2;\n}\n"),
+            "main.yul:2:12: semantic error: Mismatched return type: expected i64, found bool"
+        );
+        assert_eq!(
+            error("fn f() -> i64 {\n    return;\n}\n"),
+            "main.yul:2:5: semantic error: Missing return value of type i64"
+        );
+        assert_eq!(
+            error("fn f() {\n    return 1;\n}\n"),
+            "main.yul:2:12: semantic error: Cannot return a value from a function without a return type"
+        );
+    }
+
+    #[test]
+    fn print_takes_one_primitive_value() {
+        check("fn main() {\n    print(\"n = \");\n    print(1 + 2);\n    print(true);\n    print(1.5);\n    print('c');\n}\n");
+        assert_eq!(
+            error("fn main() {\n    print();\n}\n"),
+            "main.yul:2:5: semantic error: print expects 1 argument, found 0"
+        );
+        assert_eq!(
+            error("fn main() {\n    print(1, 2);\n}\n"),
+            "main.yul:2:5: semantic error: print expects 1 argument, found 2"
+        );
+        assert_eq!(
+            error("fn main() {\n    print(print(1));\n}\n"),
+            "main.yul:2:11: semantic error: Cannot print a value of type void"
+        );
+    }
+
+    #[test]
+    fn struct_literals_initialize_every_field_once() {
+        check("struct P { x: i64, y: i64 }\nfn main() {\n    let p = P { y: 2, x: 1 };\n}\n");
+        assert_eq!(
+            error("struct P { x: i64, y: i64 }\nfn main() {\n    let p = P { x: 1 };\n}\n"),
+            "main.yul:3:13: semantic error: Missing field 'y' in initializer of 'P'"
+        );
+        assert_eq!(
+            error("struct P { x: i64, y: i64 }\nfn main() {\n    let p = P { x: 1, y: 2, z: 3 };\n}\n"),
+            "main.yul:3:32: semantic error: Struct 'P' has no field 'z'"
+        );
+        assert_eq!(
+            error("struct P { x: i64, y: i64 }\nfn main() {\n    let p = P { x: 1, x: 2, y: 3 };\n}\n"),
+            "main.yul:3:26: semantic error: Field 'x' is initialized more than once"
+        );
+        assert_eq!(
+            error("struct P { x: i64, y: i64 }\nfn main() {\n    let p = P { x: 1, y: true };\n}\n"),
+            "main.yul:3:26: semantic error: Mismatched types for field 'y': expected i64, found bool"
+        );
+    }
+
+    #[test]
+    fn fields_are_read_through_values_and_pointers() {
+        check(
+            "struct P { x: i64 }
+fn get(p: &P) -> i64 {
+    return p.x;
+}
+fn raw(p: *P) -> i64 {
+    unsafe {
+        return p.x;
+    }
+}
+fn main() {
+    let p = P { x: 1 };
+    let n = get(&p) + raw(&p) + p.x;
+}
+",
+        );
+        assert_eq!(
+            error("struct P { x: i64 }\nfn main() {\n    let p = P { x: 1 };\n    let n = p.y;\n}\n"),
+            "main.yul:4:13: semantic error: Struct 'P' has no field 'y'"
+        );
+        assert_eq!(
+            error("fn main() {\n    let n = 1;\n    let m = n.x;\n}\n"),
+            "main.yul:3:13: semantic error: Type i64 has no fields"
+        );
+    }
+
+    #[test]
+    fn nested_payload_patterns_are_exhaustive() {
+        check(
+            "enum Shape { Dot, Line(i64), Pair(Maybe, bool) }
+enum Maybe { Some(i64), None }
+fn f(s: Shape) -> i64 {
+    return match s {
+        Shape::Dot => 0,
+        Shape::Line(n) => n,
+        Shape::Pair(Maybe::Some(n), true) => n,
+        Shape::Pair(Maybe::Some(_), false) => 1,
+        Shape::Pair(Maybe::None, _) => 2,
+    };
+}
+fn main() { print(f(Shape::Dot)); }
+",
+        );
+    }
+
+    #[test]
+    fn wildcard_and_binding_arms_cover_the_rest() {
+        check(&format!(
+            "{MAYBE}fn f(o: Maybe) -> i64 {{
+    return match o {{ Maybe::Some(3) => 1, _ => 0 }};
+}}
+fn g(n: i64) -> i64 {{
+    return match n {{ 0 => 1, m => m * 2 }};
+}}
+fn main() {{ print(f(Maybe::None) + g(4)); }}
+"
+        ));
+    }
+
+    #[test]
+    fn missing_variants_are_reported() {
+        assert_eq!(
+            error(&format!(
+                "{MAYBE}fn f(o: Nested) -> i64 {{
+    return match o {{ Nested::Some(Maybe::Some(n)) => n, Nested::None => 0 }};
+}}
+fn main() {{ print(f(Nested::None)); }}
+"
+            )),
+            "main.yul:4:12: semantic error: Non-exhaustive match: pattern `Nested::Some(Maybe::None)` is not covered"
+        );
+    }
+
+    #[test]
+    fn redundant_arms_are_reported() {
+        assert_eq!(
+            error(&format!(
+                "{MAYBE}fn f(o: Maybe) -> i64 {{
+    return match o {{ Maybe::Some(n) => n, Maybe::None => 0, Maybe::Some(1) => 1 }};
+}}
+fn main() {{ print(f(Maybe::None)); }}
+"
+            )),
+            "main.yul:4:61: semantic error: Unreachable match arm: earlier arms already cover this pattern"
+        );
+    }
+
+    #[test]
+    fn bare_variant_names_do_not_bind() {
+        assert_eq!(
+            error(&format!(
+                "{MAYBE}fn f(o: Maybe) -> i64 {{
+    return match o {{ Maybe::Some(n) => n, None => 0 }};
+}}
+fn main() {{ print(f(Maybe::None)); }}
+"
+            )),
+            "main.yul:4:43: semantic error: Pattern `None` binds a new variable that matches any value; \
+             write `Maybe::None` to match the variant"
+        );
+    }
+
+    #[test]
+    fn question_mark_unwraps_and_propagates() {
+        check(
+            "fn half(n: i64) -> Option<i64> {
+    if n % 2 == 0 { return Option::Some(n / 2) }
+    return Option::None
+}
+fn quarter(n: i64) -> Option<i64> {
+    let h: i64 = half(n)?
+    return half(h)
+}
+fn parse(n: i64) -> Result<i64, bool> {
+    if n < 0 { return Result::Err(false) }
+    return Result::Ok(n)
+}
+fn sum(a: i64, b: i64) -> Result<i64, bool> {
+    return Result::Ok(parse(a)? + parse(b)?)
+}
+fn main() { print(match quarter(8) { Option::Some(n) => n, Option::None => 0 }) }
+",
+        );
+    }
+
+    #[test]
+    fn question_mark_needs_an_option_or_result() {
+        assert_eq!(
+            error("fn f(n: i64) -> Option<i64> { return Option::Some(n?) }\nfn main() {}\n"),
+            "main.yul:1:51: semantic error: The `?` operator applies to Option and Result values, found i64"
+        );
+    }
+
+    #[test]
+    fn question_mark_needs_a_matching_return_type() {
+        assert_eq!(
+            error("fn f(o: Option<i64>) -> i64 { return o? }\nfn main() {}\n"),
+            "main.yul:1:38: semantic error: The `?` operator on Option<i64> can only be used in a function \
+             that returns Option, but this one returns i64"
+        );
+        assert_eq!(
+            error("fn f(r: Result<i64, bool>) -> Result<i64, i64> { return Result::Ok(r?) }\nfn main() {}\n"),
+            "main.yul:1:68: semantic error: The `?` operator cannot return an error of type bool from a function \
+             returning Result<i64, i64>"
+        );
+    }
+
+    const SHAPES: &str = "struct Circle { r: f64 }
+struct Square { s: f64 }
+trait Shape {
+    fn area(self) -> f64
+    fn double(self) -> f64 { return self.area() * 2.0 }
+}
+impl Shape for Circle {
+    fn area(self) -> f64 { return self.r * self.r * 3.0 }
+}
+";
+
+    #[test]
+    fn traits_dispatch_statically_and_through_dyn() {
+        check(&format!(
+            "{SHAPES}impl Shape for Square {{
+    fn area(self) -> f64 {{ return self.s * self.s }}
+    fn double(self) -> f64 {{ return 0.0 }}
+}}
+fn total<T: Shape>(a: T, b: T) -> f64 {{ return a.double() + b.area() }}
+fn show(s: dyn Shape) {{ print(s.double()) }}
+fn main() {{
+    let c = Circle {{ r: 1.0 }}
+    print(total(Circle {{ r: 2.0 }}, Circle {{ r: 3.0 }}))
+    let q = Square {{ s: 2.0 }}
+    show(&c)
+    show(&q)
+}}
+"
+        ));
+    }
+
+    #[test]
+    fn impls_must_provide_required_methods() {
+        assert_eq!(
+            error(&format!("{SHAPES}impl Shape for Square {{ fn double(self) -> f64 {{ return 0.0 }} }}\nfn main() {{}}\n")),
+            "main.yul:10:1: semantic error: Missing method 'area' in the impl of 'Shape' for Square"
+        );
+    }
+
+    #[test]
+    fn bounds_are_checked_at_calls() {
+        assert_eq!(
+            error(&format!(
+                "{SHAPES}fn area<T: Shape>(t: T) -> f64 {{ return t.area() }}\nfn main() {{ print(area(Square {{ s: 1.0 }})) }}\n"
+            )),
+            "main.yul:11:19: semantic error: Type Square does not implement trait 'Shape', which 'area' requires of 'T'"
+        );
+    }
+
+    #[test]
+    fn bounds_are_checked_once_literals_have_their_types() {
+        check(
+            "trait Area { fn area(self) -> i64 }
+struct Pair<T> { a: T, b: T }
+impl Area for Pair<i64> { fn area(self) -> i64 { return self.a * self.b } }
+fn total<T: Area>(x: T) -> i64 { return x.area() }
+fn main() { print(total(Pair { a: 3, b: 4 })) }
+",
+        );
+    }
+
+    #[test]
+    fn dyn_traits_cannot_take_self() {
+        assert_eq!(
+            error(
+                "trait Same { fn same(self, other: Self) -> bool }
+fn f(s: dyn Same) {}
+fn main() {}
+"
+            ),
+            "main.yul:2:6: semantic error: Trait 'Same' cannot be used as `dyn` because method 'same' uses `Self` \
+             other than as `self`"
+        );
+    }
+
+    /// What the closures of `source` capture, in source order.
+    fn captures(source: &str) -> Vec<Vec<(String, String)>> {
+        let (_, types) = check(source);
+        let mut closures: Vec<_> = types.captures.iter().collect();
+        closures.sort_by_key(|(span, _)| span.start);
+        closures.into_iter()
+            .map(|(_, vars)| vars.iter().map(|(name, ty)| (source_name(name).to_string(), ty.to_string())).collect())
+            .collect()
+    }
+
+    #[test]
+    fn closures_capture_the_variables_they_use() {
+        let captured = captures(
+            "fn main() {
+    let a = 1
+    let b = true
+    let f = |x: i64| { if b { print(x + a) } else { print(a) } }
+    f(2)
+}
+",
+        );
+        assert_eq!(captured, [[("b".to_string(), "bool".to_string()), ("a".to_string(), "i64".to_string())]]);
+    }
+
+    #[test]
+    fn nested_closures_capture_through_the_outer_one() {
+        let captured = captures(
+            "fn main() {
+    let a = 1
+    let f = |x: i64| { let g = |y: i64| y + a
+        print(g(x)) }
+    f(2)
+}
+",
+        );
+        let a = vec![("a".to_string(), "i64".to_string())];
+        assert_eq!(captured, [a.clone(), a]);
+    }
+
+    #[test]
+    fn closures_without_captures_are_function_pointers() {
+        check("fn apply(f: fn(i64) -> i64) -> i64 { return f(1) }\nfn main() { print(apply(|x: i64| x + 1)) }\n");
+        assert_eq!(
+            error(
+                "fn apply(f: fn(i64) -> i64) -> i64 { return f(1) }
+fn main() {
+    let a = 1
+    print(apply(|x: i64| x + a))
+}
+"
+            ),
+            "main.yul:4:17: semantic error: Mismatched types for parameter 'f' of 'apply': expected fn(i64) -> i64, \
+             found Fn(i64) -> i64"
+        );
+    }
+
+    #[test]
+    fn escaping_closures_must_move_their_captures() {
+        check("fn adder(n: i64) -> Fn(i64) -> i64 { return move |x: i64| x + n }\nfn main() { print(adder(1)(2)) }\n");
+        assert_eq!(
+            error("fn adder(n: i64) -> Fn(i64) -> i64 { return |x: i64| x + n }\nfn main() { print(adder(1)(2)) }\n"),
+            "main.yul:1:45: semantic error: Cannot return a closure that captures `n` by reference, as `n` does not \
+             outlive the function; capture it with `move`"
+        );
+    }
+
+    #[test]
+    fn literal_indices_into_arrays_are_checked_now() {
+        check("fn main() {\n    let a = [1, 2, 3]\n    let s = a[0..3]\n    print(s[5])\n    print(a[2])\n}\n");
+        assert_eq!(
+            error("fn main() {\n    let a = [1, 2, 3]\n    print(a[3])\n}\n"),
+            "main.yul:3:13: semantic error: Index 3 is out of bounds for an array of length 3"
+        );
+        assert_eq!(
+            error("fn main() {\n    let a = [1, 2, 3]\n    let s = a[1..4]\n}\n"),
+            "main.yul:3:18: semantic error: Slice bound 4 is out of bounds for an array of length 3"
+        );
+        assert_eq!(
+            error("fn main() {\n    let a = [1, 2, 3]\n    let s = a[2..1]\n}\n"),
+            "main.yul:3:13: semantic error: Slice starts at 2 but ends at 1"
+        );
+    }
+
+    #[test]
+    fn indices_are_i64() {
+        assert_eq!(
+            error("fn main() {\n    let a = [1, 2, 3]\n    let i: u8 = 1\n    print(a[i])\n}\n"),
+            "main.yul:4:13: semantic error: Index must be i64, found u8"
+        );
+    }
+
+    #[test]
+    fn only_array_places_can_be_sliced() {
+        assert_eq!(
+            error("fn main() {\n    let s = [1, 2, 3][1..]\n}\n"),
+            "main.yul:2:13: semantic error: Cannot slice a temporary array; store it in a variable first"
+        );
+        assert_eq!(
+            error("fn main() {\n    let n = 3\n    let s = n[1..]\n}\n"),
+            "main.yul:3:13: semantic error: Cannot slice a value of type i64"
+        );
+    }
+
+    #[test]
+    fn raw_pointers_are_used_in_unsafe_blocks() {
+        check(
+            "fn main() {
+    let p = alloc<i64>(2)
+    let n = 2
+        * 3
+    unsafe {
+        *p = n
+        *p.offset(1) = *p
+        print(*p.offset(1))
+        free(p)
+    }
+}
+",
+        );
+        assert_eq!(
+            error("fn main() {\n    let p = alloc<i64>(1)\n    print(*p)\n}\n"),
+            "main.yul:3:11: semantic error: Dereferencing the raw pointer type *i64 requires an `unsafe` block"
+        );
+        assert_eq!(
+            error("fn main() {\n    let p = alloc<i64>(true)\n}\n"),
+            "main.yul:2:24: semantic error: The element count of alloc must be i64, found bool"
+        );
+        assert_eq!(
+            error("fn main() {\n    unsafe { free(3) }\n}\n"),
+            "main.yul:2:19: semantic error: free expects a raw pointer from alloc, found i64"
+        );
+    }
+
+    #[test]
+    fn references_need_no_unsafe_but_respect_mutability() {
+        check("fn bump(n: &mut i64) { *n = *n + 1 }\nfn main() {\n    let mut a = 1\n    bump(&mut a)\n    print(a)\n}\n");
+        assert_eq!(
+            error("fn bump(n: &i64) { *n = *n + 1 }\nfn main() {}\n"),
+            "main.yul:1:20: semantic error: Cannot modify a value through the shared reference type &i64; use `&mut`"
+        );
+        assert_eq!(
+            error("fn main() {\n    let a = 1\n    let r = &mut a\n}\n"),
+            "main.yul:3:18: semantic error: Cannot modify `a`, which is not declared mutable; use `let mut a`"
+        );
+        assert_eq!(
+            error("fn main() {\n    let a = 1\n    print(*a)\n}\n"),
+            "main.yul:3:11: semantic error: Cannot dereference a value of type i64"
+        );
+    }
+
+    #[test]
+    fn calls_match_the_callee_signature() {
+        let add = "fn add(a: i64, b: i64) -> i64 {\n    return a + b\n}\n";
+        assert_eq!(
+            error(&format!("{add}fn main() {{\n    print(add(1))\n}}\n")),
+            "main.yul:5:11: semantic error: Function 'add' expects 2 arguments, found 1"
+        );
+        assert_eq!(
+            error(&format!("{add}fn main() {{\n    print(add(1, true))\n}}\n")),
+            "main.yul:5:18: semantic error: Mismatched types for parameter 'b' of 'add': expected i64, found bool"
+        );
+    }
+
+    /// The type of the expression at the last `text` in `source`.
+    fn type_at(source: &str, text: &str) -> String {
+        let (_, types) = check(source);
+        let start = source.rfind(text).unwrap();
+        types.exprs[&Span { start, end: start + text.len() }].to_string()
+    }
+
+    #[test]
+    fn types_are_inferred_from_later_uses() {
+        let source = "fn take(n: u8) {}\nfn main() {\n    let a = 1\n    take(a)\n    print(a)\n}\n";
+        assert_eq!(type_at(source, "a"), "u8");
+        let source = "fn main() {\n    let mut o = Option::None\n    o = Option::Some(true)\n    let p = o\n}\n";
+        assert_eq!(type_at(source, "o"), "Option<bool>");
+    }
+
+    #[test]
+    fn numeric_literals_default_to_i64_and_f64() {
+        assert_eq!(type_at("fn main() {\n    let a = 1\n    print(a)\n}\n", "a"), "i64");
+        assert_eq!(type_at("fn main() {\n    let a = 1.5\n    print(a)\n}\n", "a"), "f64");
+    }
+
+    #[test]
+    fn conflicting_annotations_are_reported() {
+        assert_eq!(
+            error("fn main() {\n    let a = 1\n    let b: u8 = a\n    let c: i32 = a\n}\n"),
+            "main.yul:4:18: semantic error: Mismatched types in `let`: expected i32, found u8"
+        );
+    }
+
+    #[test]
+    fn out_of_range_literals_are_reported() {
+        assert_eq!(
+            error("fn main() {\n    let a = 300\n    let b: u8 = a\n}\n"),
+            "main.yul:2:13: semantic error: Integer literal 300 does not fit in u8"
+        );
+        assert_eq!(
+            error("fn main() {\n    let a: i8 = 128\n}\n"),
+            "main.yul:2:17: semantic error: Integer literal 128 does not fit in i8"
+        );
+    }
+
+    #[test]
+    fn undecided_types_are_reported_with_advice() {
+        assert_eq!(
+            error("fn main() {\n    let o = Option::None\n}\n"),
+            "main.yul:2:13: semantic error: Cannot infer type parameter 'T' of 'Option::None'; give `o` a type, as in \
+             `let o: Option<_> = ...`"
+        );
+    }
+
+    #[test]
+    fn negated_literals_reach_the_minimum() {
+        check(
+            "fn main() {\n    let a = -128i8\n    let b: i64 = -9223372036854775808\n    let c = -128\n    let d: i8 = c\n}\n",
+        );
+        assert_eq!(
+            error("fn main() {\n    let a = -129i8\n}\n"),
+            "main.yul:2:14: semantic error: Integer literal -129 does not fit in i8"
+        );
+        assert_eq!(
+            error("fn main() {\n    let a = -129\n    let b: i8 = a\n}\n"),
+            "main.yul:2:14: semantic error: Integer literal -129 does not fit in i8"
+        );
+    }
+
+    #[test]
+    fn blocks_scope_their_variables() {
+        let source = "fn main() {\n    let x = 1\n    if true {\n        let x = true\n        let y = x\n    }\n    print(x)\n}\n";
+        assert_eq!(type_at(source, "x"), "i64");
+        assert_eq!(
+            error("fn main() {\n    if true {\n        let y = 1\n    }\n    print(y)\n}\n"),
+            "main.yul:5:11: semantic error: Undefined variable: y"
+        );
+        let source = "fn main() {\n    let x = 1\n    {\n        let x = true\n        let y = x\n    }\n    print(x)\n}\n";
+        assert_eq!(type_at(source, "x"), "i64");
+        assert_eq!(
+            error("fn main() {\n    {\n        let y = 1\n    }\n    print(y)\n}\n"),
+            "main.yul:5:11: semantic error: Undefined variable: y"
+        );
+    }
+
+    #[test]
+    fn later_declarations_shadow_earlier_ones() {
+        let source = "fn main() {\n    let x = 1\n    let x = x > 0\n    if x {}\n}\n";
+        assert_eq!(type_at(source, "x"), "bool");
+        check("fn main() {\n    let x = 1\n    let mut x = x\n    x = x + 1\n}\n");
+        assert_eq!(
+            error("fn main() {\n    let mut x = 1\n    let x = x\n    x = 2\n}\n"),
+            "main.yul:4:5: semantic error: Cannot modify `x`, which is not declared mutable; use `let mut x`"
+        );
+    }
+
+    #[test]
+    fn only_mutable_variables_are_assigned() {
+        check("struct P { x: i64 }\nfn main() {\n    let mut p = P { x: 1 }\n    p.x = p.x + 1\n    p = P { x: 0 }\n}\n");
+        assert_eq!(
+            error("fn main() {\n    let x = 1\n    x = x + 1\n}\n"),
+            "main.yul:3:5: semantic error: Cannot modify `x`, which is not declared mutable; use `let mut x`"
+        );
+        assert_eq!(
+            error("struct P { x: i64 }\nfn main() {\n    let p = P { x: 1 }\n    p.x = 2\n}\n"),
+            "main.yul:4:5: semantic error: Cannot modify `p`, which is not declared mutable; use `let mut p`"
+        );
+        assert_eq!(
+            error("fn main() {\n    let mut x = 1\n    x = true\n}\n"),
+            "main.yul:3:9: semantic error: Mismatched types in assignment: expected i64, found bool"
+        );
+    }
+
+    #[test]
+    fn awaiting_a_future_gives_its_output() {
+        let source = "async fn answer(n: i64) -> Option<i64> { return Option::Some(n) }
+async fn run() {
+    let v = await(answer(1))
+    let w = v
+}
+fn main() { spawn(run()) }
+";
+        assert_eq!(type_at(source, "answer(1)"), "Future<Option<i64>>");
+        assert_eq!(type_at(source, "v"), "Option<i64>");
+    }
+
+    #[test]
+    fn await_is_only_allowed_in_async_functions() {
+        assert_eq!(
+            error("async fn answer() -> i64 { return 1 }\nfn main() {\n    let a = await(answer())\n}\n"),
+            "main.yul:3:13: semantic error: `await` is only allowed inside an async function"
+        );
+    }
+
+    #[test]
+    fn only_futures_are_spawned() {
+        assert_eq!(
+            error("fn work() {}\nfn main() {\n    spawn(work())\n}\n"),
+            "main.yul:3:11: semantic error: Cannot spawn a value of type void; `spawn` takes a future, such as a call \
+             of an async function"
+        );
+    }
+
+    #[test]
+    fn futures_that_never_run_are_warned_about() {
+        let source = "async fn work() {}\nfn main() {\n    let f = work()\n    work()\n    spawn(work())\n}\n";
+        let (_, types) = check(source);
+        let warnings: Vec<_> = types.warnings.iter().map(|w| (w.span.start, w.message.as_str())).collect();
+        assert_eq!(
+            warnings,
+            [
+                (source.find("let f").unwrap(), "Future `f` is never awaited or spawned, so it never runs"),
+                (source.rfind("work()\n").unwrap(), "This future is never awaited or spawned, so it never runs"),
+            ]
+        );
+    }
+
+    #[test]
+    fn unsafe_calls_need_unsafe_blocks() {
+        check(
+            "extern fn c_add(a: i64, b: i64) -> i64
+unsafe fn first(p: *i64) -> i64 { return *p + c_add(1, 2) }
+fn main() {
+    let p = alloc<i64>(1)
+    unsafe {
+        print(first(p))
+        free(p)
+    }
+}
+",
+        );
+        assert_eq!(
+            error("extern fn c_add(a: i64, b: i64) -> i64\nfn main() {\n    print(c_add(1, 2))\n}\n"),
+            "main.yul:3:11: semantic error: Calling 'c_add' requires an `unsafe` block, as the compiler cannot check \
+             what an extern function does"
+        );
+        assert_eq!(
+            error("fn main() {\n    let p = alloc<i64>(1)\n    free(p)\n}\n"),
+            "main.yul:3:5: semantic error: Calling free requires an `unsafe` block"
+        );
+        assert_eq!(
+            error("unsafe fn danger() {}\nfn main() {\n    danger()\n}\n"),
+            "main.yul:3:5: semantic error: Calling 'danger' requires an `unsafe` block, as it is declared `unsafe`"
+        );
+    }
+
+    #[test]
+    fn unsafe_functions_are_only_called() {
+        assert_eq!(
+            error("unsafe fn danger() {}\nfn main() {\n    let f = danger\n}\n"),
+            "main.yul:3:13: semantic error: Unsafe function 'danger' can only be called, not used as a value"
+        );
+    }
+
+    #[test]
+    fn async_functions_can_be_unsafe_in_either_order() {
+        check(
+            "async unsafe fn a() {}
+unsafe async fn b() {}
+fn main() {
+    unsafe {
+        spawn(a())
+        spawn(b())
+    }
+}
+",
+        );
+        assert_eq!(
+            error("unsafe async fn b() {}\nfn main() {\n    spawn(b())\n}\n"),
+            "main.yul:3:11: semantic error: Calling 'b' requires an `unsafe` block, as it is declared `unsafe`"
+        );
+    }
+
+    #[test]
+    fn forbid_unsafe_rejects_unsafe_blocks_and_functions() {
+        let forbidden = |source| analyze_package(&[("", source)], true).err().unwrap();
+        assert_eq!(
+            forbidden("fn main() {\n    unsafe {}\n}\n"),
+            "main.yul:2:5: semantic error: `unsafe` blocks are not allowed with --forbid-unsafe"
+        );
+        assert_eq!(
+            forbidden("unsafe fn danger() {}\nfn main() {}\n"),
+            "main.yul:1:1: semantic error: Unsafe function 'danger' is not allowed with --forbid-unsafe"
+        );
+    }
+}
@@ -0,0 +1,97 @@
+use crate::ast::Span;
+use std::fmt;
+
+/// A resolved source position; lines and columns are 1-based and columns
+/// count characters, not bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SourceFile {
+    pub name: String,
+    pub source: String,
+    /// Offset of this file's first byte in the combined span space.
+    pub start: usize,
+    line_starts: Vec<usize>,
+}
+
+/// All source files of a compilation. Each file occupies its own range of
+/// byte offsets so a `Span` identifies both the file and the position in it.
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a file and returns the offset its spans start at.
+    pub fn add_file(&mut self, name: impl Into<String>, source: impl Into<String>) -> usize {
+        let source = source.into();
+        // Leave a one-byte gap so an end-of-file span never touches the next file.
+        let start = self.files.last().map_or(0, |f| f.start + f.source.len() + 1);
+        let line_starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        self.files.push(SourceFile { name: name.into(), source, start, line_starts });
+        start
+    }
+
+    pub fn file(&self, offset: usize) -> Option<&SourceFile> {
+        self.files.iter().rev().find(|f| f.start <= offset)
+    }
+
+    pub fn lookup(&self, offset: usize) -> Location {
+        let Some(file) = self.file(offset) else {
+            return Location { file: "<unknown>".to_string(), line: 1, column: 1 };
+        };
+        let local = (offset - file.start).min(file.source.len());
+        let line = file.line_starts.partition_point(|&s| s <= local) - 1;
+        let line_start = file.line_starts[line];
+        let column = file.source[line_start..local].chars().count() + 1;
+        Location { file: file.name.clone(), line: line + 1, column }
+    }
+
+    pub fn location(&self, span: Span) -> Location {
+        self.lookup(span.start)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(file: &str, line: usize, column: usize) -> Location {
+        Location { file: file.to_string(), line, column }
+    }
+
+    #[test]
+    fn lookup_across_files() {
+        let mut sources = SourceMap::new();
+        let a = sources.add_file("a.yul", "fn a() {\n    x\n}");
+        let b = sources.add_file("b.yul", "\nfn é() {}\n");
+        assert_eq!((a, b), (0, 17));
+        assert_eq!(sources.lookup(0), at("a.yul", 1, 1));
+        assert_eq!(sources.lookup(13), at("a.yul", 2, 5));
+        // The last byte of a file, and the one-byte gap after it.
+        assert_eq!(sources.lookup(15), at("a.yul", 3, 1));
+        assert_eq!(sources.lookup(16), at("a.yul", 3, 2));
+        assert_eq!(sources.lookup(b), at("b.yul", 1, 1));
+        assert_eq!(sources.lookup(b + 1), at("b.yul", 2, 1));
+        // Columns count characters: `é` takes two bytes.
+        assert_eq!(sources.lookup(b + 6), at("b.yul", 2, 5));
+        assert_eq!(sources.location(Span::new(b + 12, b + 12)), at("b.yul", 3, 1));
+    }
+}
@@ -0,0 +1,93 @@
+use crate::ast::Program;
+use crate::cli::{YulcCli, YulcCommand};
+use crate::error::YulcError;
+use crate::ir::IrModule;
+use crate::lexer::lex_at;
+use crate::parser::Parser;
+use crate::resolve::{self, Module};
+use crate::semantic::{self, TypeTable};
+use crate::source_map::SourceMap;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Runs the front end on a package, like `yulc build` does up to type
+/// checking, and gives the first error as `yulc` prints it. `files` are the
+/// modules' paths and sources, the root module (path `""`) first; each file
+/// is named after its path, as in `a/b.yul` for `a::b`.
+pub fn analyze_package(files: &[(&str, &str)], forbid_unsafe: bool) -> Result<(Program, TypeTable), String> {
+    let mut sources = SourceMap::new();
+    let mut modules = Vec::new();
+    let mut errors = Vec::new();
+    for (path, source) in files {
+        let name = if path.is_empty() { "main.yul".to_string() } else { format!("{}.yul", path.replace("::", "/")) };
+        let start = sources.add_file(name, *source);
+        let tokens = lex_at(source, start);
+        let mut parser = Parser::new(&tokens);
+        let program = parser.parse_program();
+        errors.extend_from_slice(parser.errors());
+        let path = path.split("::").filter(|s| !s.is_empty()).map(String::from).collect();
+        modules.push(Module { path, program });
+    }
+    if !errors.is_empty() {
+        return Err(YulcError::parse(&errors, &sources).to_string());
+    }
+    let mut program = resolve::resolve(modules).map_err(|e| YulcError::semantic(e, &sources).to_string())?;
+    crate::prelude::add_to(&mut program, &mut sources);
+    crate::macro_expand::expand_macros(&mut program, &mut sources)
+        .map_err(|e| YulcError::semantic(e, &sources).to_string())?;
+    let types = semantic::analyze_program(&program, forbid_unsafe)
+        .map_err(|e| YulcError::semantic(e, &sources).to_string())?;
+    Ok((program, types))
+}
+
+/// `analyze_package` for a program of one file, `main.yul`.
+pub fn analyze(source: &str) -> Result<(Program, TypeTable), String> {
+    analyze_package(&[("", source)], false)
+}
+
+/// The program and its types, after checking that `source` has no errors.
+pub fn check(source: &str) -> (Program, TypeTable) {
+    analyze(source).unwrap_or_else(|err| panic!("{}", err))
+}
+
+/// The first error in `source`, which must have one.
+pub fn error(source: &str) -> String {
+    match analyze(source) {
+        Ok(_) => panic!("expected an error in:\n{}", source),
+        Err(err) => err,
+    }
+}
+
+/// The monomorphized IR of `source`, which must have no errors.
+pub fn lower(source: &str) -> IrModule {
+    let (program, types) = check(source);
+    crate::mono::monomorphize(&crate::irgen::lower_program(&program, &types)).unwrap()
+}
+
+/// What the program `source` prints when `yulc build` compiles it and it
+/// runs, which must succeed.
+pub fn run(source: &str) -> String {
+    static BUILDS: AtomicUsize = AtomicUsize::new(0);
+    let n = BUILDS.fetch_add(1, Ordering::Relaxed);
+    let dir = std::env::temp_dir().join(format!("yulc-run-{}-{}", std::process::id(), n));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (file, output) = (dir.join("main.yul"), dir.join("main"));
+    std::fs::write(&file, source).unwrap();
+    let build = YulcCommand::Build {
+        file: file.display().to_string(),
+        release: false,
+        target: None,
+        output: Some(output.display().to_string()),
+        forbid_unsafe: false,
+        hints: false,
+    };
+    let built = YulcCli { command: build }.run();
+    let ran = built.as_ref().ok().map(|_| Command::new(&output).output().unwrap());
+    std::fs::remove_dir_all(&dir).unwrap();
+    if let Err(err) = built {
+        panic!("{}", err);
+    }
+    let ran = ran.unwrap();
+    assert!(ran.status.success(), "{} exited with {}", source, ran.status);
+    String::from_utf8(ran.stdout).unwrap()
+}
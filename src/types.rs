@@ -0,0 +1,266 @@
+/// Integer types, narrowest first within each signedness.
+pub const INT_TYPES: [&str; 8] = ["i8", "i16", "i32", "i64", "u8", "u16", "u32", "u64"];
+
+pub const FLOAT_TYPES: [&str; 2] = ["f32", "f64"];
+
+/// The built-in generic type `Future<T>` of what calling an async function
+/// with return type `T` gives; awaiting it runs the function's body.
+pub const FUTURE: &str = "Future";
+
+pub fn is_integer(ty: &str) -> bool {
+    INT_TYPES.contains(&ty)
+}
+
+pub fn is_signed(ty: &str) -> bool {
+    is_integer(ty) && ty.starts_with('i')
+}
+
+pub fn is_float(ty: &str) -> bool {
+    FLOAT_TYPES.contains(&ty)
+}
+
+pub fn is_numeric(ty: &str) -> bool {
+    is_integer(ty) || is_float(ty)
+}
+
+/// Built-in scalar types plus `string`.
+pub fn is_primitive(ty: &str) -> bool {
+    is_numeric(ty) || matches!(ty, "bool" | "char" | "string")
+}
+
+/// Whether `ty` names a type the language defines, which programs cannot
+/// define again.
+pub fn is_builtin(ty: &str) -> bool {
+    is_primitive(ty) || ty == FUTURE
+}
+
+/// Width in bits of an integer type.
+pub fn int_bits(ty: &str) -> u32 {
+    ty[1..].parse().expect("not an integer type")
+}
+
+/// Largest value representable by an integer type.
+pub fn int_max(ty: &str) -> u64 {
+    let bits = int_bits(ty);
+    if is_signed(ty) {
+        (1u64 << (bits - 1)) - 1
+    } else if bits == 64 {
+        u64::MAX
+    } else {
+        (1u64 << bits) - 1
+    }
+}
+
+/// Whether the integer literal `value`, or its negation if `negated`, is a
+/// value of the integer type `ty`.
+pub fn int_fits(value: u64, negated: bool, ty: &str) -> bool {
+    if negated && is_signed(ty) {
+        value <= int_max(ty) + 1
+    } else {
+        value <= int_max(ty)
+    }
+}
+
+/// A resolved type as seen by semantic analysis, IR and codegen.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Type {
+    /// A primitive (`i32`, `bool`, `string`, ...), a user-defined struct or
+    /// enum, or a type parameter inside a generic definition.
+    Named(String),
+    /// An instance of a generic struct or enum such as `Option<i64>`.
+    Generic(String, Vec<Type>),
+    /// `*T`: a raw pointer to a `T`, read and written through only in
+    /// `unsafe` code.
+    Pointer(Box<Type>),
+    /// `&T`, or `&mut T` when the flag is set: a reference to a `T`, which
+    /// only a `&mut` may write through.
+    Ref(Box<Type>, bool),
+    /// `[T; N]`: `N` values of type `T` stored one after another.
+    Array(Box<Type>, u64),
+    /// `[T]`: the address of a run of `T` values and how many there are.
+    Slice(Box<Type>),
+    /// `dyn Trait`: the address of a value of any type that implements the
+    /// trait, whose methods are called through that type's vtable.
+    Dyn(String),
+    /// `fn(A, ...) -> R`: the address of a function; `R` is `Void` for
+    /// functions without a return type.
+    FnPtr(Vec<Type>, Box<Type>),
+    /// `Fn(A, ...) -> R`: a function together with the variables it
+    /// captured, as made by a closure.
+    Closure(Vec<Type>, Box<Type>),
+    /// What calls to functions without a return type produce.
+    Void,
+    /// A type that inference within a function body has yet to work out.
+    /// Semantic analysis replaces every one before lowering.
+    Var(u32),
+}
+
+impl Type {
+    pub fn named(name: impl Into<String>) -> Self {
+        Type::Named(name.into())
+    }
+
+    pub fn pointer(pointee: Type) -> Self {
+        Type::Pointer(Box::new(pointee))
+    }
+
+    /// What a raw pointer or reference points to.
+    pub fn pointee(&self) -> Option<&Type> {
+        match self {
+            Type::Pointer(pointee) | Type::Ref(pointee, _) => Some(pointee),
+            _ => None,
+        }
+    }
+
+    /// Whether using a value of this type copies it instead of moving it:
+    /// true for all but structs, enums, type parameters and arrays of them.
+    pub fn is_copy(&self) -> bool {
+        match self {
+            Type::Named(name) => is_primitive(name),
+            Type::Generic(..) => false,
+            Type::Array(elem, _) => elem.is_copy(),
+            _ => true,
+        }
+    }
+
+    /// The declared struct or enum `name` applied to `args`, which are
+    /// empty for a non-generic one.
+    pub fn instance(name: impl Into<String>, args: Vec<Type>) -> Self {
+        if args.is_empty() {
+            Type::Named(name.into())
+        } else {
+            Type::Generic(name.into(), args)
+        }
+    }
+
+    /// `Future<output>`.
+    pub fn future(output: Type) -> Self {
+        Type::Generic(FUTURE.to_string(), vec![output])
+    }
+
+    /// What awaiting a value of this type gives, if it is a future.
+    pub fn future_output(&self) -> Option<&Type> {
+        match self {
+            Type::Generic(name, args) if name == FUTURE => args.first(),
+            _ => None,
+        }
+    }
+
+    /// Whether this is the named type `name`.
+    pub fn is(&self, name: &str) -> bool {
+        matches!(self, Type::Named(n) if n == name)
+    }
+
+    pub fn as_named(&self) -> Option<&str> {
+        match self {
+            Type::Named(name) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Name of the declaration a named or generic type refers to.
+    pub fn decl_name(&self) -> Option<&str> {
+        match self {
+            Type::Named(name) | Type::Generic(name, _) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Type arguments of a generic instance; empty for other types.
+    pub fn type_args(&self) -> &[Type] {
+        match self {
+            Type::Generic(_, args) => args,
+            _ => &[],
+        }
+    }
+
+    /// Replaces each type parameter in `params` with the matching type in `args`.
+    pub fn substitute(&self, params: &[String], args: &[Type]) -> Type {
+        match self {
+            Type::Named(name) => match params.iter().position(|p| p == name) {
+                Some(i) => args[i].clone(),
+                None => self.clone(),
+            },
+            Type::Generic(name, inner) => {
+                Type::Generic(name.clone(), inner.iter().map(|t| t.substitute(params, args)).collect())
+            }
+            Type::Pointer(pointee) => Type::pointer(pointee.substitute(params, args)),
+            Type::Ref(pointee, mutable) => Type::Ref(Box::new(pointee.substitute(params, args)), *mutable),
+            Type::Array(elem, len) => Type::Array(Box::new(elem.substitute(params, args)), *len),
+            Type::Slice(elem) => Type::Slice(Box::new(elem.substitute(params, args))),
+            Type::FnPtr(fn_params, ret) => Type::FnPtr(
+                fn_params.iter().map(|t| t.substitute(params, args)).collect(),
+                Box::new(ret.substitute(params, args)),
+            ),
+            Type::Closure(fn_params, ret) => Type::Closure(
+                fn_params.iter().map(|t| t.substitute(params, args)).collect(),
+                Box::new(ret.substitute(params, args)),
+            ),
+            Type::Dyn(_) | Type::Void | Type::Var(_) => self.clone(),
+        }
+    }
+
+    /// Whether any of the type parameters in `params` occurs in this type.
+    pub fn mentions(&self, params: &[String]) -> bool {
+        match self {
+            Type::Named(name) => params.contains(name),
+            Type::Generic(_, args) => args.iter().any(|a| a.mentions(params)),
+            Type::Pointer(pointee) | Type::Ref(pointee, _) | Type::Array(pointee, _) | Type::Slice(pointee) => {
+                pointee.mentions(params)
+            }
+            Type::FnPtr(fn_params, ret) | Type::Closure(fn_params, ret) => {
+                fn_params.iter().any(|t| t.mentions(params)) || ret.mentions(params)
+            }
+            Type::Dyn(_) | Type::Void | Type::Var(_) => false,
+        }
+    }
+
+    pub fn is_integer(&self) -> bool {
+        self.as_named().is_some_and(is_integer)
+    }
+
+    pub fn is_signed(&self) -> bool {
+        self.as_named().is_some_and(is_signed)
+    }
+
+    pub fn is_float(&self) -> bool {
+        self.as_named().is_some_and(is_float)
+    }
+
+    pub fn is_numeric(&self) -> bool {
+        self.as_named().is_some_and(is_numeric)
+    }
+
+    pub fn is_primitive(&self) -> bool {
+        self.as_named().is_some_and(is_primitive)
+    }
+}
+
+impl std::fmt::Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Type::Named(name) => write!(f, "{}", name),
+            Type::Generic(name, args) => {
+                let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+                write!(f, "{}<{}>", name, args.join(", "))
+            }
+            Type::Pointer(pointee) => write!(f, "*{}", pointee),
+            Type::Ref(pointee, false) => write!(f, "&{}", pointee),
+            Type::Ref(pointee, true) => write!(f, "&mut {}", pointee),
+            Type::Array(elem, len) => write!(f, "[{}; {}]", elem, len),
+            Type::Slice(elem) => write!(f, "[{}]", elem),
+            Type::Dyn(name) => write!(f, "dyn {}", name),
+            Type::FnPtr(params, ret) | Type::Closure(params, ret) => {
+                let params: Vec<String> = params.iter().map(|p| p.to_string()).collect();
+                let keyword = if matches!(self, Type::FnPtr(..)) { "fn" } else { "Fn" };
+                write!(f, "{}({})", keyword, params.join(", "))?;
+                match **ret {
+                    Type::Void => Ok(()),
+                    ref ret => write!(f, " -> {}", ret),
+                }
+            }
+            Type::Void => write!(f, "void"),
+            Type::Var(_) => write!(f, "_"),
+        }
+    }
+}